@@ -0,0 +1,121 @@
+// Nearest-driver lookups: the in-memory grid index against the table scan it
+// replaces.
+//
+//   cargo bench -p logic --bench driver_index
+//
+// Set BENCH_DATABASE_URL to a migrated database to include the Postgres scan.
+// The drivers it inserts live in a test transaction and are never committed.
+use criterion::{ black_box, criterion_group, criterion_main, Criterion };
+use diesel::prelude::*;
+use logic::api::drivers::Driver;
+use logic::api::riders::RideType;
+use logic::services::geoindex::{ DriverIndex, DEFAULT_CELL_KM };
+use logic::services::matching::{
+    nearest_by_scan, nearest_from_index, rank_candidates, vehicle_types_for, DispatchSettings,
+};
+use logic::services::pricing::GeoPoint;
+use uuid::Uuid;
+
+
+const FLEET: usize = 5_000;
+const PICKUP: GeoPoint = GeoPoint { lat: 6.4531, lng: 3.3958, name: None };
+
+// Spread over roughly 40 x 40 km of Lagos, deterministic so runs compare
+fn fleet() -> Vec<Driver> {
+    let mut seed: u64 = 0x5eed;
+    let mut next = move || {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (seed >> 33) as f64 / (1u64 << 31) as f64
+    };
+
+    (0..FLEET)
+        .map(|i| Driver {
+            driver_id: Uuid::new_v4(),
+            driver_pubkey: serde_json::json!("pubkey"),
+            name: format!("Driver {}", i),
+            email: format!("bench-{}@drivers.test", Uuid::new_v4()),
+            phone: "+2348000000001".to_string(),
+            status: if i % 4 == 0 { "busy" } else { "available" }.to_string(),
+            driver_location: serde_json::json!({
+                "lat": 6.27 + next() * 0.36,
+                "lng": 3.21 + next() * 0.36,
+                "name": null,
+            }),
+            license_number: None,
+            vehicle_type: if i % 3 == 0 { "Bike" } else { "EV" }.to_string(),
+            driver_response: serde_json::json!({}),
+            vehicle: None,
+            reserved_for: None,
+            reserved_until: None,
+        })
+        .collect()
+}
+
+fn in_memory(c: &mut Criterion) {
+    let drivers = fleet();
+    let index = DriverIndex::from_drivers(DEFAULT_CELL_KM, drivers.clone());
+    let settings = DispatchSettings { radius_km: 5.0, max_candidates: 10 };
+    let ev = vehicle_types_for(&RideType::ASAP);
+
+    let mut group = c.benchmark_group("nearest_10_of_5000");
+    group.bench_function("grid_index", |b| {
+        b.iter(|| index.nearest(black_box(&PICKUP), &ev, settings.radius_km, settings.max_candidates))
+    });
+    // What the scan does once the rows are loaded: parse every location and
+    // measure every distance
+    group.bench_function("rank_all_rows", |b| {
+        b.iter(|| {
+            let available: Vec<Driver> = drivers
+                .iter()
+                .filter(|d| d.status == "available" && ev.contains(&d.vehicle_type))
+                .cloned()
+                .collect();
+            rank_candidates(available, black_box(&PICKUP), &settings)
+        })
+    });
+    group.finish();
+}
+
+fn postgres(c: &mut Criterion) {
+    let Ok(url) = std::env::var("BENCH_DATABASE_URL") else {
+        eprintln!("BENCH_DATABASE_URL not set, skipping the Postgres comparison");
+        return;
+    };
+    let mut conn = PgConnection::establish(&url).expect("bench database");
+    conn.begin_test_transaction().unwrap();
+
+    let drivers = fleet();
+    {
+        use logic::schema::back_drivers::dsl::*;
+        for row in &drivers {
+            diesel::insert_into(back_drivers)
+                .values((
+                    driver_id.eq(row.driver_id),
+                    driver_pubkey.eq(&row.driver_pubkey),
+                    name.eq(&row.name),
+                    email.eq(&row.email),
+                    phone.eq(&row.phone),
+                    status.eq(&row.status),
+                    driver_location.eq(&row.driver_location),
+                    vehicle_type.eq(&row.vehicle_type),
+                    driver_response.eq(&row.driver_response),
+                ))
+                .execute(&mut conn)
+                .unwrap();
+        }
+    }
+    let index = DriverIndex::from_drivers(DEFAULT_CELL_KM, drivers);
+    let settings = DispatchSettings { radius_km: 5.0, max_candidates: 10 };
+
+    let mut group = c.benchmark_group("nearest_10_of_5000_postgres");
+    group.bench_function("table_scan", |b| {
+        b.iter(|| nearest_by_scan(&mut conn, &PICKUP, &RideType::ASAP, &settings).unwrap())
+    });
+    group.bench_function("index_then_confirm", |b| {
+        b.iter(|| nearest_from_index(&mut conn, &index, &PICKUP, &RideType::ASAP, &settings).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, in_memory, postgres);
+criterion_main!(benches);
@@ -1,13 +1,28 @@
-use actix_web::{ web, Scope, HttpResponse,  };
+use actix_web::{ web, Scope, HttpResponse, ResponseError };
 use serde::{ Deserialize, Serialize };
 use diesel::prelude::*;
 use serde_json::Value;
 use crate::db::{ DbPool };
 use crate::services::pricing::{ GeoPoint };
 use uuid::Uuid;
+use std::time::Instant;
+use crate::api::auth::{ Permission, Principal, Role };
+use crate::services::ratelimit::rate_limiter;
+use crate::services::escrow::{ self, PaymentEvent };
+use crate::services::surge;
+use crate::services::traffic::{ self, EtaReport, TrafficFactor };
+use crate::services::tariffs::{
+    self, create_tariff, save_zone, tariff_history, NewTariff, ServiceZone, Tariff, TariffBook, TariffError,
+};
+use crate::api::riders::RideType;
+use diesel::pg::PgConnection;
+use crate::services::roles::{ grant_role, revoke_role, role_history, roles_of, RoleAction, RoleAudit, RoleError };
 use crate::api::drivers::{ Driver, DriverResponse };
 
-pub async fn admin_dashboard(pool: web::Data<DbPool>) -> HttpResponse {
+pub async fn admin_dashboard(principal: Principal, pool: web::Data<DbPool>) -> HttpResponse {
+    if let Err(e) = principal.require(Permission::ViewAccounts) {
+        return e.error_response();
+    }
     use crate::schema::back_custom_users::dsl::{back_custom_users as riders, *};
     use crate::schema::back_drivers::dsl::{back_drivers as drivers, *};
 
@@ -47,9 +62,13 @@ pub async fn admin_dashboard(pool: web::Data<DbPool>) -> HttpResponse {
 
 
 pub async fn create_rider(
+    principal: Principal,
     pool: web::Data<DbPool>,
     body: web::Json<RiderRequest>,
 ) -> HttpResponse {
+    if let Err(e) = principal.require(Permission::ManageAccounts) {
+        return e.error_response();
+    }
     use crate::schema::back_custom_users::dsl::{back_custom_users as riders, *};
 
     let new_rider = NewRider::new(body.into_inner());
@@ -72,7 +91,10 @@ pub async fn create_rider(
     }
 }
 
-pub async fn get_riders(pool: web::Data<DbPool>) -> HttpResponse {
+pub async fn get_riders(principal: Principal, pool: web::Data<DbPool>) -> HttpResponse {
+    if let Err(e) = principal.require(Permission::ViewAccounts) {
+        return e.error_response();
+    }
     use crate::schema::back_custom_users::dsl::{back_custom_users as riders, *};
 
     let results = web::block({
@@ -101,9 +123,13 @@ pub async fn get_riders(pool: web::Data<DbPool>) -> HttpResponse {
 
 
 pub async fn create_driver(
+    principal: Principal,
     pool: web::Data<DbPool>,
     body: web::Json<DriverRequest>,
 ) -> HttpResponse {
+    if let Err(e) = principal.require(Permission::ManageAccounts) {
+        return e.error_response();
+    }
     use crate::schema::back_drivers::dsl::{back_drivers as drivers, *};
 
     let new_driver = NewDriver::new(body.into_inner());
@@ -127,7 +153,10 @@ pub async fn create_driver(
 }
 
 
-pub async fn get_drivers(pool: web::Data<DbPool>) -> HttpResponse {
+pub async fn get_drivers(principal: Principal, pool: web::Data<DbPool>) -> HttpResponse {
+    if let Err(e) = principal.require(Permission::ViewAccounts) {
+        return e.error_response();
+    }
     use crate::schema::back_drivers::dsl::{back_drivers as drivers, *};
 
     let results = web::block({
@@ -154,6 +183,350 @@ pub async fn get_drivers(pool: web::Data<DbPool>) -> HttpResponse {
     }
 }
 
+#[derive(Deserialize)]
+pub struct RoleChange {
+    pub user_id: Uuid,
+    pub role: Role,
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RoleChangeOutcome {
+    pub user_id: Uuid,
+    /// False if the grant or revoke made no difference
+    pub changed: bool,
+    pub roles: Vec<Role>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UserRoles {
+    pub user_id: Uuid,
+    pub roles: Vec<Role>,
+    pub history: Vec<RoleAudit>,
+}
+
+async fn change_role(
+    principal: Principal,
+    pool: web::Data<DbPool>,
+    body: RoleChange,
+    action: RoleAction,
+) -> HttpResponse {
+    if let Err(e) = principal.require(Permission::ManageRoles) {
+        return e.error_response();
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let result = web::block(move || -> Result<RoleChangeOutcome, RoleError> {
+        let mut conn = pool.get().map_err(|e| RoleError::Db(e.to_string()))?;
+        let changed = match action {
+            RoleAction::Grant => grant_role(&mut conn, body.user_id, body.role, principal.subject, body.reason, now)?,
+            RoleAction::Revoke => revoke_role(&mut conn, body.user_id, body.role, principal.subject, body.reason, now)?,
+        };
+        let roles = roles_of(&mut conn, body.user_id)?;
+        Ok(RoleChangeOutcome { user_id: body.user_id, changed, roles })
+    }).await;
+
+    match result {
+        Ok(Ok(outcome)) => HttpResponse::Ok().json(outcome),
+        Ok(Err(e @ RoleError::NotGrantable(_))) => HttpResponse::UnprocessableEntity().body(e.to_string()),
+        Ok(Err(e @ RoleError::OwnAdminRole)) => HttpResponse::Conflict().body(e.to_string()),
+        Ok(Err(e @ RoleError::Db(_))) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Threadpool error: {}", e)),
+    }
+}
+
+// POST /admin/roles/grant
+pub async fn grant_role_handler(
+    principal: Principal,
+    pool: web::Data<DbPool>,
+    body: web::Json<RoleChange>,
+) -> HttpResponse {
+    change_role(principal, pool, body.into_inner(), RoleAction::Grant).await
+}
+
+// POST /admin/roles/revoke
+pub async fn revoke_role_handler(
+    principal: Principal,
+    pool: web::Data<DbPool>,
+    body: web::Json<RoleChange>,
+) -> HttpResponse {
+    change_role(principal, pool, body.into_inner(), RoleAction::Revoke).await
+}
+
+// GET /admin/roles/{user_id}
+// The roles someone has and every grant and revoke made to them.
+pub async fn user_roles_handler(
+    principal: Principal,
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+) -> HttpResponse {
+    if let Err(e) = principal.require(Permission::ManageRoles) {
+        return e.error_response();
+    }
+
+    let user_id = path.into_inner();
+    let result = web::block(move || -> Result<UserRoles, String> {
+        let mut conn = pool.get().map_err(|e| e.to_string())?;
+        let roles = roles_of(&mut conn, user_id).map_err(|e| e.to_string())?;
+        let history = role_history(&mut conn, user_id).map_err(|e| e.to_string())?;
+        Ok(UserRoles { user_id, roles, history })
+    }).await;
+
+    match result {
+        Ok(Ok(user_roles)) => HttpResponse::Ok().json(user_roles),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Threadpool error: {}", e)),
+    }
+}
+
+// GET /admin/rate-limits
+// Requests let through and turned away per rate limited scope since startup.
+pub async fn rate_limits_handler(principal: Principal) -> HttpResponse {
+    if let Err(e) = principal.require(Permission::ViewAccounts) {
+        return e.error_response();
+    }
+    HttpResponse::Ok().json(rate_limiter().stats(Instant::now()))
+}
+
+#[derive(Deserialize)]
+pub struct TariffFilter {
+    pub ride_type: Option<RideType>,
+    pub zone: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TariffAt {
+    pub ride_type: RideType,
+    pub zone: String,
+    /// Unix seconds, defaults to now
+    pub at: Option<i64>,
+}
+
+fn tariff_error_response(e: TariffError) -> HttpResponse {
+    match e {
+        TariffError::Invalid(_) | TariffError::Backdated => HttpResponse::UnprocessableEntity().body(e.to_string()),
+        TariffError::UnknownZone(_) => HttpResponse::NotFound().body(e.to_string()),
+        TariffError::Duplicate => HttpResponse::Conflict().body(e.to_string()),
+        TariffError::Db(_) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+// Runs an edit to tariffs or zones and reloads what pricing quotes from.
+async fn edit_pricing<T: Send + 'static>(
+    pool: web::Data<DbPool>,
+    edit: impl FnOnce(&mut PgConnection) -> Result<T, TariffError> + Send + 'static,
+) -> Result<T, HttpResponse> {
+    let result = web::block(move || -> Result<T, TariffError> {
+        let mut conn = pool.get().map_err(|e| TariffError::Db(e.to_string()))?;
+        let edited = edit(&mut conn)?;
+        tariffs::reload(&mut conn)?;
+        Ok(edited)
+    }).await;
+
+    match result {
+        Ok(Ok(edited)) => Ok(edited),
+        Ok(Err(e)) => Err(tariff_error_response(e)),
+        Err(e) => Err(HttpResponse::InternalServerError().body(format!("Threadpool error: {}", e))),
+    }
+}
+
+// GET /admin/tariffs?ride_type=ASAP&zone=default
+// Every tariff, newest first, including the ones no longer in effect.
+pub async fn tariffs_handler(
+    principal: Principal,
+    pool: web::Data<DbPool>,
+    query: web::Query<TariffFilter>,
+) -> HttpResponse {
+    if let Err(e) = principal.require(Permission::ViewAccounts) {
+        return e.error_response();
+    }
+
+    let filter = query.into_inner();
+    let result = web::block(move || -> Result<Vec<Tariff>, String> {
+        let mut conn = pool.get().map_err(|e| e.to_string())?;
+        tariff_history(&mut conn, filter.ride_type.as_ref(), filter.zone.as_deref()).map_err(|e| e.to_string())
+    }).await;
+
+    match result {
+        Ok(Ok(history)) => HttpResponse::Ok().json(history),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Threadpool error: {}", e)),
+    }
+}
+
+// GET /admin/tariffs/in-effect?ride_type=ASAP&zone=default&at=1760000000
+// The tariff a quote made at `at` was priced with.
+pub async fn tariff_in_effect_handler(
+    principal: Principal,
+    pool: web::Data<DbPool>,
+    query: web::Query<TariffAt>,
+) -> HttpResponse {
+    if let Err(e) = principal.require(Permission::ViewAccounts) {
+        return e.error_response();
+    }
+
+    let wanted = query.into_inner();
+    let at = wanted.at.unwrap_or_else(|| chrono::Utc::now().timestamp());
+    let result = web::block(move || -> Result<Option<Tariff>, String> {
+        let mut conn = pool.get().map_err(|e| e.to_string())?;
+        let book = TariffBook::load(&mut conn).map_err(|e| e.to_string())?;
+        Ok(book.in_effect(&wanted.ride_type, &wanted.zone, at).cloned())
+    }).await;
+
+    match result {
+        Ok(Ok(Some(tariff))) => HttpResponse::Ok().json(tariff),
+        Ok(Ok(None)) => HttpResponse::NotFound().body("No tariff was in effect then"),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Threadpool error: {}", e)),
+    }
+}
+
+// POST /admin/tariffs
+// A new version of a ride type's rates in a zone, from `effective_from` on.
+pub async fn create_tariff_handler(
+    principal: Principal,
+    pool: web::Data<DbPool>,
+    body: web::Json<NewTariff>,
+) -> HttpResponse {
+    if let Err(e) = principal.require(Permission::ManagePricing) {
+        return e.error_response();
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let new = body.into_inner();
+    match edit_pricing(pool, move |conn| create_tariff(conn, new, principal.subject, now)).await {
+        Ok(tariff) => HttpResponse::Created().json(tariff),
+        Err(response) => response,
+    }
+}
+
+// GET /admin/zones
+pub async fn zones_handler(principal: Principal) -> HttpResponse {
+    if let Err(e) = principal.require(Permission::ViewAccounts) {
+        return e.error_response();
+    }
+    HttpResponse::Ok().json(tariffs::read_book().zones())
+}
+
+// POST /admin/zones
+// Adds a service zone, or moves and resizes one.
+pub async fn save_zone_handler(
+    principal: Principal,
+    pool: web::Data<DbPool>,
+    body: web::Json<ServiceZone>,
+) -> HttpResponse {
+    if let Err(e) = principal.require(Permission::ManagePricing) {
+        return e.error_response();
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let zone = body.into_inner();
+    match edit_pricing(pool, move |conn| save_zone(conn, zone, now)).await {
+        Ok(zone) => HttpResponse::Ok().json(zone),
+        Err(response) => response,
+    }
+}
+
+// GET /admin/surge
+// Zones whose fares are surging, with the demand and supply behind it.
+pub async fn surge_handler(principal: Principal) -> HttpResponse {
+    if let Err(e) = principal.require(Permission::ViewAccounts) {
+        return e.error_response();
+    }
+    HttpResponse::Ok().json(surge::read_board().surging(chrono::Utc::now().timestamp()))
+}
+
+// GET /admin/traffic-factors
+// The factors ETAs are slowed by, per zone and hour of the week.
+pub async fn traffic_factors_handler(principal: Principal) -> HttpResponse {
+    if let Err(e) = principal.require(Permission::ViewAccounts) {
+        return e.error_response();
+    }
+    HttpResponse::Ok().json(traffic::read_table().factors())
+}
+
+// POST /admin/traffic-factors/refit
+// Fits the factors now rather than waiting for the next scheduled fit.
+pub async fn refit_traffic_handler(principal: Principal, pool: web::Data<DbPool>) -> HttpResponse {
+    if let Err(e) = principal.require(Permission::ManagePricing) {
+        return e.error_response();
+    }
+
+    let result = web::block(move || -> Result<Vec<TrafficFactor>, String> {
+        let mut conn = pool.get().map_err(|e| e.to_string())?;
+        traffic::refit(&mut conn, chrono::Utc::now().timestamp()).map_err(|e| e.to_string())
+    }).await;
+
+    match result {
+        Ok(Ok(fitted)) => HttpResponse::Ok().json(fitted),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Threadpool error: {}", e)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EtaReportQuery {
+    pub days: Option<i64>,
+}
+
+// GET /admin/eta-report?days=7
+// Quoted and currently predicted trip times against how long the last
+// `days` of completed trips actually took.
+pub async fn eta_report_handler(
+    principal: Principal,
+    pool: web::Data<DbPool>,
+    query: web::Query<EtaReportQuery>,
+) -> HttpResponse {
+    if let Err(e) = principal.require(Permission::ViewAccounts) {
+        return e.error_response();
+    }
+
+    let days = query.days.unwrap_or(7).clamp(1, 90);
+    let since = chrono::Utc::now().timestamp() - days * 86_400;
+    let result = web::block(move || -> Result<EtaReport, String> {
+        let mut conn = pool.get().map_err(|e| e.to_string())?;
+        let table = traffic::read_table();
+        let samples = traffic::completed_trip_samples(&mut conn, since, table.settings(), &tariffs::read_book())
+            .map_err(|e| e.to_string())?;
+        Ok(traffic::eta_report(&samples, &table, since))
+    }).await;
+
+    match result {
+        Ok(Ok(report)) => HttpResponse::Ok().json(report),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Threadpool error: {}", e)),
+    }
+}
+
+// GET /admin/payment-events/{reference}
+// Paystack deliveries for a reference and what became of each. Only admins
+// see the raw payload.
+pub async fn payment_events_handler(
+    principal: Principal,
+    pool: web::Data<DbPool>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    if let Err(e) = principal.require(Permission::ViewAccounts) {
+        return e.error_response();
+    }
+
+    let reference = path.into_inner();
+    let result = web::block(move || -> Result<Vec<PaymentEvent>, String> {
+        let mut conn = pool.get().map_err(|e| e.to_string())?;
+        escrow::payment_events_for(&mut conn, &reference).map_err(|e| e.to_string())
+    }).await;
+
+    match result {
+        Ok(Ok(events)) if principal.is_admin() => HttpResponse::Ok().json(events),
+        Ok(Ok(events)) => {
+            let events: Vec<PaymentEvent> = events.into_iter().map(PaymentEvent::without_payload).collect();
+            HttpResponse::Ok().json(events)
+        }
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Threadpool error: {}", e)),
+    }
+}
+
 //what admin::routes() returns
 pub fn routes() -> Scope {
     web::scope("/admin")
@@ -162,6 +535,20 @@ pub fn routes() -> Scope {
         .route("/create-riders", web::post().to(create_rider))
         .route("/get-drivers", web::get().to(get_drivers))
         .route("/create-drivers", web::post().to(create_driver))
+        .route("/roles/grant", web::post().to(grant_role_handler))
+        .route("/roles/revoke", web::post().to(revoke_role_handler))
+        .route("/roles/{user_id}", web::get().to(user_roles_handler))
+        .route("/rate-limits", web::get().to(rate_limits_handler))
+        .route("/tariffs", web::get().to(tariffs_handler))
+        .route("/tariffs", web::post().to(create_tariff_handler))
+        .route("/tariffs/in-effect", web::get().to(tariff_in_effect_handler))
+        .route("/zones", web::get().to(zones_handler))
+        .route("/zones", web::post().to(save_zone_handler))
+        .route("/surge", web::get().to(surge_handler))
+        .route("/traffic-factors", web::get().to(traffic_factors_handler))
+        .route("/traffic-factors/refit", web::post().to(refit_traffic_handler))
+        .route("/eta-report", web::get().to(eta_report_handler))
+        .route("/payment-events/{reference}", web::get().to(payment_events_handler))
 }
 
 
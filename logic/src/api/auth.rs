@@ -0,0 +1,530 @@
+use actix_web::{ web, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError, Scope };
+use actix_web::body::{ EitherBody, MessageBody };
+use actix_web::dev::{ Payload, ServiceRequest, ServiceResponse };
+use actix_web::http::{ header, StatusCode };
+use actix_web::middleware::Next;
+use chrono::Utc;
+use diesel::deserialize::{ self, FromSql, FromSqlRow };
+use diesel::expression::AsExpression;
+use diesel::pg::{ Pg, PgValue };
+use diesel::serialize::{ self, IsNull, Output, ToSql };
+use jsonwebtoken::{ decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation };
+use serde::{ Deserialize, Serialize };
+use std::future::{ ready, Ready };
+use std::io::Write;
+use uuid::Uuid;
+use crate::api::trips::TripParty;
+use crate::db::DbPool;
+use crate::schema::sql_types::CustomRoles;
+use crate::services::roles::has_role;
+use crate::services::matching::env_or;
+use crate::services::wallet_login::{
+    challenge_ttl_secs, issue_challenge, redeem_challenge, ChallengeResponse, WalletLoginError,
+};
+
+
+pub const DEFAULT_TOKEN_TTL_SECS: i64 = 3600;
+
+
+/// What a token lets its holder act as, the `custom_roles` Postgres enum.
+/// Riders and drivers have the role with their account, the others are
+/// granted, see `services::roles`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[diesel(sql_type = CustomRoles)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Rider,
+    Driver,
+    Admin,
+    /// Customer support, can look at and act on anyone's rides.
+    Support,
+    /// Businesses booking rides under their own id, like a rider without the app.
+    Partner,
+}
+
+impl Role {
+    pub const ALL: [Role; 5] = [Role::Rider, Role::Driver, Role::Admin, Role::Support, Role::Partner];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Rider => "rider",
+            Role::Driver => "driver",
+            Role::Admin => "admin",
+            Role::Support => "support",
+            Role::Partner => "partner",
+        }
+    }
+
+    /// Rider and driver come with an account and can't be granted.
+    pub fn is_grantable(&self) -> bool {
+        !matches!(self, Role::Rider | Role::Driver)
+    }
+
+    pub fn permissions(&self) -> &'static [Permission] {
+        use Permission::*;
+        match self {
+            Role::Rider | Role::Driver => &[Riders, Drivers, Trips, Sockets, Locations],
+            Role::Partner => &[Riders, Drivers, Trips, Locations],
+            Role::Support => &[Riders, Drivers, Trips, ViewAccounts, ActForOthers],
+            Role::Admin => &Permission::ALL,
+        }
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Role::ALL.into_iter()
+            .find(|role| role.as_str() == s)
+            .ok_or_else(|| format!("Unknown role: {}", s))
+    }
+}
+
+impl ToSql<CustomRoles, Pg> for Role {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<CustomRoles, Pg> for Role {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        std::str::from_utf8(bytes.as_bytes())?.parse().map_err(Into::into)
+    }
+}
+
+/// What a role may do. Each scope in `api::init` needs one, and handlers
+/// still check the caller against the rider or driver a request is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// `/riders`, drivers too since they answer offers there
+    Riders,
+    /// `/drivers`, riders too for the preflight check
+    Drivers,
+    /// `/trips`
+    Trips,
+    /// `/ws`
+    Sockets,
+    /// `/matching`, moving drivers and pickup points
+    Locations,
+    /// `/admin` dashboard and account lists
+    ViewAccounts,
+    /// Onboarding riders and drivers, creating and editing trips directly
+    ManageAccounts,
+    /// Granting and revoking roles
+    ManageRoles,
+    /// Tariffs and service zones
+    ManagePricing,
+    /// Acting for any rider or driver
+    ActForOthers,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 10] = [
+        Permission::Riders,
+        Permission::Drivers,
+        Permission::Trips,
+        Permission::Sockets,
+        Permission::Locations,
+        Permission::ViewAccounts,
+        Permission::ManageAccounts,
+        Permission::ManageRoles,
+        Permission::ManagePricing,
+        Permission::ActForOthers,
+    ];
+}
+
+/// Who is calling, from the token the middleware checked. Handlers take it as
+/// an argument and compare it with the rider or driver the request is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Principal {
+    pub subject: Uuid,
+    pub role: Role,
+}
+
+impl Principal {
+    pub fn new(subject: Uuid, role: Role) -> Self {
+        Self { subject, role }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+
+    pub fn can(&self, permission: Permission) -> bool {
+        self.role.permissions().contains(&permission)
+    }
+
+    pub fn require(&self, permission: Permission) -> Result<(), AuthError> {
+        if self.can(permission) {
+            Ok(())
+        } else {
+            Err(AuthError::Forbidden(format!("The {} role may not do this", self.role.as_str())))
+        }
+    }
+
+    /// The rider themselves (or a partner booking as one), or someone who
+    /// acts for others.
+    pub fn acts_for_rider(&self, rider_id: Uuid) -> Result<(), AuthError> {
+        self.acts_as(Role::Rider, rider_id)
+    }
+
+    /// The driver themselves, or someone who acts for others.
+    pub fn acts_for_driver(&self, driver_id: Uuid) -> Result<(), AuthError> {
+        self.acts_as(Role::Driver, driver_id)
+    }
+
+    pub fn acts_for(&self, party: TripParty, id: Uuid) -> Result<(), AuthError> {
+        match party {
+            TripParty::Rider => self.acts_for_rider(id),
+            TripParty::Driver => self.acts_for_driver(id),
+        }
+    }
+
+    fn acts_as(&self, role: Role, id: Uuid) -> Result<(), AuthError> {
+        let same_role = self.role == role || (role == Role::Rider && self.role == Role::Partner);
+        if self.can(Permission::ActForOthers) || (same_role && self.subject == id) {
+            Ok(())
+        } else {
+            Err(AuthError::Forbidden(format!("Not signed in as {} {}", role.as_str(), id)))
+        }
+    }
+}
+
+impl FromRequest for Principal {
+    type Error = AuthError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<Principal>().copied().ok_or(AuthError::MissingToken))
+    }
+}
+
+/// A principal with the admin role, for routes only admins may call.
+#[derive(Debug, Clone, Copy)]
+pub struct Admin(pub Principal);
+
+impl FromRequest for Admin {
+    type Error = AuthError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let admin = match req.extensions().get::<Principal>().copied() {
+            Some(principal) if principal.is_admin() => Ok(Admin(principal)),
+            Some(_) => Err(AuthError::Forbidden("Admins only".into())),
+            None => Err(AuthError::MissingToken),
+        };
+        ready(admin)
+    }
+}
+
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    MissingToken,
+    InvalidToken(String),
+    Forbidden(String),
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::MissingToken => f.write_str("Missing bearer token"),
+            AuthError::InvalidToken(e) => write!(f, "Invalid token: {}", e),
+            AuthError::Forbidden(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::MissingToken | AuthError::InvalidToken(_) => StatusCode::UNAUTHORIZED,
+            AuthError::Forbidden(_) => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if self.status_code() == StatusCode::UNAUTHORIZED {
+            response.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        response.body(self.to_string())
+    }
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: Uuid,
+    pub role: Role,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IssuedToken {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_at: i64,
+    pub subject: Uuid,
+    pub role: Role,
+}
+
+/// Signs and checks access tokens, HS256 with `JWT_SECRET`. Tokens last
+/// `JWT_TTL_SECS`, an hour by default, and are renewed at `/auth/refresh`.
+#[derive(Clone)]
+pub struct Authenticator {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    ttl_secs: i64,
+}
+
+impl Authenticator {
+    pub fn new(secret: &[u8], ttl_secs: i64) -> Self {
+        Self {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            ttl_secs,
+        }
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        let secret = std::env::var("JWT_SECRET").map_err(|_| "JWT_SECRET must be set".to_string())?;
+        if secret.is_empty() {
+            return Err("JWT_SECRET must not be empty".into());
+        }
+        Ok(Self::new(secret.as_bytes(), env_or("JWT_TTL_SECS", DEFAULT_TOKEN_TTL_SECS)))
+    }
+
+    pub fn issue(&self, principal: Principal, now: i64) -> Result<IssuedToken, AuthError> {
+        let claims = Claims { sub: principal.subject, role: principal.role, iat: now, exp: now + self.ttl_secs };
+        let access_token = encode(&Header::new(Algorithm::HS256), &claims, &self.encoding)
+            .map_err(|e| AuthError::InvalidToken(e.to_string()))?;
+
+        Ok(IssuedToken {
+            access_token,
+            token_type: "Bearer".into(),
+            expires_at: claims.exp,
+            subject: principal.subject,
+            role: principal.role,
+        })
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Principal, AuthError> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = 0;
+        let data = decode::<Claims>(token, &self.decoding, &validation)
+            .map_err(|e| AuthError::InvalidToken(e.to_string()))?;
+        Ok(Principal::new(data.claims.sub, data.claims.role))
+    }
+}
+
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    access_token: Option<String>,
+}
+
+/// Only sockets take the token from the query string. Anywhere else it would
+/// end up in access logs and browser history.
+const QUERY_TOKEN_PREFIX: &str = "/ws/";
+
+// `Authorization: Bearer ..`, or `?access_token=..` for sockets, which
+// browsers can't open with headers.
+fn bearer_token(req: &ServiceRequest) -> Result<Option<String>, AuthError> {
+    if let Some(value) = req.headers().get(header::AUTHORIZATION) {
+        let value = value.to_str().map_err(|_| AuthError::InvalidToken("unreadable header".into()))?;
+        return match value.strip_prefix("Bearer ") {
+            Some(token) => Ok(Some(token.trim().to_string())),
+            None => Err(AuthError::InvalidToken("expected a Bearer token".into())),
+        };
+    }
+
+    if !req.path().starts_with(QUERY_TOKEN_PREFIX) {
+        return Ok(None);
+    }
+    Ok(web::Query::<TokenQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().access_token))
+}
+
+/// Middleware, `.wrap(from_fn(authenticate))`. A request with a valid token
+/// carries its `Principal`, one with a bad token is turned away with 401.
+/// Requests without a token go through, routes that need one ask for a
+/// `Principal` and answer 401 themselves.
+pub async fn authenticate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let Some(authenticator) = req.app_data::<web::Data<Authenticator>>().cloned() else {
+        let response = HttpResponse::InternalServerError().body("Authenticator not configured");
+        return Ok(req.into_response(response).map_into_right_body());
+    };
+
+    match bearer_token(&req).and_then(|token| token.map(|token| authenticator.verify(&token)).transpose()) {
+        Ok(Some(principal)) => {
+            req.extensions_mut().insert(principal);
+        }
+        Ok(None) => {}
+        Err(e) => return Ok(req.into_response(e.error_response()).map_into_right_body()),
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+/// Scope middleware, behind `authenticate`: only callers whose role has
+/// `permission` get through, see `api::init`.
+pub async fn require_permission(
+    permission: Permission,
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let allowed = match req.extensions().get::<Principal>() {
+        Some(principal) => principal.require(permission),
+        None => Err(AuthError::MissingToken),
+    };
+    if let Err(e) = allowed {
+        return Ok(req.into_response(e.error_response()).map_into_right_body());
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+
+#[derive(Deserialize)]
+pub struct IssueTokenRequest {
+    pub subject: Uuid,
+    pub role: Role,
+}
+
+async fn holds_role(pool: web::Data<DbPool>, principal: Principal) -> Result<bool, HttpResponse> {
+    let held = web::block(move || -> Result<bool, String> {
+        let mut conn = pool.get().map_err(|e| e.to_string())?;
+        has_role(&mut conn, principal.subject, principal.role).map_err(|e| e.to_string())
+    })
+    .await;
+
+    match held {
+        Ok(Ok(held)) => Ok(held),
+        Ok(Err(e)) => Err(HttpResponse::InternalServerError().body(format!("DB error: {}", e))),
+        Err(e) => Err(HttpResponse::InternalServerError().body(format!("Task error: {}", e))),
+    }
+}
+
+// POST /auth/refresh
+// A fresh token for whoever holds a valid one, as long as they still have
+// the role, so a revoked role runs out with the token.
+pub async fn refresh_token(
+    principal: Principal,
+    authenticator: web::Data<Authenticator>,
+    pool: web::Data<DbPool>,
+) -> HttpResponse {
+    match holds_role(pool, principal).await {
+        Ok(true) => {}
+        Ok(false) => return AuthError::Forbidden(format!("No longer a {}", principal.role.as_str())).error_response(),
+        Err(response) => return response,
+    }
+    match authenticator.issue(principal, Utc::now().timestamp()) {
+        Ok(token) => HttpResponse::Ok().json(token),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+// POST /auth/issue-token
+// Admins hand out tokens, e.g. to a driver they just onboarded or to
+// someone they granted the support role.
+pub async fn issue_token(
+    _admin: Admin,
+    authenticator: web::Data<Authenticator>,
+    pool: web::Data<DbPool>,
+    body: web::Json<IssueTokenRequest>,
+) -> HttpResponse {
+    let principal = Principal::new(body.subject, body.role);
+    match holds_role(pool, principal).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return HttpResponse::UnprocessableEntity()
+                .body(format!("{} does not have the {} role", principal.subject, principal.role.as_str()));
+        }
+        Err(response) => return response,
+    }
+    match authenticator.issue(principal, Utc::now().timestamp()) {
+        Ok(token) => HttpResponse::Ok().json(token),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ChallengeRequest {
+    pub subject: Uuid,
+    pub role: Role,
+}
+
+#[derive(Deserialize)]
+pub struct WalletLoginRequest {
+    pub nonce: String,
+    /// base58, as the wallet's `signMessage` returns it once encoded
+    pub signature: String,
+}
+
+fn wallet_login_error(e: WalletLoginError) -> HttpResponse {
+    match e {
+        WalletLoginError::UnknownAccount => HttpResponse::NotFound().body(e.to_string()),
+        WalletLoginError::NoWallet => HttpResponse::UnprocessableEntity().body(e.to_string()),
+        WalletLoginError::UnknownNonce
+        | WalletLoginError::Expired
+        | WalletLoginError::Replayed
+        | WalletLoginError::BadSignature => HttpResponse::Unauthorized().body(e.to_string()),
+        WalletLoginError::Db(_) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+// POST /auth/challenge
+// A nonce for the rider's or driver's wallet to sign, see /auth/wallet-login.
+pub async fn wallet_challenge(pool: web::Data<DbPool>, body: web::Json<ChallengeRequest>) -> HttpResponse {
+    let now = Utc::now().timestamp();
+    let result = web::block(move || {
+        let mut conn = pool.get().map_err(|e| WalletLoginError::Db(e.to_string()))?;
+        issue_challenge(&mut conn, body.role, body.subject, now, challenge_ttl_secs())
+    })
+    .await;
+
+    match result {
+        Ok(Ok(challenge)) => HttpResponse::Ok().json(ChallengeResponse::from(&challenge)),
+        Ok(Err(e)) => wallet_login_error(e),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Task error: {}", e)),
+    }
+}
+
+// POST /auth/wallet-login
+// Trades a signed nonce for an access token.
+pub async fn wallet_login(
+    pool: web::Data<DbPool>,
+    authenticator: web::Data<Authenticator>,
+    body: web::Json<WalletLoginRequest>,
+) -> HttpResponse {
+    let now = Utc::now().timestamp();
+    let result = web::block(move || {
+        let mut conn = pool.get().map_err(|e| WalletLoginError::Db(e.to_string()))?;
+        redeem_challenge(&mut conn, &body.nonce, &body.signature, now)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(principal)) => match authenticator.issue(principal, now) {
+            Ok(token) => HttpResponse::Ok().json(token),
+            Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        },
+        Ok(Err(e)) => wallet_login_error(e),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Task error: {}", e)),
+    }
+}
+
+pub fn routes() -> Scope {
+    web::scope("/auth")
+        .route("/refresh", web::post().to(refresh_token))
+        .route("/issue-token", web::post().to(issue_token))
+        .route("/challenge", web::post().to(wallet_challenge))
+        .route("/wallet-login", web::post().to(wallet_login))
+}
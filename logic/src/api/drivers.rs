@@ -1,4 +1,5 @@
-use actix_web::{  web, Scope, HttpResponse };
+use actix_web::middleware::from_fn;
+use actix_web::{  web, Scope, HttpResponse, ResponseError };
 use serde::{ Serialize, Deserialize };
 use diesel::prelude::*;
 use diesel::sql_types::Jsonb;
@@ -7,20 +8,52 @@ use serde_json::to_string;
 use serde_json::Value;
 use crate::db::{ DbPool };
 use diesel::pg::PgConnection;
-use crate::api::riders::{ NewRideRequest, RideType, DRIVER_NOTIFY_CHANNELS };
-use crate::services::{ pricing::{GeoPoint, distance_between, minimum_distance_between_driver_and_pickup}, escrow };
-use tokio::sync::oneshot;
+use crate::api::auth::Principal;
+use crate::api::riders::RideType;
+use crate::api::trips::TripStatus;
+use crate::services::pricing::{ self, GeoPoint };
+use crate::services::matching::{ Candidate, DispatchSettings, nearest_available_drivers };
+use crate::services::geoindex;
+use crate::services::quotes::{ FareQuote, QuoteSigner };
+use crate::services::ratelimit::rate_limit;
+use crate::services::gateway::{ self, Envelope, Peer, ServerMessage };
 use tokio::time::sleep;
 use std::time::Duration;
 
 
+// Values of back_drivers.status. A driver is reserved while a ride request
+// is offered to them, and busy while they have as many ongoing trips as
+// their vehicle can handle.
+pub const DRIVER_AVAILABLE: &str = "available";
+pub const DRIVER_RESERVED: &str = "reserved";
+pub const DRIVER_BUSY: &str = "busy";
+
+// How much longer than its offer a reservation holds, so it only expires on
+// its own when the dispatching worker died mid-offer.
+pub const RESERVATION_MARGIN_SECS: i64 = 10;
+
+/// How long to reserve a driver for an offer that waits `offer_timeout` for
+/// their answer, see `DispatchPlan::offer_timeout`.
+pub const fn reservation_secs(offer_timeout: std::time::Duration) -> i64 {
+    offer_timeout.as_secs() as i64 + RESERVATION_MARGIN_SECS
+}
+
+pub fn max_ongoing_trips(vehicle_type: &str) -> i64 {
+    if vehicle_type == "Bike" { 2 } else { 1 }
+}
+
+
 pub async fn update_driver(
     pool: web::Data<DbPool>,
+    principal: Principal,
     body: web::Json<Driver>,
 ) -> HttpResponse {
     use crate::schema::back_drivers::dsl::{back_drivers as drivers, *};
 
     let driver = body.into_inner();
+    if let Err(e) = principal.acts_for_driver(driver.driver_id) {
+        return e.error_response();
+    }
 
     let result = web::block({
         let pool = pool.clone();
@@ -32,13 +65,18 @@ pub async fn update_driver(
             let filter_value = driver.driver_location.clone();
             let filter_value2 = driver.driver_response.clone();
 
-            diesel::update(drivers.filter(driver_id.eq(driver.driver_id)))
+            let rows = diesel::update(drivers.filter(driver_id.eq(driver.driver_id)))
                 .set(DriverUpdate {
                     driver_location: filter_value,
                     driver_response: filter_value2,
                 })
                 .execute(&mut conn)
-                .map_err(|e| e.to_string())
+                .map_err(|e| e.to_string())?;
+
+            if rows > 0 {
+                geoindex::refresh_driver(&mut conn, driver.driver_id).map_err(|e| e.to_string())?;
+            }
+            Ok(rows)
         }
     }).await;
 
@@ -51,16 +89,116 @@ pub async fn update_driver(
 }
 
 
-pub fn verify_driver_account(connection: &mut PgConnection, driver_id: Uuid) -> Result<Driver, String> {
+/// Takes an available driver (or one whose reservation ran out) for a single
+/// ride request, for `hold_secs`. The check and the write are one UPDATE, so
+/// when two dispatches race for the same driver exactly one of them gets `true`.
+pub fn reserve_driver(
+    connection: &mut PgConnection,
+    driver_uuid: Uuid,
+    ride_request_id: Uuid,
+    now: i64,
+    hold_secs: i64,
+) -> QueryResult<bool> {
+    use crate::schema::back_drivers::dsl::{back_drivers as drivers, *};
+
+    let reserved = diesel::update(
+        drivers
+            .find(driver_uuid)
+            .filter(
+                status.eq(DRIVER_AVAILABLE)
+                    .or(status.eq(DRIVER_RESERVED).and(reserved_until.lt(now))),
+            ),
+    )
+    .set((
+        status.eq(DRIVER_RESERVED),
+        reserved_for.eq(ride_request_id),
+        reserved_until.eq(now + hold_secs),
+    ))
+    .execute(connection)?;
+
+    if reserved == 1 {
+        geoindex::track_status(driver_uuid, DRIVER_RESERVED);
+    }
+    Ok(reserved == 1)
+}
+
+/// Gives the driver back after a reject or timeout. Only the request holding
+/// the reservation can release it.
+pub fn release_driver(
+    connection: &mut PgConnection,
+    driver_uuid: Uuid,
+    ride_request_id: Uuid,
+) -> QueryResult<bool> {
+    use crate::schema::back_drivers::dsl::{back_drivers as drivers, *};
+
+    let released = diesel::update(
+        drivers
+            .find(driver_uuid)
+            .filter(status.eq(DRIVER_RESERVED))
+            .filter(reserved_for.eq(ride_request_id)),
+    )
+    .set((
+        status.eq(DRIVER_AVAILABLE),
+        reserved_for.eq(None::<Uuid>),
+        reserved_until.eq(None::<i64>),
+    ))
+    .execute(connection)?;
+
+    if released == 1 {
+        geoindex::track_status(driver_uuid, DRIVER_AVAILABLE);
+    }
+    Ok(released == 1)
+}
+
+/// The ride request the driver is being offered, going by their reservation.
+/// Any instance can answer for a driver this way, wherever the offer was made.
+pub fn offered_request(connection: &mut PgConnection, driver_uuid: Uuid, now: i64) -> QueryResult<Option<Uuid>> {
+    use crate::schema::back_drivers::dsl::{back_drivers as drivers, *};
+
+    let offered: Option<Option<Uuid>> = drivers
+        .find(driver_uuid)
+        .filter(status.eq(DRIVER_RESERVED))
+        .filter(reserved_until.ge(now))
+        .select(reserved_for)
+        .first(connection)
+        .optional()?;
+
+    Ok(offered.flatten())
+}
+
+pub fn release_expired_reservations(connection: &mut PgConnection, now: i64) -> QueryResult<usize> {
     use crate::schema::back_drivers::dsl::{back_drivers as drivers, *};
 
+    let released: Vec<Uuid> = diesel::update(
+        drivers
+            .filter(status.eq(DRIVER_RESERVED))
+            .filter(reserved_until.lt(now)),
+    )
+    .set((
+        status.eq(DRIVER_AVAILABLE),
+        reserved_for.eq(None::<Uuid>),
+        reserved_until.eq(None::<i64>),
+    ))
+    .returning(driver_id)
+    .get_results(connection)?;
+
+    for released_uuid in &released {
+        geoindex::track_status(*released_uuid, DRIVER_AVAILABLE);
+    }
+    Ok(released.len())
+}
+
+pub fn verify_driver_account(connection: &mut PgConnection, driver_uuid: Uuid) -> Result<Driver, String> {
+    use crate::schema::back_drivers::dsl::back_drivers as drivers;
+
     let driver: Driver = drivers
-        .find(&driver_id)
+        .find(driver_uuid)
         .select(Driver::as_select())
         .first::<Driver>(connection)
         .map_err(|e| format!("Driver not found: {}", e))?;
 
-    if driver.status != "available" {
+    // A reserved driver is still answering the offer they were sent
+    if driver.status != DRIVER_AVAILABLE && driver.status != DRIVER_RESERVED {
         return Err("Driver account is not available".into());
     }
 
@@ -68,12 +206,17 @@ pub fn verify_driver_account(connection: &mut PgConnection, driver_id: Uuid) ->
 }
 
 // Driver app calls GET /drivers/notify-driver/{driver_uuid} and holds the connection open.
-// assign_driver sends the ride request into DRIVER_NOTIFY_CHANNELS when it selects this driver.
+// Older apps only: it opens a gateway session and returns on the first ride offered on it,
+// /ws/driver gets the same offers without polling.
 pub async fn notify_driver_handler(
     pool: web::Data<DbPool>,
+    principal: Principal,
     driver_uuid: web::Path<Uuid>,
 ) -> HttpResponse {
     let driver_uuid = driver_uuid.into_inner();
+    if let Err(e) = principal.acts_for_driver(driver_uuid) {
+        return e.error_response();
+    }
 
     let driver_checked = web::block({
         let pool = pool.clone();
@@ -89,13 +232,21 @@ pub async fn notify_driver_handler(
         Err(_) => return HttpResponse::InternalServerError().body("Server error"),
     }
 
-    // Register a oneshot channel for this driver and wait for assign_driver to send a ride
-    let (tx, rx) = oneshot::channel::<NewRideRequest>();
-    DRIVER_NOTIFY_CHANNELS.lock().await.insert(driver_uuid, tx);
+    let mut connection = gateway::connect(Peer::Driver(driver_uuid), None);
 
-    match rx.await {
-        Ok(ride_request) => HttpResponse::Ok().json(ride_request),
-        Err(_) => HttpResponse::InternalServerError().body("Notification channel closed"),
+    let offered = loop {
+        match connection.messages.recv().await {
+            Some(Envelope { message: ServerMessage::RideOffer { ride }, .. }) => break Some(ride),
+            Some(_) => continue,
+            // Replaced by a newer poll or socket
+            None => break None,
+        }
+    };
+    gateway::disconnect(connection.session_id, connection.connection);
+
+    match offered {
+        Some(ride_request) => HttpResponse::Ok().json(*ride_request),
+        None => HttpResponse::InternalServerError().body("Notification channel closed"),
     }
 }
 
@@ -104,7 +255,7 @@ pub fn get_ongoing_trips_count(connection: &mut PgConnection, driver_uuid: Uuid)
 
     let count: i64 = trips
         .filter(driver_id.eq(&driver_uuid))
-        .filter(status.eq("ongoing"))
+        .filter(status.eq_any(TripStatus::ACTIVE))
         .count()
         .get_result(connection)
         .map_err(|e| format!("DB error: {}", e))?;
@@ -114,12 +265,16 @@ pub fn get_ongoing_trips_count(connection: &mut PgConnection, driver_uuid: Uuid)
 
 pub async fn driver_response_handler(
     pool: web::Data<DbPool>,
+    principal: Principal,
     payload: web::Json<DriverResponsePayload>,
 ) -> HttpResponse {
     let payloads = payload.into_inner();
     let driver_response = payloads.response.clone();
     let rider_id = payloads.rider_id;
     let input_driver_id = payloads.driver_id;
+    if let Err(e) = principal.acts_for_driver(input_driver_id) {
+        return e.error_response();
+    }
 
     let result = web::block({
         let driver_id = input_driver_id;
@@ -147,12 +302,12 @@ pub async fn driver_response_handler(
         Err(_) => return HttpResponse::InternalServerError().body("Blocking thread failed"),
     };
 
-    if ctx.driver.vehicle_type == "Bike" && ctx.ongoing_trip_count >= 2 {
+    if ctx.driver.vehicle_type == "Bike" && ctx.ongoing_trip_count >= max_ongoing_trips("Bike") {
         return HttpResponse::BadRequest()
             .body("Bike drivers can only handle 2 ongoing trips");
     }
 
-    if ctx.driver.vehicle_type != "Bike" && ctx.ongoing_trip_count >= 1 {
+    if ctx.driver.vehicle_type != "Bike" && ctx.ongoing_trip_count >= max_ongoing_trips(&ctx.driver.vehicle_type) {
         return HttpResponse::BadRequest()
             .body("Standard drivers can only handle 1 ongoing trip");
     }
@@ -176,73 +331,77 @@ pub async fn driver_response_handler(
 
 pub async fn preflight_check(
     pool: web::Data<DbPool>,
+    signer: web::Data<QuoteSigner>,
+    principal: Principal,
     req: web::Json<RidePreflightRequest>,
 ) -> HttpResponse {
-
-    use crate::schema::back_drivers::dsl::{back_drivers as drivers, *};
+    if let Err(e) = principal.acts_for_rider(req.rider_id) {
+        return e.error_response();
+    }
 
     const MIN_DRIVERS_REQUIRED: usize = 3;
     const MAX_RETRIES: usize = 4;
 
+    let req = req.into_inner();
     let pick_up_point = req.pick_up.clone();
-    let drop_off_point = req.drop_off.clone();
-
-    let distance_km = distance_between(&pick_up_point, &drop_off_point);
-
-    let estimated_time_min =
-        crate::services::pricing::estimated_time_min(distance_km, &req.ride_type);
 
-    let estimated_price = match req.ride_type {
-        RideType::ASAP => crate::services::pricing::calculate_asap(distance_km, estimated_time_min),
-        RideType::ASAPEXPRESS => crate::services::pricing::calculate_express(distance_km, estimated_time_min),
+    let route = pricing::route(&req.pick_up, &req.drop_off, &req.ride_type).await;
+
+    // Quoted whether or not it can be served now, the rider may try anyway
+    let quote = FareQuote::price(
+        req.rider_id,
+        req.ride_type.clone(),
+        req.pick_up,
+        req.drop_off,
+        &route,
+        chrono::Utc::now().timestamp(),
+        signer.ttl_secs(),
+    );
+    let quote_token = match signer.sign(&quote) {
+        Ok(token) => token,
+        Err(e) => {
+            eprintln!("Failed to sign quote: {}", e);
+            return HttpResponse::InternalServerError().body("Internal server error");
+        }
+    };
+    let preflight = |can_serve: bool| RidePreflightResponse {
+        can_serve,
+        estimated_price: quote.estimated_price,
+        distance_km: quote.distance_km,
+        estimated_time_min: quote.estimated_time_min,
+        surge_multiplier: quote.surge_multiplier,
+        surge_expires_at: quote.surge_expires_at,
+        quote_token: quote_token.clone(),
+        quote_expires_at: quote.expires_at,
+        route_polyline: route.polyline.clone(),
+        routed_by: route.source.clone(),
     };
 
-    let vehicle_filter: Vec<String> = match req.ride_type {
-        RideType::ASAP => vec!["EV".to_string()],
-        RideType::ASAPEXPRESS => vec!["Bike".to_string()],
+    // Only need to know there are enough drivers in range, not all of them
+    let settings = DispatchSettings {
+        max_candidates: MIN_DRIVERS_REQUIRED,
+        ..DispatchSettings::for_ride_type(&req.ride_type)
     };
 
     for _ in 0..MAX_RETRIES {
 
         let result = web::block({
             let pool = pool.clone();
-            let vehicle_filter = vehicle_filter.clone();
+            let pick_up_point = pick_up_point.clone();
+            let ride_type = req.ride_type.clone();
 
-            move || -> Result<Vec<Driver>, &'static str> {
+            move || -> Result<Vec<Candidate>, &'static str> {
                 let mut conn = pool.get().map_err(|_| "Failed DB connection")?;
 
-                drivers
-                    .filter(vehicle_type.eq_any(&vehicle_filter))
-                    .filter(status.eq("available"))
-                    .limit(50)
-                    .load::<Driver>(&mut conn)
+                nearest_available_drivers(&mut conn, &pick_up_point, &ride_type, &settings)
                     .map_err(|_| "Query failed")
             }
         }).await;
 
         match result {
-            Ok(Ok(driver_list)) => {
-                let mut nearby_count = 0;
-
-                for driver in driver_list {
-                    let in_range = minimum_distance_between_driver_and_pickup(
-                        pick_up_point.clone(),
-                        driver.location(),
-                    );
-
-                    if in_range {
-                        nearby_count += 1;
-                    }
-
-                    if nearby_count >= MIN_DRIVERS_REQUIRED {
-                        let response = RidePreflightResponse {
-                            can_serve: true,
-                            estimated_price,
-                            distance_km,
-                            estimated_time_min,
-                        };
-                        return HttpResponse::Ok().json(response);
-                    }
+            Ok(Ok(candidates)) => {
+                if candidates.len() >= MIN_DRIVERS_REQUIRED {
+                    return HttpResponse::Ok().json(preflight(true));
                 }
                 sleep(Duration::from_millis(500)).await;
             }
@@ -259,12 +418,7 @@ pub async fn preflight_check(
         }
     }
 
-    HttpResponse::Ok().json(RidePreflightResponse {
-        can_serve: false,
-        estimated_price,
-        distance_km,
-        estimated_time_min,
-    })
+    HttpResponse::Ok().json(preflight(false))
 }
 
 
@@ -273,7 +427,11 @@ pub fn routes() -> Scope {
         .route("/notify-driver/{driver_uuid}", web::get().to(notify_driver_handler))
         .route("/driver-response", web::post().to(driver_response_handler))
         .route("/update-driver", web::post().to(update_driver))
-        .route("/ride-preflight", web::post().to(preflight_check))
+        .service(
+            web::resource("/ride-preflight")
+                .route(web::post().to(preflight_check))
+                .wrap(from_fn(|req, next| rate_limit("ride-preflight", req, next))),
+        )
 }
 
 #[derive(Deserialize)]
@@ -330,9 +488,23 @@ pub struct RidePreflightRequest {
 #[derive(Serialize, Clone)]
 pub struct RidePreflightResponse {
     pub can_serve: bool,
+    /// Surged already
     pub estimated_price: i64,
     pub distance_km: f64,
-    pub estimated_time_min: i32
+    pub estimated_time_min: i32,
+    /// 1 when the pickup's zone isn't surging
+    pub surge_multiplier: f64,
+    /// Unix seconds, the multiplier may change after this
+    pub surge_expires_at: i64,
+    /// Signed, to send back with `POST /riders/ride-request`, which is then
+    /// priced at `estimated_price`
+    pub quote_token: String,
+    /// Unix seconds, the token isn't accepted from then on
+    pub quote_expires_at: i64,
+    /// Encoded polyline (precision 5) of the way the ride was priced along
+    pub route_polyline: String,
+    /// `osrm`, or `haversine` for a straight line
+    pub routed_by: String,
 }
 
 #[derive(Debug, Queryable, Serialize, Deserialize, Selectable, Clone)]
@@ -353,6 +525,10 @@ pub struct Driver {
 #[diesel(sql_type = diesel::sql_types::Jsonb)]
     pub driver_response: serde_json::Value,   ///treat with Privy
     pub vehicle: Option<String>,
+    #[serde(default)]
+    pub reserved_for: Option<Uuid>,
+    #[serde(default)]
+    pub reserved_until: Option<i64>,
 }
 
 impl Driver {
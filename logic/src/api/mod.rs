@@ -1,14 +1,34 @@
+use actix_web::middleware::from_fn;
 use actix_web::web::ServiceConfig;
+use auth::{ require_permission, Permission };
+use crate::services::ratelimit::rate_limit;
 
 pub mod admin;
+pub mod auth;
 pub mod riders;
 pub mod drivers;
 pub mod trips;
+pub mod ws;
 
 
+// Every scope but /auth needs a token whose role has the scope's permission,
+// see `auth::Role::permissions`. Each scope is rate limited on its own, ahead
+// of the permission check, see `services::ratelimit`.
 pub fn init(cfg: &mut ServiceConfig) {
-    cfg.service(admin::routes())
-       .service(riders::routes())
-       .service(drivers::routes())
-       .service(trips::routes());
+    cfg.service(auth::routes().wrap(from_fn(|req, next| rate_limit("auth", req, next))))
+       .service(admin::routes()
+           .wrap(from_fn(|req, next| require_permission(Permission::ViewAccounts, req, next)))
+           .wrap(from_fn(|req, next| rate_limit("admin", req, next))))
+       .service(riders::routes()
+           .wrap(from_fn(|req, next| require_permission(Permission::Riders, req, next)))
+           .wrap(from_fn(|req, next| rate_limit("riders", req, next))))
+       .service(drivers::routes()
+           .wrap(from_fn(|req, next| require_permission(Permission::Drivers, req, next)))
+           .wrap(from_fn(|req, next| rate_limit("drivers", req, next))))
+       .service(trips::routes()
+           .wrap(from_fn(|req, next| require_permission(Permission::Trips, req, next)))
+           .wrap(from_fn(|req, next| rate_limit("trips", req, next))))
+       .service(ws::routes()
+           .wrap(from_fn(|req, next| require_permission(Permission::Sockets, req, next)))
+           .wrap(from_fn(|req, next| rate_limit("ws", req, next))));
 }
\ No newline at end of file
@@ -1,70 +1,270 @@
-use actix_web::{ get, post, web, Scope, HttpResponse, Responder};
+use actix_web::middleware::from_fn;
+use actix_web::{ get, post, web, Scope, HttpResponse, Responder, ResponseError };
 use serde::{ Deserialize, Serialize };
 use diesel::dsl::sql;
 use serde_json::Value;
-use tokio::sync::{Mutex, oneshot};
-use lazy_static::lazy_static;
+use tokio::sync::{ mpsc, oneshot };
 use diesel::prelude::*;
 use diesel::pg::PgConnection;
 use uuid::Uuid;
-use tokio::time::{ Duration, sleep, timeout };
-use std::collections::HashMap;
+use chrono::Utc;
+use tokio::time::{ Duration, Instant, sleep, timeout_at };
+use std::collections::{ HashMap, HashSet };
+use std::pin::pin;
+use futures_util::future::{ select, Either };
 use crate::db::{ DbPool };
 use crate::api::admin::{ Rider, NewRider };
-use crate::services::{escrow, pricing::{ GeoPoint, minimum_distance_between_driver_and_pickup, distance_between }};
-use crate::services::pricing;
+use crate::api::auth::Principal;
+use crate::services::{gateway, pricing::GeoPoint};
+use crate::services::matching::{ Candidate, DispatchSettings, env_or, nearest_available_drivers };
+use crate::services::quotes::{ FareQuote, QuoteError, QuoteSigner };
+use crate::services::ratelimit::rate_limit;
+use crate::services::offers::{ offer_history, record_offer, settle_offer, withdraw_pending_offers, OfferOutcome, RideOffer };
 use crate::services::notifications::calculate_eta;
-use crate::api::drivers::{ DriverResponse, DriverResponsePayloadOut, DriverInfo, Driver };
+use crate::api::drivers::{
+    DriverResponse, DriverResponsePayloadOut, DriverInfo, offered_request, reserve_driver, release_driver,
+    release_expired_reservations, reservation_secs, Driver,
+};
+use crate::api::trips::{
+    CancelReason, CancelTripRequest, CancellationPolicy, Trip, TripError, TripParty, cancel_trip,
+    create_trip_for_request, notify_cancellation, trip_error_response,
+};
 
 
-lazy_static! {
-    pub static ref DRIVER_RESPONSES: Mutex<HashMap<Uuid, oneshot::Sender<DriverResponse>>> = Mutex::new(HashMap::new());
-    pub static ref DRIVER_NOTIFY_CHANNELS: Mutex<HashMap<Uuid, oneshot::Sender<NewRideRequest>>> = Mutex::new(HashMap::new());
+const DEFAULT_DISPATCH_ROUNDS: u32 = 4;
+const DEFAULT_FAN_OUT: usize = 3;
+const DEFAULT_OFFER_TIMEOUT_SECS: u64 = 50;
+
+
+/// How a round of dispatch hands the ride out to the drivers it found.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DispatchStrategy {
+    /// One driver at a time, nearest first.
+    Sequential,
+    /// Up to `fan_out` drivers at once, the first to accept gets the ride.
+    Broadcast { fan_out: usize },
+}
+
+impl DispatchStrategy {
+    /// `sequential` or `broadcast`, case insensitive.
+    pub fn parse(name: &str, fan_out: usize) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "sequential" => Some(Self::Sequential),
+            "broadcast" => Some(Self::Broadcast { fan_out }),
+            _ => None,
+        }
+    }
+
+    pub fn offers_at_once(&self) -> usize {
+        match self {
+            Self::Sequential => 1,
+            Self::Broadcast { fan_out } => (*fan_out).max(1),
+        }
+    }
+}
+
+/// Rounds of dispatch for one ride request. Round 1 searches the ride type's
+/// radius and every later round reaches `ring_km` further, skipping drivers
+/// already asked. Read from `DISPATCH_STRATEGY`, `DISPATCH_FAN_OUT`,
+/// `DISPATCH_ROUNDS`, `DISPATCH_RING_KM` and `DISPATCH_OFFER_TIMEOUT_SECS`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DispatchPlan {
+    pub strategy: DispatchStrategy,
+    pub rounds: u32,
+    pub ring_km: f64,
+    pub offer_timeout: Duration,
+}
+
+impl DispatchPlan {
+    pub fn from_env(base: &DispatchSettings) -> Self {
+        let fan_out = env_or("DISPATCH_FAN_OUT", DEFAULT_FAN_OUT);
+        let strategy = std::env::var("DISPATCH_STRATEGY")
+            .ok()
+            .and_then(|name| DispatchStrategy::parse(&name, fan_out))
+            .unwrap_or(DispatchStrategy::Broadcast { fan_out });
+
+        Self {
+            strategy,
+            rounds: env_or("DISPATCH_ROUNDS", DEFAULT_DISPATCH_ROUNDS).max(1),
+            ring_km: env_or("DISPATCH_RING_KM", base.radius_km),
+            offer_timeout: Self::offer_timeout(),
+        }
+    }
+
+    /// How long a batch of offers waits for answers, the longest a dispatch
+    /// goes without an offer being made or settled.
+    pub fn offer_timeout() -> Duration {
+        Duration::from_secs(env_or("DISPATCH_OFFER_TIMEOUT_SECS", DEFAULT_OFFER_TIMEOUT_SECS))
+    }
+
+    /// Search radius for `round`, counting from 1.
+    pub fn radius_for_round(&self, base: &DispatchSettings, round: u32) -> f64 {
+        base.radius_km + self.ring_km * round.saturating_sub(1) as f64
+    }
+}
+
+
+/// Where the background dispatch of a ride request stands.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DispatchStatus {
+    Searching,
+    Assigned,
+    NoDriversFound,
+    Cancelled,
+}
+
+impl DispatchStatus {
+    pub const ALL: [DispatchStatus; 4] = [
+        DispatchStatus::Searching,
+        DispatchStatus::Assigned,
+        DispatchStatus::NoDriversFound,
+        DispatchStatus::Cancelled,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DispatchStatus::Searching => "searching",
+            DispatchStatus::Assigned => "assigned",
+            DispatchStatus::NoDriversFound => "no_drivers_found",
+            DispatchStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl std::fmt::Display for DispatchStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for DispatchStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DispatchStatus::ALL
+            .into_iter()
+            .find(|candidate| candidate.as_str() == s)
+            .ok_or_else(|| format!("Unknown dispatch status: {}", s))
+    }
+}
+
+pub fn dispatch_status_of(connection: &mut PgConnection, ride_request_id: Uuid) -> QueryResult<DispatchStatus> {
+    use crate::schema::back_ride_request::dsl::{back_ride_request as ride_request, dispatch_status};
+
+    let stored: String = ride_request.find(ride_request_id).select(dispatch_status).first(connection)?;
+    stored.parse().map_err(|e: String| diesel::result::Error::DeserializationError(e.into()))
+}
+
+/// Ends the search with `next`. Only one caller gets to: false if the request
+/// had already stopped searching, e.g. was cancelled while a driver accepted.
+pub fn finish_search(connection: &mut PgConnection, ride_request_id: Uuid, next: DispatchStatus) -> QueryResult<bool> {
+    use crate::schema::back_ride_request::dsl::{back_ride_request as ride_request, dispatch_status};
+
+    let finished = diesel::update(
+        ride_request
+            .find(ride_request_id)
+            .filter(dispatch_status.eq(DispatchStatus::Searching.as_str())),
+    )
+    .set(dispatch_status.eq(next.as_str()))
+    .execute(connection)?;
+
+    Ok(finished == 1)
+}
+
+/// Calls off a request that is still searching: records why, settles its
+/// pending offers as withdrawn and releases the drivers they reserved. Returns
+/// those drivers, whose apps still show the offer.
+pub fn cancel_search(
+    connection: &mut PgConnection,
+    ride_request_id: Uuid,
+    reason: CancelReason,
+    now: i64,
+) -> Result<Vec<Uuid>, TripError> {
+    use crate::schema::back_ride_request::dsl::{back_ride_request as ride_request, cancel_reason, cancelled_at};
+
+    connection.transaction(|conn| {
+        // Lost the race against a driver accepting or dispatch giving up
+        if !finish_search(conn, ride_request_id, DispatchStatus::Cancelled)? {
+            return Err(TripError::RequestClosed);
+        }
+        diesel::update(ride_request.find(ride_request_id))
+            .set((cancel_reason.eq(reason.as_str()), cancelled_at.eq(now)))
+            .execute(conn)?;
+
+        let reserved = withdraw_pending_offers(conn, ride_request_id, now)?;
+        for driver_uuid in &reserved {
+            release_driver(conn, *driver_uuid, ride_request_id)?;
+        }
+        Ok(reserved)
+    })
 }
 
 
 pub async fn assign_driver_handler(
     body: web::Json<NewRideRequest>,
     pool: web::Data<DbPool>,
+    signer: web::Data<QuoteSigner>,
+    principal: Principal,
 ) -> HttpResponse {
-    run_assign_driver(pool, body.into_inner()).await
+    if let Err(e) = principal.acts_for_rider(body.rider_id) {
+        return e.error_response();
+    }
+    let mut ride = body.into_inner();
+
+    // The price is the one the rider was quoted, not whatever the body says
+    let quote = match ride.route() {
+        Ok((pick_up, drop_off, ride_type)) => ride
+            .quote_token
+            .as_deref()
+            .ok_or(QuoteError::Missing)
+            .and_then(|token| signer.verify(token, Utc::now().timestamp()))
+            .and_then(|quote| quote.covers(ride.rider_id, &ride_type, &pick_up, &drop_off).map(|_| quote)),
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    match quote {
+        Ok(quote) => ride.price_with(&quote),
+        Err(e) => return e.error_response(),
+    }
+    run_assign_driver(pool, ride).await
 }
 
 pub async fn run_assign_driver(pool: web::Data<DbPool>, body: NewRideRequest) -> HttpResponse {
 
-    use crate::schema::back_drivers::dsl::{back_drivers as drivers, *};
-
     let pick_up_geo2: GeoPoint = serde_json::from_value(body.pick_up.clone()).expect("Failed to convert pick_up JSON to GeoPoint");
-    let drop_off_geo2: GeoPoint = serde_json::from_value(body.drop_off.clone()).expect("Failed to convert drop_off JSON to GeoPoint");
 
-    let distance_km = pricing::distance_between(&pick_up_geo2, &drop_off_geo2);
     let estimated_time_min = body.estimated_time_min;
     let estimated_arrival: String = calculate_eta(estimated_time_min);
 
     let ride_type2: RideType = serde_json::from_value(body.ride_type.clone())
                                          .expect("Failed to parse ride type");
 
-    let estimated_price: i64 = match &ride_type2 {
-        RideType::ASAP => pricing::calculate_asap(distance_km, estimated_time_min),
-        RideType::ASAPEXPRESS => pricing::calculate_express(distance_km, estimated_time_min),
-    };
+    // As quoted at preflight, surge included
+    let estimated_price: i64 = body.estimated_price;
 
-    let cancel_reasons = vec![
-        "Change of plans".to_string(),
-        "Driver taking too long".to_string(),
-        "Found alternate transport".to_string(),
-        "Incorrect destination".to_string(),
-    ];
+    let cancel_reasons = CancelReason::labels();
 
-    let vehicle_filter = match &ride_type2 {
-        RideType::ASAP => vec!["EV".to_string()],
-        RideType::ASAPEXPRESS => vec!["Bike".to_string()],
-    };
+    // /riders/assign-driver can be called with a request that was never
+    // stored, offers and the trip both need the back_ride_request row
+    if let Err(response) = store_request(pool.clone(), body.clone()).await {
+        return response;
+    }
+    let mut watch = DispatchWatch::new(body.request_id);
+
+    let base = DispatchSettings::for_ride_type(&ride_type2);
+    let plan = DispatchPlan::from_env(&base);
+    let mut offered: HashSet<Uuid> = HashSet::new();
+
+    for round in 1..=plan.rounds {
+        let settings = DispatchSettings {
+            radius_km: plan.radius_for_round(&base, round),
+            // Room for the drivers already asked in an earlier round
+            max_candidates: base.max_candidates + offered.len(),
+        };
 
-    for _ in 1..=4 {
         let available_drivers = web::block({
             let pool = pool.clone();
-            let vehicle_filter = vehicle_filter.clone();
+            let pick_up_geo = pick_up_geo2.clone();
+            let ride_type = ride_type2.clone();
 
             move || {
                 let mut connection = match pool.get() {
@@ -72,96 +272,399 @@ pub async fn run_assign_driver(pool: web::Data<DbPool>, body: NewRideRequest) ->
                     Err(_) => return Err("Failed to get DB connection"),
                 };
 
-                drivers.filter(vehicle_type.eq_any(&vehicle_filter))
-                       .filter(status.eq("available"))
-                       .limit(10)
-                       .select(Driver::as_select())
-                       .load::<Driver>(&mut connection)
-                       .map_err(|_| "DB query error")
+                // Offers from a dispatcher that died are never answered
+                let _ = release_expired_reservations(&mut connection, Utc::now().timestamp());
+
+                nearest_available_drivers(&mut connection, &pick_up_geo, &ride_type, &settings)
+                    .map_err(|_| "DB query error")
             }
         }).await;
 
         match available_drivers {
-            Ok(Ok(list)) if !list.is_empty() => {
-                for driver in list {
-                    let driver_info: DriverInfo = DriverInfo {
-                        name: driver.name.clone(),
-                        phone: driver.phone.clone(),
-                        //rating: driver.rating,
-                        vehicle: driver.vehicle.clone(),
-                        license_number: driver.license_number.clone(),
-                    };
-
-                    let pick_up_geo: GeoPoint = serde_json::from_value(body.pick_up.clone())
-                        .expect("Failed to convert pick_up JSON to GeoPoint");
-
-                    if minimum_distance_between_driver_and_pickup(pick_up_geo, driver.location().clone()) {
-                        let (tx, rx) = oneshot::channel();
-                        DRIVER_RESPONSES.lock().await.insert(driver.driver_id, tx);
-
-                        // Send ride request directly to the driver's waiting long-poll connection
-                        let notified = if let Some(notify_tx) = DRIVER_NOTIFY_CHANNELS.lock().await.remove(&driver.driver_id) {
-                            notify_tx.send(body.clone()).is_ok()
-                        } else {
-                            false
-                        };
-
-                        if notified {
-                            match timeout(Duration::from_secs(50), rx).await {
-                                Ok(Ok(other_driver_response)) => match other_driver_response {
-                                    DriverResponse::Accepted => {
-                                        let ride_assignment = RideAssignment {
-                                            estimated_price,
-                                            estimated_time_min,
-                                            estimated_arrival,
-                                            validation_status: "driver is on his way.".into(),
-                                            driver_assigned: Some(driver_info),
-                                            message: Some("your package will be with you shortly.".into()),
-                                            cancel_ride: Some(cancel_reasons),
-                                        };
-                                        println!("Driver {} accepted ride", driver.driver_id);
-                                        return HttpResponse::Ok().json(ride_assignment);
-                                    }
-                                    DriverResponse::Rejected => {
-                                        println!("Driver {} rejected ride", driver.driver_id);
-                                        continue;
-                                    }
-                                    DriverResponse::Timeout => {
-                                        println!("Driver {} did not respond", driver.driver_id);
-                                        continue;
-                                    }
-                                },
-                                Ok(Err(_recv_error)) => {
-                                    println!("Driver {} channel failed", driver.driver_id);
-                                    continue;
-                                },
-                                Err(_elapsed) => {
-                                    println!("Driver {} timed out", driver.driver_id);
-                                    continue;
-                                },
-                            }
+            Ok(Ok(candidates)) => {
+                // Nearest first, skipping whoever already turned this ride down
+                let fresh: Vec<Candidate> = candidates
+                    .into_iter()
+                    .filter(|c| !offered.contains(&c.driver.driver_id))
+                    .take(base.max_candidates)
+                    .collect();
+
+                if fresh.is_empty() {
+                    // no driver found, wait and widen the search
+                    sleep(Duration::from_millis(500)).await;
+                    continue;
+                }
+
+                for batch in fresh.chunks(plan.strategy.offers_at_once()) {
+                    if !still_searching(pool.clone(), body.request_id).await {
+                        println!("Request {} stopped searching, dispatch ends", body.request_id);
+                        return HttpResponse::Conflict().body("Ride request is no longer searching for a driver");
+                    }
+                    offered.extend(batch.iter().map(|c| c.driver.driver_id));
+
+                    match offer_batch(pool.clone(), &body, batch, round, plan.offer_timeout, &mut watch.aborted).await {
+                        Ok(Some((trip, driver_info))) => {
+                            let ride_assignment = RideAssignment {
+                                trip_reference: Some(trip.reference),
+                                estimated_price,
+                                estimated_time_min,
+                                estimated_arrival,
+                                validation_status: "driver is on his way.".into(),
+                                driver_assigned: Some(driver_info),
+                                message: Some("your package will be with you shortly.".into()),
+                                cancel_ride: Some(cancel_reasons),
+                            };
+                            return HttpResponse::Ok().json(ride_assignment);
+                        }
+                        Ok(None) => continue,
+                        Err(response) => {
+                            end_search(pool.clone(), body.request_id).await;
+                            return response;
                         }
                     }
                 }
             }
-            Ok(Ok(_)) => {
-                // no driver found, wait and retry
-                sleep(Duration::from_millis(500)).await;
-            },
             Ok(Err(inner_err)) => eprintln!("Db error: {}", inner_err),
             Err(e) => {
                 eprintln!("DB error: {}", e);
+                end_search(pool.clone(), body.request_id).await;
                 return HttpResponse::InternalServerError().body("Database query failed");
             },
         }
     }
 
-    HttpResponse::NotFound().body("No suitable driver available after 4 attempts")
+    end_search(pool, body.request_id).await;
+    HttpResponse::NotFound().body(format!("No suitable driver available after {} rounds", plan.rounds))
+}
+
+
+type OfferAnswer = (Uuid, Result<DriverResponse, oneshot::error::RecvError>);
+
+// Offers the ride to every driver in `batch` at once and waits up to `wait`
+// for answers. The first acceptance that turns into a trip wins, everyone
+// still deciding has their offer withdrawn and their reservation released.
+// Every offer is stored first and settled with how it ended. The rider
+// cancelling fires `aborted` and ends the wait straight away.
+async fn offer_batch(
+    pool: web::Data<DbPool>,
+    body: &NewRideRequest,
+    batch: &[Candidate],
+    round: u32,
+    wait: Duration,
+    aborted: &mut oneshot::Receiver<()>,
+) -> Result<Option<(Trip, DriverInfo)>, HttpResponse> {
+    let (answers_tx, mut answers) = mpsc::unbounded_channel::<OfferAnswer>();
+    let mut pending: HashMap<Uuid, (Uuid, DriverInfo)> = HashMap::new();
+
+    for Candidate { driver, .. } in batch {
+        // Another request may have reserved this driver since the list was loaded
+        if !reserve(pool.clone(), driver.driver_id, body.request_id, wait).await {
+            println!("Driver {} is already being offered a ride", driver.driver_id);
+            continue;
+        }
+
+        let Some(offer_id) = record(pool.clone(), body.request_id, driver.driver_id, round, wait).await else {
+            release(pool.clone(), driver.driver_id, body.request_id).await;
+            continue;
+        };
+
+        if !send_offer(body, driver.driver_id, answers_tx.clone()).await {
+            settle(pool.clone(), offer_id, OfferOutcome::Unreachable).await;
+            release(pool.clone(), driver.driver_id, body.request_id).await;
+            continue;
+        }
+
+        pending.insert(driver.driver_id, (offer_id, DriverInfo {
+            name: driver.name.clone(),
+            phone: driver.phone.clone(),
+            //rating: driver.rating,
+            vehicle: driver.vehicle.clone(),
+            license_number: driver.license_number.clone(),
+        }));
+    }
+    drop(answers_tx);
+
+    let deadline = Instant::now() + wait;
+
+    while !pending.is_empty() {
+        let (driver_uuid, answer) = match select(pin!(timeout_at(deadline, answers.recv())), &mut *aborted).await {
+            Either::Left((Ok(Some(answer)), _)) => answer,
+            Either::Left((Ok(None), _)) => break,
+            Either::Left((Err(_elapsed), _)) => {
+                println!("{} driver(s) did not respond in time", pending.len());
+                break;
+            }
+            Either::Right(_) => {
+                println!("Request {} was cancelled, dispatch ends", body.request_id);
+                withdraw_offers(pool, pending, body.request_id, OfferOutcome::Withdrawn).await;
+                return Err(HttpResponse::Conflict().body(TripError::RequestClosed.to_string()));
+            }
+        };
+        let Some((offer_id, driver_info)) = pending.remove(&driver_uuid) else { continue };
+
+        let outcome = match answer {
+            Ok(DriverResponse::Accepted) => {
+                match accept_ride(pool.clone(), body.clone(), driver_uuid).await {
+                    Ok(Ok(trip)) => {
+                        println!("Driver {} accepted ride", driver_uuid);
+                        settle(pool.clone(), offer_id, OfferOutcome::Accepted).await;
+                        gateway::publish_trip_status(&trip);
+                        withdraw_offers(pool.clone(), pending, body.request_id, OfferOutcome::Withdrawn).await;
+                        return Ok(Some((trip, driver_info)));
+                    }
+                    Ok(Err(TripError::DriverUnavailable)) => {
+                        println!("Driver {} accepted but is no longer available", driver_uuid);
+                        OfferOutcome::Unavailable
+                    }
+                    Ok(Err(e @ TripError::RequestClosed)) => {
+                        println!("Driver {} accepted request {} after it stopped searching", driver_uuid, body.request_id);
+                        settle(pool.clone(), offer_id, OfferOutcome::Withdrawn).await;
+                        release(pool.clone(), driver_uuid, body.request_id).await;
+                        withdraw_offers(pool.clone(), pending, body.request_id, OfferOutcome::Withdrawn).await;
+                        return Err(HttpResponse::Conflict().body(e.to_string()));
+                    }
+                    Ok(Err(e)) => {
+                        eprintln!("Failed to create trip for request {}: {}", body.request_id, e);
+                        settle(pool.clone(), offer_id, OfferOutcome::Failed).await;
+                        release(pool.clone(), driver_uuid, body.request_id).await;
+                        withdraw_offers(pool.clone(), pending, body.request_id, OfferOutcome::Withdrawn).await;
+                        return Err(HttpResponse::InternalServerError().body(format!("Failed to create trip: {}", e)));
+                    }
+                    Err(e) => {
+                        eprintln!("Blocking error: {}", e);
+                        settle(pool.clone(), offer_id, OfferOutcome::Failed).await;
+                        release(pool.clone(), driver_uuid, body.request_id).await;
+                        withdraw_offers(pool.clone(), pending, body.request_id, OfferOutcome::Withdrawn).await;
+                        return Err(HttpResponse::InternalServerError().body("Failed to create trip"));
+                    }
+                }
+            }
+            Ok(DriverResponse::Rejected) => {
+                println!("Driver {} rejected ride", driver_uuid);
+                OfferOutcome::Rejected
+            }
+            Ok(DriverResponse::Timeout) => {
+                println!("Driver {} did not respond", driver_uuid);
+                OfferOutcome::TimedOut
+            }
+            Err(_recv_error) => {
+                println!("Driver {} channel failed", driver_uuid);
+                OfferOutcome::Unreachable
+            }
+        };
+        settle(pool.clone(), offer_id, outcome).await;
+        release(pool.clone(), driver_uuid, body.request_id).await;
+    }
+
+    withdraw_offers(pool, pending, body.request_id, OfferOutcome::TimedOut).await;
+    Ok(None)
+}
+
+// Registers the dispatch with the gateway for as long as it runs, so a
+// cancellation reaching any instance can stop it.
+struct DispatchWatch {
+    request_id: Uuid,
+    aborted: oneshot::Receiver<()>,
+}
+
+impl DispatchWatch {
+    fn new(request_id: Uuid) -> Self {
+        Self { request_id, aborted: gateway::watch_dispatch(request_id) }
+    }
+}
+
+impl Drop for DispatchWatch {
+    fn drop(&mut self) {
+        gateway::forget_dispatch(self.request_id);
+    }
+}
+
+// Offers the ride on the driver's gateway session, on whichever instance
+// holds it, and forwards their answer into `answers`. False if no instance
+// could show them the offer.
+async fn send_offer(
+    body: &NewRideRequest,
+    driver_uuid: Uuid,
+    answers: mpsc::UnboundedSender<OfferAnswer>,
+) -> bool {
+    let Some(rx) = gateway::offer_ride(driver_uuid, body).await else { return false };
+
+    tokio::spawn(async move {
+        let _ = answers.send((driver_uuid, rx.await));
+    });
+    true
+}
+
+// The offer has to be gone before the reservation is released, so a late
+// answer can't land on a driver who is free again.
+async fn withdraw_offers(
+    pool: web::Data<DbPool>,
+    drivers: HashMap<Uuid, (Uuid, DriverInfo)>,
+    ride_request_id: Uuid,
+    outcome: OfferOutcome,
+) {
+    for (driver_uuid, (offer_id, _)) in drivers {
+        gateway::withdraw_offer(driver_uuid, ride_request_id);
+        settle(pool.clone(), offer_id, outcome).await;
+        release(pool.clone(), driver_uuid, ride_request_id).await;
+        println!("Offer to driver {} withdrawn", driver_uuid);
+    }
+}
+
+
+async fn store_request(pool: web::Data<DbPool>, ride: NewRideRequest) -> Result<(), HttpResponse> {
+    use crate::schema::back_ride_request::dsl::{back_ride_request as ride_request, quote_id};
+
+    let ride_quote = ride.quote_id;
+    let stored = web::block(move || -> Result<Option<Option<Uuid>>, String> {
+        let mut conn = pool.get().map_err(|e| e.to_string())?;
+        diesel::insert_into(ride_request)
+            .values(&ride)
+            .on_conflict_do_nothing()
+            .execute(&mut conn)
+            .map_err(|e| e.to_string())?;
+        ride_request
+            .find(ride.request_id)
+            .select(quote_id)
+            .first(&mut conn)
+            .optional()
+            .map_err(|e| e.to_string())
+    })
+    .await;
+
+    match stored {
+        // Either this request was stored with its quote already, or nothing
+        // was because another request holds the quote
+        Ok(Ok(Some(stored_quote))) if stored_quote == ride_quote => Ok(()),
+        Ok(Ok(_)) => Err(QuoteError::Used.error_response()),
+        Ok(Err(e)) => Err(HttpResponse::InternalServerError().body(format!("DB error: {}", e))),
+        Err(_) => Err(HttpResponse::InternalServerError().body("Server error")),
+    }
+}
+
+// An offer that can't be stored isn't made, recovery would never see it.
+async fn record(
+    pool: web::Data<DbPool>,
+    ride_request_id: Uuid,
+    driver_uuid: Uuid,
+    round: u32,
+    wait: Duration,
+) -> Option<Uuid> {
+    let recorded = web::block(move || -> Result<Uuid, String> {
+        let mut conn = pool.get().map_err(|e| e.to_string())?;
+        let now = Utc::now().timestamp();
+        let expires = now + wait.as_secs().max(1) as i64;
+        record_offer(&mut conn, ride_request_id, driver_uuid, round, now, expires).map_err(|e| e.to_string())
+    })
+    .await;
+
+    match recorded {
+        Ok(Ok(offer_id)) => Some(offer_id),
+        Ok(Err(e)) => {
+            eprintln!("Failed to record offer to driver {}: {}", driver_uuid, e);
+            None
+        }
+        Err(e) => {
+            eprintln!("Blocking error: {}", e);
+            None
+        }
+    }
+}
+
+async fn settle(pool: web::Data<DbPool>, offer_id: Uuid, outcome: OfferOutcome) {
+    let settled = web::block(move || -> Result<bool, String> {
+        let mut conn = pool.get().map_err(|e| e.to_string())?;
+        settle_offer(&mut conn, offer_id, outcome, Utc::now().timestamp()).map_err(|e| e.to_string())
+    })
+    .await;
+
+    if let Ok(Err(e)) = settled {
+        eprintln!("Failed to record outcome of offer {}: {}", offer_id, e);
+    }
+}
+
+// A request that can't be read is treated as still searching, the trip
+// can't be created for a closed one anyway.
+async fn still_searching(pool: web::Data<DbPool>, ride_request_id: Uuid) -> bool {
+    let current = web::block(move || -> Result<DispatchStatus, String> {
+        let mut conn = pool.get().map_err(|e| e.to_string())?;
+        dispatch_status_of(&mut conn, ride_request_id).map_err(|e| e.to_string())
+    })
+    .await;
+
+    !matches!(current, Ok(Ok(found)) if found != DispatchStatus::Searching)
+}
+
+// Dispatch gave up. Does nothing if the rider cancelled first.
+async fn end_search(pool: web::Data<DbPool>, ride_request_id: Uuid) {
+    let ended = web::block(move || -> Result<bool, String> {
+        let mut conn = pool.get().map_err(|e| e.to_string())?;
+        finish_search(&mut conn, ride_request_id, DispatchStatus::NoDriversFound).map_err(|e| e.to_string())
+    })
+    .await;
+
+    if let Ok(Err(e)) = ended {
+        eprintln!("Failed to end search for request {}: {}", ride_request_id, e);
+    }
+}
+
+
+// Held a little longer than the offer waits, so it never runs out while the
+// driver can still answer.
+async fn reserve(pool: web::Data<DbPool>, driver_uuid: Uuid, ride_request_id: Uuid, wait: Duration) -> bool {
+    let reserved = web::block(move || -> Result<bool, String> {
+        let mut conn = pool.get().map_err(|e| e.to_string())?;
+        reserve_driver(&mut conn, driver_uuid, ride_request_id, Utc::now().timestamp(), reservation_secs(wait))
+            .map_err(|e| e.to_string())
+    })
+    .await;
+
+    match reserved {
+        Ok(Ok(reserved)) => reserved,
+        Ok(Err(e)) => {
+            eprintln!("Failed to reserve driver {}: {}", driver_uuid, e);
+            false
+        }
+        Err(e) => {
+            eprintln!("Blocking error: {}", e);
+            false
+        }
+    }
+}
+
+// A failed release is picked up by release_expired_reservations later on.
+async fn release(pool: web::Data<DbPool>, driver_uuid: Uuid, ride_request_id: Uuid) {
+    let released = web::block(move || -> Result<bool, String> {
+        let mut conn = pool.get().map_err(|e| e.to_string())?;
+        release_driver(&mut conn, driver_uuid, ride_request_id).map_err(|e| e.to_string())
+    })
+    .await;
+
+    if let Ok(Err(e)) = released {
+        eprintln!("Failed to release driver {}: {}", driver_uuid, e);
+    }
+}
+
+
+async fn accept_ride(
+    pool: web::Data<DbPool>,
+    ride: NewRideRequest,
+    driver_uuid: Uuid,
+) -> Result<Result<Trip, TripError>, actix_web::error::BlockingError> {
+    web::block(move || -> Result<Trip, TripError> {
+        let mut conn = pool.get().map_err(|e| TripError::Db(e.to_string()))?;
+        create_trip_for_request(&mut conn, ride.request_id, driver_uuid)
+    })
+    .await
 }
 
 
-// Called by the driver app to submit their accept/reject — feeds assign_driver's oneshot channel
-pub async fn driver_response(payload: web::Json<DriverResponsePayloadOut>) -> impl Responder {
+// Called by the driver app to submit their accept/reject — answers the offer assign_driver is waiting on
+pub async fn driver_response(
+    pool: web::Data<DbPool>,
+    principal: Principal,
+    payload: web::Json<DriverResponsePayloadOut>,
+) -> impl Responder {
     let data = payload.into_inner();
 
     let (driver_id, response) = match data {
@@ -175,23 +678,37 @@ pub async fn driver_response(payload: web::Json<DriverResponsePayloadOut>) -> im
             driver_id,
         } => (driver_id, DriverResponse::Rejected),
     };
-
-    if let Some(tx) = DRIVER_RESPONSES.lock().await.remove(&driver_id) {
-        let _ = tx.send(response);
+    if let Err(e) = principal.acts_for_driver(driver_id) {
+        return e.error_response();
     }
 
-    HttpResponse::Ok().json("Response received")
+    let offered = web::block(move || -> Result<Option<Uuid>, String> {
+        let mut conn = pool.get().map_err(|e| e.to_string())?;
+        offered_request(&mut conn, driver_id, Utc::now().timestamp()).map_err(|e| e.to_string())
+    })
+    .await;
+
+    match offered {
+        Ok(Ok(Some(ride_request_id))) => {
+            gateway::answer_offer(driver_id, ride_request_id, response);
+            HttpResponse::Ok().json("Response received")
+        }
+        // No offer left means the ride went to someone else or the offer timed out
+        Ok(Ok(None)) => HttpResponse::Conflict().json("Ride is no longer on offer"),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+        Err(_) => HttpResponse::InternalServerError().body("Server error"),
+    }
 }
 
 
 pub fn validate_rider_account(
     connection: &mut PgConnection,
-    rider_id: Uuid
+    rider_uuid: Uuid
 ) -> Result<Rider, String> {
-    use crate::schema::back_custom_users::dsl::{back_custom_users as riders, *};
+    use crate::schema::back_custom_users::dsl::back_custom_users as riders;
 
     let rider: Rider = riders
-        .find(rider_id)
+        .find(rider_uuid)
         .select(Rider::as_select())
         .first::<Rider>(connection)
         .map_err(|_| "Rider not found".to_string())?;
@@ -201,16 +718,31 @@ pub fn validate_rider_account(
 
 pub async fn request_ride(
     pool: web::Data<DbPool>,
+    signer: web::Data<QuoteSigner>,
+    principal: Principal,
     body: web::Json<CreateRideRequest>,
 ) -> HttpResponse {
-    use crate::schema::back_ride_request::dsl::{back_ride_request as ride_request, *};
+    use crate::schema::back_ride_request::dsl::{back_ride_request as ride_request, quote_id};
 
     let req = body.into_inner();
     let rider_uuid = req.rider_id;
-    let new_ride_request = NewRideRequest::new(req);
+    if let Err(e) = principal.acts_for_rider(rider_uuid) {
+        return e.error_response();
+    }
+    let quote = req
+        .quote_token
+        .as_deref()
+        .ok_or(QuoteError::Missing)
+        .and_then(|token| signer.verify(token, Utc::now().timestamp()))
+        .and_then(|quote| quote.covers(rider_uuid, &req.ride_type, &req.pick_up, &req.drop_off).map(|_| quote));
+    let new_ride_request = match quote {
+        Ok(quote) => NewRideRequest::new(req, &quote),
+        Err(e) => return e.error_response(),
+    };
 
     let assignment_request = new_ride_request.clone();
     let assignment_pool = pool.clone();
+    let accepted = RideRequestStatus::searching(&new_ride_request);
 
     let result = web::block({
         let pool = pool.clone();
@@ -219,33 +751,270 @@ pub async fn request_ride(
             validate_rider_account(&mut conn, rider_uuid)
                 .map_err(|e| format!("rider validation error: {}", e))?;
 
+            // Nothing inserted means the quote went to another request
             diesel::insert_into(ride_request)
                 .values(new_ride_request)
+                .on_conflict(quote_id)
+                .do_nothing()
                 .execute(&mut conn)
                 .map_err(|e| format!("DB insert error: {}", e))
         }
     }).await;
 
     match result {
-        Ok(Ok(_)) => run_assign_driver(assignment_pool, assignment_request).await,
+        Ok(Ok(0)) => QuoteError::Used.error_response(),
+        Ok(Ok(_)) => {
+            // Dispatch can take minutes, longer than mobile networks keep a
+            // request open. The rider polls GET /riders/ride-request/{id}.
+            let ride_request_id = assignment_request.request_id;
+            actix_web::rt::spawn(async move {
+                let response = run_assign_driver(assignment_pool, assignment_request).await;
+                println!("Dispatch for request {} ended with {}", ride_request_id, response.status());
+            });
+            HttpResponse::Accepted().json(accepted)
+        }
         Ok(Err(db_err)) => HttpResponse::InternalServerError().body(format!("DB error: {}", db_err)),
         Err(block_err) => HttpResponse::InternalServerError().body(format!("Blocking error: {}", block_err)),
     }
 }
 
+#[derive(Deserialize)]
+pub struct RideRequestQuery {
+    pub rider_id: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct CancelRideRequest {
+    pub rider_id: Uuid,
+    pub reason: CancelReason,
+}
+
+// GET /riders/ride-request/{request_id}?rider_id=..
+pub async fn ride_request_status_handler(
+    pool: web::Data<DbPool>,
+    principal: Principal,
+    path: web::Path<Uuid>,
+    query: web::Query<RideRequestQuery>,
+) -> HttpResponse {
+    let ride_request_id = path.into_inner();
+    let rider_uuid = query.rider_id;
+    if let Err(e) = principal.acts_for_rider(rider_uuid) {
+        return e.error_response();
+    }
+
+    let found = web::block(move || -> Result<RideRequestStatus, TripError> {
+        let mut conn = pool.get().map_err(|e| TripError::Db(e.to_string()))?;
+        load_request_status(&mut conn, ride_request_id, rider_uuid)
+    })
+    .await;
+
+    match found {
+        Ok(Ok(current)) => HttpResponse::Ok().json(current),
+        Ok(Err(TripError::NotFound)) => HttpResponse::NotFound().body("Ride request not found"),
+        Ok(Err(e)) => trip_error_response(e),
+        Err(_) => HttpResponse::InternalServerError().body("Server error"),
+    }
+}
+
+// POST /riders/ride-request/{request_id}/cancel
+// While searching, dispatch is stopped wherever it runs and the drivers still
+// deciding get `offer_withdrawn`. Once assigned, the trip is cancelled, with a
+// fee past the grace period, and the driver gets `trip_cancelled`.
+pub async fn cancel_ride_request_handler(
+    pool: web::Data<DbPool>,
+    principal: Principal,
+    path: web::Path<Uuid>,
+    body: web::Json<CancelRideRequest>,
+) -> HttpResponse {
+    let ride_request_id = path.into_inner();
+    let CancelRideRequest { rider_id: rider_uuid, reason } = body.into_inner();
+    if let Err(e) = principal.acts_for_rider(rider_uuid) {
+        return e.error_response();
+    }
+
+    let cancelled = web::block(move || -> Result<(RideRequestStatus, Cancelled), TripError> {
+        let mut conn = pool.get().map_err(|e| TripError::Db(e.to_string()))?;
+        let current = load_request_status(&mut conn, ride_request_id, rider_uuid)?;
+
+        let done = match current.state {
+            RideRequestState::Searching => {
+                let reserved = cancel_search(&mut conn, ride_request_id, reason, Utc::now().timestamp())?;
+                Cancelled::Search(reserved)
+            }
+            RideRequestState::Assigned { trip_reference, .. } => {
+                let request = CancelTripRequest { cancelled_by: TripParty::Rider, actor_id: rider_uuid, reason };
+                Cancelled::Trip(Box::new(cancel_trip(&mut conn, &trip_reference, &request, &CancellationPolicy::from_env())?))
+            }
+            RideRequestState::Cancelled { .. } => return Ok((current, Cancelled::Already)),
+            RideRequestState::NoDriversFound => return Err(TripError::RequestClosed),
+        };
+
+        Ok((load_request_status(&mut conn, ride_request_id, rider_uuid)?, done))
+    })
+    .await;
+
+    match cancelled {
+        Ok(Ok((current, done))) => {
+            match done {
+                Cancelled::Search(reserved) => {
+                    gateway::abort_dispatch(ride_request_id);
+                    for driver_uuid in reserved {
+                        gateway::withdraw_offer(driver_uuid, ride_request_id);
+                    }
+                }
+                Cancelled::Trip(trip) => {
+                    gateway::publish_trip_status(&trip);
+                    notify_cancellation(&trip);
+                }
+                Cancelled::Already => {}
+            }
+            HttpResponse::Ok().json(current)
+        }
+        Ok(Err(TripError::NotFound)) => HttpResponse::NotFound().body("Ride request not found"),
+        Ok(Err(e)) => trip_error_response(e),
+        Err(_) => HttpResponse::InternalServerError().body("Server error"),
+    }
+}
+
+// What a cancellation left for the apps to hear about.
+enum Cancelled {
+    /// The drivers whose offers were still open.
+    Search(Vec<Uuid>),
+    Trip(Box<Trip>),
+    Already,
+}
+
+/// The request as its rider sees it, with the driver and trip once assigned.
+pub fn load_request_status(
+    connection: &mut PgConnection,
+    ride_request_id: Uuid,
+    rider_uuid: Uuid,
+) -> Result<RideRequestStatus, TripError> {
+    use crate::schema::back_ride_request::dsl::back_ride_request as ride_request;
+    use crate::schema::back_trips::dsl::{back_trips as trips, request_id as trip_request_id};
+    use crate::schema::back_drivers::dsl::back_drivers as drivers;
+
+    let ride: RideRequest = ride_request
+        .find(ride_request_id)
+        .select(RideRequest::as_select())
+        .first(connection)?;
+    if ride.rider_id != rider_uuid {
+        return Err(TripError::Forbidden("Not your ride request".to_string()));
+    }
+
+    let state = match dispatch_status_of(connection, ride_request_id)? {
+        DispatchStatus::Searching => RideRequestState::Searching,
+        DispatchStatus::NoDriversFound => RideRequestState::NoDriversFound,
+        DispatchStatus::Cancelled => {
+            let trip: Option<Trip> = trips
+                .filter(trip_request_id.eq(ride_request_id))
+                .select(Trip::as_select())
+                .first(connection)
+                .optional()?;
+
+            RideRequestState::Cancelled {
+                reason: ride.cancel_reason.clone(),
+                cancelled_at: ride.cancelled_at,
+                cancellation_fee: trip.as_ref().and_then(|t| t.cancellation_fee),
+                trip_reference: trip.map(|t| t.reference),
+            }
+        }
+        DispatchStatus::Assigned => {
+            let trip: Trip = trips
+                .filter(trip_request_id.eq(ride_request_id))
+                .select(Trip::as_select())
+                .first(connection)?;
+            let driver: Driver = drivers
+                .find(trip.driver_id)
+                .select(Driver::as_select())
+                .first(connection)?;
+
+            RideRequestState::Assigned {
+                driver_id: trip.driver_id,
+                driver: DriverInfo {
+                    name: driver.name,
+                    phone: driver.phone,
+                    vehicle: driver.vehicle,
+                    license_number: driver.license_number,
+                },
+                trip_reference: trip.reference,
+            }
+        }
+    };
+
+    Ok(RideRequestStatus {
+        request_id: ride.request_id,
+        state,
+        estimated_price: ride.estimated_price,
+        estimated_time_min: ride.estimated_time_min,
+    })
+}
+
+// GET /riders/ride-request/{request_id}/offers
+// Only for the request's rider, the drivers who were asked don't see each other.
+pub async fn ride_offers_handler(
+    pool: web::Data<DbPool>,
+    principal: Principal,
+    path: web::Path<Uuid>,
+) -> HttpResponse {
+    use crate::schema::back_ride_request::dsl::{back_ride_request as ride_request, rider_id};
+
+    let ride_request_id = path.into_inner();
+    let history = web::block(move || -> Result<Option<(Uuid, Vec<RideOffer>)>, String> {
+        let mut conn = pool.get().map_err(|e| e.to_string())?;
+        let owner: Option<Uuid> = ride_request
+            .find(ride_request_id)
+            .select(rider_id)
+            .first(&mut conn)
+            .optional()
+            .map_err(|e| e.to_string())?;
+        let Some(owner) = owner else { return Ok(None) };
+        let offers = offer_history(&mut conn, ride_request_id).map_err(|e| e.to_string())?;
+        Ok(Some((owner, offers)))
+    })
+    .await;
+
+    match history {
+        Ok(Ok(Some((owner, offers)))) => match principal.acts_for_rider(owner) {
+            Ok(()) => HttpResponse::Ok().json(offers),
+            Err(e) => e.error_response(),
+        },
+        Ok(Ok(None)) => HttpResponse::NotFound().body("Ride request not found"),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+        Err(_) => HttpResponse::InternalServerError().body("Server error"),
+    }
+}
+
 pub fn routes() -> Scope {
     web::scope("/riders")
         .route("/assign-driver", web::get().to(assign_driver_handler))
         .route("/wait-driver-response", web::post().to(driver_response))
-        .route("/ride-request", web::post().to(request_ride))
+        .service(
+            web::resource("/ride-request")
+                .route(web::post().to(request_ride))
+                .wrap(from_fn(|req, next| rate_limit("ride-request", req, next))),
+        )
+        .route("/ride-request/{request_id}", web::get().to(ride_request_status_handler))
+        .route("/ride-request/{request_id}/cancel", web::post().to(cancel_ride_request_handler))
+        .route("/ride-request/{request_id}/offers", web::get().to(ride_offers_handler))
 }
 
-#[derive(Deserialize, Serialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
 pub enum RideType {
     ASAP,
     ASAPEXPRESS,
 }
 
+impl RideType {
+    /// As it's serialized, and stored in `back_tariffs`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RideType::ASAP => "ASAP",
+            RideType::ASAPEXPRESS => "ASAPEXPRESS",
+        }
+    }
+}
+
 
 ///The whole thing needs work
 #[derive(Deserialize)]
@@ -260,6 +1029,9 @@ pub struct CreateRideRequest {
     pub user_id: Option<i64>,
     pub user_phone_number: Option<String>,
     pub vendor_phone_number: Option<String>,
+    /// From `POST /drivers/ride-preflight`, required
+    #[serde(default)]
+    pub quote_token: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Insertable, Clone)]
@@ -279,19 +1051,26 @@ pub struct NewRideRequest {
     pub user_id: Option<i64>,
     pub user_phone_number: Option<String>,
     pub vendor_phone_number: Option<String>,
+    /// The tariff `estimated_price` came from, None for the built-in rates.
+    #[serde(default)]
+    pub tariff_id: Option<Uuid>,
+    #[serde(default = "no_surge")]
+    pub surge_multiplier: f64,
+    /// The preflight quote the price was taken from.
+    #[serde(default)]
+    pub quote_id: Option<Uuid>,
+    #[serde(default)]
+    pub quote_token: Option<String>,
 }
 
+fn no_surge() -> f64 {
+    1.0
+}
 
-impl NewRideRequest {
-    pub fn new(req: CreateRideRequest) -> Self {
-        let distance_km = distance_between(&req.pick_up, &req.drop_off);
-        let estimated_time_min = pricing::estimated_time_min(distance_km, &req.ride_type);
-
-        let estimated_price = match req.ride_type {
-            RideType::ASAP => pricing::calculate_asap(distance_km, estimated_time_min),
-            RideType::ASAPEXPRESS => pricing::calculate_express(distance_km, estimated_time_min),
-        };
 
+impl NewRideRequest {
+    /// Priced as `quote` says, which the caller has checked covers `req`.
+    pub fn new(req: CreateRideRequest, quote: &FareQuote) -> Self {
         Self {
             request_id: Uuid::new_v4(),
             rider_id: req.rider_id,
@@ -299,16 +1078,38 @@ impl NewRideRequest {
             drop_off: serde_json::to_value(&req.drop_off).expect("serialize drop_off"),
             ride_type: serde_json::to_value(&req.ride_type).expect("serialize ride_type"),
             items: serde_json::to_value(&req.items).expect("serialize items"),
-            estimated_price,
-            distance_km,
-            estimated_time_min,
+            estimated_price: quote.estimated_price,
+            distance_km: quote.distance_km,
+            estimated_time_min: quote.estimated_time_min,
             payment_method: req.payment_method,
             order_id: req.order_id,
             user_id: req.user_id,
             user_phone_number: req.user_phone_number,
             vendor_phone_number: req.vendor_phone_number,
+            tariff_id: quote.tariff_id,
+            surge_multiplier: quote.surge_multiplier,
+            quote_id: Some(quote.quote_id),
+            quote_token: req.quote_token,
         }
     }
+
+    /// Where from, where to and what kind of ride, as stored.
+    pub fn route(&self) -> Result<(GeoPoint, GeoPoint, RideType), String> {
+        let pick_up = serde_json::from_value(self.pick_up.clone()).map_err(|e| format!("Invalid pick_up: {}", e))?;
+        let drop_off = serde_json::from_value(self.drop_off.clone()).map_err(|e| format!("Invalid drop_off: {}", e))?;
+        let ride_type = serde_json::from_value(self.ride_type.clone()).map_err(|e| format!("Invalid ride_type: {}", e))?;
+        Ok((pick_up, drop_off, ride_type))
+    }
+
+    /// Takes the price and everything it came from off `quote`.
+    pub fn price_with(&mut self, quote: &FareQuote) {
+        self.estimated_price = quote.estimated_price;
+        self.distance_km = quote.distance_km;
+        self.estimated_time_min = quote.estimated_time_min;
+        self.tariff_id = quote.tariff_id;
+        self.surge_multiplier = quote.surge_multiplier;
+        self.quote_id = Some(quote.quote_id);
+    }
 }
 
 
@@ -333,11 +1134,82 @@ pub struct RideRequest {
     pub user_id: Option<i64>,
     pub user_phone_number: Option<String>,
     pub vendor_phone_number: Option<String>,
+    pub cancel_reason: Option<String>,
+    pub cancelled_at: Option<i64>,
+    pub tariff_id: Option<Uuid>,
+    pub surge_multiplier: f64,
+    pub quote_id: Option<Uuid>,
+    pub quote_token: Option<String>,
+}
+
+
+impl From<RideRequest> for NewRideRequest {
+    fn from(stored: RideRequest) -> Self {
+        Self {
+            request_id: stored.request_id,
+            rider_id: stored.rider_id,
+            pick_up: stored.pick_up,
+            drop_off: stored.drop_off,
+            estimated_price: stored.estimated_price,
+            distance_km: stored.distance_km,
+            estimated_time_min: stored.estimated_time_min,
+            ride_type: stored.ride_type,
+            items: stored.items,
+            payment_method: stored.payment_method,
+            order_id: stored.order_id,
+            user_id: stored.user_id,
+            user_phone_number: stored.user_phone_number,
+            vendor_phone_number: stored.vendor_phone_number,
+            tariff_id: stored.tariff_id,
+            surge_multiplier: stored.surge_multiplier,
+            quote_id: stored.quote_id,
+            quote_token: stored.quote_token,
+        }
+    }
+}
+
+
+/// What `POST /riders/ride-request` answers with straight away, and what
+/// `GET /riders/ride-request/{id}` reports after that.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RideRequestStatus {
+    pub request_id: Uuid,
+    #[serde(flatten)]
+    pub state: RideRequestState,
+    pub estimated_price: i64,
+    pub estimated_time_min: i32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum RideRequestState {
+    Searching,
+    Assigned { driver_id: Uuid, driver: DriverInfo, trip_reference: String },
+    NoDriversFound,
+    Cancelled {
+        reason: Option<String>,
+        cancelled_at: Option<i64>,
+        /// Set when the rider cancelled after a driver was assigned.
+        trip_reference: Option<String>,
+        cancellation_fee: Option<i64>,
+    },
+}
+
+impl RideRequestStatus {
+    pub fn searching(ride: &NewRideRequest) -> Self {
+        Self {
+            request_id: ride.request_id,
+            state: RideRequestState::Searching,
+            estimated_price: ride.estimated_price,
+            estimated_time_min: ride.estimated_time_min,
+        }
+    }
 }
 
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct RideAssignment {
+    pub trip_reference: Option<String>,
     pub estimated_arrival: String,
     pub estimated_time_min: i32,
     pub estimated_price: i64,
@@ -1,4 +1,4 @@
-use actix_web::{ web, HttpResponse, Scope };
+use actix_web::{ web, HttpResponse, ResponseError, Scope };
 use serde::{ Deserialize, Serialize };
 use serde_json::Value;
 use uuid::Uuid;
@@ -6,28 +6,53 @@ use diesel::prelude::*;
 use chrono::Utc;
 use sha2::{Sha256, Digest};
 use crate::schema::back_trips::dsl::{back_trips as trips, *};
-use crate::api::drivers::Driver;
-use crate::api::riders::{ RideRequest };
+use crate::api::drivers::{
+    Driver, DRIVER_AVAILABLE, DRIVER_BUSY, DRIVER_RESERVED, get_ongoing_trips_count,
+    max_ongoing_trips,
+};
+use crate::api::riders::{ finish_search, DispatchStatus, RideRequest, RideType, validate_rider_account };
 use crate::api::admin::Rider;
+use crate::api::auth::{ Permission, Principal };
 use crate::db::DbPool;
-use diesel::pg::PgConnection;
+use crate::services::pricing::{ self, GeoPoint };
+use crate::services::{ gateway, geoindex };
+use crate::services::gateway::{ Peer, ServerMessage };
+use crate::services::matching::env_or;
+use crate::services::notifications::calculate_eta;
+use futures_util::stream;
+use std::collections::VecDeque;
+use tokio::time::{ timeout, Duration };
+use diesel::pg::{ Pg, PgConnection, PgValue };
+use diesel::deserialize::{ self, FromSql, FromSqlRow };
+use diesel::expression::AsExpression;
+use diesel::serialize::{ self, Output, ToSql };
+use diesel::sql_types::Text;
+
+
+const EVENTS_KEEP_ALIVE: Duration = Duration::from_secs(15);
+const DEFAULT_CANCELLATION_FEE: i64 = 500;
+const DEFAULT_CANCELLATION_GRACE_SECS: i64 = 120;
 
 
 
 
 pub async fn create_trip(
     pool: web::Data<DbPool>,
+    principal: Principal,
     body: web::Json<CreateTripInput>
 ) -> HttpResponse {
-    
+    if let Err(e) = principal.require(Permission::ManageAccounts) {
+        return e.error_response();
+    }
+
     let trip = body.into_inner();
 
     let result = web::block({
 
         let pool = pool.clone();
-        move || {
+        move || -> Result<usize, String> {
 
-            let mut conn = pool.get().expect("Failed to get connection");
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
 
     
 
@@ -36,7 +61,7 @@ pub async fn create_trip(
                 trip_id.eq(trip.trip_id), // [u8; 32] as bytes
                 rider_id.eq(trip.rider_id), // UUID
                 reference.eq(trip.reference),
-                status.eq("ongoing"),
+                status.eq(TripStatus::DriverAssigned), // a trip only exists once a driver accepted
                 pick_up.eq(trip.pick_up), 
                 drop_off.eq(trip.drop_off), 
                 driver_location.eq(trip.driver_location), 
@@ -50,15 +75,16 @@ pub async fn create_trip(
                 fare_estimate.eq(trip.fare_estimate),
                 fare_lamports.eq(trip.fare_lamports),
                 rider_email.eq(trip.rider_email),
-
+                request_id.eq(trip.request_id),
             ))
-            .execute(&mut conn).map(|e| e.to_string());
+            .execute(&mut conn).map_err(|e| e.to_string())
         }
     })
     .await;
 
     match result {
-        Ok(_) => HttpResponse::Ok().body("Trip created"),
+        Ok(Ok(_)) => HttpResponse::Ok().body("Trip created"),
+        Ok(Err(db_err)) => HttpResponse::InternalServerError().body(format!("DB error: {}", db_err)),
         Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
     }
 }
@@ -67,42 +93,59 @@ pub async fn create_trip(
 
 pub async fn update_trip(
     pool: web::Data<DbPool>,
-    rider_uuid: web::Path<Uuid>,
+    principal: Principal,
     body: web::Json<Trip>,
 ) -> HttpResponse {
+    if let Err(e) = principal.require(Permission::ManageAccounts) {
+        return e.error_response();
+    }
     use crate::schema::back_trips::dsl::{back_trips as trips, *};
 
-    let rider_id_val = rider_uuid.into_inner();
     let trip = body.into_inner();
 
     let result = web::block({
         let pool = pool.clone();
 
-        move || -> Result<usize, String> {
-            let mut conn = pool.get().map_err(|e| e.to_string())?;
+        move || -> Result<Trip, TripError> {
+            let mut conn = pool.get().map_err(|e| TripError::Db(e.to_string()))?;
 
-            diesel::update(trips.filter(rider_id.eq(rider_id_val)))
-                .set((
-                    status.eq(trip.status),
-                    end_ts.eq(trip.end_ts),
-                    fare_estimate.eq(trip.fare_estimate),
-                    fare_lamports.eq(trip.fare_lamports),
-                ))
-                .execute(&mut conn)
-                .map_err(|e| e.to_string())
+            conn.transaction(|conn| {
+                // Fare and end_ts edits resend the current status, which is not a move
+                let current = lock_trip(conn, &trip.reference)?;
+                let updated = if current.status == trip.status {
+                    current
+                } else {
+                    set_trip_status(conn, &trip.reference, trip.status)?
+                };
+
+                diesel::update(trips.filter(reference.eq(&trip.reference)))
+                    .set((
+                        end_ts.eq(trip.end_ts.or(updated.end_ts)),
+                        fare_estimate.eq(trip.fare_estimate),
+                        fare_lamports.eq(trip.fare_lamports),
+                    ))
+                    .execute(conn)?;
+
+                Ok(get_trip_by_reference(conn, &trip.reference)?)
+            })
         }
     })
     .await;
 
-    match result {
-        Ok(Ok(rows)) if rows > 0 => HttpResponse::Ok().json(rows),
+    trip_response(result)
+}
 
-        Ok(Ok(_)) => HttpResponse::Ok().body("No rows updated"),
 
-        Ok(Err(db_err)) => {
-            HttpResponse::InternalServerError().body(format!("DB error: {}", db_err))
+/// Maps the outcome of a trip mutation onto the response every trip endpoint returns.
+pub fn trip_response(result: Result<Result<Trip, TripError>, actix_web::error::BlockingError>) -> HttpResponse {
+    match result {
+        Ok(Ok(trip)) => {
+            gateway::publish_trip_status(&trip);
+            HttpResponse::Ok().json(trip)
         }
 
+        Ok(Err(e)) => trip_error_response(e),
+
         Err(block_err) => {
             HttpResponse::InternalServerError()
                 .body(format!("Blocking error: {}", block_err))
@@ -110,9 +153,508 @@ pub async fn update_trip(
     }
 }
 
+pub fn trip_error_response(err: TripError) -> HttpResponse {
+    match err {
+        TripError::NotFound => HttpResponse::NotFound().body("Trip not found"),
+
+        e @ TripError::InvalidTransition { .. } => HttpResponse::Conflict().body(e.to_string()),
+
+        TripError::Forbidden(msg) => HttpResponse::Forbidden().body(msg),
+
+        e @ TripError::OutsideGeofence { .. } => HttpResponse::UnprocessableEntity().body(e.to_string()),
+
+        TripError::InvalidRequest(msg) => HttpResponse::BadRequest().body(msg),
+
+        e @ (TripError::DriverUnavailable | TripError::RequestClosed) => HttpResponse::Conflict().body(e.to_string()),
+
+        TripError::Db(db_err) => {
+            HttpResponse::InternalServerError().body(format!("DB error: {}", db_err))
+        }
+    }
+}
+
+
+/// The only way a trip changes status: locks the row, checks the move
+/// against `TripStatus::can_transition_to` and stamps `end_ts` when the
+/// trip completes.
+pub fn set_trip_status(
+    conn: &mut PgConnection,
+    ref_str: &str,
+    next: TripStatus,
+) -> Result<Trip, TripError> {
+    use crate::schema::back_trips::dsl::{back_trips as trips, *};
+
+    conn.transaction(|conn| {
+        let mut trip = lock_trip(conn, ref_str)?;
+
+        trip.status = trip.status.transition_to(next)?;
+        if next == TripStatus::Completed && trip.end_ts.is_none() {
+            trip.end_ts = Some(Utc::now().timestamp());
+        }
+
+        diesel::update(trips.filter(reference.eq(ref_str)))
+            .set((status.eq(trip.status), end_ts.eq(trip.end_ts)))
+            .execute(conn)?;
+        release_driver_if_done(conn, &trip)?;
+
+        Ok(trip)
+    })
+}
+
+
+/// Turns an accepted ride request into a trip. Runs in one transaction so the
+/// trip row, the driver's status and the link back to `back_ride_request`
+/// either all change or none do.
+pub fn create_trip_for_request(
+    conn: &mut PgConnection,
+    ride_request_id: Uuid,
+    driver_uuid: Uuid,
+) -> Result<Trip, TripError> {
+    use crate::schema::back_ride_request::dsl::back_ride_request as ride_requests;
+
+    conn.transaction(|conn| {
+        let ride: RideRequest = ride_requests
+            .find(ride_request_id)
+            .select(RideRequest::as_select())
+            .first(conn)?;
+
+        let driver = lock_driver(conn, driver_uuid)?;
+        let capacity = max_ongoing_trips(&driver.vehicle_type);
+        let ongoing = get_ongoing_trips_count(conn, driver_uuid).map_err(TripError::Db)?;
+        let held_for_us = driver.status == DRIVER_RESERVED && driver.reserved_for == Some(ride_request_id);
+        if !(driver.status == DRIVER_AVAILABLE || held_for_us) || ongoing >= capacity {
+            return Err(TripError::DriverUnavailable);
+        }
+
+        let rider = validate_rider_account(conn, ride.rider_id)
+            .map_err(TripError::InvalidRequest)?;
+
+        // Cancelled, given up on or already someone else's trip
+        if !finish_search(conn, ride_request_id, DispatchStatus::Assigned)? {
+            return Err(TripError::RequestClosed);
+        }
+
+        let input = CreateTripInput::new(driver, rider, ride);
+        let trip_reference = input.reference.clone();
+        diesel::insert_into(trips).values(&input).execute(conn)?;
+
+        let next_status = if ongoing + 1 >= capacity { DRIVER_BUSY } else { DRIVER_AVAILABLE };
+        set_driver_status(conn, driver_uuid, next_status)?;
+
+        Ok(get_trip_by_reference(conn, &trip_reference)?)
+    })
+}
+
+/// Once a trip is over its driver can take new requests again.
+fn release_driver_if_done(conn: &mut PgConnection, trip: &Trip) -> Result<(), TripError> {
+    if !matches!(trip.status, TripStatus::Completed | TripStatus::Cancelled) {
+        return Ok(());
+    }
+
+    // Trips created through /trips/create-trip may name a driver we don't have
+    let driver = match lock_driver(conn, trip.driver_id) {
+        Ok(driver) => driver,
+        Err(TripError::NotFound) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let ongoing = get_ongoing_trips_count(conn, trip.driver_id).map_err(TripError::Db)?;
+    if driver.status == DRIVER_BUSY && ongoing < max_ongoing_trips(&driver.vehicle_type) {
+        set_driver_status(conn, trip.driver_id, DRIVER_AVAILABLE)?;
+    }
+    Ok(())
+}
+
+fn lock_driver(conn: &mut PgConnection, driver_uuid: Uuid) -> Result<Driver, TripError> {
+    use crate::schema::back_drivers::dsl::back_drivers as drivers;
+
+    Ok(drivers
+        .find(driver_uuid)
+        .select(Driver::as_select())
+        .for_update()
+        .first(conn)?)
+}
+
+fn set_driver_status(conn: &mut PgConnection, driver_uuid: Uuid, next: &str) -> Result<(), TripError> {
+    use crate::schema::back_drivers::dsl as drivers;
+
+    diesel::update(drivers::back_drivers.find(driver_uuid))
+        .set((
+            drivers::status.eq(next),
+            drivers::reserved_for.eq(None::<Uuid>),
+            drivers::reserved_until.eq(None::<i64>),
+        ))
+        .execute(conn)?;
+    geoindex::track_status(driver_uuid, next);
+    Ok(())
+}
+
+pub fn lock_trip(conn: &mut PgConnection, ref_str: &str) -> Result<Trip, TripError> {
+    Ok(trips
+        .filter(reference.eq(ref_str))
+        .select(Trip::as_select())
+        .for_update()
+        .first(conn)?)
+}
+
+
+/// Driver reports a lifecycle milestone from where they are standing. The
+/// report is only accepted inside the geofence around the stored pickup or
+/// drop off point, and only by the driver the trip belongs to.
+pub fn record_driver_milestone(
+    conn: &mut PgConnection,
+    ref_str: &str,
+    driver_uuid: Uuid,
+    location: &GeoPoint,
+    milestone: DriverMilestone,
+) -> Result<Trip, TripError> {
+    conn.transaction(|conn| {
+        let trip = lock_trip(conn, ref_str)?;
+
+        if trip.driver_id != driver_uuid {
+            return Err(TripError::Forbidden("Trip is assigned to another driver".into()));
+        }
+
+        let next = trip.status.transition_to(milestone.next_status())?;
+        milestone.check_geofence(&trip, location)?;
+
+        let now = Utc::now().timestamp();
+        let location_text = serde_json::to_string(location).expect("serialize GeoPoint");
+
+        diesel::update(trips.filter(reference.eq(ref_str)))
+            .set((status.eq(next), driver_location.eq(location_text)))
+            .execute(conn)?;
+
+        let stamp = diesel::update(trips.filter(reference.eq(ref_str)));
+        match milestone {
+            DriverMilestone::Arrived => stamp.set(arrived_at.eq(now)).execute(conn)?,
+            DriverMilestone::PickedUp => stamp.set(picked_up_at.eq(now)).execute(conn)?,
+            DriverMilestone::DroppedOff => stamp.set(end_ts.eq(now)).execute(conn)?,
+        };
+
+        let trip = get_trip_by_reference(conn, ref_str)?;
+        release_driver_if_done(conn, &trip)?;
+        Ok(trip)
+    })
+}
+
+/// Cancels the trip for its rider or driver. A rider cancelling after the
+/// driver has been assigned for longer than the policy's grace period is
+/// charged its fee. The ride request behind the trip is marked cancelled too.
+pub fn cancel_trip(
+    conn: &mut PgConnection,
+    ref_str: &str,
+    request: &CancelTripRequest,
+    policy: &CancellationPolicy,
+) -> Result<Trip, TripError> {
+    use crate::schema::back_ride_request::dsl as ride_requests;
+
+    conn.transaction(|conn| {
+        let trip = lock_trip(conn, ref_str)?;
+
+        let owner = match request.cancelled_by {
+            TripParty::Rider => trip.rider_id,
+            TripParty::Driver => trip.driver_id,
+        };
+        if owner != request.actor_id {
+            return Err(TripError::Forbidden("Only the trip's rider or driver can cancel it".into()));
+        }
+
+        if !request.reason.allowed_for(request.cancelled_by) {
+            return Err(TripError::InvalidRequest(format!(
+                "{} is not a {} cancellation reason",
+                request.reason.label(),
+                request.cancelled_by.as_str()
+            )));
+        }
+
+        let next = trip.status.transition_to(TripStatus::Cancelled)?;
+        let now = Utc::now().timestamp();
+        let fee = match request.cancelled_by {
+            TripParty::Rider => policy.fee_for(&trip, now),
+            TripParty::Driver => None,
+        };
+
+        diesel::update(trips.filter(reference.eq(ref_str)))
+            .set((
+                status.eq(next),
+                cancelled_by.eq(request.cancelled_by.as_str()),
+                cancel_reason.eq(request.reason.as_str()),
+                cancelled_at.eq(now),
+                cancellation_fee.eq(fee),
+            ))
+            .execute(conn)?;
+
+        if let Some(ride_request_id) = trip.request_id {
+            diesel::update(ride_requests::back_ride_request.find(ride_request_id))
+                .set((
+                    ride_requests::dispatch_status.eq(DispatchStatus::Cancelled.as_str()),
+                    ride_requests::cancel_reason.eq(request.reason.as_str()),
+                    ride_requests::cancelled_at.eq(now),
+                ))
+                .execute(conn)?;
+        }
+
+        let trip = get_trip_by_reference(conn, ref_str)?;
+        release_driver_if_done(conn, &trip)?;
+        Ok(trip)
+    })
+}
+
+async fn driver_milestone(
+    pool: web::Data<DbPool>,
+    principal: Principal,
+    path: web::Path<String>,
+    body: web::Json<DriverLocationReport>,
+    milestone: DriverMilestone,
+) -> HttpResponse {
+    let reference_value = path.into_inner();
+    let report = body.into_inner();
+    if let Err(e) = principal.acts_for_driver(report.driver_id) {
+        return e.error_response();
+    }
+
+    let result = web::block({
+        let pool = pool.clone();
+        move || -> Result<Trip, TripError> {
+            let mut conn = pool.get().map_err(|e| TripError::Db(e.to_string()))?;
+            record_driver_milestone(&mut conn, &reference_value, report.driver_id, &report.location, milestone)
+        }
+    })
+    .await;
+
+    trip_response(result)
+}
+
+pub async fn driver_arrived(
+    pool: web::Data<DbPool>,
+    principal: Principal,
+    path: web::Path<String>,
+    body: web::Json<DriverLocationReport>,
+) -> HttpResponse {
+    driver_milestone(pool, principal, path, body, DriverMilestone::Arrived).await
+}
+
+pub async fn confirm_pickup(
+    pool: web::Data<DbPool>,
+    principal: Principal,
+    path: web::Path<String>,
+    body: web::Json<DriverLocationReport>,
+) -> HttpResponse {
+    driver_milestone(pool, principal, path, body, DriverMilestone::PickedUp).await
+}
+
+pub async fn confirm_dropoff(
+    pool: web::Data<DbPool>,
+    principal: Principal,
+    path: web::Path<String>,
+    body: web::Json<DriverLocationReport>,
+) -> HttpResponse {
+    driver_milestone(pool, principal, path, body, DriverMilestone::DroppedOff).await
+}
+
+pub async fn cancel_trip_handler(
+    pool: web::Data<DbPool>,
+    principal: Principal,
+    path: web::Path<String>,
+    body: web::Json<CancelTripRequest>,
+) -> HttpResponse {
+    let reference_value = path.into_inner();
+    let request = body.into_inner();
+    if let Err(e) = principal.acts_for(request.cancelled_by, request.actor_id) {
+        return e.error_response();
+    }
+
+    let result = web::block({
+        let pool = pool.clone();
+        move || -> Result<Trip, TripError> {
+            let mut conn = pool.get().map_err(|e| TripError::Db(e.to_string()))?;
+            cancel_trip(&mut conn, &reference_value, &request, &CancellationPolicy::from_env())
+        }
+    })
+    .await;
+
+    if let Ok(Ok(trip)) = &result {
+        notify_cancellation(trip);
+    }
+    trip_response(result)
+}
+
+/// Tells the other side of a cancelled trip who called it off and why, on
+/// top of the `trip_status` both of them get.
+pub fn notify_cancellation(trip: &Trip) {
+    let (Some(by), Some(reason)) = (&trip.cancelled_by, &trip.cancel_reason) else { return };
+    let Some(party) = TripParty::ALL.into_iter().find(|party| party.as_str() == by) else { return };
+    let Some(reason) = CancelReason::ALL.into_iter().find(|r| r.as_str() == reason) else { return };
+
+    let other = match party {
+        TripParty::Rider => Peer::Driver(trip.driver_id),
+        TripParty::Driver => Peer::Rider(trip.rider_id),
+    };
+    gateway::send_to(other, ServerMessage::TripCancelled {
+        trip_reference: trip.reference.clone(),
+        cancelled_by: party,
+        reason,
+        cancellation_fee: trip.cancellation_fee,
+    });
+}
+
+
+// GET /trips/{reference}/events?rider_id=..
+// Server-Sent Events for the trip's rider: the current status and driver
+// location first, then every change until the trip is completed or cancelled.
+pub async fn trip_events(
+    pool: web::Data<DbPool>,
+    principal: Principal,
+    path: web::Path<String>,
+    query: web::Query<TripEventsQuery>,
+) -> HttpResponse {
+    let reference_value = path.into_inner();
+    let rider_uuid = query.into_inner().rider_id;
+    if let Err(e) = principal.acts_for_rider(rider_uuid) {
+        return e.error_response();
+    }
 
+    // Only the trip's rider gets a watcher, so made up references can't pile them up
+    let allowed = web::block({
+        let pool = pool.clone();
+        let reference_value = reference_value.clone();
+        move || -> Result<(), TripError> {
+            let mut conn = pool.get().map_err(|e| TripError::Db(e.to_string()))?;
+            let trip = get_trip_by_reference(&mut conn, &reference_value)?;
+            if trip.rider_id != rider_uuid {
+                return Err(TripError::Forbidden("Only the trip's rider can follow it".into()));
+            }
+            Ok(())
+        }
+    })
+    .await;
+
+    match allowed {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => return trip_error_response(e),
+        Err(block_err) => {
+            return HttpResponse::InternalServerError().body(format!("Blocking error: {}", block_err));
+        }
+    }
+
+    // Subscribe before reading the trip again so nothing in between is missed
+    let updates = gateway::watch_trip(&reference_value);
+
+    let snapshot = web::block({
+        let pool = pool.clone();
+        move || -> Result<Vec<ServerMessage>, TripError> {
+            let mut conn = pool.get().map_err(|e| TripError::Db(e.to_string()))?;
+            let trip = get_trip_by_reference(&mut conn, &reference_value)?;
+            trip_snapshot(&mut conn, &trip)
+        }
+    })
+    .await;
+
+    let pending: VecDeque<ServerMessage> = match snapshot {
+        Ok(Ok(messages)) => messages.into(),
+        Ok(Err(e)) => return trip_error_response(e),
+        Err(block_err) => {
+            return HttpResponse::InternalServerError().body(format!("Blocking error: {}", block_err));
+        }
+    };
+
+    let frames = stream::unfold((pending, updates, false), |(mut pending, mut updates, done)| async move {
+        if done {
+            return None;
+        }
+
+        let message = match pending.pop_front() {
+            Some(message) => message,
+            None => match timeout(EVENTS_KEEP_ALIVE, updates.recv()).await {
+                Ok(Some(message)) => message,
+                Ok(None) => return None,
+                Err(_idle) => {
+                    let frame = web::Bytes::from_static(b": keep-alive\n\n");
+                    return Some((Ok::<_, actix_web::Error>(frame), (pending, updates, false)));
+                }
+            },
+        };
+
+        let last = matches!(&message, ServerMessage::TripStatus { status: now, .. } if now.is_finished());
+        Some((Ok(sse_frame(&message)), (pending, updates, last)))
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(frames)
+}
+
+fn sse_frame(message: &ServerMessage) -> web::Bytes {
+    let data = serde_json::to_string(message).expect("server messages serialize");
+    web::Bytes::from(format!("event: {}\ndata: {}\n\n", message.kind(), data))
+}
+
+// Where the trip stands right now, as the events a watcher would have seen.
+fn trip_snapshot(conn: &mut PgConnection, trip: &Trip) -> Result<Vec<ServerMessage>, TripError> {
+    use crate::schema::back_drivers::dsl::{ back_drivers as drivers, driver_location as current_location };
+
+    let mut messages = vec![ServerMessage::TripStatus {
+        trip_reference: trip.reference.clone(),
+        status: trip.status,
+    }];
+
+    if trip.status.is_active() {
+        let stored: Option<Value> = drivers
+            .find(trip.driver_id)
+            .select(current_location)
+            .first(conn)
+            .optional()?;
+
+        if let Some(location) = stored.and_then(|v| serde_json::from_value::<GeoPoint>(v).ok()) {
+            let eta_min = trip.eta_min(&location, &ride_type_of(conn, trip)?);
+            messages.push(ServerMessage::DriverLocation {
+                trip_reference: trip.reference.clone(),
+                location,
+                eta_min,
+                eta: eta_min.map(calculate_eta),
+            });
+        }
+    }
+    Ok(messages)
+}
+
+/// The ride type the trip was requested with, ASAP for trips created without
+/// a ride request.
+pub fn ride_type_of(conn: &mut PgConnection, trip: &Trip) -> QueryResult<RideType> {
+    use crate::schema::back_ride_request::dsl::{ back_ride_request as ride_requests, ride_type as requested_type };
+
+    let Some(ride_request_id) = trip.request_id else { return Ok(RideType::ASAP) };
+    let stored: Option<Value> = ride_requests
+        .find(ride_request_id)
+        .select(requested_type)
+        .first(conn)
+        .optional()?;
+
+    Ok(stored.and_then(|v| serde_json::from_value(v).ok()).unwrap_or(RideType::ASAP))
+}
+
+/// Called after a driver's location is written, passes it on with a fresh ETA
+/// to the riders of the driver's ongoing trips.
+pub fn share_driver_location(conn: &mut PgConnection, driver_uuid: Uuid, location: &GeoPoint) -> QueryResult<()> {
+    let ongoing: Vec<Trip> = trips
+        .filter(driver_id.eq(driver_uuid))
+        .filter(status.eq_any(TripStatus::ACTIVE))
+        .select(Trip::as_select())
+        .load(conn)?;
+
+    for trip in ongoing {
+        let eta_min = trip.eta_min(location, &ride_type_of(conn, &trip)?);
+        gateway::publish_driver_location(&trip, location, eta_min);
+    }
+    Ok(())
+}
+
+
+// For the trip's rider and driver.
 pub async fn get_trip(
     pool: web::Data<DbPool>,
+    principal: Principal,
     path: web::Path<String>,
 ) -> HttpResponse {
     let reference_value = path.into_inner();
@@ -127,7 +669,13 @@ pub async fn get_trip(
     .await;
 
     match result {
-        Ok(Ok(trip)) => HttpResponse::Ok().json(trip),
+        Ok(Ok(trip)) => {
+            let party = principal.acts_for_rider(trip.rider_id).or_else(|_| principal.acts_for_driver(trip.driver_id));
+            match party {
+                Ok(()) => HttpResponse::Ok().json(trip),
+                Err(e) => e.error_response(),
+            }
+        }
 
         Ok(Err(diesel::result::Error::NotFound)) => {
             HttpResponse::NotFound().body("Trip not found")
@@ -173,7 +721,7 @@ struct CreateTripInput {
     pub rider_pubkey: String,
     pub driver_pubkey: String,
     pub driver_id: Uuid,
-    pub status: String,
+    pub status: TripStatus,
     pub start_ts: i64,
     pub end_ts: Option<i64>,
     pub distance_km: f64,
@@ -181,6 +729,8 @@ struct CreateTripInput {
     pub fare_estimate: Option<i64>,
     pub fare_lamports: Option<i64>,
     pub rider_email: String,
+    #[serde(default)]
+    pub request_id: Option<Uuid>,
 }
 //you havent implemented trip( pull from riders, drivers & admin) ------- maybe this should be from db as well who knows remember to check it
 
@@ -203,10 +753,10 @@ impl CreateTripInput {
             pick_up: req2.pick_up.to_string(),
             drop_off: req2.drop_off.to_string(),
             driver_location: drv.driver_location.to_string(),
-            rider_pubkey: rdr.rider_pubkey.to_string(),
-            driver_pubkey: drv.driver_pubkey.to_string(),
+            rider_pubkey: json_text(&rdr.rider_pubkey),
+            driver_pubkey: json_text(&drv.driver_pubkey),
             driver_id: drv.driver_id,
-            status: "Ongoing".to_string(),
+            status: TripStatus::DriverAssigned,
             start_ts: start_ts_value,
             end_ts: None,
             distance_km: req2.distance_km,
@@ -214,12 +764,16 @@ impl CreateTripInput {
             fare_estimate: Some(req2.estimated_price),
             fare_lamports: None,  //we need to calculate this in pricing using fare_estimate
             rider_email: rdr.email,
-
-
+            request_id: Some(req2.request_id),
         }
     }
 }
 
+// Pubkeys are stored as JSON strings, `to_string` would keep the quotes.
+fn json_text(value: &Value) -> String {
+    value.as_str().map(str::to_string).unwrap_or_else(|| value.to_string())
+}
+
 
 #[derive(Serialize, Deserialize, Queryable, Selectable, Debug, Clone)]
 #[diesel(table_name = crate::schema::back_trips)]
@@ -233,7 +787,7 @@ pub struct Trip {
     pub rider_pubkey: String,
     pub driver_pubkey: String,
     pub driver_id: Uuid,
-    pub status: String,
+    pub status: TripStatus,
     pub start_ts: i64,
     pub end_ts: Option<i64>,
     pub distance_km: f64,
@@ -242,18 +796,36 @@ pub struct Trip {
     pub fare_estimate: Option<i64>,
     pub fare_lamports: Option<i64>,
     pub rider_email: String,
+    pub onchain_signature: Option<String>,
+    pub arrived_at: Option<i64>,
+    pub picked_up_at: Option<i64>,
+    pub cancelled_by: Option<String>,
+    pub cancel_reason: Option<String>,
+    pub cancelled_at: Option<i64>,
+    pub request_id: Option<Uuid>,
+    pub cancellation_fee: Option<i64>,
 }
 //you havent implemented trip( pull from riders, drivers & admin) ------- maybe this should be from db as well who knows remember to check it
 
 impl Trip {
+    pub fn pick_up_point(&self) -> Option<GeoPoint> {
+        parse_geo_point(&self.pick_up)
+    }
 
+    pub fn drop_off_point(&self) -> Option<GeoPoint> {
+        parse_geo_point(&self.drop_off)
+    }
 
-    pub fn update_status(&mut self)  {
-        
-        if self.driver_location == self.drop_off {
-            self.status = "Completed".to_string();
-            self.end_ts = Some(Utc::now().timestamp() as i64);
-        }
+    /// Minutes until the driver reaches the pickup, or the drop off once they
+    /// have arrived for the rider. None once the trip is over or when its
+    /// points aren't coordinates.
+    pub fn eta_min(&self, driver_at: &GeoPoint, requested: &RideType) -> Option<i32> {
+        let target = match self.status {
+            TripStatus::Requested | TripStatus::DriverAssigned => self.pick_up_point()?,
+            TripStatus::DriverArrived | TripStatus::InProgress => self.drop_off_point()?,
+            _ => return None,
+        };
+        Some(pricing::estimated_time_min(driver_at.distance_to(&target), requested, driver_at))
     }
    
     pub fn compute_fare_lamports(&mut self) {
@@ -269,10 +841,338 @@ impl Trip {
 
 
 
+// GPS in dense parts of Lagos drifts by tens of metres, so milestones are
+// accepted anywhere within these distances of the stored point.
+pub const PICKUP_GEOFENCE_KM: f64 = 0.15;
+pub const DROPOFF_GEOFENCE_KM: f64 = 0.2;
+
+/// Trip pick_up/drop_off/driver_location columns hold a serialized GeoPoint.
+/// Older rows only have a place name, which has no coordinates to check.
+pub fn parse_geo_point(text: &str) -> Option<GeoPoint> {
+    serde_json::from_str(text).ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DriverMilestone {
+    Arrived,
+    PickedUp,
+    DroppedOff,
+}
+
+impl DriverMilestone {
+    pub fn next_status(&self) -> TripStatus {
+        match self {
+            DriverMilestone::Arrived => TripStatus::DriverArrived,
+            DriverMilestone::PickedUp => TripStatus::InProgress,
+            DriverMilestone::DroppedOff => TripStatus::Completed,
+        }
+    }
+
+    pub fn check_geofence(&self, trip: &Trip, location: &GeoPoint) -> Result<(), TripError> {
+        let (anchor, allowed_km, what) = match self {
+            DriverMilestone::Arrived | DriverMilestone::PickedUp => {
+                (trip.pick_up_point(), PICKUP_GEOFENCE_KM, "pick up")
+            }
+            DriverMilestone::DroppedOff => (trip.drop_off_point(), DROPOFF_GEOFENCE_KM, "drop off"),
+        };
+
+        let anchor = anchor.ok_or_else(|| {
+            TripError::InvalidRequest(format!("Trip has no {} coordinates", what))
+        })?;
+
+        let away_km = location.distance_to(&anchor);
+        if away_km > allowed_km {
+            return Err(TripError::OutsideGeofence {
+                distance_m: (away_km * 1000.0).round() as i64,
+                allowed_m: (allowed_km * 1000.0).round() as i64,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TripParty {
+    Rider,
+    Driver,
+}
+
+impl TripParty {
+    pub const ALL: [TripParty; 2] = [TripParty::Rider, TripParty::Driver];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TripParty::Rider => "rider",
+            TripParty::Driver => "driver",
+        }
+    }
+}
+
+/// The reasons offered to the rider in `RideAssignment::cancel_ride`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CancelReason {
+    ChangeOfPlans,
+    DriverTakingTooLong,
+    FoundAlternateTransport,
+    IncorrectDestination,
+}
+
+impl CancelReason {
+    pub const ALL: [CancelReason; 4] = [
+        CancelReason::ChangeOfPlans,
+        CancelReason::DriverTakingTooLong,
+        CancelReason::FoundAlternateTransport,
+        CancelReason::IncorrectDestination,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CancelReason::ChangeOfPlans => "change_of_plans",
+            CancelReason::DriverTakingTooLong => "driver_taking_too_long",
+            CancelReason::FoundAlternateTransport => "found_alternate_transport",
+            CancelReason::IncorrectDestination => "incorrect_destination",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            CancelReason::ChangeOfPlans => "Change of plans",
+            CancelReason::DriverTakingTooLong => "Driver taking too long",
+            CancelReason::FoundAlternateTransport => "Found alternate transport",
+            CancelReason::IncorrectDestination => "Incorrect destination",
+        }
+    }
+
+    pub fn labels() -> Vec<String> {
+        CancelReason::ALL.iter().map(|r| r.label().to_string()).collect()
+    }
+
+    /// A driver can't cancel because they are taking too long themselves.
+    pub fn allowed_for(&self, party: TripParty) -> bool {
+        !(party == TripParty::Driver && *self == CancelReason::DriverTakingTooLong)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DriverLocationReport {
+    pub driver_id: Uuid,
+    pub location: GeoPoint,
+}
+
+#[derive(Deserialize)]
+pub struct TripEventsQuery {
+    pub rider_id: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct CancelTripRequest {
+    pub cancelled_by: TripParty,
+    pub actor_id: Uuid,
+    pub reason: CancelReason,
+}
+
+/// What a rider pays for cancelling once a driver is on the way. Read from
+/// `CANCELLATION_FEE` (same unit as the fare estimate, 0 turns it off) and
+/// `CANCELLATION_GRACE_SECS`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CancellationPolicy {
+    pub fee: i64,
+    pub grace_secs: i64,
+}
+
+impl CancellationPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            fee: env_or("CANCELLATION_FEE", DEFAULT_CANCELLATION_FEE),
+            grace_secs: env_or("CANCELLATION_GRACE_SECS", DEFAULT_CANCELLATION_GRACE_SECS),
+        }
+    }
+
+    /// The fee for cancelling `trip` at `now`, if any. Only while a driver is
+    /// assigned or waiting at the pickup, and never more than the fare.
+    pub fn fee_for(&self, trip: &Trip, now: i64) -> Option<i64> {
+        let driver_on_the_way = matches!(trip.status, TripStatus::DriverAssigned | TripStatus::DriverArrived);
+        if self.fee <= 0 || !driver_on_the_way || now - trip.start_ts <= self.grace_secs {
+            return None;
+        }
+        Some(trip.fare_estimate.map_or(self.fee, |fare| self.fee.min(fare)))
+    }
+}
+
+
+/// Lifecycle of a trip, stored in `back_trips.status` as snake_case text.
+///
+/// ```text
+/// Requested ─► DriverAssigned ─► DriverArrived ─► InProgress ─► Completed
+///     │              │                 │               │            │
+///     └──────────────┴───── Cancelled ─┘               └─► Disputed ◄┘
+/// ```
+/// Cancelled and Completed trips can still be disputed, and a dispute is
+/// resolved by moving the trip to Completed or Cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "snake_case")]
+pub enum TripStatus {
+    Requested,
+    DriverAssigned,
+    DriverArrived,
+    InProgress,
+    Completed,
+    Cancelled,
+    Disputed,
+}
+
+impl TripStatus {
+    pub const ALL: [TripStatus; 7] = [
+        TripStatus::Requested,
+        TripStatus::DriverAssigned,
+        TripStatus::DriverArrived,
+        TripStatus::InProgress,
+        TripStatus::Completed,
+        TripStatus::Cancelled,
+        TripStatus::Disputed,
+    ];
+
+    /// Statuses that occupy the driver, used for the per-vehicle trip limits.
+    pub const ACTIVE: [TripStatus; 3] = [
+        TripStatus::DriverAssigned,
+        TripStatus::DriverArrived,
+        TripStatus::InProgress,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TripStatus::Requested => "requested",
+            TripStatus::DriverAssigned => "driver_assigned",
+            TripStatus::DriverArrived => "driver_arrived",
+            TripStatus::InProgress => "in_progress",
+            TripStatus::Completed => "completed",
+            TripStatus::Cancelled => "cancelled",
+            TripStatus::Disputed => "disputed",
+        }
+    }
+
+    pub fn can_transition_to(&self, next: TripStatus) -> bool {
+        use TripStatus::*;
+
+        matches!(
+            (self, next),
+            (Requested, DriverAssigned)
+                | (Requested, Cancelled)
+                | (DriverAssigned, DriverArrived)
+                | (DriverAssigned, Cancelled)
+                | (DriverArrived, InProgress)
+                | (DriverArrived, Cancelled)
+                | (InProgress, Completed)
+                | (InProgress, Disputed)
+                | (Completed, Disputed)
+                | (Cancelled, Disputed)
+                | (Disputed, Completed)
+                | (Disputed, Cancelled)
+        )
+    }
+
+    pub fn transition_to(&self, next: TripStatus) -> Result<TripStatus, TripError> {
+        if self.can_transition_to(next) {
+            Ok(next)
+        } else {
+            Err(TripError::InvalidTransition { from: *self, to: next })
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        TripStatus::ACTIVE.contains(self)
+    }
+
+    /// Nothing more happens to the trip, short of a dispute.
+    pub fn is_finished(&self) -> bool {
+        matches!(self, TripStatus::Completed | TripStatus::Cancelled)
+    }
+}
+
+impl std::fmt::Display for TripStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for TripStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TripStatus::ALL
+            .into_iter()
+            .find(|candidate| candidate.as_str() == s)
+            .ok_or_else(|| format!("Unknown trip status: {}", s))
+    }
+}
+
+impl ToSql<Text, Pg> for TripStatus {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        <str as ToSql<Text, Pg>>::to_sql(self.as_str(), &mut out.reborrow())
+    }
+}
+
+impl FromSql<Text, Pg> for TripStatus {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        let value = <String as FromSql<Text, Pg>>::from_sql(bytes)?;
+        value.parse().map_err(Into::into)
+    }
+}
+
+#[derive(Debug)]
+pub enum TripError {
+    NotFound,
+    InvalidTransition { from: TripStatus, to: TripStatus },
+    Forbidden(String),
+    OutsideGeofence { distance_m: i64, allowed_m: i64 },
+    InvalidRequest(String),
+    DriverUnavailable,
+    /// The ride request stopped searching for a driver.
+    RequestClosed,
+    Db(String),
+}
+
+impl std::fmt::Display for TripError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TripError::NotFound => write!(f, "Trip not found"),
+            TripError::InvalidTransition { from, to } => {
+                write!(f, "Trip cannot move from {} to {}", from, to)
+            }
+            TripError::Forbidden(msg) | TripError::InvalidRequest(msg) => f.write_str(msg),
+            TripError::OutsideGeofence { distance_m, allowed_m } => {
+                write!(f, "Driver is {} m away, must be within {} m", distance_m, allowed_m)
+            }
+            TripError::DriverUnavailable => f.write_str("Driver is no longer available"),
+            TripError::RequestClosed => f.write_str("Ride request is no longer searching for a driver"),
+            TripError::Db(e) => write!(f, "DB error: {}", e),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for TripError {
+    fn from(e: diesel::result::Error) -> Self {
+        match e {
+            diesel::result::Error::NotFound => TripError::NotFound,
+            other => TripError::Db(other.to_string()),
+        }
+    }
+}
+
+
+
 pub fn routes() -> Scope {
     web::scope("/trips")
         .route("/create-trip", web::post().to(create_trip))
         .route("/update-trip", web::post().to(update_trip))
         .route("/get-trip/{reference}", web::get().to(get_trip))
+        .route("/driver-arrived/{reference}", web::post().to(driver_arrived))
+        .route("/confirm-pickup/{reference}", web::post().to(confirm_pickup))
+        .route("/confirm-dropoff/{reference}", web::post().to(confirm_dropoff))
+        .route("/cancel-trip/{reference}", web::post().to(cancel_trip_handler))
+        .route("/{reference}/events", web::get().to(trip_events))
 }
 
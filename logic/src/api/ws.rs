@@ -0,0 +1,292 @@
+use actix_web::{ web, HttpRequest, HttpResponse, Scope };
+use actix_ws::{ Message, MessageStream, Session };
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use serde::Deserialize;
+use std::cell::Cell;
+use std::rc::Rc;
+use tokio::time::{ timeout, Duration, Instant };
+use uuid::Uuid;
+use crate::db::DbPool;
+use crate::api::auth::Principal;
+use crate::api::drivers::{ offered_request, DriverResponse };
+use crate::api::riders::validate_rider_account;
+use crate::api::trips::{ get_trip_by_reference, TripParty };
+use crate::services::gateway::{ self, ClientMessage, Connection, Envelope, Peer, Resume, ServerMessage };
+use crate::services::matching::update_driver_location;
+use crate::services::pricing::GeoPoint;
+
+
+/// How often the server pings an idle socket.
+const HEARTBEAT: Duration = Duration::from_secs(10);
+/// A socket that hasn't been heard from in this long is closed, its session
+/// stays resumable.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+
+#[derive(Deserialize)]
+pub struct DriverSocketQuery {
+    pub driver_id: Uuid,
+    pub session_id: Option<Uuid>,
+    pub last_seq: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct RiderSocketQuery {
+    pub rider_id: Uuid,
+    pub session_id: Option<Uuid>,
+    pub last_seq: Option<u64>,
+}
+
+fn resume_from(session_id: Option<Uuid>, last_seq: Option<u64>) -> Option<Resume> {
+    session_id.map(|session_id| Resume { session_id, last_seq: last_seq.unwrap_or(0) })
+}
+
+
+// GET /ws/driver?driver_id=..&access_token=..[&session_id=..&last_seq=..]
+pub async fn driver_socket(
+    req: HttpRequest,
+    stream: web::Payload,
+    pool: web::Data<DbPool>,
+    principal: Principal,
+    query: web::Query<DriverSocketQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let query = query.into_inner();
+    principal.acts_for_driver(query.driver_id)?;
+    let peer = Peer::Driver(query.driver_id);
+    open_socket(req, stream, pool, peer, resume_from(query.session_id, query.last_seq)).await
+}
+
+// GET /ws/user?rider_id=..&access_token=..[&session_id=..&last_seq=..]
+pub async fn rider_socket(
+    req: HttpRequest,
+    stream: web::Payload,
+    pool: web::Data<DbPool>,
+    principal: Principal,
+    query: web::Query<RiderSocketQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let query = query.into_inner();
+    principal.acts_for_rider(query.rider_id)?;
+    let peer = Peer::Rider(query.rider_id);
+    open_socket(req, stream, pool, peer, resume_from(query.session_id, query.last_seq)).await
+}
+
+async fn open_socket(
+    req: HttpRequest,
+    stream: web::Payload,
+    pool: web::Data<DbPool>,
+    peer: Peer,
+    resume: Option<Resume>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let known = web::block({
+        let pool = pool.clone();
+        move || -> Result<bool, String> {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            peer_exists(&mut conn, peer).map_err(|e| e.to_string())
+        }
+    })
+    .await;
+
+    match known {
+        Ok(Ok(true)) => {}
+        Ok(Ok(false)) => return Ok(HttpResponse::NotFound().body("Account not found")),
+        Ok(Err(e)) => return Ok(HttpResponse::InternalServerError().body(format!("DB error: {}", e))),
+        Err(_) => return Ok(HttpResponse::InternalServerError().body("Server error")),
+    }
+
+    let (response, session, messages) = actix_ws::handle(&req, stream)?;
+    let connection = gateway::connect(peer, resume);
+    actix_web::rt::spawn(run_socket(pool, peer, connection, session, messages));
+    Ok(response)
+}
+
+fn peer_exists(conn: &mut PgConnection, peer: Peer) -> QueryResult<bool> {
+    use crate::schema::back_drivers::dsl::back_drivers as drivers;
+
+    match peer {
+        Peer::Driver(driver_uuid) => {
+            let found: i64 = drivers.find(driver_uuid).count().get_result(conn)?;
+            Ok(found > 0)
+        }
+        Peer::Rider(rider_uuid) => Ok(validate_rider_account(conn, rider_uuid).is_ok()),
+    }
+}
+
+
+// One task per socket reads what the app sends, a second one writes what the
+// hub has for it and keeps the heartbeat. Either side ending detaches the
+// session, which ends the other.
+async fn run_socket(
+    pool: web::Data<DbPool>,
+    peer: Peer,
+    connection: Connection,
+    session: Session,
+    mut messages: MessageStream,
+) {
+    let Connection { session_id, connection, messages: outgoing, .. } = connection;
+    let last_heard = Rc::new(Cell::new(Instant::now()));
+
+    actix_web::rt::spawn(write_socket(session.clone(), outgoing, last_heard.clone(), session_id, connection));
+
+    let mut replies = session.clone();
+    while let Some(Ok(frame)) = messages.recv().await {
+        last_heard.set(Instant::now());
+
+        match frame {
+            Message::Text(text) => {
+                let reply = match serde_json::from_str::<ClientMessage>(&text) {
+                    Ok(message) => handle_message(&pool, peer, message).await,
+                    Err(e) => Some(ServerMessage::Error { message: format!("Unreadable message: {}", e) }),
+                };
+                if let Some(reply) = reply {
+                    if send_envelope(&mut replies, &Envelope::reply(reply)).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            // A closed session ends the stream on the next read
+            Message::Ping(bytes) => {
+                let _ = replies.pong(&bytes).await;
+            }
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+
+    gateway::disconnect(session_id, connection);
+    let _ = session.close(None).await;
+}
+
+async fn write_socket(
+    mut session: Session,
+    mut outgoing: tokio::sync::mpsc::UnboundedReceiver<Envelope>,
+    last_heard: Rc<Cell<Instant>>,
+    session_id: Uuid,
+    connection: u64,
+) {
+    loop {
+        match timeout(HEARTBEAT, outgoing.recv()).await {
+            Ok(Some(envelope)) => {
+                if send_envelope(&mut session, &envelope).await.is_err() {
+                    break;
+                }
+            }
+            // Detached, or replaced by a newer connection
+            Ok(None) => break,
+            Err(_idle) => {
+                if last_heard.get().elapsed() > CLIENT_TIMEOUT || session.ping(b"").await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    gateway::disconnect(session_id, connection);
+    let _ = session.close(None).await;
+}
+
+async fn send_envelope(session: &mut Session, envelope: &Envelope) -> Result<(), actix_ws::Closed> {
+    let text = serde_json::to_string(envelope).expect("server messages serialize");
+    session.text(text).await
+}
+
+
+// Returns a reply for this connection only, anything meant for the other side
+// of a trip goes through the hub.
+async fn handle_message(pool: &web::Data<DbPool>, peer: Peer, message: ClientMessage) -> Option<ServerMessage> {
+    let error = |message: &str| Some(ServerMessage::Error { message: message.to_string() });
+
+    match (peer, message) {
+        (_, ClientMessage::Ping) => Some(ServerMessage::Pong),
+
+        (Peer::Driver(driver_uuid), ClientMessage::Accept { request_id }) => {
+            match still_offered(pool, driver_uuid, request_id).await {
+                Ok(true) => {
+                    gateway::answer_offer(driver_uuid, request_id, DriverResponse::Accepted);
+                    None
+                }
+                Ok(false) => error("Ride is no longer on offer"),
+                Err(e) => error(&e),
+            }
+        }
+
+        (Peer::Driver(driver_uuid), ClientMessage::Reject { request_id }) => {
+            if let Ok(true) = still_offered(pool, driver_uuid, request_id).await {
+                gateway::answer_offer(driver_uuid, request_id, DriverResponse::Rejected);
+            }
+            None
+        }
+
+        (Peer::Driver(driver_uuid), ClientMessage::Location { location }) => {
+            let pool = pool.clone();
+            match web::block(move || share_location(&pool, driver_uuid, &location)).await {
+                Ok(Ok(())) => None,
+                Ok(Err(e)) => error(&e),
+                Err(_) => error("Server error"),
+            }
+        }
+
+        (Peer::Rider(_), ClientMessage::Accept { .. } | ClientMessage::Reject { .. } | ClientMessage::Location { .. }) => {
+            error("Only drivers can send this message")
+        }
+
+        (_, ClientMessage::Chat { trip_reference, text }) => {
+            let pool = pool.clone();
+            match web::block(move || relay_chat(&pool, peer, trip_reference, text)).await {
+                Ok(Ok(())) => None,
+                Ok(Err(e)) => error(&e),
+                Err(_) => error("Server error"),
+            }
+        }
+    }
+}
+
+// The dispatching instance may be another one, the reservation is what both see.
+async fn still_offered(pool: &web::Data<DbPool>, driver_uuid: Uuid, request_id: Uuid) -> Result<bool, String> {
+    let pool = pool.clone();
+    let offered = web::block(move || -> Result<Option<Uuid>, String> {
+        let mut conn = pool.get().map_err(|e| e.to_string())?;
+        offered_request(&mut conn, driver_uuid, Utc::now().timestamp()).map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|_| "Server error".to_string())??;
+
+    Ok(offered == Some(request_id))
+}
+
+// Storing the position passes it on to the riders of the driver's ongoing trips.
+fn share_location(pool: &DbPool, driver_uuid: Uuid, location: &GeoPoint) -> Result<(), String> {
+    let mut conn = pool.get().map_err(|e| e.to_string())?;
+    update_driver_location(&mut conn, driver_uuid, location).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+fn relay_chat(pool: &DbPool, from: Peer, trip_reference: String, text: String) -> Result<(), String> {
+    let mut conn = pool.get().map_err(|e| e.to_string())?;
+    let trip = get_trip_by_reference(&mut conn, &trip_reference).map_err(|_| "Trip not found".to_string())?;
+
+    let (party, to) = match from {
+        Peer::Rider(id) if id == trip.rider_id => (TripParty::Rider, Peer::Driver(trip.driver_id)),
+        Peer::Driver(id) if id == trip.driver_id => (TripParty::Driver, Peer::Rider(trip.rider_id)),
+        _ => return Err("Not part of this trip".to_string()),
+    };
+    if !trip.status.is_active() {
+        return Err("Trip is over".to_string());
+    }
+
+    gateway::send_to(to, ServerMessage::Chat {
+        trip_reference,
+        from: party,
+        text,
+        sent_at: Utc::now().timestamp(),
+    });
+    Ok(())
+}
+
+
+pub fn routes() -> Scope {
+    web::scope("/ws")
+        .route("/driver", web::get().to(driver_socket))
+        .route("/user", web::get().to(rider_socket))
+}
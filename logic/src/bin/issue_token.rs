@@ -0,0 +1,43 @@
+//! Prints an access token signed with `JWT_SECRET`, for the first admin or a
+//! service that can't sign in:
+//!
+//! ```text
+//! cargo run --bin issue_token -- admin 7c9e6679-7425-40de-944b-e07fc1f90ae7
+//! ```
+use chrono::Utc;
+use logic::api::auth::{ Authenticator, Principal, Role };
+use uuid::Uuid;
+
+fn main() {
+    dotenv::dotenv().ok();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [role, subject] = args.as_slice() else {
+        eprintln!("usage: issue_token <rider|driver|admin> <uuid>");
+        std::process::exit(2);
+    };
+
+    let role: Role = match serde_json::from_value(serde_json::Value::String(role.clone())) {
+        Ok(role) => role,
+        Err(_) => {
+            eprintln!("Unknown role {:?}", role);
+            std::process::exit(2);
+        }
+    };
+    let subject: Uuid = subject.parse().unwrap_or_else(|e| {
+        eprintln!("Invalid subject: {}", e);
+        std::process::exit(2);
+    });
+
+    let authenticator = Authenticator::from_env().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    match authenticator.issue(Principal::new(subject, role), Utc::now().timestamp()) {
+        Ok(token) => println!("{}", token.access_token),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -1,5 +1,11 @@
 use actix_web::{ web, App, HttpServer };
+use actix_web::middleware::from_fn;
 use dotenv::dotenv;
+use std::sync::Arc;
+use logic::api::auth::{ self, Authenticator };
+use logic::services::outbox::{ self, RpcRideRecorder };
+use logic::services::quotes::QuoteSigner;
+use logic::services::{ bus, gateway, geoindex, offers, pricing, surge, tariffs, traffic };
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -12,18 +18,80 @@ async fn main() -> std::io::Result<()> {
     let app_config = logic::config::AppConfig::from_env();
     println!("Loaded config");
 
+    let authenticator = web::Data::new(Authenticator::from_env().expect("Invalid auth config"));
+    println!("Loaded auth config");
+
+    let quote_signer = web::Data::new(QuoteSigner::from_env().expect("Invalid quote config"));
+
     let pool = logic::db::init_pool(&app_config.database_url);
     println!("Database pool initialized");
 
-    println!("Starting HTTP server on 0.0.0.0:8081");
+    match pool.get().map_err(|e| e.to_string()).and_then(|mut conn| {
+        geoindex::warm_from_db(&mut conn).map_err(|e| e.to_string())
+    }) {
+        Ok(count) => println!("Driver index loaded with {} drivers", count),
+        Err(e) => eprintln!("Driver index not loaded, dispatch will scan the table: {}", e),
+    }
+
+    match pool.get().map_err(|e| e.to_string()).and_then(|mut conn| {
+        tariffs::reload(&mut conn).map_err(|e| e.to_string())
+    }) {
+        Ok(count) => println!("Loaded {} tariffs", count),
+        Err(e) => eprintln!("Tariffs not loaded, rides are priced at the built-in rates: {}", e),
+    }
+
+    match pool.get().map_err(|e| e.to_string()).and_then(|mut conn| {
+        traffic::reload(&mut conn).map_err(|e| e.to_string())
+    }) {
+        Ok(count) => println!("Loaded {} traffic factors", count),
+        Err(e) => eprintln!("Traffic factors not loaded, ETAs use the default factor: {}", e),
+    }
+
+    match bus::from_env(&app_config.database_url, pool.clone()) {
+        Ok(dispatch_bus) => {
+            println!("Dispatch bus: {}", dispatch_bus.name());
+            gateway::install_bus(dispatch_bus);
+        }
+        Err(e) => eprintln!("Dispatch bus not started, offers stay on this instance: {}", e),
+    }
+
+    match pricing::router_from_env() {
+        Ok(router) => {
+            println!("Routing with {}", router.name());
+            pricing::install_router(router);
+        }
+        Err(e) => eprintln!("Router not set up, rides are routed as the crow flies: {}", e),
+    }
+
+    match RpcRideRecorder::from_config(&app_config) {
+        Ok(recorder) => {
+            actix_web::rt::spawn(outbox::run_worker(pool.clone(), Arc::new(recorder)));
+            println!("On-chain recording worker started");
+        }
+        Err(e) => eprintln!("On-chain recording worker not started, jobs will queue up: {}", e),
+    }
+
+    actix_web::rt::spawn(offers::run_recovery(pool.clone()));
+    println!("Offer recovery started");
+
+    actix_web::rt::spawn(tariffs::run_refresh(pool.clone()));
+    actix_web::rt::spawn(surge::run_engine(pool.clone()));
+    println!("Surge engine started");
+    actix_web::rt::spawn(traffic::run_fitter(pool.clone()));
+
+    let port: u16 = std::env::var("PORT").ok().and_then(|p| p.parse().ok()).unwrap_or(8081);
+    println!("Starting HTTP server on 0.0.0.0:{}", port);
 
     HttpServer::new(move || {
         App::new()
+        .wrap(from_fn(auth::authenticate))
+        .app_data(authenticator.clone())
+        .app_data(quote_signer.clone())
         .app_data(web::Data::new(pool.clone()))
         .configure(logic::api::init)
         .configure(logic::services::init)
     })
-    .bind(("0.0.0.0", 8081))?
+    .bind(("0.0.0.0", port))?
     .run()
     .await
 }
@@ -72,6 +72,8 @@ diesel::table! {
         vehicle_type -> Text,
         driver_response -> Jsonb,
         vehicle -> Nullable<Text>,
+        reserved_for -> Nullable<Uuid>,
+        reserved_until -> Nullable<Int8>,
     }
 }
 
@@ -122,6 +124,45 @@ diesel::table! {
         user_id -> Nullable<Int8>,
         user_phone_number -> Nullable<Text>,
         vendor_phone_number -> Nullable<Text>,
+        dispatch_status -> Text,
+        cancel_reason -> Nullable<Text>,
+        cancelled_at -> Nullable<Int8>,
+        tariff_id -> Nullable<Uuid>,
+        requested_at -> Nullable<Int8>,
+        surge_multiplier -> Float8,
+        quote_id -> Nullable<Uuid>,
+        quote_token -> Nullable<Text>,
+        redispatched_at -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    back_onchain_jobs (job_id) {
+        job_id -> Uuid,
+        kind -> Text,
+        trip_reference -> Text,
+        status -> Text,
+        attempts -> Int4,
+        next_attempt_at -> Int8,
+        last_error -> Nullable<Text>,
+        signature -> Nullable<Text>,
+        created_at -> Int8,
+        updated_at -> Int8,
+    }
+}
+
+diesel::table! {
+    back_payment_events (paystack_id, event) {
+        paystack_id -> Int8,
+        event -> Text,
+        reference -> Text,
+        status -> Text,
+        outcome -> Nullable<Text>,
+        payload -> Jsonb,
+        attempts -> Int4,
+        received_at -> Int8,
+        last_attempt_at -> Int8,
+        processed_at -> Nullable<Int8>,
     }
 }
 
@@ -160,6 +201,105 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    back_ride_offers (offer_id) {
+        offer_id -> Uuid,
+        request_id -> Uuid,
+        driver_id -> Uuid,
+        round -> Int4,
+        offered_at -> Int8,
+        expires_at -> Int8,
+        outcome -> Text,
+        answered_at -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    back_auth_challenges (nonce) {
+        nonce -> Text,
+        subject -> Uuid,
+        role -> Text,
+        pubkey -> Text,
+        issued_at -> Int8,
+        expires_at -> Int8,
+        used_at -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::CustomRoles;
+
+    back_user_roles (user_id, role) {
+        user_id -> Uuid,
+        role -> CustomRoles,
+        granted_by -> Uuid,
+        granted_at -> Int8,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::CustomRoles;
+
+    back_role_audit (audit_id) {
+        audit_id -> Uuid,
+        user_id -> Uuid,
+        role -> CustomRoles,
+        action -> Text,
+        actor_id -> Uuid,
+        reason -> Nullable<Text>,
+        recorded_at -> Int8,
+    }
+}
+
+diesel::table! {
+    back_service_zones (zone) {
+        zone -> Text,
+        name -> Text,
+        center_lat -> Float8,
+        center_lng -> Float8,
+        radius_km -> Float8,
+        updated_at -> Int8,
+    }
+}
+
+diesel::table! {
+    back_tariffs (tariff_id) {
+        tariff_id -> Uuid,
+        ride_type -> Text,
+        zone -> Text,
+        base_fare -> Float8,
+        per_km -> Float8,
+        per_min -> Float8,
+        minimum_fare -> Int8,
+        rounding -> Text,
+        round_to -> Int8,
+        effective_from -> Int8,
+        created_by -> Nullable<Uuid>,
+        created_at -> Int8,
+    }
+}
+
+diesel::table! {
+    back_driver_locations (driver_id, recorded_at) {
+        driver_id -> Uuid,
+        recorded_at -> Int8,
+        lat -> Float8,
+        lng -> Float8,
+    }
+}
+
+diesel::table! {
+    back_traffic_factors (zone, hour_of_week) {
+        zone -> Text,
+        hour_of_week -> Int4,
+        factor -> Float8,
+        samples -> Int4,
+        fitted_at -> Int8,
+    }
+}
+
 diesel::table! {
     back_trips (trip_id) {
         trip_id -> Bytea,
@@ -179,10 +319,21 @@ diesel::table! {
         fare_estimate -> Nullable<Int8>,
         fare_lamports -> Nullable<Int8>,
         rider_email -> Text,
+        onchain_signature -> Nullable<Text>,
+        arrived_at -> Nullable<Int8>,
+        picked_up_at -> Nullable<Int8>,
+        cancelled_by -> Nullable<Text>,
+        cancel_reason -> Nullable<Text>,
+        cancelled_at -> Nullable<Int8>,
+        request_id -> Nullable<Uuid>,
+        cancellation_fee -> Nullable<Int8>,
     }
 }
 
 diesel::joinable!(messages -> delivery_orders (delivery_order_id));
+diesel::joinable!(back_trips -> back_ride_request (request_id));
+diesel::joinable!(back_ride_offers -> back_ride_request (request_id));
+diesel::joinable!(back_ride_request -> back_tariffs (tariff_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
     custom_users,
@@ -192,6 +343,16 @@ diesel::allow_tables_to_appear_in_same_query!(
     messages,
     package_images,
     back_ride_request,
+    back_ride_offers,
+    back_auth_challenges,
+    back_user_roles,
+    back_role_audit,
+    back_service_zones,
+    back_tariffs,
+    back_driver_locations,
+    back_traffic_factors,
+    back_onchain_jobs,
+    back_payment_events,
     back_custom_users,
     riders_current_status,
     saved_locations,
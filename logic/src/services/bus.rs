@@ -0,0 +1,173 @@
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use diesel::sql_types::Text;
+use serde::{ Deserialize, Serialize };
+use std::sync::{ mpsc, Arc };
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+use crate::api::drivers::DriverResponse;
+use crate::api::riders::NewRideRequest;
+use crate::db::DbPool;
+use crate::services::gateway::{ self, Peer, ServerMessage };
+use crate::services::geoindex::IndexedDriver;
+
+
+/// The Postgres channel every instance listens on.
+pub const CHANNEL: &str = "dispatch_bus";
+/// NOTIFY refuses payloads from 8000 bytes up.
+const MAX_PAYLOAD: usize = 7999;
+/// How often the listening connection is checked for notifications.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+
+/// What the gateway hubs of the running instances tell each other. A driver's
+/// socket can be held by one instance while another one is dispatching the
+/// ride, so offers go out to every hub and answers come back the same way.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum BusEvent {
+    /// Show the ride to the driver, on whichever instance holds their session.
+    Offer { driver_id: Uuid, ride: Box<NewRideRequest> },
+    /// The instance holding the driver's session has shown them the offer.
+    Offered { driver_id: Uuid, request_id: Uuid },
+    /// The driver's answer, for the instance dispatching the request.
+    Answer { driver_id: Uuid, request_id: Uuid, response: DriverResponse },
+    Withdraw { driver_id: Uuid, request_id: Uuid },
+    /// The rider cancelled, for the instance dispatching the request.
+    Abort { request_id: Uuid },
+    /// A message for a peer's session.
+    Deliver { peer: Peer, message: ServerMessage },
+    /// A message for the SSE streams following a trip.
+    Watchers { trip_reference: String, message: ServerMessage },
+    /// A driver's entry in the dispatch index changed, for the indexes of the
+    /// other instances. None once the driver has left it.
+    DriverIndexed { driver_id: Uuid, indexed: Option<IndexedDriver> },
+}
+
+/// Carries hub events to every running instance, this one included.
+pub trait DispatchBus: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn publish(&self, event: &BusEvent) -> Result<(), String>;
+}
+
+
+/// A single instance, events go straight to the local hub.
+pub struct InProcessBus;
+
+impl DispatchBus for InProcessBus {
+    fn name(&self) -> &'static str {
+        "in_process"
+    }
+
+    fn publish(&self, event: &BusEvent) -> Result<(), String> {
+        gateway::receive(event.clone());
+        Ok(())
+    }
+}
+
+
+/// Several instances sharing one database. Events are sent with NOTIFY and
+/// every instance, the sender too, picks them up on a LISTEN connection of its
+/// own. Nothing is stored: an instance that is down when an event goes out
+/// never sees it, which is the same as a socket that dropped.
+pub struct PgNotifyBus {
+    outgoing: mpsc::Sender<String>,
+}
+
+impl PgNotifyBus {
+    /// Starts listening before returning, so events published from here on
+    /// reach this instance as well.
+    pub fn start(database_url: &str, pool: DbPool) -> Result<Self, String> {
+        let listener = listen_on(database_url).map_err(|e| e.to_string())?;
+        let database_url = database_url.to_string();
+        thread::Builder::new()
+            .name("dispatch-bus-listen".into())
+            .spawn(move || run_listener(database_url, listener))
+            .map_err(|e| e.to_string())?;
+
+        let (outgoing, payloads) = mpsc::channel();
+        thread::Builder::new()
+            .name("dispatch-bus-notify".into())
+            .spawn(move || run_notifier(pool, payloads))
+            .map_err(|e| e.to_string())?;
+
+        Ok(Self { outgoing })
+    }
+}
+
+impl DispatchBus for PgNotifyBus {
+    fn name(&self) -> &'static str {
+        "postgres"
+    }
+
+    // Queued for the notifier thread, the hub is often called from async code.
+    fn publish(&self, event: &BusEvent) -> Result<(), String> {
+        let payload = serde_json::to_string(event).map_err(|e| e.to_string())?;
+        if payload.len() > MAX_PAYLOAD {
+            return Err(format!("Bus event of {} bytes is too large for NOTIFY", payload.len()));
+        }
+        self.outgoing.send(payload).map_err(|_| "Dispatch bus notifier has stopped".to_string())
+    }
+}
+
+fn listen_on(database_url: &str) -> ConnectionResult<PgConnection> {
+    let mut conn = PgConnection::establish(database_url)?;
+    diesel::sql_query(format!("LISTEN {}", CHANNEL))
+        .execute(&mut conn)
+        .map_err(|e| ConnectionError::BadConnection(e.to_string()))?;
+    Ok(conn)
+}
+
+fn run_listener(database_url: String, mut conn: PgConnection) {
+    loop {
+        let lost = loop {
+            match conn.notifications_iter().next() {
+                Some(Ok(notification)) => match serde_json::from_str::<BusEvent>(&notification.payload) {
+                    Ok(event) => gateway::receive(event),
+                    Err(e) => eprintln!("Unreadable dispatch bus event: {}", e),
+                },
+                Some(Err(e)) => break e,
+                None => thread::sleep(POLL_INTERVAL),
+            }
+        };
+        eprintln!("Dispatch bus listener lost its connection: {}", lost);
+
+        conn = loop {
+            thread::sleep(RECONNECT_DELAY);
+            match listen_on(&database_url) {
+                Ok(conn) => break conn,
+                Err(e) => eprintln!("Dispatch bus listener could not reconnect: {}", e),
+            }
+        };
+    }
+}
+
+// One thread sends every NOTIFY so events keep the order they were published in.
+fn run_notifier(pool: DbPool, payloads: mpsc::Receiver<String>) {
+    for payload in payloads {
+        let sent = pool.get().map_err(|e| e.to_string()).and_then(|mut conn| {
+            diesel::sql_query("SELECT pg_notify($1, $2)")
+                .bind::<Text, _>(CHANNEL)
+                .bind::<Text, _>(&payload)
+                .execute(&mut conn)
+                .map_err(|e| e.to_string())
+        });
+
+        if let Err(e) = sent {
+            eprintln!("Dispatch bus event not sent: {}", e);
+        }
+    }
+}
+
+
+/// `DISPATCH_BUS=postgres` for more than one instance, in process otherwise.
+pub fn from_env(database_url: &str, pool: DbPool) -> Result<Arc<dyn DispatchBus>, String> {
+    match std::env::var("DISPATCH_BUS").unwrap_or_default().to_lowercase().as_str() {
+        "" | "in_process" => Ok(Arc::new(InProcessBus)),
+        "postgres" => Ok(Arc::new(PgNotifyBus::start(database_url, pool)?)),
+        other => Err(format!("Unknown DISPATCH_BUS {:?}", other)),
+    }
+}
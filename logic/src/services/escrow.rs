@@ -1,10 +1,10 @@
-use actix_web::{web, post, Scope, HttpResponse};
+use actix_web::{web, post, Scope, HttpRequest, HttpResponse};
 use serde::{ Deserialize, Serialize };
-use sha2::{Sha256, Digest};
-use solana_client::rpc_client::RpcClient;
+use sha2::{Sha256, Sha512, Digest};
+use hmac::{ Hmac, Mac };
 use solana_sdk::{
-    signature::{ Keypair, read_keypair_file, Signer },
-    transaction::Transaction,
+    transaction::TransactionError,
+    instruction::{ Instruction, InstructionError },
     pubkey::Pubkey,
     system_program,
 };
@@ -12,10 +12,15 @@ use anyhow::{ Result, anyhow };
 use std::str::FromStr;
 use ride_program::accounts::RecordRide;
 use ride_program::instruction::RecordRide as RecordRideIx;
-use ride_program::RideInput;
+use ride_program::{ RideInput, RideError };
 use anchor_client::anchor_lang::{ InstructionData, ToAccountMetas };
+use anchor_client::anchor_lang::error::ERROR_CODE_OFFSET;
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
 use crate::{ api::{ riders, drivers }, db::{ DbPool } };
-use crate::api::trips::get_trip_by_reference;
+use crate::api::trips::{ get_trip_by_reference, Trip };
+use crate::services::outbox::{ self, OnchainJob };
 use crate::schema::back_trips::dsl::{back_trips as trips, *};
 use std::env;
 
@@ -42,29 +47,151 @@ pub fn vec_to_array_32(v: Vec<u8>) -> Result<[u8; 32]> {
     Ok(arr)
 }
 
-fn get_program_id() -> Pubkey {
-    let program_id_str =
-        std::env::var("SOLANA_PROGRAM_ID").expect("SOLANA_PROGRAM_ID must be set");
-    program_id_str
-        .parse::<Pubkey>()
-        .expect("Invalid program ID")
+pub const PAYSTACK_SIGNATURE_HEADER: &str = "x-paystack-signature";
+
+/// Paystack signs every webhook with an HMAC-SHA512 of the raw request body,
+/// keyed with the account secret key, and sends it hex encoded in
+/// `x-paystack-signature`. The comparison is constant time.
+pub fn verify_paystack_signature(secret: &[u8], body: &[u8], signature_hex: &str) -> bool {
+    let signature = match hex::decode(signature_hex.trim()) {
+        Ok(bytes) => bytes,
+        Err(_) => return false,
+    };
+
+    let mut mac = match Hmac::<Sha512>::new_from_slice(secret) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
 }
 
+// A delivery stuck in "processing" this long was most likely cut off by a restart.
+const STALE_PROCESSING_SECS: i64 = 300;
+
+
 
 pub async fn handle_payment_confirmation(
-    payload: web::Json<PaystackWebhook>,
+    req: HttpRequest,
+    body: web::Bytes,
     pool: web::Data<DbPool>,
 ) -> HttpResponse {
-    // 1️⃣ Ignore non-success events
+    let peer = req
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|| "unknown".into());
+
+    // 0️⃣ Reject anything Paystack did not sign
+    let secret_key = match env::var("PAYSTACK_SECRET_KEY") {
+        Ok(key) => key,
+        Err(_) => {
+            eprintln!("PAYSTACK_SECRET_KEY is not set, cannot verify webhook");
+            return HttpResponse::InternalServerError().body("Webhook verification unavailable");
+        }
+    };
+
+    let signature = match req
+        .headers()
+        .get(PAYSTACK_SIGNATURE_HEADER)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(sig) => sig,
+        None => {
+            eprintln!("⛔ Rejected Paystack webhook from {}: missing {} header", peer, PAYSTACK_SIGNATURE_HEADER);
+            return HttpResponse::Unauthorized().body("Missing signature");
+        }
+    };
+
+    if !verify_paystack_signature(secret_key.as_bytes(), &body, signature) {
+        eprintln!("⛔ Rejected Paystack webhook from {}: signature mismatch", peer);
+        return HttpResponse::Unauthorized().body("Invalid signature");
+    }
+
+    let payload: PaystackWebhook = match serde_json::from_slice(&body) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("Malformed Paystack webhook from {}: {}", peer, e);
+            return HttpResponse::BadRequest().body("Malformed webhook payload");
+        }
+    };
+
+    // 1️⃣ Log the delivery; retries of an event we already handled stop here
+    let claim = match web::block({
+        let pool = pool.clone();
+        let raw = serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default();
+        let event_name = payload.event.clone();
+        let paystack_id = payload.data.id;
+        let reference_value = payload.data.reference.clone();
+
+        move || -> Result<EventClaim, String> {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            claim_payment_event(&mut conn, paystack_id, &event_name, &reference_value, raw)
+                .map_err(|e| e.to_string())
+        }
+    })
+    .await
+    {
+        Ok(Ok(claim)) => claim,
+        Ok(Err(e)) => {
+            eprintln!("DB error: {}", e);
+            return HttpResponse::InternalServerError().body("Database error");
+        }
+        Err(e) => {
+            eprintln!("Threadpool error: {:?}", e);
+            return HttpResponse::InternalServerError().body("Threadpool error");
+        }
+    };
+
+    if let EventClaim::Duplicate(existing) = claim {
+        println!(
+            "🔁 Duplicate Paystack {} for {} ({}), already {}",
+            existing.event, existing.reference, existing.paystack_id, existing.status
+        );
+        return HttpResponse::Ok().json(serde_json::json!({
+            "status": "duplicate",
+            "event_status": existing.status,
+            "outcome": existing.outcome,
+        }));
+    }
+
+    // 2️⃣ Ignore non-success events
     if payload.event != "charge.success" {
+        finish_payment_event(&pool, &payload, PaymentEventStatus::Ignored, "Ignoring non-success event").await;
         return HttpResponse::Ok().body("Ignoring non-success event");
     }
 
+    match process_charge_success(&pool, &payload).await {
+        Ok(outcome) => HttpResponse::Ok().json(outcome),
+        Err(WebhookFailure::BadRequest(msg)) => {
+            finish_payment_event(&pool, &payload, PaymentEventStatus::Failed, &msg).await;
+            HttpResponse::BadRequest().body(msg)
+        }
+        Err(WebhookFailure::Unprocessable(msg)) => {
+            // Retrying cannot fix it, so Paystack gets its 2xx and the event
+            // log keeps the failure for an operator
+            finish_payment_event(&pool, &payload, PaymentEventStatus::Failed, &msg).await;
+            HttpResponse::Ok().json(serde_json::json!({
+                "status": "failed",
+                "reason": msg,
+            }))
+        }
+        Err(WebhookFailure::Internal(msg)) => {
+            finish_payment_event(&pool, &payload, PaymentEventStatus::Failed, &msg).await;
+            HttpResponse::InternalServerError().body(msg)
+        }
+    }
+}
+
+
+async fn process_charge_success(
+    pool: &web::Data<DbPool>,
+    payload: &PaystackWebhook,
+) -> Result<serde_json::Value, WebhookFailure> {
     let trip_reference = payload.data.reference.clone();
     let amount_kobo = payload.data.amount;
     let rider_email_stack = payload.data.customer.email.clone();
 
-    // 2️⃣ Paystack split (OFF-CHAIN)
+    // 3️⃣ Paystack split (OFF-CHAIN)
     let driver_share = (amount_kobo * 80) / 100;
     let treasury_share = amount_kobo - driver_share;
 
@@ -83,96 +210,105 @@ pub async fn handle_payment_confirmation(
 
 
 
-
-
-    let trip = match web::block({
+    // 4️⃣ Queue the on-chain recording in the same transaction that marks the
+    // event processed; outbox::run_worker picks it up from there.
+    let job = match web::block({
         let pool = pool.clone();
         let trip_reference = trip_reference.clone();
-
-        move || {
-            let mut conn = pool.get().expect("Failed to get connection");
-            get_trip_by_reference(&mut conn, &trip_reference)
+        let paystack_id = payload.data.id;
+        let event_name = payload.event.clone();
+
+        move || -> Result<OnchainJob, String> {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            conn.transaction(|conn| {
+                let trip = get_trip_by_reference(conn, &trip_reference)?;
+                let job = outbox::enqueue_record_ride(conn, &trip.reference, Utc::now().timestamp())?;
+                complete_payment_event(
+                    conn,
+                    paystack_id,
+                    &event_name,
+                    PaymentEventStatus::Processed,
+                    &format!("record_ride job {} queued", job.job_id),
+                )?;
+                Ok(job)
+            })
+            .map_err(|e: diesel::result::Error| match e {
+                diesel::result::Error::NotFound => "Trip not found".to_string(),
+                other => other.to_string(),
+            })
         }
     })
     .await
     {
-        Ok(Ok(trip)) => trip,
+        Ok(Ok(job)) => job,
+
+        Ok(Err(e)) if e == "Trip not found" => {
+            eprintln!("No trip for paid reference {}", trip_reference);
+            return Err(WebhookFailure::Unprocessable(e));
+        }
 
         Ok(Err(e)) => {
             eprintln!("DB error: {:?}", e);
-            return HttpResponse::InternalServerError().body("Database error");
+            return Err(WebhookFailure::Internal("Database error".into()));
         }
 
         Err(e) => {
             eprintln!("Threadpool error: {:?}", e);
-            return HttpResponse::InternalServerError().body("Threadpool error");
+            return Err(WebhookFailure::Internal("Threadpool error".into()));
         }
     };
 
     println!(
-        "✅ Payment confirmed for trip {} by rider {}",
-        trip_reference, rider_email_stack
+        "✅ Payment confirmed for trip {} by rider {}, record_ride job {} queued",
+        trip_reference, rider_email_stack, job.job_id
     );
 
-    // 4️⃣ Convert DB → Solana-safe types
-    let passenger = match pubkey_from_string(&trip.rider_pubkey) {
-        Ok(v) => v,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid rider pubkey"),
-    };
+    Ok(serde_json::json!({
+            "status": "success",
+            "trip_reference": trip_reference,
+            "rider": rider_email_stack,
+            "amount_kobo": amount_kobo,
+            "job_id": job.job_id,
+    }))
+}
 
-    let driver = match pubkey_from_string(&trip.driver_pubkey) {
-        Ok(v) => v,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid driver pubkey"),
-    };
 
-    let start_ts_program = match i64_to_u64(trip.start_ts) {
-        Ok(v) => v,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid start_ts"),
-    };
+/// Builds the `record_ride` instruction for a finished trip. Errors describe
+/// trip data the program would never accept, so they are not worth retrying.
+pub fn build_record_ride_instruction(
+    trip: &Trip,
+    program_id: Pubkey,
+    authority: Pubkey,
+) -> std::result::Result<Instruction, String> {
+    // Convert DB → Solana-safe types
+    let passenger = pubkey_from_string(&trip.rider_pubkey)
+        .map_err(|_| "Invalid rider pubkey".to_string())?;
+
+    let driver = pubkey_from_string(&trip.driver_pubkey)
+        .map_err(|_| "Invalid driver pubkey".to_string())?;
+
+    let start_ts_program = i64_to_u64(trip.start_ts)
+        .map_err(|_| "Invalid start_ts".to_string())?;
 
     let end_ts_program: u64 = match trip.end_ts {
-    Some(v) => match i64_to_u64(v) {
-        Ok(v) => v,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid end_ts"),
-    },
-    None => return HttpResponse::BadRequest().body("end_ts is required"),
+        Some(v) => i64_to_u64(v).map_err(|_| "Invalid end_ts".to_string())?,
+        None => return Err("end_ts is required".into()),
     };
 
-
-    let fare_lamports_program = match trip
+    let fare_lamports_program = trip
         .fare_lamports
         .map(i64_to_u64)
         .transpose()
-    {
-        Ok(v) => v,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid fare"),
-    };
+        .map_err(|_| "Invalid fare".to_string())?;
 
-    let fare_estimate_program = match trip
+    let fare_estimate_program = trip
         .fare_estimate
         .map(i64_to_u64)
         .transpose()
-    {
-        Ok(v) => v,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid fare estimate"),
-    };
-
-    let trip_id_program = match vec_to_array_32(trip.trip_id) {
-        Ok(v) => v,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid trip_id"),
-    };
-
-    // 5️⃣ Solana setup
-    let rpc_url = std::env::var("SOLANA_RPC_URL")
-        .unwrap_or_else(|_| "https://api.devnet.solana.com".into());
-    let client = RpcClient::new(rpc_url);
+        .map_err(|_| "Invalid fare estimate".to_string())?;
 
-    let payer_path = std::env::var("HOME").unwrap() + "/.config/solana/id.json";
-    let payer: Keypair = read_keypair_file(&payer_path)
-                        .expect("Keypair load failed");
-    
-
-    let program_id = get_program_id();
+    let trip_id_program = vec_to_array_32(trip.trip_id.clone())
+        .map_err(|_| "Invalid trip_id".to_string())?;
 
     let seeds: &[&[u8]] = &[b"ride", &trip_id_program];
 
@@ -181,18 +317,17 @@ pub async fn handle_payment_confirmation(
 
     let escrow_tx_hash = {
         let mut hasher = Sha256::new();
-        hasher.update(trip_reference.as_bytes());
+        hasher.update(trip.reference.as_bytes());
         let result = hasher.finalize();
         <[u8; 32]>::try_from(result.as_slice()).unwrap()
     };
 
-    // 6️⃣ Build instruction
-    let instruction = solana_sdk::instruction::Instruction {
+    Ok(Instruction {
         program_id,
         accounts: RecordRide {
             ride_account: ride_pda,
-            authority: payer.pubkey(),
-            payer: payer.pubkey(),
+            authority,
+            payer: authority,
             system_program: system_program::id(),
         }
         .to_account_metas(None),
@@ -203,8 +338,8 @@ pub async fn handle_payment_confirmation(
                 driver,
                 start_ts_program,
                 end_ts_program,
-                pick_up: trip.pick_up,
-                drop_off: trip.drop_off,
+                pick_up: trip.pick_up.clone(),
+                drop_off: trip.drop_off.clone(),
                 distance_km: trip.distance_km,
                 fare_lamports_program,
                 fare_estimate_program,
@@ -212,34 +347,138 @@ pub async fn handle_payment_confirmation(
             },
         }
         .data(),
-    };
+    })
+}
 
-    // 7️⃣ Send transaction
-    let blockhash = match client.get_latest_blockhash() {
-        Ok(h) => h,
-        Err(_) => return HttpResponse::InternalServerError().body("RPC error"),
-    };
 
-    let tx = Transaction::new_signed_with_payer(
-        &[instruction],
-        Some(&payer.pubkey()),
-        &[&payer],
-        blockhash,
-    );
+/// `record_ride` fails with `RideError::AlreadyRecorded` once the ride PDA exists.
+pub fn is_already_recorded(err: &TransactionError) -> bool {
+    let already_recorded = ERROR_CODE_OFFSET + RideError::AlreadyRecorded as u32;
+    matches!(
+        err,
+        TransactionError::InstructionError(_, InstructionError::Custom(code)) if *code == already_recorded
+    )
+}
+
 
-    if let Err(e) = client.send_and_confirm_transaction(&tx) {
-        eprintln!("Transaction failed: {:?}", e);
-        return HttpResponse::InternalServerError().body("Transaction failed");
+/// Paystack retries a webhook until it gets a 2xx, so a delivery is only
+/// processed again if the previous attempt failed or never finished.
+pub fn can_reclaim_payment_event(status_value: &str, last_attempt_at_value: i64, now: i64) -> bool {
+    match status_value {
+        "failed" => true,
+        "processing" => now - last_attempt_at_value > STALE_PROCESSING_SECS,
+        _ => false,
     }
+}
 
-    println!("Ride recorded on-chain for reference {}", trip_reference);
+pub fn claim_payment_event(
+    conn: &mut PgConnection,
+    paystack_id_value: i64,
+    event_value: &str,
+    reference_value: &str,
+    payload_value: serde_json::Value,
+) -> QueryResult<EventClaim> {
+    use crate::schema::back_payment_events::dsl::*;
+
+    let now = Utc::now().timestamp();
+
+    conn.transaction(|conn| {
+        let inserted = diesel::insert_into(back_payment_events)
+            .values(NewPaymentEvent {
+                paystack_id: paystack_id_value,
+                event: event_value.to_string(),
+                reference: reference_value.to_string(),
+                status: PaymentEventStatus::Processing.as_str().to_string(),
+                payload: payload_value,
+                received_at: now,
+                last_attempt_at: now,
+            })
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+
+        if inserted > 0 {
+            return Ok(EventClaim::New);
+        }
 
-    HttpResponse::Ok().json(serde_json::json!({
-            "status": "success",
-            "trip_id": hex::encode(trip_id_program),
-            "rider": rider_email_stack,
-            "amount_kobo": amount_kobo
-    }))
+        let existing: PaymentEvent = back_payment_events
+            .find((paystack_id_value, event_value))
+            .select(PaymentEvent::as_select())
+            .for_update()
+            .first(conn)?;
+
+        if !can_reclaim_payment_event(&existing.status, existing.last_attempt_at, now) {
+            return Ok(EventClaim::Duplicate(existing));
+        }
+
+        diesel::update(back_payment_events.find((paystack_id_value, event_value)))
+            .set((
+                status.eq(PaymentEventStatus::Processing.as_str()),
+                attempts.eq(attempts + 1),
+                last_attempt_at.eq(now),
+            ))
+            .execute(conn)?;
+
+        Ok(EventClaim::Retry)
+    })
+}
+
+pub fn complete_payment_event(
+    conn: &mut PgConnection,
+    paystack_id_value: i64,
+    event_value: &str,
+    status_value: PaymentEventStatus,
+    outcome_value: &str,
+) -> QueryResult<usize> {
+    use crate::schema::back_payment_events::dsl::*;
+
+    diesel::update(back_payment_events.find((paystack_id_value, event_value)))
+        .set((
+            status.eq(status_value.as_str()),
+            outcome.eq(outcome_value),
+            processed_at.eq(Utc::now().timestamp()),
+        ))
+        .execute(conn)
+}
+
+// Failing to write the outcome must not change what we tell Paystack.
+async fn finish_payment_event(
+    pool: &web::Data<DbPool>,
+    payload: &PaystackWebhook,
+    status_value: PaymentEventStatus,
+    outcome_value: &str,
+) {
+    let result = web::block({
+        let pool = pool.clone();
+        let paystack_id = payload.data.id;
+        let event_name = payload.event.clone();
+        let outcome_value = outcome_value.to_string();
+
+        move || -> Result<usize, String> {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            complete_payment_event(&mut conn, paystack_id, &event_name, status_value, &outcome_value)
+                .map_err(|e| e.to_string())
+        }
+    })
+    .await;
+
+    match result {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => eprintln!("Failed to record payment event outcome: {}", e),
+        Err(e) => eprintln!("Threadpool error: {:?}", e),
+    }
+}
+
+
+/// Every delivery logged for `reference_value`, oldest first. Served to
+/// operators from `/admin/payment-events/{reference}`.
+pub fn payment_events_for(conn: &mut PgConnection, reference_value: &str) -> QueryResult<Vec<PaymentEvent>> {
+    use crate::schema::back_payment_events::dsl::*;
+
+    back_payment_events
+        .filter(reference.eq(reference_value))
+        .order(received_at.asc())
+        .select(PaymentEvent::as_select())
+        .load::<PaymentEvent>(conn)
 }
 
 
@@ -273,6 +512,77 @@ pub struct PaystackCustomer {
     pub email: String,
 }
 
+#[derive(Debug)]
+pub enum WebhookFailure {
+    BadRequest(String),
+    /// Valid delivery we can never process, acknowledged so Paystack stops retrying
+    Unprocessable(String),
+    Internal(String),
+}
+
+pub enum EventClaim {
+    New,
+    Retry,
+    Duplicate(PaymentEvent),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PaymentEventStatus {
+    Processing,
+    Processed,
+    Ignored,
+    Failed,
+}
+
+impl PaymentEventStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentEventStatus::Processing => "processing",
+            PaymentEventStatus::Processed => "processed",
+            PaymentEventStatus::Ignored => "ignored",
+            PaymentEventStatus::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Queryable, Selectable, Serialize, Deserialize, Clone)]
+#[diesel(table_name = crate::schema::back_payment_events)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct PaymentEvent {
+    pub paystack_id: i64,
+    pub event: String,
+    pub reference: String,
+    pub status: String,
+    pub outcome: Option<String>,
+#[diesel(sql_type = diesel::sql_types::Jsonb)]
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    pub payload: serde_json::Value,
+    pub attempts: i32,
+    pub received_at: i64,
+    pub last_attempt_at: i64,
+    pub processed_at: Option<i64>,
+}
+
+impl PaymentEvent {
+    /// The event without Paystack's raw body, which carries the customer's
+    /// email and card details.
+    pub fn without_payload(self) -> Self {
+        Self { payload: serde_json::Value::Null, ..self }
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::back_payment_events)]
+pub struct NewPaymentEvent {
+    pub paystack_id: i64,
+    pub event: String,
+    pub reference: String,
+    pub status: String,
+    pub payload: serde_json::Value,
+    pub received_at: i64,
+    pub last_attempt_at: i64,
+}
+
 
 //write paystack post json with reference
 
@@ -0,0 +1,529 @@
+use lazy_static::lazy_static;
+use serde::{ Deserialize, Serialize };
+use std::collections::{ HashMap, VecDeque };
+use std::sync::{ Arc, Mutex, MutexGuard, RwLock };
+use std::time::{ Duration, Instant };
+use tokio::sync::{ mpsc, oneshot };
+use tokio::time::timeout;
+use uuid::Uuid;
+use crate::api::drivers::DriverResponse;
+use crate::api::riders::NewRideRequest;
+use crate::api::trips::{ CancelReason, Trip, TripParty, TripStatus };
+use crate::services::bus::{ BusEvent, DispatchBus, InProcessBus };
+use crate::services::geoindex::{ self, IndexedDriver };
+use crate::services::notifications::calculate_eta;
+use crate::services::pricing::GeoPoint;
+
+
+/// How long a dropped session keeps its messages for the app to reconnect.
+pub const RESUME_WINDOW: Duration = Duration::from_secs(120);
+const BACKLOG_LEN: usize = 100;
+/// How long an offer may take to reach the instance holding the driver's
+/// session before the driver is treated as unreachable.
+pub const OFFER_DELIVERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+
+lazy_static! {
+    /// Every driver and rider connected to /ws (or long-polling for offers)
+    /// on this instance, and the ride offers its dispatch is waiting on.
+    pub static ref HUB: Mutex<Hub> = Mutex::new(Hub::default());
+
+    /// How hub events reach the other instances, see `install_bus`.
+    static ref BUS: RwLock<Arc<dyn DispatchBus>> = RwLock::new(Arc::new(InProcessBus));
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "role", content = "id", rename_all = "snake_case")]
+pub enum Peer {
+    Driver(Uuid),
+    Rider(Uuid),
+}
+
+/// What the server sends down a socket.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Welcome { session_id: Uuid, resumed: bool, last_seq: u64 },
+    RideOffer { ride: Box<NewRideRequest> },
+    OfferWithdrawn { request_id: Uuid },
+    TripStatus { trip_reference: String, status: TripStatus },
+    DriverLocation { trip_reference: String, location: GeoPoint, eta_min: Option<i32>, eta: Option<String> },
+    Chat { trip_reference: String, from: TripParty, text: String, sent_at: i64 },
+    /// To the other side of a trip its rider or driver cancelled.
+    TripCancelled { trip_reference: String, cancelled_by: TripParty, reason: CancelReason, cancellation_fee: Option<i64> },
+    Error { message: String },
+    Pong,
+}
+
+impl ServerMessage {
+    /// The `type` tag, also used as the SSE event name.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Welcome { .. } => "welcome",
+            Self::RideOffer { .. } => "ride_offer",
+            Self::OfferWithdrawn { .. } => "offer_withdrawn",
+            Self::TripStatus { .. } => "trip_status",
+            Self::DriverLocation { .. } => "driver_location",
+            Self::Chat { .. } => "chat",
+            Self::TripCancelled { .. } => "trip_cancelled",
+            Self::Error { .. } => "error",
+            Self::Pong => "pong",
+        }
+    }
+}
+
+/// What the apps send up a socket. Accept, reject and location only make
+/// sense from a driver.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Accept { request_id: Uuid },
+    Reject { request_id: Uuid },
+    Location { location: GeoPoint },
+    Chat { trip_reference: String, text: String },
+    Ping,
+}
+
+/// A server message on the wire, `{"seq": 7, "type": "ride_offer", ...}`.
+/// Sequence numbers count up per session from 1; replies to the connection
+/// itself (welcome, pong, errors) are sent with seq 0 and never replayed.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub message: ServerMessage,
+}
+
+impl Envelope {
+    pub fn reply(message: ServerMessage) -> Self {
+        Self { seq: 0, message }
+    }
+}
+
+/// Where a reconnecting app left off.
+#[derive(Debug, Clone, Copy)]
+pub struct Resume {
+    pub session_id: Uuid,
+    pub last_seq: u64,
+}
+
+pub struct Connection {
+    pub session_id: Uuid,
+    /// Tells this socket apart from an earlier one on the same session.
+    pub connection: u64,
+    pub resumed: bool,
+    pub messages: mpsc::UnboundedReceiver<Envelope>,
+}
+
+struct Session {
+    peer: Peer,
+    last_seq: u64,
+    backlog: VecDeque<Envelope>,
+    outbox: Option<(u64, mpsc::UnboundedSender<Envelope>)>,
+    detached_at: Option<Instant>,
+}
+
+struct AwaitedAnswer {
+    request_id: Uuid,
+    delivered: Option<oneshot::Sender<()>>,
+    answer: oneshot::Sender<DriverResponse>,
+}
+
+
+/// Sessions outlive their sockets. A message for a peer whose app has dropped
+/// off is kept in the session's backlog, and reconnecting with the session id
+/// and the last seq seen replays what was missed. Sessions nobody comes back
+/// to within `RESUME_WINDOW` are forgotten.
+///
+/// Offers are split in two halves that may live on different instances: the
+/// ride shown on the driver's session, and the answer the dispatching side is
+/// waiting for. `apply` is how either half hears from the other. A rider
+/// cancelling reaches the dispatch of their request the same way.
+#[derive(Default)]
+pub struct Hub {
+    sessions: HashMap<Uuid, Session>,
+    by_peer: HashMap<Peer, Uuid>,
+    offers: HashMap<Uuid, NewRideRequest>,
+    awaiting: HashMap<Uuid, AwaitedAnswer>,
+    dispatches: HashMap<Uuid, oneshot::Sender<()>>,
+    watchers: HashMap<String, Vec<mpsc::UnboundedSender<ServerMessage>>>,
+    connections: u64,
+}
+
+impl Hub {
+    pub fn connect(&mut self, peer: Peer, resume: Option<Resume>, now: Instant) -> Connection {
+        self.prune(now);
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.connections += 1;
+        let connection = self.connections;
+
+        let resumable = self.by_peer.get(&peer).copied().zip(resume)
+            .filter(|(current, resume)| *current == resume.session_id);
+
+        let (session_id, resumed) = match resumable {
+            Some((session_id, resume)) => {
+                let session = self.sessions.get_mut(&session_id).expect("indexed session");
+                let welcome = Envelope::reply(ServerMessage::Welcome {
+                    session_id,
+                    resumed: true,
+                    last_seq: session.last_seq,
+                });
+                let _ = tx.send(welcome);
+                for missed in session.backlog.iter().filter(|e| e.seq > resume.last_seq) {
+                    let _ = tx.send(missed.clone());
+                }
+                session.outbox = Some((connection, tx));
+                session.detached_at = None;
+                (session_id, true)
+            }
+            None => {
+                // A fresh start replaces whatever session the peer had, and
+                // closes its socket by dropping the sender
+                if let Some(old) = self.by_peer.remove(&peer) {
+                    self.sessions.remove(&old);
+                }
+
+                let session_id = Uuid::new_v4();
+                let _ = tx.send(Envelope::reply(ServerMessage::Welcome { session_id, resumed: false, last_seq: 0 }));
+                self.sessions.insert(session_id, Session {
+                    peer,
+                    last_seq: 0,
+                    backlog: VecDeque::new(),
+                    outbox: Some((connection, tx)),
+                    detached_at: None,
+                });
+                self.by_peer.insert(peer, session_id);
+
+                // An offer made before the app restarted is still waiting
+                if let Peer::Driver(driver_uuid) = peer {
+                    if let Some(offer) = self.offers.get(&driver_uuid) {
+                        let ride = Box::new(offer.clone());
+                        self.send(peer, ServerMessage::RideOffer { ride }, now);
+                    }
+                }
+                (session_id, false)
+            }
+        };
+
+        Connection { session_id, connection, resumed, messages: rx }
+    }
+
+    /// Only detaches if `connection` is still the session's socket, a resumed
+    /// connection may already have taken over.
+    pub fn disconnect(&mut self, session_id: Uuid, connection: u64, now: Instant) {
+        if let Some(session) = self.sessions.get_mut(&session_id) {
+            if matches!(session.outbox, Some((current, _)) if current == connection) {
+                session.outbox = None;
+                session.detached_at = Some(now);
+            }
+        }
+    }
+
+    /// True if the peer has a session, connected or waiting to be resumed.
+    pub fn is_reachable(&mut self, peer: Peer, now: Instant) -> bool {
+        self.prune(now);
+        self.by_peer.contains_key(&peer)
+    }
+
+    /// Queues `message` on the peer's session and pushes it down the socket if
+    /// one is open. False if the peer has no session.
+    pub fn send(&mut self, peer: Peer, message: ServerMessage, now: Instant) -> bool {
+        self.prune(now);
+        let Some(session) = self.by_peer.get(&peer).and_then(|id| self.sessions.get_mut(id)) else {
+            return false;
+        };
+
+        session.last_seq += 1;
+        let envelope = Envelope { seq: session.last_seq, message };
+        session.backlog.push_back(envelope.clone());
+        if session.backlog.len() > BACKLOG_LEN {
+            session.backlog.pop_front();
+        }
+
+        if let Some((_, outbox)) = &session.outbox {
+            if outbox.send(envelope).is_err() {
+                session.outbox = None;
+                session.detached_at = Some(now);
+            }
+        }
+        true
+    }
+
+    /// Registers the dispatch side of an offer: the first receiver fires once
+    /// some instance has shown the ride to the driver, the second one gets
+    /// their answer.
+    pub fn await_answer(
+        &mut self,
+        driver_uuid: Uuid,
+        request_id: Uuid,
+    ) -> (oneshot::Receiver<()>, oneshot::Receiver<DriverResponse>) {
+        let (delivered, delivered_rx) = oneshot::channel();
+        let (answer, answer_rx) = oneshot::channel();
+        self.awaiting.insert(driver_uuid, AwaitedAnswer { request_id, delivered: Some(delivered), answer });
+        (delivered_rx, answer_rx)
+    }
+
+    /// Stops waiting on the driver, a later answer is dropped.
+    pub fn forget_answer(&mut self, driver_uuid: Uuid, request_id: Uuid) {
+        if self.awaiting.get(&driver_uuid).is_some_and(|awaited| awaited.request_id == request_id) {
+            self.awaiting.remove(&driver_uuid);
+        }
+    }
+
+    /// Registers a running dispatch, the receiver fires if its request is
+    /// cancelled.
+    pub fn watch_dispatch(&mut self, request_id: Uuid) -> oneshot::Receiver<()> {
+        let (abort, abort_rx) = oneshot::channel();
+        self.dispatches.insert(request_id, abort);
+        abort_rx
+    }
+
+    pub fn forget_dispatch(&mut self, request_id: Uuid) {
+        self.dispatches.remove(&request_id);
+    }
+
+    /// Acts on an event from the bus and returns the events it leads to,
+    /// for the caller to publish once the hub is unlocked.
+    pub fn apply(&mut self, event: BusEvent, now: Instant) -> Vec<BusEvent> {
+        match event {
+            BusEvent::Offer { driver_id, ride } => {
+                // Not our driver, the instance holding their session answers
+                if !self.is_reachable(Peer::Driver(driver_id), now) {
+                    return vec![];
+                }
+                let request_id = ride.request_id;
+                self.offers.insert(driver_id, (*ride).clone());
+                self.send(Peer::Driver(driver_id), ServerMessage::RideOffer { ride }, now);
+                vec![BusEvent::Offered { driver_id, request_id }]
+            }
+
+            BusEvent::Offered { driver_id, request_id } => {
+                if let Some(awaited) = self.awaiting.get_mut(&driver_id).filter(|a| a.request_id == request_id) {
+                    if let Some(delivered) = awaited.delivered.take() {
+                        let _ = delivered.send(());
+                    }
+                }
+                vec![]
+            }
+
+            BusEvent::Answer { driver_id, request_id, response } => {
+                if self.offers.get(&driver_id).is_some_and(|ride| ride.request_id == request_id) {
+                    self.offers.remove(&driver_id);
+                }
+                if self.awaiting.get(&driver_id).is_some_and(|a| a.request_id == request_id) {
+                    let awaited = self.awaiting.remove(&driver_id).expect("checked above");
+                    // The answer can overtake `Offered` on the bus, it was shown all the same
+                    if let Some(delivered) = awaited.delivered {
+                        let _ = delivered.send(());
+                    }
+                    let _ = awaited.answer.send(response);
+                }
+                vec![]
+            }
+
+            BusEvent::Withdraw { driver_id, request_id } => {
+                if self.offers.get(&driver_id).is_some_and(|ride| ride.request_id == request_id) {
+                    self.offers.remove(&driver_id);
+                    self.send(Peer::Driver(driver_id), ServerMessage::OfferWithdrawn { request_id }, now);
+                }
+                vec![]
+            }
+
+            BusEvent::Abort { request_id } => {
+                if let Some(abort) = self.dispatches.remove(&request_id) {
+                    let _ = abort.send(());
+                }
+                vec![]
+            }
+
+            BusEvent::Deliver { peer, message } => {
+                self.send(peer, message, now);
+                vec![]
+            }
+
+            BusEvent::Watchers { trip_reference, message } => {
+                self.publish_to_watchers(&trip_reference, message);
+                vec![]
+            }
+
+            // Not the hub's, `receive` hands it to the driver index
+            BusEvent::DriverIndexed { .. } => vec![],
+        }
+    }
+
+    /// Trip status and driver location for one trip, for the SSE stream. The
+    /// channel closes once the trip is completed or cancelled. Watchers whose
+    /// stream has gone away are dropped here too, since a trip that never
+    /// changes again would otherwise keep them.
+    pub fn watch_trip(&mut self, trip_reference: &str) -> mpsc::UnboundedReceiver<ServerMessage> {
+        self.watchers.retain(|_, watchers| {
+            watchers.retain(|watcher| !watcher.is_closed());
+            !watchers.is_empty()
+        });
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.watchers.entry(trip_reference.to_string()).or_default().push(tx);
+        rx
+    }
+
+    pub fn is_watched(&self, trip_reference: &str) -> bool {
+        self.watchers.contains_key(trip_reference)
+    }
+
+    pub fn publish_to_watchers(&mut self, trip_reference: &str, message: ServerMessage) {
+        let finished = matches!(&message, ServerMessage::TripStatus { status, .. } if status.is_finished());
+
+        if let Some(watchers) = self.watchers.get_mut(trip_reference) {
+            watchers.retain(|watcher| watcher.send(message.clone()).is_ok());
+            if watchers.is_empty() || finished {
+                self.watchers.remove(trip_reference);
+            }
+        }
+    }
+
+    pub fn has_offer(&self, driver_uuid: Uuid) -> bool {
+        self.offers.contains_key(&driver_uuid)
+    }
+
+    fn prune(&mut self, now: Instant) {
+        let expired: Vec<Uuid> = self.sessions
+            .iter()
+            .filter(|(_, s)| s.detached_at.is_some_and(|at| now.duration_since(at) > RESUME_WINDOW))
+            .map(|(id, _)| *id)
+            .collect();
+
+        for session_id in expired {
+            if let Some(session) = self.sessions.remove(&session_id) {
+                if self.by_peer.get(&session.peer) == Some(&session_id) {
+                    self.by_peer.remove(&session.peer);
+                }
+            }
+        }
+    }
+}
+
+
+// Like the driver index, a panic halfway through one update leaves nothing
+// worth refusing to serve over.
+fn hub() -> MutexGuard<'static, Hub> {
+    HUB.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub fn connect(peer: Peer, resume: Option<Resume>) -> Connection {
+    hub().connect(peer, resume, Instant::now())
+}
+
+pub fn disconnect(session_id: Uuid, connection: u64) {
+    hub().disconnect(session_id, connection, Instant::now())
+}
+
+/// Replaces the in-process bus, called once at startup.
+pub fn install_bus(bus: Arc<dyn DispatchBus>) {
+    *BUS.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = bus;
+}
+
+fn publish(event: BusEvent) {
+    let bus = BUS.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone();
+    if let Err(e) = bus.publish(&event) {
+        eprintln!("Dispatch bus ({}): {}", bus.name(), e);
+    }
+}
+
+/// Hands an event from the bus to this instance's hub.
+pub fn receive(event: BusEvent) {
+    if let BusEvent::DriverIndexed { driver_id, indexed } = event {
+        geoindex::apply_shared(driver_id, indexed);
+        return;
+    }
+    let follow_ups = hub().apply(event, Instant::now());
+    for event in follow_ups {
+        publish(event);
+    }
+}
+
+/// Offers the ride to a driver, wherever their session is, and hands back
+/// where their answer will arrive. None if no instance could show it to them.
+pub async fn offer_ride(driver_uuid: Uuid, ride: &NewRideRequest) -> Option<oneshot::Receiver<DriverResponse>> {
+    let (delivered, answer) = hub().await_answer(driver_uuid, ride.request_id);
+    publish(BusEvent::Offer { driver_id: driver_uuid, ride: Box::new(ride.clone()) });
+
+    match timeout(OFFER_DELIVERY_TIMEOUT, delivered).await {
+        Ok(Ok(())) => Some(answer),
+        _ => {
+            hub().forget_answer(driver_uuid, ride.request_id);
+            None
+        }
+    }
+}
+
+/// Passes the driver's answer to whichever instance is dispatching the
+/// request. Callers check the offer is still open against the driver's
+/// reservation first, see `offered_request`.
+pub fn answer_offer(driver_uuid: Uuid, request_id: Uuid, response: DriverResponse) {
+    publish(BusEvent::Answer { driver_id: driver_uuid, request_id, response });
+}
+
+/// Takes back an offer the driver hasn't answered and tells their app.
+pub fn withdraw_offer(driver_uuid: Uuid, request_id: Uuid) {
+    hub().forget_answer(driver_uuid, request_id);
+    publish(BusEvent::Withdraw { driver_id: driver_uuid, request_id });
+}
+
+pub fn watch_dispatch(request_id: Uuid) -> oneshot::Receiver<()> {
+    hub().watch_dispatch(request_id)
+}
+
+pub fn forget_dispatch(request_id: Uuid) {
+    hub().forget_dispatch(request_id);
+}
+
+/// Stops the dispatch of a cancelled request, on whichever instance runs it.
+pub fn abort_dispatch(request_id: Uuid) {
+    publish(BusEvent::Abort { request_id });
+}
+
+pub fn send_to(peer: Peer, message: ServerMessage) {
+    publish(BusEvent::Deliver { peer, message });
+}
+
+pub fn watch_trip(trip_reference: &str) -> mpsc::UnboundedReceiver<ServerMessage> {
+    hub().watch_trip(trip_reference)
+}
+
+pub fn is_watched(trip_reference: &str) -> bool {
+    hub().is_watched(trip_reference)
+}
+
+/// Tells both sides of a trip, and anyone watching it, about its current status.
+pub fn publish_trip_status(trip: &Trip) {
+    let message = ServerMessage::TripStatus {
+        trip_reference: trip.reference.clone(),
+        status: trip.status,
+    };
+
+    for peer in [Peer::Rider(trip.rider_id), Peer::Driver(trip.driver_id)] {
+        send_to(peer, message.clone());
+    }
+    publish(BusEvent::Watchers { trip_reference: trip.reference.clone(), message });
+}
+
+/// Passes a change to a driver's index entry on to the other instances, whose
+/// dispatch would otherwise keep offering from what they last saw.
+pub fn share_driver_index(driver_uuid: Uuid, indexed: Option<IndexedDriver>) {
+    publish(BusEvent::DriverIndexed { driver_id: driver_uuid, indexed });
+}
+
+/// Passes the driver's position on to the trip's rider and watchers.
+pub fn publish_driver_location(trip: &Trip, location: &GeoPoint, eta_min: Option<i32>) {
+    let message = ServerMessage::DriverLocation {
+        trip_reference: trip.reference.clone(),
+        location: location.clone(),
+        eta_min,
+        eta: eta_min.map(calculate_eta),
+    };
+
+    send_to(Peer::Rider(trip.rider_id), message.clone());
+    publish(BusEvent::Watchers { trip_reference: trip.reference.clone(), message });
+}
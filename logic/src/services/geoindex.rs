@@ -0,0 +1,305 @@
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use lazy_static::lazy_static;
+use serde::{ Deserialize, Serialize };
+use std::collections::{ HashMap, HashSet };
+use std::sync::{ RwLock, RwLockReadGuard, RwLockWriteGuard };
+use uuid::Uuid;
+use crate::api::drivers::{ Driver, DRIVER_AVAILABLE };
+use crate::services::gateway;
+use crate::services::pricing::GeoPoint;
+
+
+const KM_PER_DEGREE_LAT: f64 = 111.32;
+pub const DEFAULT_CELL_KM: f64 = 1.0;
+
+
+lazy_static! {
+    /// Live driver positions for dispatch. Filled from back_drivers at startup
+    /// and kept current by the location and status writes, this instance's
+    /// and, over the dispatch bus, the other instances'.
+    pub static ref DRIVER_INDEX: RwLock<DriverIndex> = RwLock::new(DriverIndex::new(DEFAULT_CELL_KM));
+}
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexedDriver {
+    pub location: GeoPoint,
+    pub vehicle_type: String,
+    pub status: String,
+}
+
+/// Drivers bucketed into square grid cells of `cell_km` (in degrees of
+/// latitude). A query walks rings of cells outwards from the point and stops
+/// once the rings cover the k-th nearest match, so only the cells around the
+/// pickup are ever looked at.
+#[derive(Debug)]
+pub struct DriverIndex {
+    cell_deg: f64,
+    cells: HashMap<(i32, i32), HashSet<Uuid>>,
+    drivers: HashMap<Uuid, IndexedDriver>,
+    warm: bool,
+}
+
+impl DriverIndex {
+    pub fn new(cell_km: f64) -> Self {
+        Self {
+            cell_deg: cell_km / KM_PER_DEGREE_LAT,
+            cells: HashMap::new(),
+            drivers: HashMap::new(),
+            warm: false,
+        }
+    }
+
+    pub fn from_drivers(cell_km: f64, rows: impl IntoIterator<Item = Driver>) -> Self {
+        let mut index = Self::new(cell_km);
+        for row in rows {
+            index.upsert_driver(&row);
+        }
+        index.warm = true;
+        index
+    }
+
+    pub fn load(conn: &mut PgConnection, cell_km: f64) -> QueryResult<Self> {
+        use crate::schema::back_drivers::dsl::back_drivers as drivers;
+
+        let rows = drivers.select(Driver::as_select()).load(conn)?;
+        Ok(Self::from_drivers(cell_km, rows))
+    }
+
+    /// Until the index has been loaded once it knows nothing, and dispatch
+    /// should scan the table instead.
+    pub fn is_warm(&self) -> bool {
+        self.warm
+    }
+
+    pub fn len(&self) -> usize {
+        self.drivers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.drivers.is_empty()
+    }
+
+    pub fn get(&self, driver_uuid: &Uuid) -> Option<&IndexedDriver> {
+        self.drivers.get(driver_uuid)
+    }
+
+    fn cell_of(&self, point: &GeoPoint) -> (i32, i32) {
+        (
+            (point.lat / self.cell_deg).floor() as i32,
+            (point.lng / self.cell_deg).floor() as i32,
+        )
+    }
+
+    /// Rows whose location doesn't parse are left out of the index.
+    pub fn upsert_driver(&mut self, row: &Driver) {
+        match serde_json::from_value::<GeoPoint>(row.driver_location.clone()) {
+            Ok(location) => self.upsert(row.driver_id, location, &row.vehicle_type, &row.status),
+            Err(_) => self.remove(&row.driver_id),
+        }
+    }
+
+    pub fn upsert(&mut self, driver_uuid: Uuid, location: GeoPoint, vehicle_type: &str, status: &str) {
+        self.remove(&driver_uuid);
+        self.cells.entry(self.cell_of(&location)).or_default().insert(driver_uuid);
+        self.drivers.insert(driver_uuid, IndexedDriver {
+            location,
+            vehicle_type: vehicle_type.to_string(),
+            status: status.to_string(),
+        });
+    }
+
+    /// Returns false for a driver the index hasn't seen, who needs a full
+    /// `upsert` since their vehicle type and status aren't known here.
+    pub fn update_location(&mut self, driver_uuid: Uuid, location: GeoPoint) -> bool {
+        let new_cell = self.cell_of(&location);
+        let Some(entry) = self.drivers.get_mut(&driver_uuid) else { return false };
+
+        let old_cell = (
+            (entry.location.lat / self.cell_deg).floor() as i32,
+            (entry.location.lng / self.cell_deg).floor() as i32,
+        );
+        entry.location = location;
+
+        if old_cell != new_cell {
+            if let Some(members) = self.cells.get_mut(&old_cell) {
+                members.remove(&driver_uuid);
+                if members.is_empty() {
+                    self.cells.remove(&old_cell);
+                }
+            }
+            self.cells.entry(new_cell).or_default().insert(driver_uuid);
+        }
+        true
+    }
+
+    pub fn set_status(&mut self, driver_uuid: Uuid, status: &str) {
+        if let Some(entry) = self.drivers.get_mut(&driver_uuid) {
+            entry.status = status.to_string();
+        }
+    }
+
+    pub fn remove(&mut self, driver_uuid: &Uuid) {
+        if let Some(entry) = self.drivers.remove(driver_uuid) {
+            let cell = self.cell_of(&entry.location);
+            if let Some(members) = self.cells.get_mut(&cell) {
+                members.remove(driver_uuid);
+                if members.is_empty() {
+                    self.cells.remove(&cell);
+                }
+            }
+        }
+    }
+
+    /// Up to `k` available drivers with one of `vehicle_types` within
+    /// `radius_km` of `at`, nearest first, with their distance in km.
+    pub fn nearest(
+        &self,
+        at: &GeoPoint,
+        vehicle_types: &[String],
+        radius_km: f64,
+        k: usize,
+    ) -> Vec<(Uuid, f64)> {
+        if k == 0 {
+            return Vec::new();
+        }
+
+        // A ring of r cells is at least this far away in every direction,
+        // longitude degrees being the shorter ones away from the equator
+        let cell_km = self.cell_deg * KM_PER_DEGREE_LAT;
+        let ring_km = cell_km * at.lat.to_radians().cos().abs().max(0.01);
+        let max_ring = (radius_km / ring_km).ceil() as i32 + 1;
+
+        let (lat_cell, lng_cell) = self.cell_of(at);
+        let mut found: Vec<(Uuid, f64)> = Vec::new();
+
+        for ring in 0..=max_ring {
+            for (dlat, dlng) in ring_cells(ring) {
+                let Some(members) = self.cells.get(&(lat_cell + dlat, lng_cell + dlng)) else { continue };
+
+                for driver_uuid in members {
+                    let entry = &self.drivers[driver_uuid];
+                    if entry.status != DRIVER_AVAILABLE || !vehicle_types.contains(&entry.vehicle_type) {
+                        continue;
+                    }
+                    let away = entry.location.distance_to(at);
+                    if away <= radius_km {
+                        found.push((*driver_uuid, away));
+                    }
+                }
+            }
+
+            // Everything closer than the rings walked so far has been seen
+            if found.len() >= k {
+                found.sort_by(|a, b| a.1.total_cmp(&b.1));
+                if found[k - 1].1 <= ring as f64 * ring_km {
+                    break;
+                }
+            }
+        }
+
+        found.sort_by(|a, b| a.1.total_cmp(&b.1));
+        found.truncate(k);
+        found
+    }
+}
+
+// Offsets of the cells on the square ring `ring` cells out from the centre.
+fn ring_cells(ring: i32) -> Vec<(i32, i32)> {
+    if ring == 0 {
+        return vec![(0, 0)];
+    }
+    let mut cells = Vec::with_capacity(8 * ring as usize);
+    for d in -ring..=ring {
+        cells.push((-ring, d));
+        cells.push((ring, d));
+    }
+    for d in (-ring + 1)..ring {
+        cells.push((d, -ring));
+        cells.push((d, ring));
+    }
+    cells
+}
+
+
+// A poisoned lock only means a writer panicked halfway through one update,
+// the index is still usable and the DB stays the source of truth.
+pub fn read_index() -> RwLockReadGuard<'static, DriverIndex> {
+    DRIVER_INDEX.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn write_index() -> RwLockWriteGuard<'static, DriverIndex> {
+    DRIVER_INDEX.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub fn warm_from_db(conn: &mut PgConnection) -> QueryResult<usize> {
+    let loaded = DriverIndex::load(conn, DEFAULT_CELL_KM)?;
+    let count = loaded.len();
+    *write_index() = loaded;
+    Ok(count)
+}
+
+/// Called after a driver's location is written to back_drivers.
+pub fn track_location(conn: &mut PgConnection, driver_uuid: Uuid, location: &GeoPoint) -> QueryResult<()> {
+    let moved = {
+        let mut index = write_index();
+        if !index.is_warm() {
+            return Ok(());
+        }
+        index.update_location(driver_uuid, location.clone())
+            .then(|| index.get(&driver_uuid).cloned())
+    };
+    match moved {
+        Some(entry) => {
+            gateway::share_driver_index(driver_uuid, entry);
+            Ok(())
+        }
+        None => refresh_driver(conn, driver_uuid),
+    }
+}
+
+/// Called after a driver's status is written to back_drivers.
+pub fn track_status(driver_uuid: Uuid, status: &str) {
+    let entry = {
+        let mut index = write_index();
+        index.set_status(driver_uuid, status);
+        index.get(&driver_uuid).cloned()
+    };
+    // A driver this index doesn't have can't be in the others' either
+    if entry.is_some() {
+        gateway::share_driver_index(driver_uuid, entry);
+    }
+}
+
+pub fn refresh_driver(conn: &mut PgConnection, driver_uuid: Uuid) -> QueryResult<()> {
+    use crate::schema::back_drivers::dsl::back_drivers as drivers;
+
+    let row: Option<Driver> = drivers
+        .find(driver_uuid)
+        .select(Driver::as_select())
+        .first(conn)
+        .optional()?;
+
+    let entry = {
+        let mut index = write_index();
+        match row {
+            Some(row) => index.upsert_driver(&row),
+            None => index.remove(&driver_uuid),
+        }
+        index.get(&driver_uuid).cloned()
+    };
+    gateway::share_driver_index(driver_uuid, entry);
+    Ok(())
+}
+
+/// Applies another instance's change to a driver's entry, see
+/// `BusEvent::DriverIndexed`. The sender's own copy comes back through here
+/// too and changes nothing.
+pub fn apply_shared(driver_uuid: Uuid, entry: Option<IndexedDriver>) {
+    let mut index = write_index();
+    match entry {
+        Some(entry) => index.upsert(driver_uuid, entry.location, &entry.vehicle_type, &entry.status),
+        None => index.remove(&driver_uuid),
+    }
+}
@@ -1,4 +1,4 @@
-use actix_web::{ web, Scope, HttpResponse };
+use actix_web::{ web, Scope, HttpResponse, ResponseError };
 use diesel::prelude::*;
 use diesel::sql_types::Jsonb;
 use diesel::pg::PgConnection;
@@ -6,40 +6,246 @@ use uuid::Uuid;
 use serde_json::Value;
 use serde::{ Deserialize, Serialize };
 use crate::services::pricing::GeoPoint;
+use crate::services::quotes::QuoteError;
+use crate::services::geoindex::{ self, DriverIndex };
+use crate::services::traffic;
+use crate::api::auth::Principal;
+use crate::api::drivers::{ Driver, DRIVER_AVAILABLE };
+use crate::api::riders::RideType;
+use crate::api::trips::share_driver_location;
 use crate::schema::back_drivers::dsl::{back_drivers as drivers, *};
 use crate::schema::back_ride_request::dsl::{back_ride_request as ride_request, *};
 use crate::db::DbPool;
+use std::env;
 
 
+const DEFAULT_RADIUS_KM: f64 = 5.0;
+const DEFAULT_MAX_CANDIDATES: usize = 10;
+const KM_PER_DEGREE_LAT: f64 = 111.32;
+
+
+/// How far out and how many drivers a ride type is dispatched to. Defaults to
+/// the old 5 km / 10 drivers, override per ride type with
+/// `DISPATCH_<RIDE_TYPE>_RADIUS_KM` and `DISPATCH_<RIDE_TYPE>_MAX_CANDIDATES`,
+/// e.g. `DISPATCH_ASAPEXPRESS_RADIUS_KM=8`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DispatchSettings {
+    pub radius_km: f64,
+    pub max_candidates: usize,
+}
+
+impl DispatchSettings {
+    pub fn for_ride_type(requested: &RideType) -> Self {
+        let prefix = match requested {
+            RideType::ASAP => "DISPATCH_ASAP",
+            RideType::ASAPEXPRESS => "DISPATCH_ASAPEXPRESS",
+        };
+
+        Self {
+            radius_km: env_or(&format!("{}_RADIUS_KM", prefix), DEFAULT_RADIUS_KM),
+            max_candidates: env_or(&format!("{}_MAX_CANDIDATES", prefix), DEFAULT_MAX_CANDIDATES),
+        }
+    }
+}
+
+pub(crate) fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    env::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+pub fn vehicle_types_for(requested: &RideType) -> Vec<String> {
+    match requested {
+        RideType::ASAP => vec!["EV".to_string()],
+        RideType::ASAPEXPRESS => vec!["Bike".to_string()],
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub driver: Driver,
+    pub distance_km: f64,
+}
+
+/// Keeps the drivers within `radius_km` of the pickup, nearest first, and at
+/// most `max_candidates` of them. Drivers whose stored location doesn't parse
+/// are skipped rather than failing the whole dispatch.
+pub fn rank_candidates(
+    pool_of_drivers: Vec<Driver>,
+    pickup: &GeoPoint,
+    settings: &DispatchSettings,
+) -> Vec<Candidate> {
+    let mut ranked: Vec<Candidate> = pool_of_drivers
+        .into_iter()
+        .filter_map(|driver| {
+            let at: GeoPoint = serde_json::from_value(driver.driver_location.clone()).ok()?;
+            let away = at.distance_to(pickup);
+            (away <= settings.radius_km).then_some(Candidate { driver, distance_km: away })
+        })
+        .collect();
+
+    ranked.sort_by(|a, b| a.distance_km.total_cmp(&b.distance_km));
+    ranked.truncate(settings.max_candidates);
+    ranked
+}
+
+/// Available drivers for a ride type, nearest to the pickup first. Uses the
+/// in-memory driver index once it has been loaded, the table otherwise.
+pub fn nearest_available_drivers(
+    conn: &mut PgConnection,
+    pickup: &GeoPoint,
+    requested: &RideType,
+    settings: &DispatchSettings,
+) -> QueryResult<Vec<Candidate>> {
+    // Clone out what we need so the read lock isn't held across the query
+    let from_index = {
+        let index = geoindex::read_index();
+        index.is_warm().then(|| index_candidates(&index, pickup, requested, settings))
+    };
+
+    match from_index {
+        Some(ids) => confirm_candidates(conn, ids, pickup, requested, settings),
+        None => nearest_by_scan(conn, pickup, requested, settings),
+    }
+}
+
+// The index can lag a status change by a moment, so ask for a few more than
+// needed and let the table have the final say.
+fn index_candidates(
+    index: &DriverIndex,
+    pickup: &GeoPoint,
+    requested: &RideType,
+    settings: &DispatchSettings,
+) -> Vec<Uuid> {
+    let want = settings.max_candidates * 2 + 5;
+    index
+        .nearest(pickup, &vehicle_types_for(requested), settings.radius_km, want)
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect()
+}
+
+pub fn nearest_from_index(
+    conn: &mut PgConnection,
+    index: &DriverIndex,
+    pickup: &GeoPoint,
+    requested: &RideType,
+    settings: &DispatchSettings,
+) -> QueryResult<Vec<Candidate>> {
+    let ids = index_candidates(index, pickup, requested, settings);
+    confirm_candidates(conn, ids, pickup, requested, settings)
+}
+
+fn confirm_candidates(
+    conn: &mut PgConnection,
+    ids: Vec<Uuid>,
+    pickup: &GeoPoint,
+    requested: &RideType,
+    settings: &DispatchSettings,
+) -> QueryResult<Vec<Candidate>> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let confirmed: Vec<Driver> = drivers
+        .filter(driver_id.eq_any(ids))
+        .filter(vehicle_type.eq_any(vehicle_types_for(requested)))
+        .filter(status.eq(DRIVER_AVAILABLE))
+        .select(Driver::as_select())
+        .load(conn)?;
+
+    Ok(rank_candidates(confirmed, pickup, settings))
+}
+
+/// The table scan: a bounding box around the pickup narrows the rows loaded,
+/// the exact cut and ordering happen in `rank_candidates`.
+pub fn nearest_by_scan(
+    conn: &mut PgConnection,
+    pickup: &GeoPoint,
+    requested: &RideType,
+    settings: &DispatchSettings,
+) -> QueryResult<Vec<Candidate>> {
+    let lat_span = settings.radius_km / KM_PER_DEGREE_LAT;
+    let lng_span = settings.radius_km / (KM_PER_DEGREE_LAT * pickup.lat.to_radians().cos().max(0.01));
+
+    // CASE so a location without numeric coordinates is skipped instead of
+    // failing the cast for the whole query
+    let in_box = diesel::dsl::sql::<diesel::sql_types::Bool>(&format!(
+        "CASE WHEN jsonb_typeof(driver_location->'lat') = 'number' \
+              AND jsonb_typeof(driver_location->'lng') = 'number' \
+         THEN (driver_location->>'lat')::float8 BETWEEN {} AND {} \
+          AND (driver_location->>'lng')::float8 BETWEEN {} AND {} \
+         ELSE false END",
+        pickup.lat - lat_span,
+        pickup.lat + lat_span,
+        pickup.lng - lng_span,
+        pickup.lng + lng_span,
+    ));
+
+    let nearby: Vec<Driver> = drivers
+        .filter(vehicle_type.eq_any(vehicle_types_for(requested)))
+        .filter(status.eq(DRIVER_AVAILABLE))
+        .filter(in_box)
+        .select(Driver::as_select())
+        .load(conn)?;
+
+    Ok(rank_candidates(nearby, pickup, settings))
+}
 
 
 pub async fn process_geolocation(
     pool: web::Data<DbPool>,
     path: web::Path<Uuid>,             // id of driver or ride
     payload: web::Json<GeoPointRequest>,
+    principal: Principal,
 ) -> HttpResponse {
     let id = path.into_inner();
     let gp = GeoPoint::new(payload.lat, payload.lng, payload.name.clone());
-    let kind = payload.kind.clone();
+    let kind = payload.kind;
+
+    if kind == GeoPointKind::DriverLocation {
+        if let Err(e) = principal.acts_for_driver(id) {
+            return e.error_response();
+        }
+    }
 
     let result = web::block({
         
         let pool = pool.clone();
         
-        move || -> Result<usize, String> {
+        move || -> Result<Result<usize, Box<dyn ResponseError + Send>>, String> {
             let mut conn = pool.get().map_err(|e| e.to_string())?;
             match kind {
                 GeoPointKind::DriverLocation => {
                     update_driver_location(&mut conn, id, &gp)
+                        .map(Ok)
                         .map_err(|e| format!("{:?}", e))
                 }
-                GeoPointKind::PickUp => {
-                    update_request_pickup(&mut conn, id, &gp)
-                        .map_err(|e| format!("{:?}", e))
-                }
-                GeoPointKind::DropOff => {
-                    update_request_dropoff(&mut conn, id, &gp)
-                        .map_err(|e| format!("{:?}", e))
+                GeoPointKind::PickUp | GeoPointKind::DropOff => {
+                    // Only the rider who made the request moves its points
+                    let owner: Option<(Uuid, Option<Uuid>)> = ride_request
+                        .find(id)
+                        .select((crate::schema::back_ride_request::rider_id, quote_id))
+                        .first(&mut conn)
+                        .optional()
+                        .map_err(|e| format!("{:?}", e))?;
+                    match owner {
+                        None => return Ok(Ok(0)),
+                        Some((owner, quoted)) => {
+                            if let Err(e) = principal.acts_for_rider(owner) {
+                                return Ok(Err(Box::new(e)));
+                            }
+                            // Its price was locked for the quoted points
+                            if quoted.is_some() {
+                                return Ok(Err(Box::new(QuoteError::Locked)));
+                            }
+                        }
+                    }
+
+                    let updated = if kind == GeoPointKind::PickUp {
+                        update_request_pickup(&mut conn, id, &gp)
+                    } else {
+                        update_request_dropoff(&mut conn, id, &gp)
+                    };
+                    updated.map(Ok).map_err(|e| format!("{:?}", e))
                 }
             }
         }
@@ -48,8 +254,9 @@ pub async fn process_geolocation(
     .await;
 
     match result {
-        Ok(Ok(rows)) if rows > 0 => HttpResponse::Ok().json(serde_json::json!({"status":"ok"})),
-        Ok(Ok(_)) => HttpResponse::NotFound().body("Row not found"),
+        Ok(Ok(Ok(rows))) if rows > 0 => HttpResponse::Ok().json(serde_json::json!({"status":"ok"})),
+        Ok(Ok(Ok(_))) => HttpResponse::NotFound().body("Row not found"),
+        Ok(Ok(Err(e))) => e.error_response(),
         Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("DB error: {:?}", e)),
         Err(e) => HttpResponse::InternalServerError().body(format!("Task error: {}", e)),
     }
@@ -64,11 +271,19 @@ pub fn update_driver_location(
 
     let jsonl: serde_json::Value = serde_json::to_value(gp).expect("Failed to serialize GeoPoint");  
 
-    diesel::update(drivers.filter(driver_id.eq(&driver_id_val)))
+    let rows = diesel::update(drivers.filter(driver_id.eq(&driver_id_val)))
         .set(DriverUpdateLocation {
         driver_location: jsonl,
     })
-        .execute(conn)
+        .execute(conn)?;
+
+    if rows > 0 {
+        geoindex::track_location(conn, driver_id_val, gp)?;
+        // Kept for the traffic fit, which checks trips against where they went
+        traffic::record_location(conn, driver_id_val, gp, chrono::Utc::now().timestamp())?;
+        share_driver_location(conn, driver_id_val, gp)?;
+    }
+    Ok(rows)
 }
 
 pub fn update_request_pickup(
@@ -110,7 +325,7 @@ pub struct GeoPointRequest {
     pub kind: GeoPointKind,
 }
 
-#[derive(Deserialize, Serialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum GeoPointKind {
     DriverLocation,
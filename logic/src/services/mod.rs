@@ -1,13 +1,34 @@
+use actix_web::middleware::from_fn;
 use actix_web::web::ServiceConfig;
+use crate::api::auth::{ require_permission, Permission };
+use ratelimit::rate_limit;
 
 pub mod pricing;
 pub mod notifications;
 pub mod matching;
 pub mod escrow;
 pub mod paystack;
+pub mod outbox;
+pub mod geoindex;
+pub mod gateway;
+pub mod bus;
+pub mod offers;
+pub mod wallet_login;
+pub mod roles;
+pub mod ratelimit;
+pub mod tariffs;
+pub mod surge;
+pub mod quotes;
+pub mod traffic;
 
+// /escrow only serves Paystack's webhook, which is signed instead of
+// authenticated. Its delivery log is under /admin.
 pub fn init(cfg: &mut ServiceConfig) {
     cfg.service(escrow::routes())
-       .service(matching::routes())
-       .service(paystack::routes());
+       .service(matching::routes()
+           .wrap(from_fn(|req, next| require_permission(Permission::Locations, req, next)))
+           .wrap(from_fn(|req, next| rate_limit("matching", req, next))))
+       .service(paystack::routes()
+           .wrap(from_fn(|req, next| require_permission(Permission::Trips, req, next)))
+           .wrap(from_fn(|req, next| rate_limit("paystack", req, next))));
 }
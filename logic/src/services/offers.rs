@@ -0,0 +1,389 @@
+use actix_web::web;
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use serde::{ Deserialize, Serialize };
+use std::time::Duration;
+use tokio::time::sleep;
+use uuid::Uuid;
+use crate::api::drivers::release_driver;
+use crate::api::riders::{
+    dispatch_status_of, finish_search, run_assign_driver, DispatchPlan, DispatchStatus, NewRideRequest, RideRequest,
+};
+use crate::db::DbPool;
+use crate::services::matching::env_or;
+
+
+/// A pending offer this far past its expiry was left behind by a dispatch
+/// that is no longer running. A live one settles its offers at the deadline.
+pub const ORPHAN_GRACE_SECS: i64 = 30;
+/// Orphaned requests first offered within this long are dispatched again,
+/// older ones are given up on.
+const DEFAULT_RECOVERY_WINDOW_SECS: i64 = 300;
+const RECOVERY_INTERVAL: Duration = Duration::from_secs(30);
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OfferOutcome {
+    Pending,
+    Accepted,
+    Rejected,
+    TimedOut,
+    /// Another driver got the ride first.
+    Withdrawn,
+    /// No instance could show the offer to the driver.
+    Unreachable,
+    /// Accepted, but the driver was taken by another request meanwhile.
+    Unavailable,
+    /// Accepted, but the trip could not be created.
+    Failed,
+    /// Left pending by a dispatch that stopped, see `recover_orphaned_requests`.
+    Expired,
+}
+
+impl OfferOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OfferOutcome::Pending => "pending",
+            OfferOutcome::Accepted => "accepted",
+            OfferOutcome::Rejected => "rejected",
+            OfferOutcome::TimedOut => "timed_out",
+            OfferOutcome::Withdrawn => "withdrawn",
+            OfferOutcome::Unreachable => "unreachable",
+            OfferOutcome::Unavailable => "unavailable",
+            OfferOutcome::Failed => "failed",
+            OfferOutcome::Expired => "expired",
+        }
+    }
+}
+
+#[derive(Debug, Queryable, Selectable, Serialize, Deserialize, Clone)]
+#[diesel(table_name = crate::schema::back_ride_offers)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct RideOffer {
+    pub offer_id: Uuid,
+    pub request_id: Uuid,
+    pub driver_id: Uuid,
+    pub round: i32,
+    pub offered_at: i64,
+    pub expires_at: i64,
+    pub outcome: String,
+    pub answered_at: Option<i64>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::back_ride_offers)]
+pub struct NewRideOffer {
+    pub offer_id: Uuid,
+    pub request_id: Uuid,
+    pub driver_id: Uuid,
+    pub round: i32,
+    pub offered_at: i64,
+    pub expires_at: i64,
+    pub outcome: String,
+}
+
+
+/// Stores an offer before it goes out, pending until `settle_offer`.
+pub fn record_offer(
+    connection: &mut PgConnection,
+    ride_request_id: Uuid,
+    driver_uuid: Uuid,
+    dispatch_round: u32,
+    now: i64,
+    expires: i64,
+) -> QueryResult<Uuid> {
+    use crate::schema::back_ride_offers::dsl::back_ride_offers as offers;
+
+    let offer = NewRideOffer {
+        offer_id: Uuid::new_v4(),
+        request_id: ride_request_id,
+        driver_id: driver_uuid,
+        round: dispatch_round as i32,
+        offered_at: now,
+        expires_at: expires,
+        outcome: OfferOutcome::Pending.as_str().to_string(),
+    };
+
+    diesel::insert_into(offers).values(&offer).execute(connection)?;
+    Ok(offer.offer_id)
+}
+
+/// Records how a pending offer ended. False if it had already been settled,
+/// e.g. expired by recovery.
+pub fn settle_offer(
+    connection: &mut PgConnection,
+    offer_uuid: Uuid,
+    how: OfferOutcome,
+    now: i64,
+) -> QueryResult<bool> {
+    use crate::schema::back_ride_offers::dsl::{back_ride_offers as offers, *};
+
+    let settled = diesel::update(
+        offers
+            .find(offer_uuid)
+            .filter(outcome.eq(OfferOutcome::Pending.as_str())),
+    )
+    .set((outcome.eq(how.as_str()), answered_at.eq(now)))
+    .execute(connection)?;
+
+    Ok(settled == 1)
+}
+
+/// Settles every offer still pending for a cancelled request as withdrawn
+/// and returns the drivers they went to.
+pub fn withdraw_pending_offers(
+    connection: &mut PgConnection,
+    ride_request_id: Uuid,
+    now: i64,
+) -> QueryResult<Vec<Uuid>> {
+    use crate::schema::back_ride_offers::dsl::{back_ride_offers as offers, *};
+
+    diesel::update(
+        offers
+            .filter(request_id.eq(ride_request_id))
+            .filter(outcome.eq(OfferOutcome::Pending.as_str())),
+    )
+    .set((outcome.eq(OfferOutcome::Withdrawn.as_str()), answered_at.eq(now)))
+    .returning(driver_id)
+    .get_results(connection)
+}
+
+/// Every offer made for a request, oldest first.
+pub fn offer_history(connection: &mut PgConnection, ride_request_id: Uuid) -> QueryResult<Vec<RideOffer>> {
+    use crate::schema::back_ride_offers::dsl::{back_ride_offers as offers, *};
+
+    offers
+        .filter(request_id.eq(ride_request_id))
+        .order((offered_at.asc(), round.asc()))
+        .select(RideOffer::as_select())
+        .load(connection)
+}
+
+
+/// A request still searching whose dispatch stopped without a trip.
+pub struct Orphan {
+    pub ride: RideRequest,
+    /// When it was requested, for a request that never got an offer out.
+    pub first_offered_at: i64,
+}
+
+/// Expires the pending offers dispatch left behind, hands their drivers back
+/// and returns the requests that still have no trip, along with the requests
+/// left searching with no offer out at all: their dispatch stopped between
+/// rounds or before the first offer. A live dispatch makes or settles an offer
+/// at least once per `offer_timeout`. Each request is claimed with one UPDATE, so two
+/// instances recovering at once don't both get it.
+pub fn claim_orphaned_requests(connection: &mut PgConnection, now: i64, offer_timeout: Duration) -> QueryResult<Vec<Orphan>> {
+    use crate::schema::back_ride_offers::dsl::{back_ride_offers as offers, *};
+    use crate::schema::back_ride_request::dsl::back_ride_request as ride_requests;
+    use crate::schema::back_trips::dsl::{back_trips as trips, request_id as trip_request_id};
+
+    let cutoff = now - ORPHAN_GRACE_SECS;
+    let candidates: Vec<Uuid> = offers
+        .filter(outcome.eq(OfferOutcome::Pending.as_str()))
+        .filter(expires_at.lt(cutoff))
+        .select(request_id)
+        .distinct()
+        .load(connection)?;
+
+    let mut orphans = Vec::new();
+    for ride_request_id in candidates {
+        // Still dispatching somewhere: it has an offer out that hasn't run out yet
+        let live: i64 = offers
+            .filter(request_id.eq(ride_request_id))
+            .filter(outcome.eq(OfferOutcome::Pending.as_str()))
+            .filter(expires_at.ge(cutoff))
+            .count()
+            .get_result(connection)?;
+        if live > 0 {
+            continue;
+        }
+
+        let claimed: Vec<Uuid> = diesel::update(
+            offers
+                .filter(request_id.eq(ride_request_id))
+                .filter(outcome.eq(OfferOutcome::Pending.as_str()))
+                .filter(expires_at.lt(cutoff)),
+        )
+        .set((outcome.eq(OfferOutcome::Expired.as_str()), answered_at.eq(now)))
+        .returning(driver_id)
+        .get_results(connection)?;
+        if claimed.is_empty() {
+            continue;
+        }
+
+        for driver_uuid in claimed {
+            release_driver(connection, driver_uuid, ride_request_id)?;
+        }
+
+        let has_trip: i64 = trips
+            .filter(trip_request_id.eq(ride_request_id))
+            .count()
+            .get_result(connection)?;
+        if has_trip > 0 || dispatch_status_of(connection, ride_request_id)? != DispatchStatus::Searching {
+            continue;
+        }
+
+        let first_offered_at: i64 = offers
+            .filter(request_id.eq(ride_request_id))
+            .select(diesel::dsl::min(offered_at))
+            .first::<Option<i64>>(connection)?
+            .unwrap_or(now);
+        let ride: RideRequest = ride_requests
+            .find(ride_request_id)
+            .select(RideRequest::as_select())
+            .first(connection)?;
+
+        orphans.push(Orphan { ride, first_offered_at });
+    }
+
+    orphans.extend(claim_stalled_requests(connection, now, offer_timeout)?);
+    Ok(orphans)
+}
+
+// Requests searching with nothing pending, no trip, and no offer made or
+// settled for an offer timeout and the grace period. Those just expired above were
+// settled now, so they aren't picked up twice.
+fn claim_stalled_requests(connection: &mut PgConnection, now: i64, offer_timeout: Duration) -> QueryResult<Vec<Orphan>> {
+    use diesel::dsl::{ exists, not };
+    use crate::schema::back_ride_offers::dsl::{
+        back_ride_offers as offers, answered_at, offered_at, outcome, request_id as offer_request_id,
+    };
+    use crate::schema::back_ride_request::dsl::{
+        back_ride_request as ride_requests, dispatch_status, redispatched_at, request_id, requested_at,
+    };
+    use crate::schema::back_trips::dsl::{back_trips as trips, request_id as trip_request_id};
+
+    let quiet_since = now - offer_timeout.as_secs() as i64 - ORPHAN_GRACE_SECS;
+    let not_redispatched_since = redispatched_at.is_null().or(redispatched_at.lt(quiet_since));
+
+    // Rows from before requested_at was stored have none and are left alone
+    let candidates: Vec<(Uuid, Option<i64>)> = ride_requests
+        .filter(dispatch_status.eq(DispatchStatus::Searching.as_str()))
+        .filter(requested_at.lt(quiet_since))
+        .filter(not_redispatched_since)
+        .filter(not(exists(
+            offers
+                .filter(offer_request_id.eq(request_id))
+                .filter(
+                    outcome.eq(OfferOutcome::Pending.as_str())
+                        .or(offered_at.ge(quiet_since))
+                        .or(answered_at.ge(quiet_since)),
+                ),
+        )))
+        .filter(not(exists(trips.filter(trip_request_id.eq(request_id.nullable())))))
+        .select((request_id, requested_at))
+        .load(connection)?;
+
+    let mut orphans = Vec::new();
+    for (ride_request_id, requested) in candidates {
+        let claimed = diesel::update(
+            ride_requests
+                .find(ride_request_id)
+                .filter(dispatch_status.eq(DispatchStatus::Searching.as_str()))
+                .filter(not_redispatched_since),
+        )
+        .set(redispatched_at.eq(now))
+        .execute(connection)?;
+        if claimed == 0 {
+            continue;
+        }
+
+        let first_offered_at: i64 = offers
+            .filter(offer_request_id.eq(ride_request_id))
+            .select(diesel::dsl::min(offered_at))
+            .first::<Option<i64>>(connection)?
+            .or(requested)
+            .unwrap_or(now);
+        let ride: RideRequest = ride_requests
+            .find(ride_request_id)
+            .select(RideRequest::as_select())
+            .first(connection)?;
+
+        orphans.push(Orphan { ride, first_offered_at });
+    }
+
+    Ok(orphans)
+}
+
+
+#[derive(Debug, Default)]
+pub struct Recovery {
+    pub redispatched: Vec<Uuid>,
+    pub given_up: Vec<Uuid>,
+}
+
+/// Starts dispatch again for orphaned requests that are still recent enough
+/// (`DISPATCH_RECOVERY_WINDOW_SECS`, 5 minutes by default), in the background.
+/// The rider hears about the trip on their socket; the HTTP request that
+/// started the first dispatch is long gone.
+pub async fn recover_orphaned_requests(pool: web::Data<DbPool>) -> Result<Recovery, String> {
+    let now = Utc::now().timestamp();
+    let offer_timeout = DispatchPlan::offer_timeout();
+    let orphans = web::block({
+        let pool = pool.clone();
+        move || -> Result<Vec<Orphan>, String> {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            conn.transaction(|conn| claim_orphaned_requests(conn, now, offer_timeout))
+                .map_err(|e| e.to_string())
+        }
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+
+    let window = env_or("DISPATCH_RECOVERY_WINDOW_SECS", DEFAULT_RECOVERY_WINDOW_SECS);
+    let mut recovery = Recovery::default();
+
+    for Orphan { ride, first_offered_at } in orphans {
+        let ride_request_id = ride.request_id;
+        if now - first_offered_at > window {
+            recovery.given_up.push(ride_request_id);
+            continue;
+        }
+
+        let pool = pool.clone();
+        actix_web::rt::spawn(async move {
+            let response = run_assign_driver(pool, NewRideRequest::from(ride)).await;
+            println!("Recovered dispatch for request {} ended with {}", ride_request_id, response.status());
+        });
+        recovery.redispatched.push(ride_request_id);
+    }
+
+    if !recovery.given_up.is_empty() {
+        let given_up = recovery.given_up.clone();
+        web::block(move || -> Result<(), String> {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            for ride_request_id in given_up {
+                finish_search(&mut conn, ride_request_id, DispatchStatus::NoDriversFound).map_err(|e| e.to_string())?;
+            }
+            Ok(())
+        })
+        .await
+        .map_err(|e| e.to_string())??;
+    }
+
+    Ok(recovery)
+}
+
+/// Runs recovery at startup and then every 30 seconds, which also picks up
+/// requests another instance was dispatching when it went down.
+pub async fn run_recovery(pool: DbPool) {
+    let pool = web::Data::new(pool);
+    loop {
+        match recover_orphaned_requests(pool.clone()).await {
+            Ok(Recovery { redispatched, given_up }) => {
+                if !redispatched.is_empty() || !given_up.is_empty() {
+                    println!(
+                        "Offer recovery: {} request(s) dispatched again, {} given up",
+                        redispatched.len(),
+                        given_up.len(),
+                    );
+                }
+            }
+            Err(e) => eprintln!("Offer recovery failed: {}", e),
+        }
+
+        sleep(RECOVERY_INTERVAL).await;
+    }
+}
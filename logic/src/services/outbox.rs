@@ -0,0 +1,363 @@
+use actix_web::web;
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use serde::{ Deserialize, Serialize };
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    instruction::Instruction,
+    pubkey::Pubkey,
+    signature::{ Keypair, read_keypair_file, Signer },
+    transaction::{ Transaction, TransactionError },
+};
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+use uuid::Uuid;
+use crate::api::trips::get_trip_by_reference;
+use crate::config::AppConfig;
+use crate::db::DbPool;
+use crate::services::escrow::{ build_record_ride_instruction, is_already_recorded };
+
+
+pub const RECORD_RIDE: &str = "record_ride";
+
+const BACKOFF_BASE_SECS: i64 = 5;
+const BACKOFF_MAX_SECS: i64 = 600;
+pub const MAX_ATTEMPTS: i32 = 10;
+// A job left in "submitting" this long belongs to a worker that died mid-send.
+// Resending is safe: record_ride answers AlreadyRecorded if the first one landed.
+const SUBMIT_LEASE_SECS: i64 = 120;
+const BATCH_SIZE: i64 = 10;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+
+/// Where record_ride transactions go. The worker only needs to hand over an
+/// instruction and learn whether it landed, so tests can swap the RPC out
+/// for a local ledger.
+pub trait RideRecorder: Send + Sync {
+    fn program_id(&self) -> Pubkey;
+    fn authority(&self) -> Pubkey;
+    fn submit(&self, instruction: Instruction) -> Result<String, SubmitError>;
+}
+
+#[derive(Debug)]
+pub enum SubmitError {
+    /// The ride PDA already exists, an earlier attempt landed.
+    AlreadyRecorded,
+    /// RPC unavailable, blockhash expired, confirmation timed out... worth retrying.
+    Transient(String),
+    /// The program rejected the transaction, resending will not help.
+    Rejected(String),
+}
+
+pub struct RpcRideRecorder {
+    client: RpcClient,
+    payer: Keypair,
+    program_id: Pubkey,
+}
+
+impl RpcRideRecorder {
+    pub fn new(rpc_url: String, payer: Keypair, program_id: Pubkey) -> Self {
+        Self { client: RpcClient::new(rpc_url), payer, program_id }
+    }
+
+    /// Uses SOLANA_PAYER_KEYPAIR, falling back to the Solana CLI default keypair.
+    pub fn from_config(config: &AppConfig) -> Result<Self, String> {
+        let payer_path = env::var("SOLANA_PAYER_KEYPAIR").unwrap_or_else(|_| {
+            env::var("HOME").unwrap_or_default() + "/.config/solana/id.json"
+        });
+        let payer = read_keypair_file(&payer_path)
+            .map_err(|e| format!("Keypair load failed ({}): {}", payer_path, e))?;
+
+        Ok(Self::new(config.solana_rpc_url.clone(), payer, config.program_id))
+    }
+}
+
+impl RideRecorder for RpcRideRecorder {
+    fn program_id(&self) -> Pubkey {
+        self.program_id
+    }
+
+    fn authority(&self) -> Pubkey {
+        self.payer.pubkey()
+    }
+
+    fn submit(&self, instruction: Instruction) -> Result<String, SubmitError> {
+        let blockhash = self
+            .client
+            .get_latest_blockhash()
+            .map_err(|e| SubmitError::Transient(format!("RPC error: {}", e)))?;
+
+        let tx = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&self.payer.pubkey()),
+            &[&self.payer],
+            blockhash,
+        );
+
+        match self.client.send_and_confirm_transaction(&tx) {
+            Ok(signature) => Ok(signature.to_string()),
+            Err(e) => match e.get_transaction_error() {
+                Some(tx_err) => Err(submit_error(tx_err)),
+                None => Err(SubmitError::Transient(format!("Transaction failed: {}", e))),
+            },
+        }
+    }
+}
+
+/// What a failed record_ride transaction means for the job: landed before,
+/// refused by the program, or worth sending again.
+pub fn submit_error(tx_err: TransactionError) -> SubmitError {
+    if is_already_recorded(&tx_err) {
+        return SubmitError::AlreadyRecorded;
+    }
+    match tx_err {
+        TransactionError::InstructionError(..) => SubmitError::Rejected(format!("Transaction failed: {}", tx_err)),
+        other => SubmitError::Transient(format!("Transaction failed: {}", other)),
+    }
+}
+
+
+/// Seconds to wait before the next attempt: 5s, 10s, 20s ... capped at 10 minutes.
+pub fn backoff_secs(attempts: i32) -> i64 {
+    let exponent = (attempts.max(1) - 1).min(16) as u32;
+    (BACKOFF_BASE_SECS * 2i64.pow(exponent)).min(BACKOFF_MAX_SECS)
+}
+
+pub fn enqueue_record_ride(
+    conn: &mut PgConnection,
+    reference_value: &str,
+    now: i64,
+) -> QueryResult<OnchainJob> {
+    use crate::schema::back_onchain_jobs::dsl::*;
+
+    // One job per trip no matter how many payment events point at it
+    diesel::insert_into(back_onchain_jobs)
+        .values(NewOnchainJob {
+            job_id: Uuid::new_v4(),
+            kind: RECORD_RIDE.to_string(),
+            trip_reference: reference_value.to_string(),
+            status: JobStatus::Pending.as_str().to_string(),
+            next_attempt_at: now,
+            created_at: now,
+            updated_at: now,
+        })
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+
+    back_onchain_jobs
+        .filter(kind.eq(RECORD_RIDE))
+        .filter(trip_reference.eq(reference_value))
+        .select(OnchainJob::as_select())
+        .first(conn)
+}
+
+/// Locks due jobs for this worker and bumps their attempt count. Runs in its
+/// own transaction so nothing is held open while a transaction is in flight.
+pub fn claim_due_jobs(conn: &mut PgConnection, now: i64, limit: i64) -> QueryResult<Vec<OnchainJob>> {
+    use crate::schema::back_onchain_jobs::dsl::*;
+
+    conn.transaction(|conn| {
+        let due: Vec<Uuid> = back_onchain_jobs
+            .filter(
+                status.eq(JobStatus::Pending.as_str()).and(next_attempt_at.le(now))
+                    .or(status.eq(JobStatus::Submitting.as_str()).and(updated_at.lt(now - SUBMIT_LEASE_SECS))),
+            )
+            .order(next_attempt_at.asc())
+            .limit(limit)
+            .select(job_id)
+            .for_update()
+            .skip_locked()
+            .load(conn)?;
+
+        if due.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        diesel::update(back_onchain_jobs.filter(job_id.eq_any(&due)))
+            .set((
+                status.eq(JobStatus::Submitting.as_str()),
+                attempts.eq(attempts + 1),
+                updated_at.eq(now),
+            ))
+            .returning(OnchainJob::as_returning())
+            .get_results(conn)
+    })
+}
+
+pub fn process_job(
+    conn: &mut PgConnection,
+    recorder: &dyn RideRecorder,
+    job: &OnchainJob,
+    now: i64,
+) -> QueryResult<JobStatus> {
+    let trip = match get_trip_by_reference(conn, &job.trip_reference) {
+        Ok(trip) => trip,
+        Err(diesel::result::Error::NotFound) => {
+            return fail_job(conn, job, "Trip not found", now);
+        }
+        Err(e) => return Err(e),
+    };
+
+    let instruction = match build_record_ride_instruction(&trip, recorder.program_id(), recorder.authority()) {
+        Ok(ix) => ix,
+        Err(msg) => return fail_job(conn, job, &msg, now),
+    };
+
+    match recorder.submit(instruction) {
+        Ok(sig) => {
+            println!("Ride recorded on-chain for reference {}: {}", job.trip_reference, sig);
+            confirm_job(conn, job, Some(sig), now)
+        }
+        Err(SubmitError::AlreadyRecorded) => {
+            println!("Ride already recorded on-chain for reference {}", job.trip_reference);
+            confirm_job(conn, job, None, now)
+        }
+        Err(SubmitError::Rejected(msg)) => fail_job(conn, job, &msg, now),
+        Err(SubmitError::Transient(msg)) if job.attempts >= MAX_ATTEMPTS => fail_job(conn, job, &msg, now),
+        Err(SubmitError::Transient(msg)) => {
+            use crate::schema::back_onchain_jobs::dsl::*;
+
+            let delay = backoff_secs(job.attempts);
+            eprintln!(
+                "record_ride for {} failed (attempt {}), retrying in {}s: {}",
+                job.trip_reference, job.attempts, delay, msg
+            );
+            diesel::update(back_onchain_jobs.find(job.job_id))
+                .set((
+                    status.eq(JobStatus::Pending.as_str()),
+                    next_attempt_at.eq(now + delay),
+                    last_error.eq(msg),
+                    updated_at.eq(now),
+                ))
+                .execute(conn)?;
+            Ok(JobStatus::Pending)
+        }
+    }
+}
+
+fn confirm_job(
+    conn: &mut PgConnection,
+    job: &OnchainJob,
+    sig: Option<String>,
+    now: i64,
+) -> QueryResult<JobStatus> {
+    use crate::schema::back_onchain_jobs::dsl::*;
+    use crate::schema::back_trips::dsl::{ back_trips, reference, onchain_signature };
+
+    conn.transaction(|conn| {
+        diesel::update(back_onchain_jobs.find(job.job_id))
+            .set((
+                status.eq(JobStatus::Confirmed.as_str()),
+                signature.eq(&sig),
+                last_error.eq(None::<String>),
+                updated_at.eq(now),
+            ))
+            .execute(conn)?;
+
+        if let Some(sig) = &sig {
+            diesel::update(back_trips.filter(reference.eq(&job.trip_reference)))
+                .set(onchain_signature.eq(sig))
+                .execute(conn)?;
+        }
+
+        Ok(JobStatus::Confirmed)
+    })
+}
+
+fn fail_job(conn: &mut PgConnection, job: &OnchainJob, msg: &str, now: i64) -> QueryResult<JobStatus> {
+    use crate::schema::back_onchain_jobs::dsl::*;
+
+    eprintln!("record_ride for {} gave up after {} attempt(s): {}", job.trip_reference, job.attempts, msg);
+    diesel::update(back_onchain_jobs.find(job.job_id))
+        .set((
+            status.eq(JobStatus::Failed.as_str()),
+            last_error.eq(msg),
+            updated_at.eq(now),
+        ))
+        .execute(conn)?;
+    Ok(JobStatus::Failed)
+}
+
+/// One worker tick: claim whatever is due and try each job once.
+pub fn run_due_jobs(conn: &mut PgConnection, recorder: &dyn RideRecorder, now: i64) -> QueryResult<usize> {
+    let jobs = claim_due_jobs(conn, now, BATCH_SIZE)?;
+    for job in &jobs {
+        process_job(conn, recorder, job, now)?;
+    }
+    Ok(jobs.len())
+}
+
+pub async fn run_worker(pool: DbPool, recorder: Arc<dyn RideRecorder>) {
+    loop {
+        let result = web::block({
+            let pool = pool.clone();
+            let recorder = recorder.clone();
+            move || -> Result<usize, String> {
+                let mut conn = pool.get().map_err(|e| e.to_string())?;
+                run_due_jobs(&mut conn, recorder.as_ref(), Utc::now().timestamp())
+                    .map_err(|e| e.to_string())
+            }
+        })
+        .await;
+
+        match result {
+            Ok(Ok(0)) => {}
+            Ok(Ok(n)) => println!("Processed {} on-chain job(s)", n),
+            Ok(Err(e)) => eprintln!("On-chain worker DB error: {}", e),
+            Err(e) => eprintln!("On-chain worker threadpool error: {:?}", e),
+        }
+
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobStatus {
+    Pending,
+    Submitting,
+    Confirmed,
+    Failed,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Submitting => "submitting",
+            JobStatus::Confirmed => "confirmed",
+            JobStatus::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Queryable, Selectable, Serialize, Deserialize, Clone)]
+#[diesel(table_name = crate::schema::back_onchain_jobs)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct OnchainJob {
+    pub job_id: Uuid,
+    pub kind: String,
+    pub trip_reference: String,
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt_at: i64,
+    pub last_error: Option<String>,
+    pub signature: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::back_onchain_jobs)]
+pub struct NewOnchainJob {
+    pub job_id: Uuid,
+    pub kind: String,
+    pub trip_reference: String,
+    pub status: String,
+    pub next_attempt_at: i64,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
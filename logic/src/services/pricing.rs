@@ -1,9 +1,15 @@
 use crate::api::riders::{ RideType };
+use crate::services::tariffs::{ self, Fare };
+use crate::services::surge;
+use crate::services::traffic;
+use crate::services::matching::env_or;
+use chrono::Utc;
+use lazy_static::lazy_static;
 use serde::{ Serialize, Deserialize };
 use diesel::prelude::*;
-
-
-const TRAFFIC_FACTOR: f64 = 1.3; //this needs to be recalculated using driver tracking data
+use futures_util::future::{ ready, BoxFuture };
+use std::sync::{ Arc, RwLock };
+use std::time::Duration;
 
 
 pub fn distance_between(pick_up: &GeoPoint, drop_off: &GeoPoint) -> f64 {
@@ -11,37 +17,282 @@ pub fn distance_between(pick_up: &GeoPoint, drop_off: &GeoPoint) -> f64 {
     pick_up.distance_to(drop_off)
 }
 
-pub fn estimated_time_min(distance_km: f64, ride_type: &RideType) -> i32 {
-    
-    let traffic_factor = TRAFFIC_FACTOR;
-    let avg_speed = match ride_type {
+/// Minutes to drive `distance_km` from `from` now, at the traffic factor
+/// fitted for its zone and hour of the week. See `services::traffic`.
+pub fn estimated_time_min(distance_km: f64, ride_type: &RideType, from: &GeoPoint) -> i32 {
+    travel_time_min(distance_km, ride_type, traffic::factor_at(from, Utc::now().timestamp()))
+}
+
+pub fn travel_time_min(distance_km: f64, ride_type: &RideType, traffic_factor: f64) -> i32 {
+    in_traffic(distance_km / average_speed_kmh(ride_type) * 60.0, traffic_factor)
+}
+
+/// A drive of `free_flow_min` at a traffic factor, which multiplies the
+/// speed: above 1 is faster. Factors are fitted against the straight line at
+/// the ride type's average speed, so only that free-flow time goes through here.
+pub fn in_traffic(free_flow_min: f64, traffic_factor: f64) -> i32 {
+    (free_flow_min / traffic_factor).round() as i32
+}
+
+/// Before traffic.
+pub fn average_speed_kmh(ride_type: &RideType) -> f64 {
+    match ride_type {
         RideType::ASAP => 30.0,         
         RideType::ASAPEXPRESS => 40.0,   
-    };
+    }
+}
+
 
-    let time_hours = distance_km / (avg_speed * traffic_factor); 
-    (time_hours * 60.0).round() as i32
+/// Prices a ride with the tariff in effect now for its pickup's zone, surged
+/// by the zone's current multiplier. See `services::tariffs` and
+/// `services::surge`.
+pub fn quote(ride_type: &RideType, pick_up: &GeoPoint, distance_km: f64, estimated_time_min: i32) -> Quote {
+    let now = Utc::now().timestamp();
+    let book = tariffs::read_book();
+    let zone = book.zone_for(pick_up);
+    let surge = surge::current(zone, now);
+    Quote {
+        fare: book.quote(ride_type, zone, distance_km, estimated_time_min, now, surge.multiplier),
+        surge_expires_at: surge.expires_at,
+    }
 }
 
+/// A fare, and until when its surge multiplier holds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Quote {
+    pub fare: Fare,
+    pub surge_expires_at: i64,
+}
 
-pub fn calculate_asap(distance_km: f64, estimated_time_min: i32) -> i64 {
-    let base_fare = 900.0;
-    let per_km_rate = 25.76;
-    let per_min_rate = 12.267;
 
-    let fare = base_fare + (distance_km * per_km_rate) + (estimated_time_min as f64 * per_min_rate);
-    fare.round() as i64
+lazy_static! {
+    /// What rides are routed with, see `install_router`.
+    static ref ROUTER: RwLock<Arc<dyn Router>> = RwLock::new(Arc::new(HaversineRouter));
 }
 
+/// The way a ride would go, by road unless it came from `HaversineRouter`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Route {
+    pub distance_km: f64,
+    pub duration_min: i32,
+    /// Encoded polyline (precision 5) of the way there.
+    pub polyline: String,
+    /// The router that found it.
+    pub source: String,
+}
 
-pub fn calculate_express(distance_km: f64, estimated_time_min: i32) -> i64 {
-    
-    let base_fare = 900.0;
-    let per_km_rate = 25.76;
-    let per_min_rate = 12.267;
+#[derive(Debug, PartialEq)]
+pub enum RouteError {
+    /// The router couldn't be reached or answered with an error.
+    Unavailable(String),
+    /// It answered, but knows no way between the points.
+    NoRoute,
+}
+
+impl std::fmt::Display for RouteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteError::Unavailable(e) => write!(f, "Router unavailable: {}", e),
+            RouteError::NoRoute => f.write_str("No route between the points"),
+        }
+    }
+}
+
+/// Finds the way from one point to another, over the network for anything
+/// but `HaversineRouter`.
+pub trait Router: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn route<'a>(&'a self, from: &'a GeoPoint, to: &'a GeoPoint, ride_type: &'a RideType) -> BoxFuture<'a, Result<Route, RouteError>>;
+}
+
+/// As the crow flies at the ride type's average speed. Never fails, so it's
+/// what every other router falls back to.
+pub struct HaversineRouter;
+
+impl Router for HaversineRouter {
+    fn name(&self) -> &'static str {
+        "haversine"
+    }
+
+    fn route<'a>(&'a self, from: &'a GeoPoint, to: &'a GeoPoint, ride_type: &'a RideType) -> BoxFuture<'a, Result<Route, RouteError>> {
+        Box::pin(ready(Ok(self.straight_line(from, to, ride_type))))
+    }
+}
+
+impl HaversineRouter {
+    pub fn straight_line(&self, from: &GeoPoint, to: &GeoPoint, ride_type: &RideType) -> Route {
+        let distance_km = distance_between(from, to);
+        Route {
+            distance_km,
+            duration_min: estimated_time_min(distance_km, ride_type, from),
+            polyline: encode_polyline(&[(from.lat, from.lng), (to.lat, to.lng)]),
+            source: self.name().to_string(),
+        }
+    }
+}
+
+/// Any server speaking the OSRM `/route/v1` API, e.g. `osrm-routed` on a
+/// local OSM extract.
+pub struct OsrmRouter {
+    base_url: String,
+    profile: String,
+    client: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct OsrmResponse {
+    code: String,
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    routes: Vec<OsrmRoute>,
+}
+
+#[derive(Deserialize)]
+struct OsrmRoute {
+    /// Metres
+    distance: f64,
+    /// Seconds
+    duration: f64,
+    geometry: String,
+}
+
+impl OsrmRouter {
+    pub fn new(base_url: &str, profile: &str, timeout: Duration) -> Result<Self, String> {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            profile: profile.to_string(),
+            client,
+        })
+    }
+}
+
+impl Router for OsrmRouter {
+    fn name(&self) -> &'static str {
+        "osrm"
+    }
+
+    fn route<'a>(&'a self, from: &'a GeoPoint, to: &'a GeoPoint, _ride_type: &'a RideType) -> BoxFuture<'a, Result<Route, RouteError>> {
+        // OSRM takes longitude first
+        let url = format!(
+            "{}/route/v1/{}/{},{};{},{}?overview=full&geometries=polyline",
+            self.base_url, self.profile, from.lng, from.lat, to.lng, to.lat,
+        );
+        Box::pin(async move {
+            let response: OsrmResponse = self.client
+                .get(&url)
+                .send()
+                .await
+                .map_err(|e| RouteError::Unavailable(e.to_string()))?
+                .json()
+                .await
+                .map_err(|e| RouteError::Unavailable(e.to_string()))?;
+
+            match response.code.as_str() {
+                "Ok" => {}
+                "NoRoute" | "NoSegment" => return Err(RouteError::NoRoute),
+                code => return Err(RouteError::Unavailable(format!("{}: {}", code, response.message.unwrap_or_default()))),
+            }
+            let route = response.routes.into_iter().next().ok_or(RouteError::NoRoute)?;
+            // Already at road speeds. The traffic factors were fitted against
+            // the straight line at average speed, dividing by them again would
+            // count the speed model twice
+            Ok(Route {
+                distance_km: route.distance / 1000.0,
+                duration_min: (route.duration / 60.0).round() as i32,
+                polyline: route.geometry,
+                source: self.name().to_string(),
+            })
+        })
+    }
+}
+
+/// `ROUTER=osrm` routes with the server at `OSRM_URL`, on the
+/// `OSRM_PROFILE` profile (default `driving`), giving up after
+/// `OSRM_TIMEOUT_MS` (default `2000`). Haversine otherwise.
+pub fn router_from_env() -> Result<Arc<dyn Router>, String> {
+    match std::env::var("ROUTER").unwrap_or_default().to_lowercase().as_str() {
+        "" | "haversine" => Ok(Arc::new(HaversineRouter)),
+        "osrm" => {
+            let base_url = std::env::var("OSRM_URL").map_err(|_| "OSRM_URL must be set for ROUTER=osrm".to_string())?;
+            let profile = std::env::var("OSRM_PROFILE").unwrap_or_else(|_| "driving".to_string());
+            let timeout = Duration::from_millis(env_or("OSRM_TIMEOUT_MS", 2000));
+            Ok(Arc::new(OsrmRouter::new(&base_url, &profile, timeout)?))
+        }
+        other => Err(format!("Unknown ROUTER {:?}", other)),
+    }
+}
+
+pub fn install_router(router: Arc<dyn Router>) {
+    *ROUTER.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = router;
+}
+
+pub fn router() -> Arc<dyn Router> {
+    ROUTER.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+}
+
+/// The installed router's way from `from` to `to`, or the straight line when
+/// it has none.
+pub async fn route(from: &GeoPoint, to: &GeoPoint, ride_type: &RideType) -> Route {
+    let router = router();
+    match router.route(from, to, ride_type).await {
+        Ok(route) => route,
+        Err(e) => {
+            eprintln!("{} routing failed, using haversine: {}", router.name(), e);
+            HaversineRouter.straight_line(from, to, ride_type)
+        }
+    }
+}
+
+/// Google's encoded polyline format, (lat, lng) pairs to 5 decimals.
+pub fn encode_polyline(points: &[(f64, f64)]) -> String {
+    let mut encoded = String::new();
+    let mut previous = (0i64, 0i64);
+    for (lat, lng) in points {
+        let current = ((lat * 1e5).round() as i64, (lng * 1e5).round() as i64);
+        for delta in [current.0 - previous.0, current.1 - previous.1] {
+            let mut value = if delta < 0 { !(delta << 1) } else { delta << 1 };
+            while value >= 0x20 {
+                encoded.push((((value & 0x1f) | 0x20) as u8 + 63) as char);
+                value >>= 5;
+            }
+            encoded.push((value as u8 + 63) as char);
+        }
+        previous = current;
+    }
+    encoded
+}
 
-    let fare = base_fare + (distance_km * per_km_rate) + (estimated_time_min as f64 * per_min_rate);
-    fare.round() as i64
+/// The (lat, lng) pairs in an encoded polyline, None if it's malformed.
+pub fn decode_polyline(encoded: &str) -> Option<Vec<(f64, f64)>> {
+    let mut values = Vec::new();
+    let (mut value, mut shift) = (0i64, 0);
+    for byte in encoded.bytes() {
+        let chunk = (byte as i64).checked_sub(63).filter(|c| (0..64).contains(c))?;
+        value |= (chunk & 0x1f) << shift;
+        shift += 5;
+        if chunk < 0x20 {
+            values.push(if value & 1 == 1 { !(value >> 1) } else { value >> 1 });
+            value = 0;
+            shift = 0;
+        }
+    }
+    if shift != 0 || values.len() % 2 != 0 {
+        return None;
+    }
+
+    let mut points = Vec::with_capacity(values.len() / 2);
+    let mut at = (0i64, 0i64);
+    for pair in values.chunks(2) {
+        at = (at.0 + pair[0], at.1 + pair[1]);
+        points.push((at.0 as f64 / 1e5, at.1 as f64 / 1e5));
+    }
+    Some(points)
 }
 
 
@@ -75,7 +326,7 @@ pub fn minimum_distance_between_driver_and_pickup(
 
 
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct GeoPoint {
     pub lat: f64,
     pub lng: f64,
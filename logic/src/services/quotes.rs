@@ -0,0 +1,188 @@
+use actix_web::{ HttpResponse, ResponseError };
+use actix_web::http::StatusCode;
+use jsonwebtoken::{ decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation };
+use serde::{ Deserialize, Serialize };
+use uuid::Uuid;
+use crate::api::riders::RideType;
+use crate::services::matching::env_or;
+use crate::services::pricing::{ self, GeoPoint, Route };
+
+
+/// How long a preflight price holds, `QUOTE_TTL_SECS` overrides it.
+pub const DEFAULT_QUOTE_TTL_SECS: i64 = 300;
+
+/// How far the requested pickup and dropoff may be from the quoted ones, for
+/// a location that moved a little between preflight and request.
+pub const QUOTE_MATCH_KM: f64 = 0.1;
+
+// Keeps quotes and access tokens from being taken for one another
+const QUOTE_AUDIENCE: &str = "fare-quote";
+
+
+/// A price the rider was shown, and everything it was worked out from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FareQuote {
+    pub quote_id: Uuid,
+    pub rider_id: Uuid,
+    pub ride_type: RideType,
+    pub pick_up: GeoPoint,
+    pub drop_off: GeoPoint,
+    pub distance_km: f64,
+    pub estimated_time_min: i32,
+    pub estimated_price: i64,
+    pub tariff_id: Option<Uuid>,
+    pub zone: String,
+    pub surge_multiplier: f64,
+    pub surge_expires_at: i64,
+    pub issued_at: i64,
+    pub expires_at: i64,
+}
+
+impl FareQuote {
+    /// Prices the ride along `route` now, see `pricing::quote`.
+    pub fn price(rider_id: Uuid, ride_type: RideType, pick_up: GeoPoint, drop_off: GeoPoint, route: &Route, now: i64, ttl_secs: i64) -> Self {
+        let distance_km = route.distance_km;
+        let estimated_time_min = route.duration_min;
+        let quote = pricing::quote(&ride_type, &pick_up, distance_km, estimated_time_min);
+
+        Self {
+            quote_id: Uuid::new_v4(),
+            rider_id,
+            ride_type,
+            pick_up,
+            drop_off,
+            distance_km,
+            estimated_time_min,
+            estimated_price: quote.fare.amount,
+            tariff_id: quote.fare.tariff_id,
+            zone: quote.fare.zone,
+            surge_multiplier: quote.fare.surge_multiplier,
+            surge_expires_at: quote.surge_expires_at,
+            issued_at: now,
+            expires_at: now + ttl_secs,
+        }
+    }
+
+    /// Whether the quote is for this rider's ride between these points.
+    pub fn covers(&self, rider_id: Uuid, ride_type: &RideType, pick_up: &GeoPoint, drop_off: &GeoPoint) -> Result<(), QuoteError> {
+        if self.rider_id != rider_id {
+            return Err(QuoteError::Mismatch("quoted for another rider"));
+        }
+        if &self.ride_type != ride_type {
+            return Err(QuoteError::Mismatch("quoted for another ride type"));
+        }
+        if self.pick_up.distance_to(pick_up) > QUOTE_MATCH_KM {
+            return Err(QuoteError::Mismatch("quoted for another pickup"));
+        }
+        if self.drop_off.distance_to(drop_off) > QUOTE_MATCH_KM {
+            return Err(QuoteError::Mismatch("quoted for another dropoff"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct QuoteClaims {
+    #[serde(flatten)]
+    quote: FareQuote,
+    aud: String,
+    exp: i64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum QuoteError {
+    Missing,
+    Invalid(String),
+    /// Past `expires_at`, the rider needs a fresh preflight.
+    Expired,
+    Mismatch(&'static str),
+    /// Another ride request was already made with it.
+    Used,
+    /// The request was priced from it, so its points can't move.
+    Locked,
+}
+
+impl std::fmt::Display for QuoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuoteError::Missing => f.write_str("A quote_token from /drivers/ride-preflight is required"),
+            QuoteError::Invalid(e) => write!(f, "Invalid quote: {}", e),
+            QuoteError::Expired => f.write_str("Quote has expired, run the preflight check again"),
+            QuoteError::Mismatch(what) => write!(f, "Quote doesn't match the request: {}", what),
+            QuoteError::Used => f.write_str("Quote has already been used for a ride request"),
+            QuoteError::Locked => f.write_str("The request's price is locked to its quote, run the preflight check again for other points"),
+        }
+    }
+}
+
+impl ResponseError for QuoteError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            QuoteError::Missing => StatusCode::BAD_REQUEST,
+            QuoteError::Invalid(_) | QuoteError::Mismatch(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            QuoteError::Expired => StatusCode::GONE,
+            QuoteError::Used | QuoteError::Locked => StatusCode::CONFLICT,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).body(self.to_string())
+    }
+}
+
+
+/// Signs and checks quote tokens, HS256 with `QUOTE_SECRET`, or
+/// `JWT_SECRET` when that isn't set.
+#[derive(Clone)]
+pub struct QuoteSigner {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    ttl_secs: i64,
+}
+
+impl QuoteSigner {
+    pub fn new(secret: &[u8], ttl_secs: i64) -> Self {
+        Self {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            ttl_secs,
+        }
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        let secret = std::env::var("QUOTE_SECRET")
+            .or_else(|_| std::env::var("JWT_SECRET"))
+            .map_err(|_| "QUOTE_SECRET or JWT_SECRET must be set".to_string())?;
+        if secret.is_empty() {
+            return Err("QUOTE_SECRET must not be empty".into());
+        }
+        Ok(Self::new(secret.as_bytes(), env_or("QUOTE_TTL_SECS", DEFAULT_QUOTE_TTL_SECS)))
+    }
+
+    pub fn ttl_secs(&self) -> i64 {
+        self.ttl_secs
+    }
+
+    pub fn sign(&self, quote: &FareQuote) -> Result<String, QuoteError> {
+        let claims = QuoteClaims { quote: quote.clone(), aud: QUOTE_AUDIENCE.to_string(), exp: quote.expires_at };
+        encode(&Header::new(Algorithm::HS256), &claims, &self.encoding)
+            .map_err(|e| QuoteError::Invalid(e.to_string()))
+    }
+
+    pub fn verify(&self, token: &str, now: i64) -> Result<FareQuote, QuoteError> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_audience(&[QUOTE_AUDIENCE]);
+        // Expiry is checked below against `now`, so it can be tested
+        validation.validate_exp = false;
+        validation.required_spec_claims.clear();
+
+        let quote = decode::<QuoteClaims>(token, &self.decoding, &validation)
+            .map_err(|e| QuoteError::Invalid(e.to_string()))?
+            .claims
+            .quote;
+        if now >= quote.expires_at {
+            return Err(QuoteError::Expired);
+        }
+        Ok(quote)
+    }
+}
@@ -0,0 +1,224 @@
+use actix_web::body::{ EitherBody, MessageBody };
+use actix_web::dev::{ ServiceRequest, ServiceResponse };
+use actix_web::middleware::Next;
+use actix_web::{ HttpMessage, HttpResponse };
+use lazy_static::lazy_static;
+use serde::{ Deserialize, Serialize };
+use std::collections::HashMap;
+use std::sync::{ Mutex, MutexGuard };
+use std::time::{ Duration, Instant };
+use crate::api::auth::Principal;
+use crate::services::matching::env_or;
+
+
+/// How many requests the limiter handles between sweeps of idle buckets.
+const SWEEP_EVERY: u64 = 1024;
+
+
+lazy_static! {
+    /// Buckets and counters for every rate limited scope, shared by all workers.
+    pub static ref RATE_LIMITER: Mutex<RateLimiter> = Mutex::new(RateLimiter::new());
+}
+
+
+/// Requests a minute, and how many can be spent at once.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Limit {
+    pub per_minute: u32,
+    pub burst: u32,
+}
+
+impl Limit {
+    /// The defaults for each scope, `RATE_LIMIT_<SCOPE>_PER_MIN` and
+    /// `RATE_LIMIT_<SCOPE>_BURST` override them (`ride-preflight` is
+    /// `RATE_LIMIT_RIDE_PREFLIGHT_...`).
+    pub fn for_scope(scope: &str) -> Self {
+        let default = match scope {
+            // Each check retries the driver search a few times before it answers
+            "ride-preflight" => Limit { per_minute: 12, burst: 4 },
+            "ride-request" => Limit { per_minute: 6, burst: 3 },
+            "auth" => Limit { per_minute: 30, burst: 10 },
+            "ws" => Limit { per_minute: 30, burst: 10 },
+            // Drivers send a location every few seconds while online
+            "matching" => Limit { per_minute: 600, burst: 120 },
+            _ => Limit { per_minute: 120, burst: 60 },
+        };
+        let prefix = format!("RATE_LIMIT_{}", scope.to_uppercase().replace('-', "_"));
+        Limit {
+            per_minute: env_or(&format!("{}_PER_MIN", prefix), default.per_minute).max(1),
+            burst: env_or(&format!("{}_BURST", prefix), default.burst).max(1),
+        }
+    }
+
+    fn tokens_per_sec(&self) -> f64 {
+        self.per_minute as f64 / 60.0
+    }
+}
+
+
+/// Starts full and refills at `per_minute`, up to `burst` tokens.
+#[derive(Debug, Clone)]
+pub struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    pub fn full(limit: Limit, now: Instant) -> Self {
+        Self { tokens: limit.burst as f64, refilled_at: now }
+    }
+
+    fn refill(&mut self, limit: Limit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.tokens_per_sec()).min(limit.burst as f64);
+        self.refilled_at = now;
+    }
+
+    /// Spends a token, or says how long until there is one.
+    pub fn take(&mut self, limit: Limit, now: Instant) -> Result<(), Duration> {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / limit.tokens_per_sec()))
+    }
+
+    /// A bucket that has filled back up is no different from a new one.
+    pub fn is_full(&self, limit: Limit, now: Instant) -> bool {
+        let mut bucket = self.clone();
+        bucket.refill(limit, now);
+        bucket.tokens >= limit.burst as f64
+    }
+}
+
+
+#[derive(Debug, Clone, Default)]
+struct ScopeCounters {
+    allowed: u64,
+    limited: u64,
+}
+
+/// What `GET /admin/rate-limits` reports for a scope since the server started.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScopeStats {
+    pub scope: String,
+    pub limit: Limit,
+    pub allowed: u64,
+    pub limited: u64,
+    /// Principals and addresses with a bucket that hasn't filled back up.
+    pub tracked_keys: usize,
+}
+
+/// Token buckets per scope and caller. Callers are keyed by who the token
+/// says they are, or by address when there's no token.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    limits: HashMap<String, Limit>,
+    buckets: HashMap<(String, String), Bucket>,
+    counters: HashMap<String, ScopeCounters>,
+    checks: u64,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets a scope's limit instead of reading it from the environment.
+    pub fn set_limit(&mut self, scope: &str, limit: Limit) {
+        self.limits.insert(scope.to_string(), limit);
+        self.buckets.retain(|(bucket_scope, _), _| bucket_scope != scope);
+    }
+
+    pub fn limit(&mut self, scope: &str) -> Limit {
+        *self.limits
+            .entry(scope.to_string())
+            .or_insert_with(|| Limit::for_scope(scope))
+    }
+
+    /// Spends one of `key`'s tokens in `scope`. Err is how long to wait.
+    pub fn check(&mut self, scope: &str, key: &str, now: Instant) -> Result<(), Duration> {
+        let limit = self.limit(scope);
+
+        self.checks += 1;
+        if self.checks.is_multiple_of(SWEEP_EVERY) {
+            self.sweep(now);
+        }
+
+        let outcome = self.buckets
+            .entry((scope.to_string(), key.to_string()))
+            .or_insert_with(|| Bucket::full(limit, now))
+            .take(limit, now);
+
+        let counters = self.counters.entry(scope.to_string()).or_default();
+        match outcome {
+            Ok(()) => counters.allowed += 1,
+            Err(_) => counters.limited += 1,
+        }
+        outcome
+    }
+
+    /// Drops the buckets that have filled back up, so one-off addresses
+    /// don't pile up.
+    pub fn sweep(&mut self, now: Instant) {
+        let limits = &self.limits;
+        self.buckets.retain(|(scope, _), bucket| match limits.get(scope) {
+            Some(limit) => !bucket.is_full(*limit, now),
+            None => false,
+        });
+    }
+
+    pub fn stats(&mut self, now: Instant) -> Vec<ScopeStats> {
+        self.sweep(now);
+        let mut stats: Vec<ScopeStats> = self.counters
+            .iter()
+            .map(|(scope, counters)| ScopeStats {
+                scope: scope.clone(),
+                limit: self.limits.get(scope).copied().unwrap_or_else(|| Limit::for_scope(scope)),
+                allowed: counters.allowed,
+                limited: counters.limited,
+                tracked_keys: self.buckets.keys().filter(|(bucket_scope, _)| bucket_scope == scope).count(),
+            })
+            .collect();
+        stats.sort_by(|a, b| a.scope.cmp(&b.scope));
+        stats
+    }
+}
+
+
+pub fn rate_limiter() -> MutexGuard<'static, RateLimiter> {
+    RATE_LIMITER.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Who a request counts against: the token's subject, or the peer address.
+pub fn caller_key(req: &ServiceRequest) -> String {
+    if let Some(principal) = req.extensions().get::<Principal>() {
+        return format!("user:{}", principal.subject);
+    }
+    match req.peer_addr() {
+        Some(addr) => format!("ip:{}", addr.ip()),
+        None => "ip:unknown".to_string(),
+    }
+}
+
+/// Rate limits a scope, wrap it with
+/// `from_fn(|req, next| rate_limit("riders", req, next))`.
+/// Over the limit gets `429` with `Retry-After` in whole seconds.
+pub async fn rate_limit(
+    scope: &'static str,
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let key = caller_key(&req);
+    let outcome = rate_limiter().check(scope, &key, Instant::now());
+
+    if let Err(wait) = outcome {
+        let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+        let response = HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", retry_after.to_string()))
+            .body(format!("Too many requests, try again in {}s", retry_after));
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
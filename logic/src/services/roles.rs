@@ -0,0 +1,216 @@
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use serde::{ Deserialize, Serialize };
+use uuid::Uuid;
+use crate::api::auth::Role;
+use crate::schema::back_role_audit::dsl::back_role_audit as audit;
+use crate::schema::back_user_roles::dsl::back_user_roles as user_roles;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RoleAction {
+    Grant,
+    Revoke,
+}
+
+impl RoleAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RoleAction::Grant => "grant",
+            RoleAction::Revoke => "revoke",
+        }
+    }
+}
+
+#[derive(Debug, Queryable, Selectable, Insertable, Serialize, Deserialize, Clone)]
+#[diesel(table_name = crate::schema::back_user_roles)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct RoleGrant {
+    pub user_id: Uuid,
+    pub role: Role,
+    pub granted_by: Uuid,
+    pub granted_at: i64,
+}
+
+/// One grant or revoke, kept in `back_role_audit`.
+#[derive(Debug, Queryable, Selectable, Insertable, Serialize, Deserialize, Clone)]
+#[diesel(table_name = crate::schema::back_role_audit)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct RoleAudit {
+    pub audit_id: Uuid,
+    pub user_id: Uuid,
+    pub role: Role,
+    pub action: String,
+    pub actor_id: Uuid,
+    pub reason: Option<String>,
+    pub recorded_at: i64,
+}
+
+#[derive(Debug)]
+pub enum RoleError {
+    /// Rider and driver come with the account, they aren't granted.
+    NotGrantable(Role),
+    /// An admin taking their own admin role away, leaving nobody to give it back.
+    OwnAdminRole,
+    Db(String),
+}
+
+impl std::fmt::Display for RoleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoleError::NotGrantable(role) => write!(f, "The {} role comes with an account and can't be granted", role.as_str()),
+            RoleError::OwnAdminRole => f.write_str("Admins can't revoke their own admin role"),
+            RoleError::Db(e) => write!(f, "DB error: {}", e),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for RoleError {
+    fn from(e: diesel::result::Error) -> Self {
+        RoleError::Db(e.to_string())
+    }
+}
+
+
+/// Every role `user` has: riders and drivers from their accounts, the rest
+/// from grants and the `custom_users` table. A `custom_role` of rider or
+/// driver grants nothing, the account does.
+pub fn roles_of(conn: &mut PgConnection, user: Uuid) -> QueryResult<Vec<Role>> {
+    use crate::schema::back_user_roles::dsl::{ role, user_id };
+
+    let mut roles = Vec::new();
+    if has_account(conn, Role::Rider, user)? {
+        roles.push(Role::Rider);
+    }
+    if has_account(conn, Role::Driver, user)? {
+        roles.push(Role::Driver);
+    }
+
+    let granted: Vec<Role> = user_roles.filter(user_id.eq(user)).select(role).load(conn)?;
+    let legacy: Option<Role> = {
+        use crate::schema::custom_users::dsl::{ custom_role, custom_users };
+        custom_users.find(user).select(custom_role).first(conn).optional()?
+    };
+    for held in granted.into_iter().chain(legacy.filter(Role::is_grantable)) {
+        if !roles.contains(&held) {
+            roles.push(held);
+        }
+    }
+    Ok(roles)
+}
+
+pub fn has_role(conn: &mut PgConnection, user: Uuid, wanted: Role) -> QueryResult<bool> {
+    Ok(roles_of(conn, user)?.contains(&wanted))
+}
+
+fn has_account(conn: &mut PgConnection, account_role: Role, user: Uuid) -> QueryResult<bool> {
+    use diesel::dsl::exists;
+    use diesel::select;
+
+    match account_role {
+        Role::Rider => {
+            use crate::schema::back_custom_users::dsl::back_custom_users;
+            select(exists(back_custom_users.find(user))).get_result(conn)
+        }
+        Role::Driver => {
+            use crate::schema::back_drivers::dsl::back_drivers;
+            select(exists(back_drivers.find(user))).get_result(conn)
+        }
+        _ => Ok(false),
+    }
+}
+
+fn record(
+    conn: &mut PgConnection,
+    user: Uuid,
+    changed: Role,
+    action: RoleAction,
+    actor: Uuid,
+    reason: Option<String>,
+    now: i64,
+) -> QueryResult<usize> {
+    diesel::insert_into(audit)
+        .values(RoleAudit {
+            audit_id: Uuid::new_v4(),
+            user_id: user,
+            role: changed,
+            action: action.as_str().to_string(),
+            actor_id: actor,
+            reason,
+            recorded_at: now,
+        })
+        .execute(conn)
+}
+
+/// Gives `user` the role. False if they already had it, which isn't audited.
+pub fn grant_role(
+    conn: &mut PgConnection,
+    user: Uuid,
+    granted: Role,
+    actor: Uuid,
+    reason: Option<String>,
+    now: i64,
+) -> Result<bool, RoleError> {
+    if !granted.is_grantable() {
+        return Err(RoleError::NotGrantable(granted));
+    }
+
+    conn.transaction(|conn| {
+        let inserted = diesel::insert_into(user_roles)
+            .values(RoleGrant { user_id: user, role: granted, granted_by: actor, granted_at: now })
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+        if inserted > 0 {
+            record(conn, user, granted, RoleAction::Grant, actor, reason, now)?;
+        }
+        Ok(inserted > 0)
+    })
+}
+
+/// Takes the role back, from the grants and from `custom_users`, whose
+/// `custom_role` can't be null and drops to rider. False if `user` didn't
+/// have it either way.
+pub fn revoke_role(
+    conn: &mut PgConnection,
+    user: Uuid,
+    revoked: Role,
+    actor: Uuid,
+    reason: Option<String>,
+    now: i64,
+) -> Result<bool, RoleError> {
+    use crate::schema::back_user_roles::dsl::{ role, user_id };
+
+    if !revoked.is_grantable() {
+        return Err(RoleError::NotGrantable(revoked));
+    }
+    if revoked == Role::Admin && user == actor {
+        return Err(RoleError::OwnAdminRole);
+    }
+
+    conn.transaction(|conn| {
+        let deleted = diesel::delete(user_roles.filter(user_id.eq(user)).filter(role.eq(revoked)))
+            .execute(conn)?;
+        let demoted = {
+            use crate::schema::custom_users::dsl::{ custom_role, custom_users, id };
+            diesel::update(custom_users.filter(id.eq(user)).filter(custom_role.eq(revoked)))
+                .set(custom_role.eq(Role::Rider))
+                .execute(conn)?
+        };
+        let revoked_any = deleted + demoted > 0;
+        if revoked_any {
+            record(conn, user, revoked, RoleAction::Revoke, actor, reason, now)?;
+        }
+        Ok(revoked_any)
+    })
+}
+
+/// Grants and revokes for `user`, oldest first.
+pub fn role_history(conn: &mut PgConnection, user: Uuid) -> QueryResult<Vec<RoleAudit>> {
+    use crate::schema::back_role_audit::dsl::{ recorded_at, user_id };
+
+    audit
+        .filter(user_id.eq(user))
+        .order(recorded_at.asc())
+        .select(RoleAudit::as_select())
+        .load(conn)
+}
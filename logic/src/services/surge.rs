@@ -0,0 +1,259 @@
+use actix_web::web;
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use lazy_static::lazy_static;
+use serde::{ Deserialize, Serialize };
+use std::collections::HashMap;
+use std::sync::{ RwLock, RwLockReadGuard, RwLockWriteGuard };
+use tokio::time::{ sleep, Duration };
+use crate::api::drivers::DRIVER_AVAILABLE;
+use crate::db::DbPool;
+use crate::services::matching::env_or;
+use crate::services::pricing::GeoPoint;
+use crate::services::tariffs::{ self, TariffBook };
+
+
+lazy_static! {
+    /// The multiplier each zone's fares are surged by, recomputed by
+    /// `run_engine`.
+    pub static ref SURGE_BOARD: RwLock<SurgeBoard> = RwLock::new(SurgeBoard::new(SurgeSettings::from_env()));
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SurgeSettings {
+    /// How far back ride requests count as demand.
+    pub window_secs: i64,
+    /// How often the multipliers are recomputed.
+    pub interval_secs: i64,
+    /// How long a multiplier holds once computed. Longer than the interval,
+    /// so one late run doesn't drop every zone back to 1.
+    pub ttl_secs: i64,
+    /// Requests per available driver a zone can take before it surges.
+    pub threshold: f64,
+    /// Added to the multiplier for each request per driver over the threshold.
+    pub sensitivity: f64,
+    pub max_multiplier: f64,
+    /// The most the multiplier moves in one run, either way.
+    pub max_step: f64,
+    /// Weight of the new reading against the last multiplier, 1 for none.
+    pub smoothing: f64,
+}
+
+impl Default for SurgeSettings {
+    fn default() -> Self {
+        Self {
+            window_secs: 600,
+            interval_secs: 60,
+            ttl_secs: 180,
+            threshold: 1.0,
+            sensitivity: 0.5,
+            max_multiplier: 2.5,
+            max_step: 0.5,
+            smoothing: 0.5,
+        }
+    }
+}
+
+impl SurgeSettings {
+    /// The defaults, each overridable with `SURGE_WINDOW_SECS`,
+    /// `SURGE_INTERVAL_SECS`, `SURGE_TTL_SECS`, `SURGE_THRESHOLD`,
+    /// `SURGE_SENSITIVITY`, `SURGE_MAX_MULTIPLIER`, `SURGE_MAX_STEP` and
+    /// `SURGE_SMOOTHING`.
+    pub fn from_env() -> Self {
+        let base = Self::default();
+        Self {
+            window_secs: env_or("SURGE_WINDOW_SECS", base.window_secs).max(1),
+            interval_secs: env_or("SURGE_INTERVAL_SECS", base.interval_secs).max(1),
+            ttl_secs: env_or("SURGE_TTL_SECS", base.ttl_secs).max(1),
+            threshold: env_or("SURGE_THRESHOLD", base.threshold).max(0.0),
+            sensitivity: env_or("SURGE_SENSITIVITY", base.sensitivity).max(0.0),
+            max_multiplier: env_or("SURGE_MAX_MULTIPLIER", base.max_multiplier).max(1.0),
+            max_step: env_or("SURGE_MAX_STEP", base.max_step).max(0.01),
+            smoothing: env_or("SURGE_SMOOTHING", base.smoothing).clamp(0.01, 1.0),
+        }
+    }
+
+    /// Where the multiplier would go on this reading alone, before smoothing.
+    pub fn target(&self, demand: u32, supply: u32) -> f64 {
+        let per_driver = demand as f64 / supply.max(1) as f64;
+        let over = (per_driver - self.threshold).max(0.0);
+        (1.0 + self.sensitivity * over).min(self.max_multiplier)
+    }
+
+    /// The next multiplier from the last one, moved part of the way to the
+    /// target and no further than `max_step`, to two decimals.
+    pub fn smooth(&self, previous: f64, target: f64) -> f64 {
+        let moved = (target - previous) * self.smoothing;
+        let next = previous + moved.clamp(-self.max_step, self.max_step);
+        (next.clamp(1.0, self.max_multiplier) * 100.0).round() / 100.0
+    }
+}
+
+
+/// A zone's multiplier and the reading it came from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ZoneSurge {
+    pub zone: String,
+    pub multiplier: f64,
+    /// Ride requests picked up in the zone within the window.
+    pub demand: u32,
+    /// Available drivers in the zone.
+    pub supply: u32,
+    pub computed_at: i64,
+    pub expires_at: i64,
+}
+
+impl ZoneSurge {
+    /// No surge, until the next run could change that.
+    pub fn calm(zone: &str, now: i64, settings: &SurgeSettings) -> Self {
+        Self {
+            zone: zone.to_string(),
+            multiplier: 1.0,
+            demand: 0,
+            supply: 0,
+            computed_at: now,
+            expires_at: now + settings.interval_secs,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SurgeBoard {
+    settings: SurgeSettings,
+    zones: HashMap<String, ZoneSurge>,
+}
+
+impl SurgeBoard {
+    pub fn new(settings: SurgeSettings) -> Self {
+        Self { settings, zones: HashMap::new() }
+    }
+
+    pub fn settings(&self) -> &SurgeSettings {
+        &self.settings
+    }
+
+    /// The zone's multiplier, or a calm one if it has none or it expired.
+    pub fn current(&self, zone: &str, now: i64) -> ZoneSurge {
+        match self.zones.get(zone) {
+            Some(surge) if surge.expires_at > now => surge.clone(),
+            _ => ZoneSurge::calm(zone, now, &self.settings),
+        }
+    }
+
+    /// Zones surging right now, highest first.
+    pub fn surging(&self, now: i64) -> Vec<ZoneSurge> {
+        let mut surging: Vec<ZoneSurge> = self.zones
+            .values()
+            .filter(|surge| surge.expires_at > now && surge.multiplier > 1.0)
+            .cloned()
+            .collect();
+        surging.sort_by(|a, b| b.multiplier.total_cmp(&a.multiplier).then(a.zone.cmp(&b.zone)));
+        surging
+    }
+
+    /// Takes a reading of (demand, supply) per zone. Zones missing from it
+    /// had no requests and ease back towards 1.
+    pub fn update(&mut self, readings: &HashMap<String, (u32, u32)>, now: i64) {
+        let settings = self.settings;
+        let mut zones: Vec<String> = readings.keys().cloned().collect();
+        zones.extend(self.zones.keys().filter(|zone| !readings.contains_key(*zone)).cloned());
+
+        for zone in zones {
+            let previous = self.current(&zone, now).multiplier;
+            let (demand, supply) = readings.get(&zone).copied().unwrap_or((0, 0));
+            let multiplier = settings.smooth(previous, settings.target(demand, supply));
+
+            if multiplier <= 1.0 && !readings.contains_key(&zone) {
+                self.zones.remove(&zone);
+                continue;
+            }
+            self.zones.insert(zone.clone(), ZoneSurge {
+                zone,
+                multiplier,
+                demand,
+                supply,
+                computed_at: now,
+                expires_at: now + settings.ttl_secs,
+            });
+        }
+    }
+}
+
+
+pub fn read_board() -> RwLockReadGuard<'static, SurgeBoard> {
+    SURGE_BOARD.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn write_board() -> RwLockWriteGuard<'static, SurgeBoard> {
+    SURGE_BOARD.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub fn current(zone: &str, now: i64) -> ZoneSurge {
+    read_board().current(zone, now)
+}
+
+/// (requests, drivers) per zone, by the zone each point falls in.
+pub fn readings_by_zone(book: &TariffBook, pick_ups: &[GeoPoint], drivers: &[GeoPoint]) -> HashMap<String, (u32, u32)> {
+    let mut readings: HashMap<String, (u32, u32)> = HashMap::new();
+    for pick_up in pick_ups {
+        readings.entry(book.zone_for(pick_up).to_string()).or_default().0 += 1;
+    }
+    for driver in drivers {
+        readings.entry(book.zone_for(driver).to_string()).or_default().1 += 1;
+    }
+    readings
+}
+
+/// Pickup points of the ride requests made since `since`.
+pub fn recent_pick_ups(conn: &mut PgConnection, since: i64) -> QueryResult<Vec<GeoPoint>> {
+    use crate::schema::back_ride_request::dsl::{ back_ride_request, pick_up, requested_at };
+
+    let rows: Vec<serde_json::Value> = back_ride_request
+        .filter(requested_at.ge(since))
+        .select(pick_up)
+        .load(conn)?;
+    Ok(rows.into_iter().filter_map(|point| serde_json::from_value(point).ok()).collect())
+}
+
+pub fn available_driver_locations(conn: &mut PgConnection) -> QueryResult<Vec<GeoPoint>> {
+    use crate::schema::back_drivers::dsl::{ back_drivers, driver_location, status };
+
+    let rows: Vec<serde_json::Value> = back_drivers
+        .filter(status.eq(DRIVER_AVAILABLE))
+        .select(driver_location)
+        .load(conn)?;
+    Ok(rows.into_iter().filter_map(|point| serde_json::from_value(point).ok()).collect())
+}
+
+/// Reads demand and supply per zone and updates the board. Hands back the
+/// zones left surging.
+pub fn recompute(conn: &mut PgConnection, now: i64) -> QueryResult<Vec<ZoneSurge>> {
+    let window = read_board().settings().window_secs;
+    let pick_ups = recent_pick_ups(conn, now - window)?;
+    let drivers = available_driver_locations(conn)?;
+    let readings = readings_by_zone(&tariffs::read_book(), &pick_ups, &drivers);
+
+    let mut board = write_board();
+    board.update(&readings, now);
+    Ok(board.surging(now))
+}
+
+/// Recomputes the multipliers every `SURGE_INTERVAL_SECS`.
+pub async fn run_engine(pool: DbPool) {
+    let every = Duration::from_secs(read_board().settings().interval_secs as u64);
+    loop {
+        let pool = pool.clone();
+        let recomputed = web::block(move || -> Result<Vec<ZoneSurge>, String> {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            recompute(&mut conn, chrono::Utc::now().timestamp()).map_err(|e| e.to_string())
+        }).await;
+        match recomputed {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => eprintln!("Surge recompute failed: {}", e),
+            Err(e) => eprintln!("Surge recompute failed: {}", e),
+        }
+
+        sleep(every).await;
+    }
+}
@@ -0,0 +1,417 @@
+use actix_web::web;
+use diesel::prelude::*;
+use diesel::pg::{ Pg, PgConnection, PgValue };
+use diesel::deserialize::{ self, FromSql, FromSqlRow };
+use diesel::expression::AsExpression;
+use diesel::serialize::{ self, Output, ToSql };
+use diesel::sql_types::Text;
+use lazy_static::lazy_static;
+use serde::{ Deserialize, Serialize };
+use std::sync::{ RwLock, RwLockReadGuard, RwLockWriteGuard };
+use tokio::time::{ sleep, Duration };
+use uuid::Uuid;
+use crate::api::riders::RideType;
+use crate::db::DbPool;
+use crate::services::matching::env_or;
+use crate::services::pricing::GeoPoint;
+use crate::schema::back_service_zones::dsl::back_service_zones as zone_table;
+use crate::schema::back_tariffs::dsl::back_tariffs as tariff_table;
+
+
+/// Where a pickup outside every service zone is priced.
+pub const DEFAULT_ZONE: &str = "default";
+
+/// How often each instance reloads tariffs and zones, so edits made through
+/// another instance reach it. `TARIFF_REFRESH_SECS` overrides it.
+pub const DEFAULT_REFRESH_SECS: u64 = 60;
+
+
+lazy_static! {
+    /// The tariffs and zones pricing quotes from. Loaded at startup, reloaded
+    /// after every admin edit and every `TARIFF_REFRESH_SECS`.
+    pub static ref TARIFF_BOOK: RwLock<TariffBook> = RwLock::new(TariffBook::default());
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "snake_case")]
+pub enum Rounding {
+    Nearest,
+    Up,
+    Down,
+}
+
+impl Rounding {
+    pub const ALL: [Rounding; 3] = [Rounding::Nearest, Rounding::Up, Rounding::Down];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rounding::Nearest => "nearest",
+            Rounding::Up => "up",
+            Rounding::Down => "down",
+        }
+    }
+
+    /// `amount` to a multiple of `step`.
+    pub fn apply(&self, amount: f64, step: i64) -> i64 {
+        let step = step.max(1) as f64;
+        let steps = amount / step;
+        let steps = match self {
+            Rounding::Nearest => steps.round(),
+            Rounding::Up => steps.ceil(),
+            Rounding::Down => steps.floor(),
+        };
+        (steps * step) as i64
+    }
+}
+
+impl std::str::FromStr for Rounding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Rounding::ALL
+            .into_iter()
+            .find(|candidate| candidate.as_str() == s)
+            .ok_or_else(|| format!("Unknown rounding: {}", s))
+    }
+}
+
+impl ToSql<Text, Pg> for Rounding {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        <str as ToSql<Text, Pg>>::to_sql(self.as_str(), &mut out.reborrow())
+    }
+}
+
+impl FromSql<Text, Pg> for Rounding {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        let value = <String as FromSql<Text, Pg>>::from_sql(bytes)?;
+        value.parse().map_err(Into::into)
+    }
+}
+
+
+/// A circle priced on its own.
+#[derive(Debug, Clone, Queryable, Selectable, Insertable, AsChangeset, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::back_service_zones)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ServiceZone {
+    pub zone: String,
+    pub name: String,
+    pub center_lat: f64,
+    pub center_lng: f64,
+    pub radius_km: f64,
+    #[serde(default)]
+    pub updated_at: i64,
+}
+
+impl ServiceZone {
+    pub fn center(&self) -> GeoPoint {
+        GeoPoint { lat: self.center_lat, lng: self.center_lng, name: Some(self.name.clone()) }
+    }
+
+    pub fn contains(&self, point: &GeoPoint) -> bool {
+        self.center().distance_to(point) <= self.radius_km
+    }
+}
+
+/// Rates for one ride type in one zone, from `effective_from` until the next
+/// tariff for the same pair takes over.
+#[derive(Debug, Clone, Queryable, Selectable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::back_tariffs)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Tariff {
+    pub tariff_id: Uuid,
+    pub ride_type: String,
+    pub zone: String,
+    pub base_fare: f64,
+    pub per_km: f64,
+    pub per_min: f64,
+    pub minimum_fare: i64,
+    pub rounding: Rounding,
+    pub round_to: i64,
+    pub effective_from: i64,
+    pub created_by: Option<Uuid>,
+    pub created_at: i64,
+}
+
+impl Tariff {
+    /// What pricing charges when no tariff has been loaded, the rates the
+    /// migration seeds. Its id is nil since it isn't a stored tariff.
+    pub fn builtin(ride_type: &RideType) -> Self {
+        Tariff {
+            tariff_id: Uuid::nil(),
+            ride_type: ride_type.as_str().to_string(),
+            zone: DEFAULT_ZONE.to_string(),
+            base_fare: 900.0,
+            per_km: 25.76,
+            per_min: 12.267,
+            minimum_fare: 900,
+            rounding: Rounding::Nearest,
+            round_to: 1,
+            effective_from: 0,
+            created_by: None,
+            created_at: 0,
+        }
+    }
+
+    pub fn fare(&self, distance_km: f64, estimated_time_min: i32) -> i64 {
+        self.surged_fare(distance_km, estimated_time_min, 1.0)
+    }
+
+    /// The fare times the zone's surge multiplier, rounded after surging.
+    pub fn surged_fare(&self, distance_km: f64, estimated_time_min: i32, multiplier: f64) -> i64 {
+        let fare = self.base_fare + (distance_km * self.per_km) + (estimated_time_min as f64 * self.per_min);
+        self.rounding.apply(fare * multiplier, self.round_to).max(self.minimum_fare)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewTariff {
+    pub ride_type: RideType,
+    pub zone: String,
+    pub base_fare: f64,
+    pub per_km: f64,
+    pub per_min: f64,
+    pub minimum_fare: i64,
+    pub rounding: Rounding,
+    pub round_to: i64,
+    /// Unix seconds, now or later. Defaults to now.
+    pub effective_from: Option<i64>,
+}
+
+/// A price and the tariff and surge it came from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Fare {
+    pub amount: i64,
+    /// None for the built-in rates.
+    pub tariff_id: Option<Uuid>,
+    pub zone: String,
+    pub surge_multiplier: f64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TariffError {
+    Invalid(String),
+    UnknownZone(String),
+    /// Past quotes have to keep their tariff, so new ones can't start earlier.
+    Backdated,
+    /// There's already a tariff starting then for the ride type and zone.
+    Duplicate,
+    Db(String),
+}
+
+impl std::fmt::Display for TariffError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TariffError::Invalid(reason) => write!(f, "Invalid tariff: {}", reason),
+            TariffError::UnknownZone(zone) => write!(f, "Unknown service zone: {}", zone),
+            TariffError::Backdated => f.write_str("A tariff can't take effect in the past"),
+            TariffError::Duplicate => f.write_str("A tariff for this ride type and zone already starts then"),
+            TariffError::Db(e) => write!(f, "DB error: {}", e),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for TariffError {
+    fn from(e: diesel::result::Error) -> Self {
+        use diesel::result::{ DatabaseErrorKind, Error };
+        match e {
+            Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => TariffError::Duplicate,
+            e => TariffError::Db(e.to_string()),
+        }
+    }
+}
+
+
+/// Every zone and every tariff, past and future.
+#[derive(Debug, Default)]
+pub struct TariffBook {
+    zones: Vec<ServiceZone>,
+    tariffs: Vec<Tariff>,
+}
+
+impl TariffBook {
+    pub fn new(zones: Vec<ServiceZone>, tariffs: Vec<Tariff>) -> Self {
+        Self { zones, tariffs }
+    }
+
+    pub fn load(conn: &mut PgConnection) -> QueryResult<Self> {
+        let zones = zone_table.select(ServiceZone::as_select()).load(conn)?;
+        let all = tariff_table.select(Tariff::as_select()).load(conn)?;
+        Ok(Self::new(zones, all))
+    }
+
+    pub fn zones(&self) -> &[ServiceZone] {
+        &self.zones
+    }
+
+    pub fn len(&self) -> usize {
+        self.tariffs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tariffs.is_empty()
+    }
+
+    /// The smallest zone `point` is in, so a zone inside a bigger one wins.
+    pub fn zone_for(&self, point: &GeoPoint) -> &str {
+        self.zones
+            .iter()
+            .filter(|zone| zone.contains(point))
+            .min_by(|a, b| a.radius_km.total_cmp(&b.radius_km))
+            .map(|zone| zone.zone.as_str())
+            .unwrap_or(DEFAULT_ZONE)
+    }
+
+    /// The latest tariff for the zone that started by `at`, or the default
+    /// zone's if the zone has none yet.
+    pub fn in_effect(&self, ride_type: &RideType, zone: &str, at: i64) -> Option<&Tariff> {
+        let latest_in = |wanted: &str| {
+            self.tariffs
+                .iter()
+                .filter(|t| t.ride_type == ride_type.as_str() && t.zone == wanted && t.effective_from <= at)
+                .max_by_key(|t| t.effective_from)
+        };
+        latest_in(zone).or_else(|| latest_in(DEFAULT_ZONE))
+    }
+
+    /// Prices a ride picked up in `zone` at `at`, surged by `multiplier`.
+    pub fn quote(
+        &self,
+        ride_type: &RideType,
+        zone: &str,
+        distance_km: f64,
+        estimated_time_min: i32,
+        at: i64,
+        multiplier: f64,
+    ) -> Fare {
+        let (amount, tariff_id) = match self.in_effect(ride_type, zone, at) {
+            Some(tariff) => (tariff.surged_fare(distance_km, estimated_time_min, multiplier), Some(tariff.tariff_id)),
+            None => (Tariff::builtin(ride_type).surged_fare(distance_km, estimated_time_min, multiplier), None),
+        };
+        Fare { amount, tariff_id, zone: zone.to_string(), surge_multiplier: multiplier }
+    }
+}
+
+
+pub fn read_book() -> RwLockReadGuard<'static, TariffBook> {
+    TARIFF_BOOK.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn write_book() -> RwLockWriteGuard<'static, TariffBook> {
+    TARIFF_BOOK.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub fn reload(conn: &mut PgConnection) -> QueryResult<usize> {
+    let loaded = TariffBook::load(conn)?;
+    let count = loaded.len();
+    *write_book() = loaded;
+    Ok(count)
+}
+
+/// Reloads tariffs and zones every `TARIFF_REFRESH_SECS`.
+pub async fn run_refresh(pool: DbPool) {
+    let every = Duration::from_secs(env_or("TARIFF_REFRESH_SECS", DEFAULT_REFRESH_SECS).max(1));
+    loop {
+        sleep(every).await;
+
+        let pool = pool.clone();
+        let reloaded = web::block(move || -> Result<usize, String> {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            reload(&mut conn).map_err(|e| e.to_string())
+        }).await;
+        match reloaded {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => eprintln!("Tariff refresh failed: {}", e),
+            Err(e) => eprintln!("Tariff refresh failed: {}", e),
+        }
+    }
+}
+
+
+/// Stores a new tariff version. The ones before it stay, for the quotes
+/// they priced.
+pub fn create_tariff(
+    conn: &mut PgConnection,
+    new: NewTariff,
+    actor: Uuid,
+    now: i64,
+) -> Result<Tariff, TariffError> {
+    let effective_from = new.effective_from.unwrap_or(now);
+    if effective_from < now {
+        return Err(TariffError::Backdated);
+    }
+    if [new.base_fare, new.per_km, new.per_min].iter().any(|rate| !rate.is_finite() || *rate < 0.0) {
+        return Err(TariffError::Invalid("rates can't be negative".into()));
+    }
+    if new.minimum_fare < 0 {
+        return Err(TariffError::Invalid("minimum_fare can't be negative".into()));
+    }
+    if new.round_to < 1 {
+        return Err(TariffError::Invalid("round_to must be at least 1".into()));
+    }
+    if new.zone != DEFAULT_ZONE {
+        use diesel::dsl::exists;
+        if !diesel::select(exists(zone_table.find(&new.zone))).get_result::<bool>(conn)? {
+            return Err(TariffError::UnknownZone(new.zone));
+        }
+    }
+
+    let tariff = Tariff {
+        tariff_id: Uuid::new_v4(),
+        ride_type: new.ride_type.as_str().to_string(),
+        zone: new.zone,
+        base_fare: new.base_fare,
+        per_km: new.per_km,
+        per_min: new.per_min,
+        minimum_fare: new.minimum_fare,
+        rounding: new.rounding,
+        round_to: new.round_to,
+        effective_from,
+        created_by: Some(actor),
+        created_at: now,
+    };
+    diesel::insert_into(tariff_table).values(&tariff).execute(conn)?;
+    Ok(tariff)
+}
+
+/// Every tariff, newest first, optionally for one ride type or zone.
+pub fn tariff_history(
+    conn: &mut PgConnection,
+    for_ride_type: Option<&RideType>,
+    for_zone: Option<&str>,
+) -> QueryResult<Vec<Tariff>> {
+    use crate::schema::back_tariffs::dsl::{ effective_from, ride_type, zone };
+
+    let mut query = tariff_table.select(Tariff::as_select()).into_boxed();
+    if let Some(wanted) = for_ride_type {
+        query = query.filter(ride_type.eq(wanted.as_str()));
+    }
+    if let Some(wanted) = for_zone {
+        query = query.filter(zone.eq(wanted.to_string()));
+    }
+    query.order(effective_from.desc()).load(conn)
+}
+
+/// Adds the zone, or moves and resizes it.
+pub fn save_zone(conn: &mut PgConnection, mut zone: ServiceZone, now: i64) -> Result<ServiceZone, TariffError> {
+    if zone.zone.trim().is_empty() || zone.zone == DEFAULT_ZONE {
+        return Err(TariffError::Invalid(format!("zone can't be empty or '{}'", DEFAULT_ZONE)));
+    }
+    if !(zone.radius_km.is_finite() && zone.radius_km > 0.0) {
+        return Err(TariffError::Invalid("radius_km must be above 0".into()));
+    }
+    if !(-90.0..=90.0).contains(&zone.center_lat) || !(-180.0..=180.0).contains(&zone.center_lng) {
+        return Err(TariffError::Invalid("center is not a valid coordinate".into()));
+    }
+
+    zone.updated_at = now;
+    diesel::insert_into(zone_table)
+        .values(&zone)
+        .on_conflict(crate::schema::back_service_zones::dsl::zone)
+        .do_update()
+        .set(&zone)
+        .execute(conn)?;
+    Ok(zone)
+}
@@ -0,0 +1,461 @@
+use actix_web::web;
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use lazy_static::lazy_static;
+use serde::{ Deserialize, Serialize };
+use std::collections::{ BTreeMap, HashMap };
+use std::sync::{ RwLock, RwLockReadGuard, RwLockWriteGuard };
+use tokio::time::{ sleep, Duration };
+use uuid::Uuid;
+use crate::api::riders::RideType;
+use crate::api::trips::{ parse_geo_point, TripStatus };
+use crate::db::DbPool;
+use crate::schema::back_traffic_factors;
+use crate::services::matching::env_or;
+use crate::services::pricing::{ average_speed_kmh, in_traffic, GeoPoint };
+use crate::services::tariffs::{ self, TariffBook, DEFAULT_ZONE };
+
+
+/// The factor for a zone and hour with none fitted, the one ETAs used before
+/// there was a fit.
+pub const DEFAULT_TRAFFIC_FACTOR: f64 = 1.3;
+pub const HOURS_PER_WEEK: i32 = 168;
+
+
+lazy_static! {
+    /// The fitted factors ETAs look up, refitted by `run_fitter`.
+    pub static ref TRAFFIC_TABLE: RwLock<TrafficTable> = RwLock::new(TrafficTable::new(TrafficSettings::from_env(), vec![]));
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrafficSettings {
+    /// How far back completed trips count, and how long location history is kept.
+    pub window_days: i64,
+    /// How often the factors are refitted.
+    pub interval_secs: i64,
+    /// Trips a zone needs in an hour of the week before it gets its own factor.
+    pub min_samples: usize,
+    pub min_factor: f64,
+    pub max_factor: f64,
+    /// A trip whose recorded path is this many times longer or shorter than
+    /// its distance wasn't driven the way it was priced, and isn't fitted.
+    pub max_detour: f64,
+    /// Hours of the week are local time, UTC+1 for Lagos.
+    pub utc_offset_hours: i64,
+}
+
+impl Default for TrafficSettings {
+    fn default() -> Self {
+        Self {
+            window_days: 28,
+            interval_secs: 3600,
+            min_samples: 5,
+            min_factor: 0.2,
+            max_factor: 3.0,
+            max_detour: 3.0,
+            utc_offset_hours: 1,
+        }
+    }
+}
+
+impl TrafficSettings {
+    /// The defaults, each overridable with `TRAFFIC_WINDOW_DAYS`,
+    /// `TRAFFIC_FIT_INTERVAL_SECS`, `TRAFFIC_MIN_SAMPLES`,
+    /// `TRAFFIC_MIN_FACTOR`, `TRAFFIC_MAX_FACTOR`, `TRAFFIC_MAX_DETOUR` and
+    /// `TRAFFIC_UTC_OFFSET_HOURS`.
+    pub fn from_env() -> Self {
+        let base = Self::default();
+        let min_factor = env_or("TRAFFIC_MIN_FACTOR", base.min_factor).max(0.01);
+        Self {
+            window_days: env_or("TRAFFIC_WINDOW_DAYS", base.window_days).max(1),
+            interval_secs: env_or("TRAFFIC_FIT_INTERVAL_SECS", base.interval_secs).max(1),
+            min_samples: env_or("TRAFFIC_MIN_SAMPLES", base.min_samples).max(1),
+            min_factor,
+            max_factor: env_or("TRAFFIC_MAX_FACTOR", base.max_factor).max(min_factor),
+            max_detour: env_or("TRAFFIC_MAX_DETOUR", base.max_detour).max(1.0),
+            utc_offset_hours: env_or("TRAFFIC_UTC_OFFSET_HOURS", base.utc_offset_hours).clamp(-12, 14),
+        }
+    }
+
+    /// 0 for Monday midnight local time, up to 167 for Sunday 23:00.
+    pub fn hour_of_week(&self, at: i64) -> i32 {
+        let local_hours = (at + self.utc_offset_hours * 3600).div_euclid(3600);
+        // The epoch was a Thursday, 72 hours into its week
+        (local_hours + 72).rem_euclid(HOURS_PER_WEEK as i64) as i32
+    }
+
+    pub fn window_secs(&self) -> i64 {
+        self.window_days * 86_400
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Queryable, Selectable, Insertable)]
+#[diesel(table_name = back_traffic_factors)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct TrafficFactor {
+    pub zone: String,
+    pub hour_of_week: i32,
+    /// Multiplies the ride type's average speed, above 1 is faster.
+    pub factor: f64,
+    /// Trips it was fitted from.
+    pub samples: i32,
+    pub fitted_at: i64,
+}
+
+#[derive(Debug)]
+pub struct TrafficTable {
+    settings: TrafficSettings,
+    factors: HashMap<(String, i32), TrafficFactor>,
+}
+
+impl TrafficTable {
+    pub fn new(settings: TrafficSettings, factors: Vec<TrafficFactor>) -> Self {
+        let factors = factors
+            .into_iter()
+            .map(|factor| ((factor.zone.clone(), factor.hour_of_week), factor))
+            .collect();
+        Self { settings, factors }
+    }
+
+    pub fn load(conn: &mut PgConnection, settings: TrafficSettings) -> QueryResult<Self> {
+        let factors = back_traffic_factors::table
+            .select(TrafficFactor::as_select())
+            .load(conn)?;
+        Ok(Self::new(settings, factors))
+    }
+
+    pub fn settings(&self) -> &TrafficSettings {
+        &self.settings
+    }
+
+    pub fn len(&self) -> usize {
+        self.factors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.factors.is_empty()
+    }
+
+    /// By zone, then hour of the week.
+    pub fn factors(&self) -> Vec<TrafficFactor> {
+        let mut factors: Vec<TrafficFactor> = self.factors.values().cloned().collect();
+        factors.sort_by(|a, b| a.zone.cmp(&b.zone).then(a.hour_of_week.cmp(&b.hour_of_week)));
+        factors
+    }
+
+    /// The zone's factor for that hour, the default zone's when it has none
+    /// fitted, and `DEFAULT_TRAFFIC_FACTOR` when neither does.
+    pub fn factor_for(&self, zone: &str, hour_of_week: i32) -> f64 {
+        self.factors
+            .get(&(zone.to_string(), hour_of_week))
+            .or_else(|| self.factors.get(&(DEFAULT_ZONE.to_string(), hour_of_week)))
+            .map_or(DEFAULT_TRAFFIC_FACTOR, |fitted| fitted.factor)
+    }
+
+    pub fn factor_at(&self, zone: &str, at: i64) -> f64 {
+        self.factor_for(zone, self.settings.hour_of_week(at))
+    }
+}
+
+
+/// A completed trip, as the fit and the ETA report see it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TripSample {
+    pub zone: String,
+    pub hour_of_week: i32,
+    pub ride_type: RideType,
+    pub distance_km: f64,
+    /// From pickup, or the trip's start without one, to drop off.
+    pub actual_min: f64,
+    /// What the ride request was quoted, when the trip came from one.
+    pub quoted_min: Option<i32>,
+}
+
+impl TripSample {
+    /// The trip at the ride type's average speed, before traffic.
+    pub fn free_flow_min(&self) -> f64 {
+        self.distance_km / average_speed_kmh(&self.ride_type) * 60.0
+    }
+
+    /// The factor that would have predicted this trip exactly, its speed over
+    /// the ride type's average.
+    pub fn observed_factor(&self) -> f64 {
+        self.free_flow_min() / self.actual_min
+    }
+}
+
+/// The median observed factor of each zone and hour of the week with at
+/// least `min_samples` trips, within the settings' bounds.
+pub fn fit(samples: &[TripSample], settings: &TrafficSettings, now: i64) -> Vec<TrafficFactor> {
+    let mut observed: BTreeMap<(String, i32), Vec<f64>> = BTreeMap::new();
+    for sample in samples {
+        let factor = sample.observed_factor();
+        if factor.is_finite() && factor > 0.0 {
+            observed.entry((sample.zone.clone(), sample.hour_of_week)).or_default().push(factor);
+        }
+    }
+
+    observed
+        .into_iter()
+        .filter(|(_, factors)| factors.len() >= settings.min_samples)
+        .map(|((zone, hour_of_week), mut factors)| {
+            factors.sort_by(f64::total_cmp);
+            let middle = factors.len() / 2;
+            let median = if factors.len() % 2 == 0 {
+                (factors[middle - 1] + factors[middle]) / 2.0
+            } else {
+                factors[middle]
+            };
+            TrafficFactor {
+                zone,
+                hour_of_week,
+                factor: (median.clamp(settings.min_factor, settings.max_factor) * 1000.0).round() / 1000.0,
+                samples: factors.len() as i32,
+                fitted_at: now,
+            }
+        })
+        .collect()
+}
+
+/// Length of the path through `points`, in km.
+pub fn path_km(points: &[GeoPoint]) -> f64 {
+    points.windows(2).map(|pair| pair[0].distance_to(&pair[1])).sum()
+}
+
+
+#[derive(Queryable)]
+struct CompletedTrip {
+    driver_id: Uuid,
+    pick_up: String,
+    start_ts: i64,
+    picked_up_at: Option<i64>,
+    end_ts: Option<i64>,
+    distance_km: f64,
+    ride_type: Option<serde_json::Value>,
+    quoted_min: Option<i32>,
+}
+
+/// Completed trips that ended since `since`. Trips whose driver's recorded
+/// path strays more than `max_detour` from the trip's distance are left out.
+pub fn completed_trip_samples(
+    conn: &mut PgConnection,
+    since: i64,
+    settings: &TrafficSettings,
+    book: &TariffBook,
+) -> QueryResult<Vec<TripSample>> {
+    use crate::schema::back_ride_request as requests;
+    use crate::schema::back_trips::dsl as trips;
+
+    let completed: Vec<CompletedTrip> = trips::back_trips
+        .left_join(requests::table)
+        .filter(trips::status.eq(TripStatus::Completed))
+        .filter(trips::end_ts.ge(since))
+        .select((
+            trips::driver_id,
+            trips::pick_up,
+            trips::start_ts,
+            trips::picked_up_at,
+            trips::end_ts,
+            trips::distance_km,
+            requests::ride_type.nullable(),
+            requests::estimated_time_min.nullable(),
+        ))
+        .load(conn)?;
+
+    let mut samples = Vec::with_capacity(completed.len());
+    for trip in completed {
+        let started = trip.picked_up_at.unwrap_or(trip.start_ts);
+        let Some(ended) = trip.end_ts else { continue };
+        let Some(pick_up) = parse_geo_point(&trip.pick_up) else { continue };
+        if ended <= started || trip.distance_km <= 0.0 {
+            continue;
+        }
+
+        let path = driver_path(conn, trip.driver_id, started, ended)?;
+        if path.len() >= 2 {
+            let driven = path_km(&path);
+            if driven > trip.distance_km * settings.max_detour || driven < trip.distance_km / settings.max_detour {
+                continue;
+            }
+        }
+
+        samples.push(TripSample {
+            zone: book.zone_for(&pick_up).to_string(),
+            hour_of_week: settings.hour_of_week(started),
+            ride_type: trip.ride_type.and_then(|json| serde_json::from_value(json).ok()).unwrap_or(RideType::ASAP),
+            distance_km: trip.distance_km,
+            actual_min: (ended - started) as f64 / 60.0,
+            quoted_min: trip.quoted_min,
+        });
+    }
+    Ok(samples)
+}
+
+/// Where the driver was between `from` and `to`, in order.
+pub fn driver_path(conn: &mut PgConnection, driver: Uuid, from: i64, to: i64) -> QueryResult<Vec<GeoPoint>> {
+    use crate::schema::back_driver_locations::dsl::*;
+
+    let points: Vec<(f64, f64)> = back_driver_locations
+        .filter(driver_id.eq(driver))
+        .filter(recorded_at.between(from, to))
+        .order(recorded_at.asc())
+        .select((lat, lng))
+        .load(conn)?;
+    Ok(points.into_iter().map(|(point_lat, point_lng)| GeoPoint { lat: point_lat, lng: point_lng, name: None }).collect())
+}
+
+/// Adds a location update to the driver's history.
+pub fn record_location(conn: &mut PgConnection, driver: Uuid, point: &GeoPoint, at: i64) -> QueryResult<usize> {
+    use crate::schema::back_driver_locations::dsl::*;
+
+    diesel::insert_into(back_driver_locations)
+        .values((driver_id.eq(driver), recorded_at.eq(at), lat.eq(point.lat), lng.eq(point.lng)))
+        .on_conflict_do_nothing()
+        .execute(conn)
+}
+
+
+pub fn read_table() -> RwLockReadGuard<'static, TrafficTable> {
+    TRAFFIC_TABLE.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn write_table() -> RwLockWriteGuard<'static, TrafficTable> {
+    TRAFFIC_TABLE.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// The factor for a trip starting at `point` at `at`.
+pub fn factor_at(point: &GeoPoint, at: i64) -> f64 {
+    let zone = tariffs::read_book().zone_for(point).to_string();
+    read_table().factor_at(&zone, at)
+}
+
+pub fn reload(conn: &mut PgConnection) -> QueryResult<usize> {
+    let settings = *read_table().settings();
+    let loaded = TrafficTable::load(conn, settings)?;
+    let count = loaded.len();
+    *write_table() = loaded;
+    Ok(count)
+}
+
+/// Fits the factors again from the window's completed trips, replaces the
+/// stored ones and drops location history older than the window.
+pub fn refit(conn: &mut PgConnection, now: i64) -> QueryResult<Vec<TrafficFactor>> {
+    let settings = *read_table().settings();
+    let since = now - settings.window_secs();
+    let samples = completed_trip_samples(conn, since, &settings, &tariffs::read_book())?;
+    let fitted = fit(&samples, &settings, now);
+
+    conn.transaction(|conn| {
+        diesel::delete(back_traffic_factors::table).execute(conn)?;
+        diesel::insert_into(back_traffic_factors::table).values(&fitted).execute(conn)?;
+        {
+            use crate::schema::back_driver_locations::dsl::*;
+            diesel::delete(back_driver_locations.filter(recorded_at.lt(since))).execute(conn)?;
+        }
+        Ok::<_, diesel::result::Error>(())
+    })?;
+
+    *write_table() = TrafficTable::new(settings, fitted.clone());
+    Ok(fitted)
+}
+
+/// Refits the factors every `TRAFFIC_FIT_INTERVAL_SECS`.
+pub async fn run_fitter(pool: DbPool) {
+    let every = Duration::from_secs(read_table().settings().interval_secs as u64);
+    loop {
+        sleep(every).await;
+
+        let pool = pool.clone();
+        let refitted = web::block(move || -> Result<Vec<TrafficFactor>, String> {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            refit(&mut conn, chrono::Utc::now().timestamp()).map_err(|e| e.to_string())
+        }).await;
+        match refitted {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => eprintln!("Traffic refit failed: {}", e),
+            Err(e) => eprintln!("Traffic refit failed: {}", e),
+        }
+    }
+}
+
+
+/// How far predictions were from the trips' actual durations.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EtaError {
+    pub trips: usize,
+    /// Predicted minus actual, below 0 when predictions run short.
+    pub mean_error_min: f64,
+    pub mean_abs_error_min: f64,
+    /// Mean absolute error as a percentage of the actual duration.
+    pub mean_abs_pct_error: f64,
+}
+
+impl EtaError {
+    /// From (predicted, actual) minutes.
+    pub fn of(pairs: &[(f64, f64)]) -> Self {
+        let trips = pairs.len();
+        if trips == 0 {
+            return Self { trips, mean_error_min: 0.0, mean_abs_error_min: 0.0, mean_abs_pct_error: 0.0 };
+        }
+        let n = trips as f64;
+        let round = |value: f64| (value * 100.0).round() / 100.0;
+        Self {
+            trips,
+            mean_error_min: round(pairs.iter().map(|(predicted, actual)| predicted - actual).sum::<f64>() / n),
+            mean_abs_error_min: round(pairs.iter().map(|(predicted, actual)| (predicted - actual).abs()).sum::<f64>() / n),
+            mean_abs_pct_error: round(pairs.iter().map(|(predicted, actual)| (predicted - actual).abs() / actual * 100.0).sum::<f64>() / n),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ZoneEta {
+    pub zone: String,
+    /// What riders were told when they requested.
+    pub quoted: EtaError,
+    /// What the factors in use now would have predicted.
+    pub current: EtaError,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EtaReport {
+    pub since: i64,
+    pub quoted: EtaError,
+    pub current: EtaError,
+    pub zones: Vec<ZoneEta>,
+}
+
+/// Predicted against actual trip durations, overall and per zone. Current
+/// predictions put the trip's straight-line free-flow time in traffic the way
+/// haversine quotes do, see `pricing::in_traffic`.
+pub fn eta_report(samples: &[TripSample], table: &TrafficTable, since: i64) -> EtaReport {
+    let predicted = |sample: &TripSample| {
+        let factor = table.factor_for(&sample.zone, sample.hour_of_week);
+        in_traffic(sample.free_flow_min(), factor) as f64
+    };
+    let errors = |samples: &[&TripSample]| {
+        let quoted: Vec<(f64, f64)> = samples
+            .iter()
+            .filter_map(|sample| sample.quoted_min.map(|quoted| (quoted as f64, sample.actual_min)))
+            .collect();
+        let current: Vec<(f64, f64)> = samples.iter().map(|sample| (predicted(sample), sample.actual_min)).collect();
+        (EtaError::of(&quoted), EtaError::of(&current))
+    };
+
+    let mut by_zone: BTreeMap<&str, Vec<&TripSample>> = BTreeMap::new();
+    for sample in samples {
+        by_zone.entry(sample.zone.as_str()).or_default().push(sample);
+    }
+    let zones = by_zone
+        .into_iter()
+        .map(|(zone, samples)| {
+            let (quoted, current) = errors(&samples);
+            ZoneEta { zone: zone.to_string(), quoted, current }
+        })
+        .collect();
+
+    let (quoted, current) = errors(&samples.iter().collect::<Vec<_>>());
+    EtaReport { since, quoted, current, zones }
+}
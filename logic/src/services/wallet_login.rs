@@ -0,0 +1,193 @@
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use serde::{ Deserialize, Serialize };
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Signature;
+use std::str::FromStr;
+use uuid::Uuid;
+use crate::api::auth::{ Principal, Role };
+use crate::schema::back_auth_challenges::dsl::{ back_auth_challenges as challenges, * };
+use crate::services::matching::env_or;
+
+
+/// How long a nonce can be signed for, `WALLET_CHALLENGE_TTL_SECS` overrides it.
+pub const DEFAULT_CHALLENGE_TTL_SECS: i64 = 300;
+
+
+/// A nonce handed to a rider or driver to sign with the wallet on their account.
+#[derive(Debug, Queryable, Selectable, Insertable, Serialize, Deserialize, Clone)]
+#[diesel(table_name = crate::schema::back_auth_challenges)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Challenge {
+    pub nonce: String,
+    pub subject: Uuid,
+    pub role: String,
+    pub pubkey: String,
+    pub issued_at: i64,
+    pub expires_at: i64,
+    pub used_at: Option<i64>,
+}
+
+impl Challenge {
+    /// The exact text the wallet signs, UTF-8 encoded.
+    pub fn message(&self) -> String {
+        format!(
+            "Sign in to Ride as {} {}\n\nWallet: {}\nNonce: {}\nIssued At: {}\nExpires At: {}",
+            self.role, self.subject, self.pubkey, self.nonce, self.issued_at, self.expires_at,
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChallengeResponse {
+    pub nonce: String,
+    pub message: String,
+    pub expires_at: i64,
+}
+
+impl From<&Challenge> for ChallengeResponse {
+    fn from(challenge: &Challenge) -> Self {
+        Self {
+            nonce: challenge.nonce.clone(),
+            message: challenge.message(),
+            expires_at: challenge.expires_at,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WalletLoginError {
+    UnknownAccount,
+    /// Roles other than rider and driver, or an account whose stored pubkey
+    /// isn't a Solana address.
+    NoWallet,
+    UnknownNonce,
+    Expired,
+    /// The nonce has already been used to sign in.
+    Replayed,
+    BadSignature,
+    Db(String),
+}
+
+impl std::fmt::Display for WalletLoginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WalletLoginError::UnknownAccount => f.write_str("Account not found"),
+            WalletLoginError::NoWallet => f.write_str("Account has no wallet to sign in with"),
+            WalletLoginError::UnknownNonce => f.write_str("Unknown nonce"),
+            WalletLoginError::Expired => f.write_str("Nonce has expired, ask for a new one"),
+            WalletLoginError::Replayed => f.write_str("Nonce has already been used"),
+            WalletLoginError::BadSignature => f.write_str("Signature does not match the account's wallet"),
+            WalletLoginError::Db(e) => write!(f, "DB error: {}", e),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for WalletLoginError {
+    fn from(e: diesel::result::Error) -> Self {
+        WalletLoginError::Db(e.to_string())
+    }
+}
+
+
+pub fn challenge_ttl_secs() -> i64 {
+    env_or("WALLET_CHALLENGE_TTL_SECS", DEFAULT_CHALLENGE_TTL_SECS)
+}
+
+/// The wallet registered on the account, `rider_pubkey` or `driver_pubkey`.
+pub fn account_wallet(conn: &mut PgConnection, account_role: Role, id: Uuid) -> Result<Pubkey, WalletLoginError> {
+    let stored: Option<serde_json::Value> = match account_role {
+        Role::Rider => {
+            use crate::schema::back_custom_users::dsl::{ back_custom_users, rider_pubkey };
+            back_custom_users.find(id).select(rider_pubkey).first(conn).optional()?
+        }
+        Role::Driver => {
+            use crate::schema::back_drivers::dsl::{ back_drivers, driver_pubkey };
+            back_drivers.find(id).select(driver_pubkey).first(conn).optional()?
+        }
+        Role::Admin | Role::Support | Role::Partner => return Err(WalletLoginError::NoWallet),
+    };
+
+    let stored = stored.ok_or(WalletLoginError::UnknownAccount)?;
+    stored.as_str()
+        .and_then(|key| Pubkey::from_str(key).ok())
+        .ok_or(WalletLoginError::NoWallet)
+}
+
+/// Hands out a fresh nonce for the account's wallet to sign. Expired nonces
+/// are cleared out on the way.
+pub fn issue_challenge(
+    conn: &mut PgConnection,
+    account_role: Role,
+    id: Uuid,
+    now: i64,
+    ttl_secs: i64,
+) -> Result<Challenge, WalletLoginError> {
+    let wallet = account_wallet(conn, account_role, id)?;
+
+    diesel::delete(challenges.filter(expires_at.lt(now))).execute(conn)?;
+
+    let challenge = Challenge {
+        nonce: format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple()),
+        subject: id,
+        role: account_role.as_str().to_string(),
+        pubkey: wallet.to_string(),
+        issued_at: now,
+        expires_at: now + ttl_secs,
+        used_at: None,
+    };
+    diesel::insert_into(challenges).values(&challenge).execute(conn)?;
+    Ok(challenge)
+}
+
+/// True if `signature` (base58, as wallets hand it back) is `wallet`'s
+/// signature of `message`.
+pub fn signed_by(wallet: &Pubkey, message: &str, signature: &str) -> bool {
+    Signature::from_str(signature)
+        .map(|signature| signature.verify(wallet.as_ref(), message.as_bytes()))
+        .unwrap_or(false)
+}
+
+/// Checks the signed nonce and uses it up, so the same signature can't sign
+/// in a second time. Hands back who signed in.
+pub fn redeem_challenge(
+    conn: &mut PgConnection,
+    signed_nonce: &str,
+    signature: &str,
+    now: i64,
+) -> Result<Principal, WalletLoginError> {
+    let challenge: Challenge = challenges
+        .find(signed_nonce)
+        .select(Challenge::as_select())
+        .first(conn)
+        .optional()?
+        .ok_or(WalletLoginError::UnknownNonce)?;
+
+    if challenge.used_at.is_some() {
+        return Err(WalletLoginError::Replayed);
+    }
+    if now >= challenge.expires_at {
+        return Err(WalletLoginError::Expired);
+    }
+
+    let wallet = Pubkey::from_str(&challenge.pubkey).map_err(|_| WalletLoginError::NoWallet)?;
+    if !signed_by(&wallet, &challenge.message(), signature) {
+        return Err(WalletLoginError::BadSignature);
+    }
+
+    // Two requests racing with the same signature, only one gets to use it
+    let claimed = diesel::update(
+        challenges
+            .filter(nonce.eq(signed_nonce))
+            .filter(used_at.is_null())
+            .filter(expires_at.gt(now)),
+    )
+    .set(used_at.eq(now))
+    .execute(conn)?;
+    if claimed == 0 {
+        return Err(WalletLoginError::Replayed);
+    }
+
+    let account_role: Role = challenge.role.parse().map_err(|_| WalletLoginError::NoWallet)?;
+    Ok(Principal::new(challenge.subject, account_role))
+}
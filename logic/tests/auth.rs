@@ -0,0 +1,321 @@
+use actix_web::{ web, App };
+use actix_web::http::header;
+use actix_web::middleware::from_fn;
+use actix_web::test::{ call_service, init_service, read_body_json, TestRequest };
+use diesel::prelude::*;
+use logic::api::auth::{ self, authenticate, IssuedToken, Role };
+use logic::services::wallet_login::{ issue_challenge, redeem_challenge, signed_by, ChallengeResponse, WalletLoginError };
+use solana_sdk::signature::{ Keypair, Signer };
+use uuid::Uuid;
+
+mod common;
+
+
+// ─── /auth (needs TEST_DATABASE_URL) ─────────────────────────────────────────
+
+#[actix_web::test]
+async fn refresh_needs_a_valid_token() {
+    let Some(pool) = common::test_pool() else { return };
+    let rider = common::insert_rider(&mut pool.get().unwrap());
+    let app = init_service(
+        App::new()
+            .wrap(from_fn(authenticate))
+            .app_data(common::authenticator())
+            .app_data(web::Data::new(pool))
+            .service(auth::routes()),
+    ).await;
+
+    let anonymous = call_service(&app, TestRequest::post().uri("/auth/refresh").to_request()).await;
+    assert_eq!(anonymous.status(), 401);
+    assert_eq!(anonymous.headers().get(header::WWW_AUTHENTICATE).unwrap(), "Bearer");
+
+    let forged = TestRequest::post()
+        .uri("/auth/refresh")
+        .insert_header((header::AUTHORIZATION, "Bearer not.a.token"))
+        .to_request();
+    assert_eq!(call_service(&app, forged).await.status(), 401);
+
+    let basic = TestRequest::post()
+        .uri("/auth/refresh")
+        .insert_header((header::AUTHORIZATION, "Basic dXNlcjpwYXNz"))
+        .to_request();
+    assert_eq!(call_service(&app, basic).await.status(), 401);
+
+    let refreshed = TestRequest::post()
+        .uri("/auth/refresh")
+        .insert_header(common::bearer(Role::Rider, rider))
+        .to_request();
+    let response = call_service(&app, refreshed).await;
+    assert_eq!(response.status(), 200);
+    let token: IssuedToken = read_body_json(response).await;
+    assert_eq!((token.subject, token.role), (rider, Role::Rider));
+
+    // The query parameter is for sockets only, elsewhere it isn't a token
+    let by_query = TestRequest::post()
+        .uri(&format!("/auth/refresh?access_token={}", token.access_token))
+        .to_request();
+    assert_eq!(call_service(&app, by_query).await.status(), 401);
+
+    // Nobody by that id has a rider account
+    let stranger = TestRequest::post()
+        .uri("/auth/refresh")
+        .insert_header(common::bearer(Role::Rider, Uuid::new_v4()))
+        .to_request();
+    assert_eq!(call_service(&app, stranger).await.status(), 403);
+}
+
+#[actix_web::test]
+async fn only_admins_issue_tokens() {
+    let Some(pool) = common::test_pool() else { return };
+    let driver = common::insert_driver(&mut pool.get().unwrap(), "offline", (6.5244, 3.3792));
+    let app = init_service(
+        App::new()
+            .wrap(from_fn(authenticate))
+            .app_data(common::authenticator())
+            .app_data(web::Data::new(pool))
+            .service(auth::routes()),
+    ).await;
+    let body = serde_json::json!({ "subject": driver, "role": "driver" });
+
+    let as_driver = TestRequest::post()
+        .uri("/auth/issue-token")
+        .insert_header(common::bearer(Role::Driver, driver))
+        .set_json(&body)
+        .to_request();
+    assert_eq!(call_service(&app, as_driver).await.status(), 403);
+
+    let as_admin = TestRequest::post()
+        .uri("/auth/issue-token")
+        .insert_header(common::bearer(Role::Admin, Uuid::new_v4()))
+        .set_json(&body)
+        .to_request();
+    let response = call_service(&app, as_admin).await;
+    assert_eq!(response.status(), 200);
+    let token: IssuedToken = read_body_json(response).await;
+    assert_eq!((token.subject, token.role), (driver, Role::Driver));
+
+    // Only for roles the account has
+    let not_support = TestRequest::post()
+        .uri("/auth/issue-token")
+        .insert_header(common::bearer(Role::Admin, Uuid::new_v4()))
+        .set_json(serde_json::json!({ "subject": driver, "role": "support" }))
+        .to_request();
+    assert_eq!(call_service(&app, not_support).await.status(), 422);
+}
+
+
+// ─── Sign-in with a wallet (needs TEST_DATABASE_URL) ─────────────────────────
+
+fn rider_with_wallet(conn: &mut PgConnection, wallet: &Keypair) -> Uuid {
+    use logic::schema::back_custom_users::dsl::*;
+
+    let id = common::insert_rider(conn);
+    diesel::update(back_custom_users.find(id))
+        .set(rider_pubkey.eq(serde_json::json!(wallet.pubkey().to_string())))
+        .execute(conn)
+        .unwrap();
+    id
+}
+
+fn driver_with_wallet(conn: &mut PgConnection, wallet: &Keypair) -> Uuid {
+    use logic::schema::back_drivers::dsl::*;
+
+    let id = common::insert_driver(conn, "offline", (6.5244, 3.3792));
+    diesel::update(back_drivers.find(id))
+        .set(driver_pubkey.eq(serde_json::json!(wallet.pubkey().to_string())))
+        .execute(conn)
+        .unwrap();
+    id
+}
+
+fn sign(wallet: &Keypair, message: &str) -> String {
+    wallet.sign_message(message.as_bytes()).to_string()
+}
+
+#[actix_web::test]
+async fn riders_sign_in_by_signing_a_nonce() {
+    let Some(pool) = common::test_pool() else { return };
+    let wallet = Keypair::new();
+    let rider = rider_with_wallet(&mut pool.get().unwrap(), &wallet);
+    let app = init_service(
+        App::new()
+            .wrap(from_fn(authenticate))
+            .app_data(common::authenticator())
+            .app_data(web::Data::new(pool.clone()))
+            .service(auth::routes()),
+    ).await;
+
+    let ask = TestRequest::post()
+        .uri("/auth/challenge")
+        .set_json(serde_json::json!({ "subject": rider, "role": "rider" }))
+        .to_request();
+    let response = call_service(&app, ask).await;
+    assert_eq!(response.status(), 200);
+    let challenge: ChallengeResponse = read_body_json(response).await;
+    assert!(challenge.message.contains(&challenge.nonce));
+    assert!(challenge.message.contains(&wallet.pubkey().to_string()));
+
+    // Someone else's wallet can't answer it
+    let login = |signature: String| TestRequest::post()
+        .uri("/auth/wallet-login")
+        .set_json(serde_json::json!({ "nonce": challenge.nonce, "signature": signature }))
+        .to_request();
+    let forged = sign(&Keypair::new(), &challenge.message);
+    assert_eq!(call_service(&app, login(forged)).await.status(), 401);
+    assert_eq!(call_service(&app, login("not-base58!".into())).await.status(), 401);
+
+    let signature = sign(&wallet, &challenge.message);
+    let response = call_service(&app, login(signature.clone())).await;
+    assert_eq!(response.status(), 200);
+    let token: IssuedToken = read_body_json(response).await;
+    assert_eq!((token.subject, token.role), (rider, Role::Rider));
+
+    // The token works, the signature only once
+    let refresh = TestRequest::post()
+        .uri("/auth/refresh")
+        .insert_header((header::AUTHORIZATION, format!("Bearer {}", token.access_token)))
+        .to_request();
+    assert_eq!(call_service(&app, refresh).await.status(), 200);
+    assert_eq!(call_service(&app, login(signature)).await.status(), 401);
+
+    let unknown = TestRequest::post()
+        .uri("/auth/challenge")
+        .set_json(serde_json::json!({ "subject": Uuid::new_v4(), "role": "rider" }))
+        .to_request();
+    assert_eq!(call_service(&app, unknown).await.status(), 404);
+    let admin = TestRequest::post()
+        .uri("/auth/challenge")
+        .set_json(serde_json::json!({ "subject": rider, "role": "admin" }))
+        .to_request();
+    assert_eq!(call_service(&app, admin).await.status(), 422);
+}
+
+#[test]
+fn nonces_expire_and_are_used_once() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+    let wallet = Keypair::new();
+    let driver = driver_with_wallet(&mut conn, &wallet);
+    let now = 1_800_000_000;
+
+    let late = issue_challenge(&mut conn, Role::Driver, driver, now, 60).unwrap();
+    let signature = sign(&wallet, &late.message());
+    assert_eq!(redeem_challenge(&mut conn, &late.nonce, &signature, now + 60), Err(WalletLoginError::Expired));
+
+    let challenge = issue_challenge(&mut conn, Role::Driver, driver, now, 60).unwrap();
+    assert_ne!(challenge.nonce, late.nonce);
+    let signature = sign(&wallet, &challenge.message());
+    // A signature is for one nonce only
+    assert_eq!(redeem_challenge(&mut conn, &late.nonce, &signature, now + 1), Err(WalletLoginError::BadSignature));
+    let principal = redeem_challenge(&mut conn, &challenge.nonce, &signature, now + 1).unwrap();
+    assert_eq!((principal.subject, principal.role), (driver, Role::Driver));
+    assert_eq!(redeem_challenge(&mut conn, &challenge.nonce, &signature, now + 2), Err(WalletLoginError::Replayed));
+    assert_eq!(redeem_challenge(&mut conn, "no-such-nonce", &signature, now), Err(WalletLoginError::UnknownNonce));
+    // A rider wallet for a driver id the account doesn't have
+    assert!(issue_challenge(&mut conn, Role::Rider, driver, now, 60).is_err());
+    assert!(signed_by(&wallet.pubkey(), &challenge.message(), &signature));
+}
+
+
+// ─── Roles (needs TEST_DATABASE_URL) ─────────────────────────────────────────
+
+#[actix_web::test]
+async fn admins_grant_and_revoke_roles_on_the_record() {
+    let Some(pool) = common::test_pool() else { return };
+    let agent = common::insert_rider(&mut pool.get().unwrap());
+    let admin = Uuid::new_v4();
+    let app = init_service(
+        App::new()
+            .wrap(from_fn(authenticate))
+            .app_data(common::authenticator())
+            .app_data(web::Data::new(pool.clone()))
+            .configure(logic::api::init),
+    ).await;
+    let change = |action: &str, as_role: Role, as_id: Uuid, role: &str| TestRequest::post()
+        .uri(&format!("/admin/roles/{}", action))
+        .insert_header(common::bearer(as_role, as_id))
+        .set_json(serde_json::json!({ "user_id": agent, "role": role, "reason": "joined the help desk" }))
+        .to_request();
+
+    // Riders can't get into /admin at all, support can look but not grant
+    assert_eq!(call_service(&app, change("grant", Role::Rider, agent, "support")).await.status(), 403);
+    assert_eq!(call_service(&app, change("grant", Role::Support, agent, "support")).await.status(), 403);
+
+    let response = call_service(&app, change("grant", Role::Admin, admin, "support")).await;
+    assert_eq!(response.status(), 200);
+    let granted: serde_json::Value = read_body_json(response).await;
+    assert_eq!(granted["changed"], true);
+    assert_eq!(granted["roles"], serde_json::json!(["rider", "support"]));
+    let again: serde_json::Value = read_body_json(call_service(&app, change("grant", Role::Admin, admin, "support")).await).await;
+    assert_eq!(again["changed"], false);
+    assert_eq!(call_service(&app, change("grant", Role::Admin, admin, "driver")).await.status(), 422);
+
+    // The support role opens the dashboard and other riders' requests, not onboarding
+    let dashboard = TestRequest::get()
+        .uri("/admin/dashboard")
+        .insert_header(common::bearer(Role::Support, agent))
+        .to_request();
+    assert_eq!(call_service(&app, dashboard).await.status(), 200);
+    let onboard = TestRequest::post()
+        .uri("/admin/create-riders")
+        .insert_header(common::bearer(Role::Support, agent))
+        .set_json(serde_json::json!({ "name": "A", "email": "a@b.c", "phone": "1", "rider_pubkey": "x" }))
+        .to_request();
+    assert_eq!(call_service(&app, onboard).await.status(), 403);
+    let as_rider = TestRequest::get()
+        .uri("/admin/dashboard")
+        .insert_header(common::bearer(Role::Rider, agent))
+        .to_request();
+    assert_eq!(call_service(&app, as_rider).await.status(), 403);
+
+    let refresh = || TestRequest::post()
+        .uri("/auth/refresh")
+        .insert_header(common::bearer(Role::Support, agent))
+        .to_request();
+    assert_eq!(call_service(&app, refresh()).await.status(), 200);
+    assert_eq!(call_service(&app, change("revoke", Role::Admin, admin, "support")).await.status(), 200);
+    assert_eq!(call_service(&app, refresh()).await.status(), 403);
+
+    let history = TestRequest::get()
+        .uri(&format!("/admin/roles/{}", agent))
+        .insert_header(common::bearer(Role::Admin, admin))
+        .to_request();
+    let roles: serde_json::Value = read_body_json(call_service(&app, history).await).await;
+    assert_eq!(roles["roles"], serde_json::json!(["rider"]));
+    let entries = roles["history"].as_array().unwrap();
+    let actions: Vec<&str> = entries.iter().map(|e| e["action"].as_str().unwrap()).collect();
+    assert_eq!(actions, vec!["grant", "revoke"]);
+    assert!(entries.iter().all(|e| e["actor_id"] == serde_json::json!(admin) && e["reason"] == "joined the help desk"));
+}
+
+#[test]
+fn roles_come_from_accounts_grants_and_custom_users() {
+    use logic::services::roles::{ grant_role, revoke_role, role_history, roles_of, RoleError };
+
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+    let admin = Uuid::new_v4();
+    diesel::sql_query("INSERT INTO custom_users (id, username, custom_role) VALUES ($1, 'ops', 'admin')")
+        .bind::<diesel::sql_types::Uuid, _>(admin)
+        .execute(&mut conn)
+        .unwrap();
+    assert_eq!(roles_of(&mut conn, admin).unwrap(), vec![Role::Admin]);
+
+    let driver = common::insert_driver(&mut conn, "offline", (6.5244, 3.3792));
+    assert!(grant_role(&mut conn, driver, Role::Partner, admin, None, 1_800_000_000).unwrap());
+    assert_eq!(roles_of(&mut conn, driver).unwrap(), vec![Role::Driver, Role::Partner]);
+
+    assert!(matches!(revoke_role(&mut conn, admin, Role::Admin, admin, None, 1_800_000_001), Err(RoleError::OwnAdminRole)));
+    assert!(matches!(revoke_role(&mut conn, driver, Role::Driver, admin, None, 1_800_000_001), Err(RoleError::NotGrantable(Role::Driver))));
+    assert!(!revoke_role(&mut conn, driver, Role::Support, admin, None, 1_800_000_001).unwrap());
+
+    // Another admin taking away the admin role that came from custom_users.
+    let other_admin = Uuid::new_v4();
+    assert!(grant_role(&mut conn, other_admin, Role::Admin, admin, None, 1_800_000_002).unwrap());
+    assert!(revoke_role(&mut conn, admin, Role::Admin, other_admin, Some("left".into()), 1_800_000_003).unwrap());
+    assert!(roles_of(&mut conn, admin).unwrap().is_empty());
+    let history = role_history(&mut conn, admin).unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!((history[0].role, history[0].action.as_str(), history[0].actor_id), (Role::Admin, "revoke", other_admin));
+    assert!(!revoke_role(&mut conn, admin, Role::Admin, other_admin, None, 1_800_000_004).unwrap());
+}
@@ -0,0 +1,182 @@
+use futures_util::SinkExt;
+use logic::api::auth::Role;
+use logic::api::riders::NewRideRequest;
+use serde_json::json;
+use std::net::TcpListener;
+use std::process::{ Child, Command, Stdio };
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
+
+mod common;
+
+
+const MAIDUGURI: (f64, f64) = (11.8311, 13.1510);
+const SOKOTO: (f64, f64) = (13.0059, 5.2476);
+const GOMBE: (f64, f64) = (10.2897, 11.1673);
+const MAKURDI: (f64, f64) = (7.7322, 8.5391);
+
+// A replica of the server in its own process, so its hub is its own. Killed
+// when the test is done with it.
+struct Instance {
+    port: u16,
+    process: Child,
+}
+
+impl Instance {
+    async fn start(database_url: &str) -> Self {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let process = Command::new(env!("CARGO_BIN_EXE_logic"))
+            .env("DATABASE_URL", database_url)
+            .env("PORT", port.to_string())
+            .env("DISPATCH_BUS", "postgres")
+            .env("JWT_SECRET", std::str::from_utf8(common::JWT_SECRET).unwrap())
+            .env("DISPATCH_ROUNDS", "1")
+            .env("DISPATCH_OFFER_TIMEOUT_SECS", "10")
+            .env("PAYSTACK_SECRET", "sk_test")
+            .env("SOLANA_PROGRAM_ID", "11111111111111111111111111111111")
+            .env("SOLANA_PAYER_KEYPAIR", "/nonexistent")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("start logic binary");
+
+        let instance = Self { port, process };
+        for _ in 0..100 {
+            if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+                return instance;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("instance on port {} did not start", port);
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{}", self.port, path)
+    }
+
+    async fn open(&self, path: &str) -> common::Socket {
+        let (socket, _) = connect_async(format!("ws://127.0.0.1:{}{}", self.port, path)).await.expect("socket opens");
+        socket
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+fn dispatch_on(instance: &Instance, ride: &NewRideRequest) -> tokio::task::JoinHandle<reqwest::StatusCode> {
+    let request = reqwest::Client::new()
+        .get(instance.url("/riders/assign-driver"))
+        .bearer_auth(common::token(Role::Rider, ride.rider_id))
+        .json(ride);
+    tokio::spawn(async move { request.send().await.expect("dispatch request").status() })
+}
+
+
+// ─── Two instances, one database (needs TEST_DATABASE_URL) ───────────────────
+
+#[actix_web::test]
+async fn offers_and_answers_cross_instances() {
+    let Some(pool) = common::test_pool() else { return };
+    let (rider, driver) = {
+        let mut conn = pool.get().unwrap();
+        let rider = common::insert_rider(&mut conn);
+        (rider, common::insert_driver(&mut conn, "available", (MAIDUGURI.0 + 0.002, MAIDUGURI.1)))
+    };
+    let database_url = std::env::var("TEST_DATABASE_URL").unwrap();
+    let a = Instance::start(&database_url).await;
+    let b = Instance::start(&database_url).await;
+
+    // The driver's app is on A, the rider's on B, and B dispatches
+    let mut driver_ws = a.open(&format!("/ws/driver?driver_id={}&access_token={}", driver, common::token(Role::Driver, driver))).await;
+    common::next_of(&mut driver_ws, "welcome").await;
+    let mut rider_ws = b.open(&format!("/ws/user?rider_id={}&access_token={}", rider, common::token(Role::Rider, rider))).await;
+    common::next_of(&mut rider_ws, "welcome").await;
+
+    let ride = common::ride_from(rider, MAIDUGURI);
+    let dispatch = dispatch_on(&b, &ride);
+
+    let offer = common::next_of(&mut driver_ws, "ride_offer").await;
+    assert_eq!(offer["ride"]["request_id"], json!(ride.request_id));
+    driver_ws.send(Message::Text(json!({ "type": "accept", "request_id": ride.request_id }).to_string())).await.unwrap();
+
+    assert_eq!(dispatch.await.unwrap(), 200);
+    let assigned = common::next_of(&mut rider_ws, "trip_status").await;
+    assert_eq!(assigned["status"], "driver_assigned");
+    assert_eq!(common::next_of(&mut driver_ws, "trip_status").await["trip_reference"], assigned["trip_reference"]);
+    assert_eq!(common::driver_status(&mut pool.get().unwrap(), driver), "busy");
+}
+
+#[actix_web::test]
+async fn a_driver_can_answer_on_any_instance() {
+    let Some(pool) = common::test_pool() else { return };
+    let (rider, driver) = {
+        let mut conn = pool.get().unwrap();
+        let rider = common::insert_rider(&mut conn);
+        (rider, common::insert_driver(&mut conn, "available", (SOKOTO.0 + 0.002, SOKOTO.1)))
+    };
+    let database_url = std::env::var("TEST_DATABASE_URL").unwrap();
+    let a = Instance::start(&database_url).await;
+    let b = Instance::start(&database_url).await;
+
+    let mut driver_ws = a.open(&format!("/ws/driver?driver_id={}&access_token={}", driver, common::token(Role::Driver, driver))).await;
+    common::next_of(&mut driver_ws, "welcome").await;
+
+    let ride = common::ride_from(rider, SOKOTO);
+    let dispatch = dispatch_on(&a, &ride);
+    common::next_of(&mut driver_ws, "ride_offer").await;
+
+    // The HTTP answer lands on B, which never saw the offer
+    let answer = |instance: &Instance| {
+        reqwest::Client::new()
+            .post(instance.url("/riders/wait-driver-response"))
+            .bearer_auth(common::token(Role::Driver, driver))
+            .json(&json!({ "status": "rejected", "driver_id": driver }))
+            .send()
+    };
+    assert_eq!(answer(&b).await.unwrap().status(), 200);
+
+    // One round only, so a rejection leaves nobody to ask
+    assert_eq!(dispatch.await.unwrap(), 404);
+    assert_eq!(common::driver_status(&mut pool.get().unwrap(), driver), "available");
+    assert_eq!(answer(&b).await.unwrap().status(), 409);
+}
+
+#[actix_web::test]
+async fn a_driver_moved_on_one_instance_is_found_by_another() {
+    let Some(pool) = common::test_pool() else { return };
+    let (rider, driver) = {
+        let mut conn = pool.get().unwrap();
+        let rider = common::insert_rider(&mut conn);
+        (rider, common::insert_driver(&mut conn, "available", GOMBE))
+    };
+    let database_url = std::env::var("TEST_DATABASE_URL").unwrap();
+    let a = Instance::start(&database_url).await;
+    let b = Instance::start(&database_url).await;
+
+    let mut driver_ws = a.open(&format!("/ws/driver?driver_id={}&access_token={}", driver, common::token(Role::Driver, driver))).await;
+    common::next_of(&mut driver_ws, "welcome").await;
+
+    // Both loaded the driver in Gombe, and only B sees the move to Makurdi
+    let moved = reqwest::Client::new()
+        .post(b.url(&format!("/matching/process-geolocation/{}", driver)))
+        .bearer_auth(common::token(Role::Driver, driver))
+        .json(&json!({ "lat": MAKURDI.0 + 0.002, "lng": MAKURDI.1, "name": "", "kind": "driver_location" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(moved.status(), 200);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let ride = common::ride_from(rider, MAKURDI);
+    let dispatch = dispatch_on(&a, &ride);
+    let offer = common::next_of(&mut driver_ws, "ride_offer").await;
+    assert_eq!(offer["ride"]["request_id"], json!(ride.request_id));
+    driver_ws.send(Message::Text(json!({ "type": "accept", "request_id": ride.request_id }).to_string())).await.unwrap();
+    assert_eq!(dispatch.await.unwrap(), 200);
+}
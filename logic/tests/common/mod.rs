@@ -0,0 +1,334 @@
+// Shared helpers for the tests that need a real Postgres.
+//
+// These tests only run when TEST_DATABASE_URL points at a scratch database,
+// e.g. `TEST_DATABASE_URL=postgres://postgres@localhost/ride_test cargo test`.
+// The database is wiped and rebuilt from tests/fixtures/schema.sql plus every
+// migration newer than the hosted schema the first time a test binary asks for it.
+#![allow(dead_code)]
+
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use futures_util::StreamExt;
+use logic::api::auth::{ Authenticator, Principal, Role };
+use logic::api::riders::{ CreateRideRequest, NewRideRequest, RideType };
+use logic::services::pricing::{ GeoPoint, HaversineRouter };
+use logic::services::quotes::{ FareQuote, QuoteSigner };
+use logic::db::{ init_pool, DbPool };
+use serde_json::Value;
+use std::sync::{ Mutex, MutexGuard, Once };
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{ MaybeTlsStream, WebSocketStream };
+use tokio_tungstenite::tungstenite::Message;
+
+pub const JWT_SECRET: &[u8] = b"test-secret";
+
+/// How long dispatch reserves a driver with the default 50 second offers.
+pub const HOLD_SECS: i64 = logic::api::drivers::reservation_secs(std::time::Duration::from_secs(50));
+
+// Migrations up to and including this one are already part of schema.sql.
+const HOSTED_SCHEMA_UP_TO: &str = "2026-02-06-004911-0000";
+
+static SETUP: Once = Once::new();
+static SERIAL: Mutex<()> = Mutex::new(());
+
+
+pub fn test_pool() -> Option<DbPool> {
+    let url = match std::env::var("TEST_DATABASE_URL") {
+        Ok(url) => url,
+        Err(_) => {
+            eprintln!("TEST_DATABASE_URL not set, skipping database test");
+            return None;
+        }
+    };
+
+    let pool = init_pool(&url);
+    SETUP.call_once(|| {
+        let mut conn = pool.get().expect("test database connection");
+        reset_schema(&mut conn);
+    });
+
+    Some(pool)
+}
+
+fn reset_schema(conn: &mut diesel::pg::PgConnection) {
+    conn.batch_execute("DROP SCHEMA public CASCADE; CREATE SCHEMA public;")
+        .expect("reset test schema");
+    conn.batch_execute(include_str!("../fixtures/schema.sql"))
+        .expect("load hosted schema");
+
+    let migrations_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/../migrations");
+    let mut pending: Vec<_> = std::fs::read_dir(migrations_dir)
+        .expect("read migrations dir")
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.split('_').next())
+                .is_some_and(|version| version > HOSTED_SCHEMA_UP_TO && path.join("up.sql").exists())
+        })
+        .collect();
+    pending.sort();
+
+    for dir in pending {
+        let sql = std::fs::read_to_string(dir.join("up.sql")).expect("read up.sql");
+        conn.batch_execute(&sql)
+            .unwrap_or_else(|e| panic!("migration {} failed: {}", dir.display(), e));
+    }
+}
+
+
+/// For tests that sweep whole tables (workers, counters) and would otherwise
+/// pick up rows written by tests running alongside them.
+pub fn serial() -> MutexGuard<'static, ()> {
+    SERIAL.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Inserts a finished trip the way the trip endpoints store it.
+pub fn insert_trip(
+    conn: &mut diesel::pg::PgConnection,
+    trip_reference: &str,
+    rider_pubkey_value: &str,
+    driver_pubkey_value: &str,
+) -> [u8; 32] {
+    insert_trip_with(conn, trip_reference, rider_pubkey_value, driver_pubkey_value, TripOverrides::default())
+}
+
+/// What a test can choose about the completed trip `insert_trip_with` stores.
+/// The default is the Lagos Island one `insert_trip` stores.
+pub struct TripOverrides {
+    pub driver: uuid::Uuid,
+    /// Also where the driver was last seen.
+    pub pick_up: String,
+    pub drop_off: String,
+    pub started: i64,
+    pub picked_up_at: Option<i64>,
+    pub ended: i64,
+    pub distance_km: f64,
+    pub request: Option<uuid::Uuid>,
+}
+
+impl Default for TripOverrides {
+    fn default() -> Self {
+        Self {
+            driver: uuid::Uuid::new_v4(),
+            pick_up: "Lagos Island".into(),
+            drop_off: "Victoria Island".into(),
+            started: 1_700_000_000,
+            picked_up_at: None,
+            ended: 1_700_001_800,
+            distance_km: 4.2,
+            request: None,
+        }
+    }
+}
+
+pub fn insert_trip_with(
+    conn: &mut diesel::pg::PgConnection,
+    trip_reference: &str,
+    rider_pubkey_value: &str,
+    driver_pubkey_value: &str,
+    trip: TripOverrides,
+) -> [u8; 32] {
+    use logic::schema::back_trips::dsl::*;
+
+    let id: [u8; 32] = *uuid::Uuid::new_v4().as_bytes().repeat(2).first_chunk().unwrap();
+    diesel::insert_into(back_trips)
+        .values((
+            trip_id.eq(id.to_vec()),
+            rider_id.eq(uuid::Uuid::new_v4()),
+            reference.eq(trip_reference),
+            pick_up.eq(&trip.pick_up),
+            drop_off.eq(&trip.drop_off),
+            driver_location.eq(&trip.drop_off),
+            rider_pubkey.eq(rider_pubkey_value),
+            driver_pubkey.eq(driver_pubkey_value),
+            driver_id.eq(trip.driver),
+            status.eq("completed"),
+            start_ts.eq(trip.started),
+            picked_up_at.eq(trip.picked_up_at),
+            end_ts.eq(Some(trip.ended)),
+            distance_km.eq(trip.distance_km),
+            item.eq(serde_json::json!([])),
+            fare_estimate.eq(Some(1500i64)),
+            fare_lamports.eq(Some(192_000i64)),
+            rider_email.eq("rider@test.com"),
+            request_id.eq(trip.request),
+        ))
+        .execute(conn)
+        .expect("insert trip");
+    id
+}
+
+pub fn insert_rider(conn: &mut diesel::pg::PgConnection) -> uuid::Uuid {
+    use logic::schema::back_custom_users::dsl::*;
+
+    let id = uuid::Uuid::new_v4();
+    diesel::insert_into(back_custom_users)
+        .values((
+            rider_id.eq(id),
+            rider_pubkey.eq(serde_json::json!("RiderPubkey1111111111111111111111111111111")),
+            name.eq("Test Rider"),
+            email.eq(format!("{}@riders.test", id)),
+            phone.eq("+2348000000000"),
+        ))
+        .execute(conn)
+        .expect("insert rider");
+    id
+}
+
+pub fn insert_driver(
+    conn: &mut diesel::pg::PgConnection,
+    driver_status: &str,
+    location: (f64, f64),
+) -> uuid::Uuid {
+    insert_driver_with_vehicle(conn, driver_status, location, "EV")
+}
+
+pub fn insert_driver_with_vehicle(
+    conn: &mut diesel::pg::PgConnection,
+    driver_status: &str,
+    (lat, lng): (f64, f64),
+    vehicle_kind: &str,
+) -> uuid::Uuid {
+    use logic::schema::back_drivers::dsl::*;
+
+    let id = uuid::Uuid::new_v4();
+    diesel::insert_into(back_drivers)
+        .values((
+            driver_id.eq(id),
+            driver_pubkey.eq(serde_json::json!("DriverPubkey111111111111111111111111111111")),
+            name.eq("Test Driver"),
+            email.eq(format!("{}@drivers.test", id)),
+            phone.eq("+2348000000001"),
+            status.eq(driver_status),
+            driver_location.eq(serde_json::json!({ "lat": lat, "lng": lng, "name": null })),
+            vehicle_type.eq(vehicle_kind),
+            driver_response.eq(serde_json::json!({})),
+        ))
+        .execute(conn)
+        .expect("insert driver");
+    id
+}
+
+/// Stores an ASAP request from `rider` between two points on Lagos Island.
+pub fn insert_ride_request(conn: &mut diesel::pg::PgConnection, rider: uuid::Uuid) -> uuid::Uuid {
+    insert_ride_request_between(
+        conn,
+        rider,
+        &GeoPoint { lat: 6.4531, lng: 3.3958, name: Some("Lagos Island".into()) },
+        &GeoPoint { lat: 6.4280, lng: 3.4219, name: Some("Victoria Island".into()) },
+    )
+}
+
+/// Stores an ASAP request from `rider`, priced like the Lagos Island one
+/// whatever the points.
+pub fn insert_ride_request_between(
+    conn: &mut diesel::pg::PgConnection,
+    rider: uuid::Uuid,
+    from: &GeoPoint,
+    to: &GeoPoint,
+) -> uuid::Uuid {
+    use logic::schema::back_ride_request::dsl::*;
+
+    let id = uuid::Uuid::new_v4();
+    diesel::insert_into(back_ride_request)
+        .values((
+            request_id.eq(id),
+            rider_id.eq(rider),
+            pick_up.eq(serde_json::to_value(from).unwrap()),
+            drop_off.eq(serde_json::to_value(to).unwrap()),
+            estimated_price.eq(2_500i64),
+            distance_km.eq(3.9),
+            estimated_time_min.eq(12),
+            ride_type.eq(serde_json::json!("ASAP")),
+            items.eq(serde_json::json!([])),
+            payment_method.eq("card"),
+        ))
+        .execute(conn)
+        .expect("insert ride request");
+    id
+}
+
+/// The authenticator test apps sign their tokens with.
+pub fn authenticator() -> actix_web::web::Data<Authenticator> {
+    actix_web::web::Data::new(Authenticator::new(JWT_SECRET, 3600))
+}
+
+pub fn quote_signer() -> actix_web::web::Data<QuoteSigner> {
+    actix_web::web::Data::new(QuoteSigner::new(JWT_SECRET, 300))
+}
+
+/// `req` with a quote for it signed the way preflight would, and the ride
+/// request priced from that quote.
+pub fn quoted(req: CreateRideRequest) -> NewRideRequest {
+    let route = HaversineRouter.straight_line(&req.pick_up, &req.drop_off, &req.ride_type);
+    let quote = FareQuote::price(
+        req.rider_id,
+        req.ride_type.clone(),
+        req.pick_up.clone(),
+        req.drop_off.clone(),
+        &route,
+        chrono::Utc::now().timestamp(),
+        300,
+    );
+    let token = quote_signer().sign(&quote).expect("sign test quote");
+    NewRideRequest::new(CreateRideRequest { quote_token: Some(token), ..req }, &quote)
+}
+
+/// A quoted ASAP ride from `(lat, lng)` to a little north of it.
+pub fn ride_from(rider: uuid::Uuid, (lat, lng): (f64, f64)) -> NewRideRequest {
+    quoted(CreateRideRequest {
+        rider_id: rider,
+        pick_up: GeoPoint { lat, lng, name: None },
+        drop_off: GeoPoint { lat: lat + 0.02, lng, name: None },
+        ride_type: RideType::ASAP,
+        payment_method: "card".to_string(),
+        items: vec![],
+        order_id: None,
+        user_id: None,
+        user_phone_number: None,
+        vendor_phone_number: None,
+        quote_token: None,
+    })
+}
+
+pub type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// Skips whatever else arrives until a message of `kind` does.
+pub async fn next_of(socket: &mut Socket, kind: &str) -> Value {
+    let wait = async {
+        loop {
+            let text = match socket.next().await {
+                Some(Ok(Message::Text(text))) => text,
+                Some(Ok(_)) => continue,
+                ended => panic!("socket ended waiting for {}: {:?}", kind, ended),
+            };
+            let message: Value = serde_json::from_str(&text).unwrap();
+            if message["type"] == kind {
+                return message;
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), wait).await.unwrap_or_else(|_| panic!("no {} message", kind))
+}
+
+pub fn token(role: Role, subject: uuid::Uuid) -> String {
+    let now = chrono::Utc::now().timestamp();
+    Authenticator::new(JWT_SECRET, 3600)
+        .issue(Principal::new(subject, role), now)
+        .expect("issue test token")
+        .access_token
+}
+
+/// `Authorization: Bearer ..` for `subject`, to pass to `insert_header`.
+pub fn bearer(role: Role, subject: uuid::Uuid) -> (actix_web::http::header::HeaderName, String) {
+    (actix_web::http::header::AUTHORIZATION, format!("Bearer {}", token(role, subject)))
+}
+
+pub fn driver_status(conn: &mut diesel::pg::PgConnection, id: uuid::Uuid) -> String {
+    use logic::schema::back_drivers::dsl::*;
+
+    back_drivers.find(id).select(status).first(conn).expect("driver status")
+}
@@ -0,0 +1,229 @@
+use logic::api::drivers::{ release_driver, release_expired_reservations, reserve_driver };
+use logic::api::riders::RideType;
+use logic::api::trips::{ create_trip_for_request, TripError };
+use logic::services::geoindex::DriverIndex;
+use logic::services::matching::{
+    nearest_available_drivers, nearest_by_scan, nearest_from_index, DispatchSettings,
+};
+use logic::services::pricing::GeoPoint;
+use std::sync::{ Arc, Barrier };
+use std::thread;
+use uuid::Uuid;
+
+mod common;
+
+
+const LAGOS_ISLAND: (f64, f64) = (6.4531, 3.3958);
+
+fn reservation(conn: &mut diesel::pg::PgConnection, id: Uuid) -> (String, Option<Uuid>, Option<i64>) {
+    use diesel::prelude::*;
+    use logic::schema::back_drivers::dsl::*;
+
+    back_drivers
+        .find(id)
+        .select((status, reserved_for, reserved_until))
+        .first(conn)
+        .unwrap()
+}
+
+
+// ─── reserve_driver / release_driver (needs TEST_DATABASE_URL) ───────────────
+
+#[test]
+fn concurrent_dispatches_reserve_a_driver_once() {
+    let Some(pool) = common::test_pool() else { return };
+    let _serial = common::serial();
+    let driver = common::insert_driver(&mut pool.get().unwrap(), "available", LAGOS_ISLAND);
+
+    let contenders = 8;
+    let barrier = Arc::new(Barrier::new(contenders));
+    let handles: Vec<_> = (0..contenders)
+        .map(|_| {
+            let pool = pool.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                let mut conn = pool.get().unwrap();
+                let request = Uuid::new_v4();
+                barrier.wait();
+                (request, reserve_driver(&mut conn, driver, request, 1_000, common::HOLD_SECS).unwrap())
+            })
+        })
+        .collect();
+    let results: Vec<(Uuid, bool)> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+    let winners: Vec<Uuid> = results.iter().filter(|(_, won)| *won).map(|(r, _)| *r).collect();
+    assert_eq!(winners.len(), 1);
+
+    let (status, held_for, until) = reservation(&mut pool.get().unwrap(), driver);
+    assert_eq!(status, "reserved");
+    assert_eq!(held_for, Some(winners[0]));
+    assert_eq!(until, Some(1_060));
+}
+
+#[test]
+fn only_the_holding_request_can_release() {
+    let Some(pool) = common::test_pool() else { return };
+    let _serial = common::serial();
+    let mut conn = pool.get().unwrap();
+    let driver = common::insert_driver(&mut conn, "available", LAGOS_ISLAND);
+    let holder = Uuid::new_v4();
+
+    assert!(reserve_driver(&mut conn, driver, holder, 1_000, common::HOLD_SECS).unwrap());
+    assert!(!release_driver(&mut conn, driver, Uuid::new_v4()).unwrap());
+    assert_eq!(reservation(&mut conn, driver).0, "reserved");
+
+    assert!(release_driver(&mut conn, driver, holder).unwrap());
+    assert_eq!(reservation(&mut conn, driver), ("available".to_string(), None, None));
+
+    // Released drivers can be offered the next request straight away
+    assert!(reserve_driver(&mut conn, driver, Uuid::new_v4(), 1_001, common::HOLD_SECS).unwrap());
+}
+
+#[test]
+fn expired_reservation_can_be_taken_over() {
+    let Some(pool) = common::test_pool() else { return };
+    let _serial = common::serial();
+    let mut conn = pool.get().unwrap();
+    let driver = common::insert_driver(&mut conn, "available", LAGOS_ISLAND);
+
+    assert!(reserve_driver(&mut conn, driver, Uuid::new_v4(), 1_000, common::HOLD_SECS).unwrap());
+    assert!(!reserve_driver(&mut conn, driver, Uuid::new_v4(), 1_059, common::HOLD_SECS).unwrap());
+
+    let next = Uuid::new_v4();
+    assert!(reserve_driver(&mut conn, driver, next, 1_061, common::HOLD_SECS).unwrap());
+    assert_eq!(reservation(&mut conn, driver).1, Some(next));
+}
+
+#[test]
+fn sweep_hands_back_expired_reservations_only() {
+    let Some(pool) = common::test_pool() else { return };
+    let _serial = common::serial();
+    let mut conn = pool.get().unwrap();
+    let stale = common::insert_driver(&mut conn, "available", LAGOS_ISLAND);
+    let fresh = common::insert_driver(&mut conn, "available", LAGOS_ISLAND);
+
+    reserve_driver(&mut conn, stale, Uuid::new_v4(), 1_000, common::HOLD_SECS).unwrap();
+    reserve_driver(&mut conn, fresh, Uuid::new_v4(), 1_050, common::HOLD_SECS).unwrap();
+    release_expired_reservations(&mut conn, 1_061).unwrap();
+
+    assert_eq!(reservation(&mut conn, stale).0, "available");
+    assert_eq!(reservation(&mut conn, fresh).0, "reserved");
+}
+
+#[test]
+fn busy_driver_is_never_reserved() {
+    let Some(pool) = common::test_pool() else { return };
+    let _serial = common::serial();
+    let mut conn = pool.get().unwrap();
+    let driver = common::insert_driver(&mut conn, "busy", LAGOS_ISLAND);
+
+    assert!(!reserve_driver(&mut conn, driver, Uuid::new_v4(), 1_000, common::HOLD_SECS).unwrap());
+    assert_eq!(reservation(&mut conn, driver).0, "busy");
+}
+
+
+// ─── Accepting a reserved offer ──────────────────────────────────────────────
+
+#[test]
+fn reserved_driver_only_accepts_the_request_they_hold() {
+    let Some(pool) = common::test_pool() else { return };
+    let _serial = common::serial();
+    let mut conn = pool.get().unwrap();
+    let rider = common::insert_rider(&mut conn);
+    let driver = common::insert_driver(&mut conn, "available", LAGOS_ISLAND);
+    let offered = common::insert_ride_request(&mut conn, rider);
+    let other = common::insert_ride_request(&mut conn, rider);
+
+    assert!(reserve_driver(&mut conn, driver, offered, chrono::Utc::now().timestamp(), common::HOLD_SECS).unwrap());
+
+    let err = create_trip_for_request(&mut conn, other, driver).unwrap_err();
+    assert!(matches!(err, TripError::DriverUnavailable));
+
+    let trip = create_trip_for_request(&mut conn, offered, driver).unwrap();
+    assert_eq!(trip.request_id, Some(offered));
+    assert_eq!(reservation(&mut conn, driver), ("busy".to_string(), None, None));
+}
+
+
+// ─── nearest_available_drivers ───────────────────────────────────────────────
+
+#[test]
+fn nearest_driver_is_found_past_the_first_page() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+
+    // Abuja, away from the Lagos drivers other tests insert
+    let pickup = GeoPoint { lat: 9.0765, lng: 7.3986, name: None };
+    let mut farther = Vec::new();
+    for i in 0..15 {
+        farther.push(common::insert_driver(&mut conn, "available", (9.0765 + 0.02 + i as f64 * 0.001, 7.3986)));
+    }
+    let nearest = common::insert_driver(&mut conn, "available", (9.0770, 7.3986));
+    common::insert_driver(&mut conn, "busy", (9.0766, 7.3986));
+    common::insert_driver(&mut conn, "available", (9.2000, 7.3986)); // ~14 km out
+
+    let settings = DispatchSettings { radius_km: 5.0, max_candidates: 10 };
+    let candidates = nearest_available_drivers(&mut conn, &pickup, &RideType::ASAP, &settings).unwrap();
+
+    assert_eq!(candidates.len(), 10);
+    assert_eq!(candidates[0].driver.driver_id, nearest);
+    assert!(candidates.windows(2).all(|w| w[0].distance_km <= w[1].distance_km));
+    assert!(candidates[1..].iter().all(|c| farther.contains(&c.driver.driver_id)));
+}
+
+#[test]
+fn bike_requests_only_see_bikes() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+
+    // Kano
+    let pickup = GeoPoint { lat: 12.0022, lng: 8.5920, name: None };
+    common::insert_driver(&mut conn, "available", (12.0023, 8.5920));
+    let bike = common::insert_driver_with_vehicle(&mut conn, "available", (12.0030, 8.5920), "Bike");
+
+    let settings = DispatchSettings { radius_km: 5.0, max_candidates: 10 };
+    let candidates = nearest_available_drivers(&mut conn, &pickup, &RideType::ASAPEXPRESS, &settings).unwrap();
+    let ids: Vec<Uuid> = candidates.iter().map(|c| c.driver.driver_id).collect();
+    assert_eq!(ids, vec![bike]);
+}
+
+#[test]
+fn index_lookup_agrees_with_table_scan() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+
+    // Port Harcourt
+    let pickup = GeoPoint { lat: 4.8156, lng: 7.0498, name: None };
+    for i in 0..12 {
+        common::insert_driver(&mut conn, "available", (4.8156 + i as f64 * 0.003, 7.0498 - i as f64 * 0.002));
+    }
+    let index = DriverIndex::load(&mut conn, 1.0).unwrap();
+
+    let settings = DispatchSettings { radius_km: 5.0, max_candidates: 5 };
+    let ids = |candidates: Vec<logic::services::matching::Candidate>| -> Vec<Uuid> {
+        candidates.into_iter().map(|c| c.driver.driver_id).collect()
+    };
+    let scanned = ids(nearest_by_scan(&mut conn, &pickup, &RideType::ASAP, &settings).unwrap());
+    let indexed = ids(nearest_from_index(&mut conn, &index, &pickup, &RideType::ASAP, &settings).unwrap());
+    assert_eq!(scanned.len(), 5);
+    assert_eq!(indexed, scanned);
+}
+
+#[test]
+fn stale_index_status_is_corrected_by_the_table() {
+    let Some(pool) = common::test_pool() else { return };
+    let _serial = common::serial();
+    let mut conn = pool.get().unwrap();
+
+    // Ibadan
+    let pickup = GeoPoint { lat: 7.3775, lng: 3.9470, name: None };
+    let driver = common::insert_driver(&mut conn, "available", (7.3780, 3.9470));
+    let index = DriverIndex::load(&mut conn, 1.0).unwrap();
+
+    // Reserved after the index last heard about it
+    reserve_driver(&mut conn, driver, Uuid::new_v4(), chrono::Utc::now().timestamp(), common::HOLD_SECS).unwrap();
+
+    let settings = DispatchSettings { radius_km: 5.0, max_candidates: 5 };
+    let candidates = nearest_from_index(&mut conn, &index, &pickup, &RideType::ASAP, &settings).unwrap();
+    assert!(candidates.is_empty());
+}
@@ -0,0 +1,80 @@
+-- Tables the backend reads and writes, as they exist in the hosted database
+-- (see logic/src/schema.rs). Migrations added after these are applied on top
+-- by tests/common/mod.rs.
+CREATE TYPE custom_roles AS ENUM ('rider', 'driver', 'admin');
+
+CREATE TABLE custom_users (
+    id UUID PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    username TEXT NOT NULL,
+    custom_role custom_roles NOT NULL,
+    phone BIGINT
+);
+
+CREATE TABLE riders_current_status (
+    id UUID PRIMARY KEY,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    email VARCHAR NOT NULL,
+    longitude NUMERIC NOT NULL,
+    latitude NUMERIC NOT NULL,
+    active_mode custom_roles NOT NULL
+);
+
+CREATE TABLE back_custom_users (
+    rider_id UUID PRIMARY KEY,
+    rider_pubkey JSONB NOT NULL,
+    name TEXT NOT NULL,
+    email TEXT UNIQUE NOT NULL,
+    phone TEXT NOT NULL
+);
+
+CREATE TABLE back_drivers (
+    driver_id UUID PRIMARY KEY,
+    driver_pubkey JSONB NOT NULL,
+    name TEXT NOT NULL,
+    email TEXT UNIQUE NOT NULL,
+    phone TEXT NOT NULL,
+    status TEXT NOT NULL,
+    driver_location JSONB NOT NULL,
+    license_number TEXT,
+    vehicle_type TEXT NOT NULL,
+    driver_response JSONB NOT NULL,
+    vehicle TEXT
+);
+
+CREATE TABLE back_ride_request (
+    request_id UUID PRIMARY KEY,
+    rider_id UUID NOT NULL,
+    pick_up JSONB NOT NULL,
+    drop_off JSONB NOT NULL,
+    estimated_price BIGINT NOT NULL,
+    distance_km DOUBLE PRECISION NOT NULL,
+    estimated_time_min INT NOT NULL,
+    ride_type JSONB NOT NULL,
+    items JSONB NOT NULL,
+    payment_method TEXT NOT NULL,
+    order_id UUID,
+    user_id BIGINT,
+    user_phone_number TEXT,
+    vendor_phone_number TEXT
+);
+
+CREATE TABLE back_trips (
+    trip_id BYTEA PRIMARY KEY,
+    rider_id UUID NOT NULL,
+    reference TEXT NOT NULL,
+    pick_up TEXT NOT NULL,
+    drop_off TEXT NOT NULL,
+    driver_location TEXT NOT NULL,
+    rider_pubkey TEXT NOT NULL,
+    driver_pubkey TEXT NOT NULL,
+    driver_id UUID NOT NULL,
+    status TEXT NOT NULL,
+    start_ts BIGINT NOT NULL,
+    end_ts BIGINT,
+    distance_km DOUBLE PRECISION NOT NULL,
+    item JSONB NOT NULL,
+    fare_estimate BIGINT,
+    fare_lamports BIGINT,
+    rider_email TEXT NOT NULL DEFAULT ''
+);
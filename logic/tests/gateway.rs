@@ -0,0 +1,123 @@
+use actix_web::{ web, App, HttpServer };
+use actix_web::middleware::from_fn;
+use futures_util::SinkExt;
+use logic::api::auth::{ authenticate, Role };
+use logic::api::riders::run_assign_driver;
+use serde_json::{ json, Value };
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
+use uuid::Uuid;
+
+mod common;
+
+
+const KADUNA: (f64, f64) = (10.5105, 7.4165);
+
+// The real server on a free port, sockets can't go through init_service.
+fn serve(pool: logic::db::DbPool) -> SocketAddr {
+    let server = HttpServer::new(move || {
+        App::new()
+            .wrap(from_fn(authenticate))
+            .app_data(common::authenticator())
+            .app_data(web::Data::new(pool.clone()))
+            .configure(logic::api::init)
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+
+    let addr = server.addrs()[0];
+    actix_web::rt::spawn(server.run());
+    addr
+}
+
+async fn open(addr: SocketAddr, path: &str) -> common::Socket {
+    let (socket, _) = connect_async(format!("ws://{}{}", addr, path)).await.expect("socket opens");
+    socket
+}
+
+async fn send(socket: &mut common::Socket, message: Value) {
+    socket.send(Message::Text(message.to_string())).await.unwrap();
+}
+
+
+// ─── /ws (needs TEST_DATABASE_URL) ───────────────────────────────────────────
+
+#[actix_web::test]
+async fn ride_offer_to_trip_over_sockets() {
+    let Some(pool) = common::test_pool() else { return };
+    let (rider, driver) = {
+        let mut conn = pool.get().unwrap();
+        let rider = common::insert_rider(&mut conn);
+        (rider, common::insert_driver(&mut conn, "available", (KADUNA.0 + 0.002, KADUNA.1)))
+    };
+    let addr = serve(pool.clone());
+
+    let mut driver_ws = open(addr, &format!("/ws/driver?driver_id={}&access_token={}", driver, common::token(Role::Driver, driver))).await;
+    let welcome = common::next_of(&mut driver_ws, "welcome").await;
+    assert_eq!(welcome["resumed"], false);
+    let session_id = welcome["session_id"].as_str().unwrap().to_string();
+
+    let mut rider_ws = open(addr, &format!("/ws/user?rider_id={}&access_token={}", rider, common::token(Role::Rider, rider))).await;
+    common::next_of(&mut rider_ws, "welcome").await;
+
+    let ride = common::ride_from(rider, KADUNA);
+    let dispatch = actix_web::rt::spawn(run_assign_driver(web::Data::new(pool.clone()), ride.clone()));
+
+    let offer = common::next_of(&mut driver_ws, "ride_offer").await;
+    assert_eq!(offer["ride"]["request_id"], json!(ride.request_id));
+    send(&mut driver_ws, json!({ "type": "accept", "request_id": ride.request_id })).await;
+
+    assert_eq!(dispatch.await.unwrap().status(), 200);
+    let assigned = common::next_of(&mut rider_ws, "trip_status").await;
+    assert_eq!(assigned["status"], "driver_assigned");
+    let trip_reference = assigned["trip_reference"].clone();
+    let driver_status = common::next_of(&mut driver_ws, "trip_status").await;
+    assert_eq!(driver_status["trip_reference"], trip_reference);
+
+    // Driver location goes to the rider of the ongoing trip
+    send(&mut driver_ws, json!({ "type": "location", "location": { "lat": KADUNA.0 + 0.001, "lng": KADUNA.1, "name": null } })).await;
+    let moved = common::next_of(&mut rider_ws, "driver_location").await;
+    assert_eq!(moved["trip_reference"], trip_reference);
+    assert_eq!(moved["location"]["lat"], json!(KADUNA.0 + 0.001));
+
+    // Riders can't answer offers
+    send(&mut rider_ws, json!({ "type": "accept", "request_id": ride.request_id })).await;
+    assert_eq!(common::next_of(&mut rider_ws, "error").await["seq"], 0);
+
+    // Chat sent while the driver's app is away arrives when it resumes
+    let last_seq = driver_status["seq"].as_u64().unwrap();
+    driver_ws.close(None).await.unwrap();
+    send(&mut rider_ws, json!({ "type": "chat", "trip_reference": trip_reference, "text": "At the gate" })).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut resumed_ws = open(addr, &format!(
+        "/ws/driver?driver_id={}&session_id={}&last_seq={}&access_token={}",
+        driver, session_id, last_seq, common::token(Role::Driver, driver),
+    )).await;
+    let welcome = common::next_of(&mut resumed_ws, "welcome").await;
+    assert_eq!(welcome["resumed"], true);
+    assert_eq!(welcome["session_id"], json!(session_id));
+    let chat = common::next_of(&mut resumed_ws, "chat").await;
+    assert_eq!(chat["seq"], last_seq + 1);
+    assert_eq!(chat["from"], "rider");
+    assert_eq!(chat["text"], "At the gate");
+}
+
+#[actix_web::test]
+async fn unknown_accounts_cannot_open_a_socket() {
+    let Some(pool) = common::test_pool() else { return };
+    let addr = serve(pool);
+
+    let unknown = Uuid::new_v4();
+    let refused = connect_async(format!(
+        "ws://{}/ws/driver?driver_id={}&access_token={}",
+        addr, unknown, common::token(Role::Driver, unknown),
+    )).await;
+    assert!(refused.is_err());
+
+    let anonymous = connect_async(format!("ws://{}/ws/driver?driver_id={}", addr, Uuid::new_v4())).await;
+    assert!(anonymous.is_err());
+}
@@ -0,0 +1,398 @@
+use anchor_client::anchor_lang::AccountDeserialize;
+use diesel::prelude::*;
+use litesvm::LiteSVM;
+use logic::api::trips::get_trip_by_reference;
+use logic::services::escrow::build_record_ride_instruction;
+use logic::services::outbox::{
+    backoff_secs, enqueue_record_ride, run_due_jobs, submit_error, JobStatus, OnchainJob, RideRecorder,
+    SubmitError, MAX_ATTEMPTS,
+};
+use ride_program::Ride;
+use solana_instruction_v3::error::InstructionError as SvmInstructionError;
+use solana_sdk::{
+    instruction::{ Instruction, InstructionError },
+    pubkey::Pubkey,
+    signature::{ Keypair, Signature, Signer },
+    transaction::TransactionError,
+};
+use solana_signer_v3::Signer as _;
+use solana_transaction_error_v3::TransactionError as SvmTransactionError;
+use std::collections::HashSet;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+mod common;
+
+
+// Stands in for the validator: keeps the ride PDAs it has seen and answers a
+// second record_ride for the same trip with AlreadyRecorded, like the program.
+struct LocalLedger {
+    program_id: Pubkey,
+    authority: Keypair,
+    recorded: Mutex<HashSet<Pubkey>>,
+    outages_left: Mutex<u32>,
+    submissions: Mutex<u32>,
+}
+
+impl LocalLedger {
+    fn new() -> Self {
+        Self::with_outages(0)
+    }
+
+    fn with_outages(outages: u32) -> Self {
+        Self {
+            program_id: Pubkey::new_unique(),
+            authority: Keypair::new(),
+            recorded: Mutex::new(HashSet::new()),
+            outages_left: Mutex::new(outages),
+            submissions: Mutex::new(0),
+        }
+    }
+
+    fn submissions(&self) -> u32 {
+        *self.submissions.lock().unwrap()
+    }
+}
+
+impl RideRecorder for LocalLedger {
+    fn program_id(&self) -> Pubkey {
+        self.program_id
+    }
+
+    fn authority(&self) -> Pubkey {
+        self.authority.pubkey()
+    }
+
+    fn submit(&self, instruction: Instruction) -> Result<String, SubmitError> {
+        *self.submissions.lock().unwrap() += 1;
+
+        let mut outages = self.outages_left.lock().unwrap();
+        if *outages > 0 {
+            *outages -= 1;
+            return Err(SubmitError::Transient("RPC error: connection refused".into()));
+        }
+
+        assert_eq!(instruction.program_id, self.program_id);
+        let ride_pda = instruction.accounts[0].pubkey;
+        if !self.recorded.lock().unwrap().insert(ride_pda) {
+            return Err(SubmitError::AlreadyRecorded);
+        }
+        Ok(Signature::new_unique().to_string())
+    }
+}
+
+// The ride program itself, run in litesvm. Needs the program built first
+// (`anchor build`), from RIDE_PROGRAM_SO or target/deploy/ride_program.so.
+struct SvmLedger {
+    svm: Mutex<LiteSVM>,
+    authority: solana_keypair_v3::Keypair,
+}
+
+impl SvmLedger {
+    fn load() -> Option<Self> {
+        let path = std::env::var("RIDE_PROGRAM_SO").unwrap_or_else(|_| {
+            concat!(env!("CARGO_MANIFEST_DIR"), "/../target/deploy/ride_program.so").to_string()
+        });
+        if !std::path::Path::new(&path).exists() {
+            eprintln!("{} not built, skipping litesvm test", path);
+            return None;
+        }
+
+        let mut svm = LiteSVM::new();
+        svm.add_program_from_file(ride_program::ID.to_bytes(), &path).expect("load ride program");
+        let authority = solana_keypair_v3::Keypair::new();
+        svm.airdrop(&authority.pubkey(), 10_000_000_000).expect("fund authority");
+        Some(Self { svm: Mutex::new(svm), authority })
+    }
+
+    fn ride(&self, ride_pda: Pubkey) -> Option<Ride> {
+        let account = self.svm.lock().unwrap().get_account(&ride_pda.to_bytes().into())?;
+        Some(Ride::try_deserialize(&mut account.data.as_slice()).expect("ride account"))
+    }
+}
+
+impl RideRecorder for SvmLedger {
+    fn program_id(&self) -> Pubkey {
+        ride_program::ID
+    }
+
+    fn authority(&self) -> Pubkey {
+        Pubkey::new_from_array(self.authority.pubkey().to_bytes())
+    }
+
+    fn submit(&self, instruction: Instruction) -> Result<String, SubmitError> {
+        let instruction = solana_instruction_v3::Instruction {
+            program_id: instruction.program_id.to_bytes().into(),
+            accounts: instruction.accounts.iter()
+                .map(|meta| solana_instruction_v3::AccountMeta {
+                    pubkey: meta.pubkey.to_bytes().into(),
+                    is_signer: meta.is_signer,
+                    is_writable: meta.is_writable,
+                })
+                .collect(),
+            data: instruction.data,
+        };
+
+        let mut svm = self.svm.lock().unwrap();
+        let tx = solana_transaction_v3::Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&self.authority.pubkey()),
+            &[&self.authority],
+            svm.latest_blockhash(),
+        );
+        // A resend of the same instruction must not look like a replay
+        let result = svm.send_transaction(tx);
+        svm.expire_blockhash();
+
+        match result {
+            Ok(meta) => Ok(meta.signature.to_string()),
+            // Only program errors are classified, the rest is the harness misbehaving
+            Err(failed) => match failed.err {
+                SvmTransactionError::InstructionError(index, SvmInstructionError::Custom(code)) => {
+                    Err(submit_error(TransactionError::InstructionError(index, InstructionError::Custom(code))))
+                }
+                other => Err(SubmitError::Rejected(format!("Transaction failed: {}", other))),
+            },
+        }
+    }
+}
+
+fn job(conn: &mut PgConnection, job_uuid: Uuid) -> OnchainJob {
+    use logic::schema::back_onchain_jobs::dsl::*;
+
+    back_onchain_jobs.find(job_uuid).select(OnchainJob::as_select()).first(conn).unwrap()
+}
+
+fn clear_jobs(conn: &mut PgConnection) {
+    use logic::schema::back_onchain_jobs::dsl::*;
+
+    diesel::delete(back_onchain_jobs).execute(conn).unwrap();
+}
+
+fn valid_pubkey() -> String {
+    Keypair::new().pubkey().to_string()
+}
+
+
+// ─── backoff_secs ────────────────────────────────────────────────────────────
+
+#[test]
+fn backoff_doubles_from_five_seconds() {
+    assert_eq!(backoff_secs(1), 5);
+    assert_eq!(backoff_secs(2), 10);
+    assert_eq!(backoff_secs(3), 20);
+    assert_eq!(backoff_secs(4), 40);
+}
+
+#[test]
+fn backoff_is_capped_at_ten_minutes() {
+    assert_eq!(backoff_secs(9), 600);
+    assert_eq!(backoff_secs(i32::MAX), 600);
+}
+
+#[test]
+fn backoff_treats_zero_attempts_as_first() {
+    assert_eq!(backoff_secs(0), 5);
+}
+
+
+// ─── Worker (needs TEST_DATABASE_URL) ────────────────────────────────────────
+
+#[test]
+fn enqueueing_the_same_trip_twice_keeps_one_job() {
+    let Some(pool) = common::test_pool() else { return };
+    let _serial = common::serial();
+    let mut conn = pool.get().unwrap();
+
+    let first = enqueue_record_ride(&mut conn, "outbox-dedupe", 1_000).unwrap();
+    let second = enqueue_record_ride(&mut conn, "outbox-dedupe", 2_000).unwrap();
+    assert_eq!(first.job_id, second.job_id);
+    assert_eq!(second.next_attempt_at, 1_000);
+}
+
+#[test]
+fn confirmed_job_marks_trip_with_signature() {
+    let Some(pool) = common::test_pool() else { return };
+    let _serial = common::serial();
+    let mut conn = pool.get().unwrap();
+    clear_jobs(&mut conn);
+
+    common::insert_trip(&mut conn, "outbox-happy", &valid_pubkey(), &valid_pubkey());
+    let queued = enqueue_record_ride(&mut conn, "outbox-happy", 1_000).unwrap();
+
+    let ledger = LocalLedger::new();
+    assert_eq!(run_due_jobs(&mut conn, &ledger, 1_000).unwrap(), 1);
+
+    let done = job(&mut conn, queued.job_id);
+    assert_eq!(done.status, JobStatus::Confirmed.as_str());
+    assert_eq!(done.attempts, 1);
+    let trip = get_trip_by_reference(&mut conn, "outbox-happy").unwrap();
+    assert!(trip.onchain_signature.is_some());
+    assert_eq!(trip.onchain_signature, done.signature);
+}
+
+#[test]
+fn rpc_outage_is_retried_with_backoff() {
+    let Some(pool) = common::test_pool() else { return };
+    let _serial = common::serial();
+    let mut conn = pool.get().unwrap();
+    clear_jobs(&mut conn);
+
+    common::insert_trip(&mut conn, "outbox-outage", &valid_pubkey(), &valid_pubkey());
+    let queued = enqueue_record_ride(&mut conn, "outbox-outage", 1_000).unwrap();
+    let ledger = LocalLedger::with_outages(2);
+
+    run_due_jobs(&mut conn, &ledger, 1_000).unwrap();
+    let after_first = job(&mut conn, queued.job_id);
+    assert_eq!(after_first.status, JobStatus::Pending.as_str());
+    assert_eq!(after_first.next_attempt_at, 1_005);
+    assert!(after_first.last_error.is_some());
+
+    // Not due yet
+    assert_eq!(run_due_jobs(&mut conn, &ledger, 1_004).unwrap(), 0);
+
+    run_due_jobs(&mut conn, &ledger, 1_005).unwrap();
+    assert_eq!(job(&mut conn, queued.job_id).next_attempt_at, 1_015);
+
+    run_due_jobs(&mut conn, &ledger, 1_015).unwrap();
+    let done = job(&mut conn, queued.job_id);
+    assert_eq!(done.status, JobStatus::Confirmed.as_str());
+    assert_eq!(done.attempts, 3);
+    assert_eq!(ledger.submissions(), 3);
+}
+
+#[test]
+fn ride_already_on_chain_confirms_without_new_signature() {
+    let Some(pool) = common::test_pool() else { return };
+    let _serial = common::serial();
+    let mut conn = pool.get().unwrap();
+    clear_jobs(&mut conn);
+
+    common::insert_trip(&mut conn, "outbox-landed", &valid_pubkey(), &valid_pubkey());
+    let queued = enqueue_record_ride(&mut conn, "outbox-landed", 1_000).unwrap();
+
+    // An earlier send landed but its confirmation never reached us
+    let ledger = LocalLedger::new();
+    let trip = get_trip_by_reference(&mut conn, "outbox-landed").unwrap();
+    let ix = build_record_ride_instruction(&trip, ledger.program_id(), ledger.authority()).unwrap();
+    ledger.submit(ix).unwrap();
+
+    run_due_jobs(&mut conn, &ledger, 1_000).unwrap();
+    let done = job(&mut conn, queued.job_id);
+    assert_eq!(done.status, JobStatus::Confirmed.as_str());
+    assert_eq!(done.signature, None);
+}
+
+#[test]
+fn invalid_trip_data_fails_without_submitting() {
+    let Some(pool) = common::test_pool() else { return };
+    let _serial = common::serial();
+    let mut conn = pool.get().unwrap();
+    clear_jobs(&mut conn);
+
+    common::insert_trip(&mut conn, "outbox-bad-key", "not-a-pubkey", &valid_pubkey());
+    let queued = enqueue_record_ride(&mut conn, "outbox-bad-key", 1_000).unwrap();
+
+    let ledger = LocalLedger::new();
+    run_due_jobs(&mut conn, &ledger, 1_000).unwrap();
+
+    let done = job(&mut conn, queued.job_id);
+    assert_eq!(done.status, JobStatus::Failed.as_str());
+    assert_eq!(done.last_error.as_deref(), Some("Invalid rider pubkey"));
+    assert_eq!(ledger.submissions(), 0);
+}
+
+#[test]
+fn job_gives_up_after_max_attempts() {
+    let Some(pool) = common::test_pool() else { return };
+    let _serial = common::serial();
+    let mut conn = pool.get().unwrap();
+    clear_jobs(&mut conn);
+
+    common::insert_trip(&mut conn, "outbox-down", &valid_pubkey(), &valid_pubkey());
+    let queued = enqueue_record_ride(&mut conn, "outbox-down", 0).unwrap();
+
+    let ledger = LocalLedger::with_outages(u32::MAX);
+    let mut now = 0;
+    for _ in 0..MAX_ATTEMPTS {
+        run_due_jobs(&mut conn, &ledger, now).unwrap();
+        now += 3_600;
+    }
+
+    let done = job(&mut conn, queued.job_id);
+    assert_eq!(done.status, JobStatus::Failed.as_str());
+    assert_eq!(done.attempts, MAX_ATTEMPTS);
+    assert_eq!(run_due_jobs(&mut conn, &ledger, now).unwrap(), 0);
+}
+
+#[test]
+fn abandoned_submission_is_picked_up_again() {
+    let Some(pool) = common::test_pool() else { return };
+    let _serial = common::serial();
+    let mut conn = pool.get().unwrap();
+    clear_jobs(&mut conn);
+
+    common::insert_trip(&mut conn, "outbox-crashed", &valid_pubkey(), &valid_pubkey());
+    let queued = enqueue_record_ride(&mut conn, "outbox-crashed", 1_000).unwrap();
+
+    // A worker claimed it and died before reporting back
+    logic::services::outbox::claim_due_jobs(&mut conn, 1_000, 10).unwrap();
+    let ledger = LocalLedger::new();
+    assert_eq!(run_due_jobs(&mut conn, &ledger, 1_060).unwrap(), 0);
+
+    assert_eq!(run_due_jobs(&mut conn, &ledger, 1_121).unwrap(), 1);
+    let done = job(&mut conn, queued.job_id);
+    assert_eq!(done.status, JobStatus::Confirmed.as_str());
+    assert_eq!(done.attempts, 2);
+}
+
+
+// ─── ride_program in litesvm (needs TEST_DATABASE_URL and the built program) ─
+
+#[test]
+fn record_ride_lands_on_chain_once() {
+    let Some(pool) = common::test_pool() else { return };
+    let Some(ledger) = SvmLedger::load() else { return };
+    let _serial = common::serial();
+    let mut conn = pool.get().unwrap();
+    clear_jobs(&mut conn);
+
+    let rider = Keypair::new().pubkey();
+    common::insert_trip(&mut conn, "outbox-svm", &rider.to_string(), &valid_pubkey());
+    let queued = enqueue_record_ride(&mut conn, "outbox-svm", 1_000).unwrap();
+
+    assert_eq!(run_due_jobs(&mut conn, &ledger, 1_000).unwrap(), 1);
+    let done = job(&mut conn, queued.job_id);
+    assert_eq!(done.status, JobStatus::Confirmed.as_str());
+    assert!(done.signature.is_some());
+
+    let trip = get_trip_by_reference(&mut conn, "outbox-svm").unwrap();
+    let ix = build_record_ride_instruction(&trip, ledger.program_id(), ledger.authority()).unwrap();
+    let ride = ledger.ride(ix.accounts[0].pubkey).expect("ride PDA created");
+    assert!(ride.is_initialized);
+    assert_eq!(ride.passenger, rider);
+    assert_eq!(ride.end_ts, 1_700_001_800);
+
+    // The program refuses to record the same trip again
+    assert!(matches!(ledger.submit(ix), Err(SubmitError::AlreadyRecorded)));
+}
+
+#[test]
+fn ride_recorded_before_the_worker_ran_confirms_on_chain() {
+    let Some(pool) = common::test_pool() else { return };
+    let Some(ledger) = SvmLedger::load() else { return };
+    let _serial = common::serial();
+    let mut conn = pool.get().unwrap();
+    clear_jobs(&mut conn);
+
+    common::insert_trip(&mut conn, "outbox-svm-landed", &valid_pubkey(), &valid_pubkey());
+    let queued = enqueue_record_ride(&mut conn, "outbox-svm-landed", 1_000).unwrap();
+    let trip = get_trip_by_reference(&mut conn, "outbox-svm-landed").unwrap();
+    let ix = build_record_ride_instruction(&trip, ledger.program_id(), ledger.authority()).unwrap();
+    ledger.submit(ix).unwrap();
+
+    run_due_jobs(&mut conn, &ledger, 1_000).unwrap();
+    let done = job(&mut conn, queued.job_id);
+    assert_eq!(done.status, JobStatus::Confirmed.as_str());
+    assert_eq!(done.signature, None);
+}
@@ -0,0 +1,98 @@
+use actix_web::{ web, App };
+use actix_web::middleware::from_fn;
+use actix_web::test::{ call_service, init_service, read_body_json, TestRequest };
+use diesel::prelude::*;
+use logic::api::auth::{ authenticate, Role };
+use logic::api::trips::create_trip_for_request;
+use logic::services::ratelimit::{ rate_limiter, Limit };
+use uuid::Uuid;
+
+mod common;
+
+const BENIN: (f64, f64) = (6.3350, 5.6037);
+
+
+fn trip_to(rider: Uuid, drop_lat: f64, quote_token: Option<&str>) -> serde_json::Value {
+    serde_json::json!({
+        "rider_id": rider,
+        "pick_up": { "lat": BENIN.0, "lng": BENIN.1, "name": null },
+        "drop_off": { "lat": drop_lat, "lng": BENIN.1, "name": null },
+        "ride_type": "ASAP",
+        "payment_method": "card",
+        "items": [],
+        "quote_token": quote_token,
+    })
+}
+
+
+// ─── Preflight quotes (needs TEST_DATABASE_URL) ──────────────────────────────
+
+#[actix_web::test]
+async fn rides_are_requested_at_the_price_preflight_quoted() {
+    let Some(pool) = common::test_pool() else { return };
+    let rider = common::insert_rider(&mut pool.get().unwrap());
+    // More tries than a rider gets in a burst
+    rate_limiter().set_limit("ride-request", Limit { per_minute: 60, burst: 10 });
+    let app = init_service(
+        App::new()
+            .wrap(from_fn(authenticate))
+            .app_data(common::authenticator())
+            .app_data(common::quote_signer())
+            .app_data(web::Data::new(pool.clone()))
+            .configure(logic::api::init)
+            .configure(logic::services::init),
+    ).await;
+    let as_rider = |req: TestRequest| req.insert_header(common::bearer(Role::Rider, rider)).to_request();
+    let drop_lat = BENIN.0 + 0.03;
+
+    let preflight = TestRequest::post().uri("/drivers/ride-preflight").set_json(serde_json::json!({
+        "rider_id": rider,
+        "pick_up": { "lat": BENIN.0, "lng": BENIN.1, "name": null },
+        "drop_off": { "lat": drop_lat, "lng": BENIN.1, "name": null },
+        "ride_type": "ASAP",
+    }));
+    let response = call_service(&app, as_rider(preflight)).await;
+    assert_eq!(response.status(), 200);
+    let quoted: serde_json::Value = read_body_json(response).await;
+    let token = quoted["quote_token"].as_str().expect("preflight quotes").to_string();
+    assert!(quoted["quote_expires_at"].as_i64().unwrap() <= chrono::Utc::now().timestamp() + 300);
+
+    let request = |body: serde_json::Value| as_rider(TestRequest::post().uri("/riders/ride-request").set_json(body));
+
+    // No quote, someone else's route, or a token that's been tampered with
+    assert_eq!(call_service(&app, request(trip_to(rider, drop_lat, None))).await.status(), 400);
+    assert_eq!(call_service(&app, request(trip_to(rider, drop_lat + 0.05, Some(&token)))).await.status(), 422);
+    let tampered = format!("{}x", token);
+    assert_eq!(call_service(&app, request(trip_to(rider, drop_lat, Some(&tampered)))).await.status(), 422);
+
+    let response = call_service(&app, request(trip_to(rider, drop_lat, Some(&token)))).await;
+    assert_eq!(response.status(), 202);
+    let accepted: serde_json::Value = read_body_json(response).await;
+    assert_eq!(accepted["estimated_price"], quoted["estimated_price"]);
+    let request_id: Uuid = serde_json::from_value(accepted["request_id"].clone()).unwrap();
+
+    // One quote, one ride
+    assert_eq!(call_service(&app, request(trip_to(rider, drop_lat, Some(&token)))).await.status(), 409);
+
+    // Its points are the quoted ones until the rider asks for another quote
+    let move_drop_off = |id: Uuid| as_rider(
+        TestRequest::post()
+            .uri(&format!("/matching/process-geolocation/{}", id))
+            .set_json(serde_json::json!({ "lat": drop_lat + 0.2, "lng": BENIN.1, "name": "", "kind": "drop_off" })),
+    );
+    assert_eq!(call_service(&app, move_drop_off(request_id)).await.status(), 409);
+    let unquoted = common::insert_ride_request(&mut pool.get().unwrap(), rider);
+    assert_eq!(call_service(&app, move_drop_off(unquoted)).await.status(), 200);
+
+    let mut conn = pool.get().unwrap();
+    let stored_token: Option<String> = {
+        use logic::schema::back_ride_request::dsl::{ back_ride_request, quote_token };
+        back_ride_request.find(request_id).select(quote_token).first(&mut conn).unwrap()
+    };
+    assert_eq!(stored_token.as_deref(), Some(token.as_str()));
+
+    // Out of dispatch's reach, so only this test assigns it
+    let driver = common::insert_driver(&mut conn, "available", (BENIN.0 + 1.0, BENIN.1));
+    let trip = create_trip_for_request(&mut conn, request_id, driver).unwrap();
+    assert_eq!(trip.fare_estimate, quoted["estimated_price"].as_i64());
+}
@@ -0,0 +1,61 @@
+use actix_web::{ web, App, HttpResponse };
+use actix_web::http::header;
+use actix_web::middleware::from_fn;
+use actix_web::test::{ call_service, init_service, read_body_json, TestRequest };
+use logic::api::{ admin, auth::{ authenticate, Role } };
+use logic::services::ratelimit::{ rate_limit, rate_limiter, Limit, ScopeStats };
+use uuid::Uuid;
+
+mod common;
+
+
+// ─── rate_limit middleware ───────────────────────────────────────────────────
+
+#[actix_web::test]
+async fn callers_over_the_limit_are_told_when_to_come_back() {
+    rate_limiter().set_limit("limited", Limit { per_minute: 2, burst: 2 });
+    let app = init_service(
+        App::new()
+            .wrap(from_fn(authenticate))
+            .app_data(common::authenticator())
+            .service(
+                web::scope("/limited")
+                    .route("", web::get().to(HttpResponse::Ok))
+                    .wrap(from_fn(|req, next| rate_limit("limited", req, next))),
+            )
+            .service(
+                web::scope("/admin")
+                    .route("/rate-limits", web::get().to(admin::rate_limits_handler)),
+            ),
+    ).await;
+
+    let rider = Uuid::new_v4();
+    let as_rider = || TestRequest::get().uri("/limited").insert_header(common::bearer(Role::Rider, rider)).to_request();
+    assert_eq!(call_service(&app, as_rider()).await.status(), 200);
+    assert_eq!(call_service(&app, as_rider()).await.status(), 200);
+
+    let limited = call_service(&app, as_rider()).await;
+    assert_eq!(limited.status(), 429);
+    // Two a minute, so the next token is 30 seconds away
+    let retry_after: u64 = limited.headers().get(header::RETRY_AFTER).unwrap().to_str().unwrap().parse().unwrap();
+    assert!((29..=30).contains(&retry_after), "Retry-After was {}", retry_after);
+
+    // Someone else still has their own bucket
+    let other = TestRequest::get().uri("/limited").insert_header(common::bearer(Role::Rider, Uuid::new_v4())).to_request();
+    assert_eq!(call_service(&app, other).await.status(), 200);
+
+    // Without a token the caller's address is the key
+    let anonymous = TestRequest::get().uri("/limited").peer_addr("10.0.0.7:5000".parse().unwrap());
+    assert_eq!(call_service(&app, anonymous.to_request()).await.status(), 200);
+
+    let rider_report = TestRequest::get().uri("/admin/rate-limits").insert_header(common::bearer(Role::Rider, rider)).to_request();
+    assert_eq!(call_service(&app, rider_report).await.status(), 403);
+
+    let report = TestRequest::get()
+        .uri("/admin/rate-limits")
+        .insert_header(common::bearer(Role::Admin, Uuid::new_v4()))
+        .to_request();
+    let stats: Vec<ScopeStats> = read_body_json(call_service(&app, report).await).await;
+    let scope = stats.iter().find(|s| s.scope == "limited").unwrap();
+    assert_eq!((scope.allowed, scope.limited, scope.tracked_keys), (4, 1, 3));
+}
@@ -0,0 +1,396 @@
+use actix_web::{ web, App };
+use actix_web::middleware::from_fn;
+use actix_web::test::{ call_service, init_service, read_body_json, TestRequest };
+use chrono::Utc;
+use diesel::prelude::*;
+use logic::api::auth::{ authenticate, Role };
+use logic::api::drivers::reserve_driver;
+use logic::api::riders::{
+    self, dispatch_status_of, run_assign_driver, DispatchStatus, NewRideRequest,
+};
+use logic::api::trips::{ CancelReason, TripStatus };
+use logic::db::DbPool;
+use logic::services::gateway::{ self, Connection, Envelope, Peer, ServerMessage };
+use logic::services::offers::{
+    offer_history, record_offer, recover_orphaned_requests, settle_offer, OfferOutcome, RideOffer,
+};
+use uuid::Uuid;
+
+mod common;
+
+
+// Each test dispatches in its own city so their drivers never meet
+const ENUGU: (f64, f64) = (6.4584, 7.5464);
+const JOS: (f64, f64) = (9.8965, 8.8583);
+const OWERRI: (f64, f64) = (5.4840, 7.0351);
+const CALABAR: (f64, f64) = (4.9757, 8.3417);
+const ABEOKUTA: (f64, f64) = (7.1475, 3.3619);
+const ILORIN: (f64, f64) = (8.4966, 4.5426);
+const ASABA: (f64, f64) = (6.1980, 6.7319);
+
+// The gateway session /ws/driver opens for a driver app.
+fn listen(driver: Uuid) -> Connection {
+    gateway::connect(Peer::Driver(driver), None)
+}
+
+async fn next_of(session: &mut Connection, kind: &str) -> ServerMessage {
+    loop {
+        let Envelope { message, .. } = session.messages.recv().await.expect("session open");
+        if message.kind() == kind {
+            return message;
+        }
+    }
+}
+
+async fn next_offer(session: &mut Connection) -> NewRideRequest {
+    loop {
+        match session.messages.recv().await.expect("session open") {
+            Envelope { message: ServerMessage::RideOffer { ride }, .. } => return *ride,
+            _ => continue,
+        }
+    }
+}
+
+fn answer(driver: Uuid, accepted: bool) -> TestRequest {
+    let payload = if accepted {
+        serde_json::json!({ "status": "accepted", "rider_id": Uuid::new_v4(), "driver_id": driver, "message": "on my way" })
+    } else {
+        serde_json::json!({ "status": "rejected", "driver_id": driver })
+    };
+    TestRequest::post()
+        .uri("/riders/wait-driver-response")
+        .insert_header(common::bearer(Role::Driver, driver))
+        .set_json(payload)
+}
+
+fn ride_request(rider: Uuid, (lat, lng): (f64, f64)) -> TestRequest {
+    let quoted = common::ride_from(rider, (lat, lng));
+    TestRequest::post().uri("/riders/ride-request").insert_header(common::bearer(Role::Rider, rider)).set_json(serde_json::json!({
+        "rider_id": rider,
+        "pick_up": { "lat": lat, "lng": lng, "name": null },
+        "drop_off": { "lat": lat + 0.02, "lng": lng, "name": null },
+        "ride_type": "ASAP",
+        "payment_method": "card",
+        "items": [],
+        "quote_token": quoted.quote_token,
+    }))
+}
+
+fn request_status(request_id: &serde_json::Value, rider: Uuid) -> TestRequest {
+    let request_id = request_id.as_str().unwrap();
+    TestRequest::get()
+        .uri(&format!("/riders/ride-request/{}?rider_id={}", request_id, rider))
+        .insert_header(common::bearer(Role::Rider, rider))
+}
+
+fn driver_ids(pool: &DbPool, count: usize, (lat, lng): (f64, f64)) -> Vec<Uuid> {
+    let mut conn = pool.get().unwrap();
+    (0..count)
+        .map(|i| common::insert_driver(&mut conn, "available", (lat + 0.002 * (i + 1) as f64, lng)))
+        .collect()
+}
+
+
+// ─── run_assign_driver (needs TEST_DATABASE_URL) ─────────────────────────────
+
+#[actix_web::test]
+async fn broadcast_goes_to_the_first_driver_to_accept() {
+    let Some(pool) = common::test_pool() else { return };
+    let rider = common::insert_rider(&mut pool.get().unwrap());
+    let drivers = driver_ids(&pool, 3, ENUGU);
+    let ride = common::ride_from(rider, ENUGU);
+
+    let mut sessions: Vec<Connection> = drivers.iter().map(|driver| listen(*driver)).collect();
+
+    let data = web::Data::new(pool.clone());
+    let dispatch = actix_web::rt::spawn(run_assign_driver(data.clone(), ride.clone()));
+
+    // All three hear about the ride before anyone has answered
+    for session in sessions.iter_mut() {
+        assert_eq!(next_offer(session).await.request_id, ride.request_id);
+    }
+
+    let app = init_service(App::new().wrap(from_fn(authenticate)).app_data(common::authenticator()).app_data(common::quote_signer()).app_data(data).service(riders::routes())).await;
+    assert_eq!(call_service(&app, answer(drivers[0], false).to_request()).await.status(), 200);
+    assert_eq!(call_service(&app, answer(drivers[2], true).to_request()).await.status(), 200);
+
+    let assigned = dispatch.await.unwrap();
+    assert_eq!(assigned.status(), 200);
+
+    // The driver still deciding has lost the ride, and their app is told
+    assert_eq!(call_service(&app, answer(drivers[1], true).to_request()).await.status(), 409);
+    let withdrawn = loop {
+        match sessions[1].messages.recv().await.expect("session open").message {
+            ServerMessage::OfferWithdrawn { request_id } => break request_id,
+            _ => continue,
+        }
+    };
+    assert_eq!(withdrawn, ride.request_id);
+
+    let mut conn = pool.get().unwrap();
+    assert_eq!(common::driver_status(&mut conn, drivers[0]), "available");
+    assert_eq!(common::driver_status(&mut conn, drivers[1]), "available");
+    assert_eq!(common::driver_status(&mut conn, drivers[2]), "busy");
+
+    // Every offer is on record with how it ended
+    let history = TestRequest::get()
+        .uri(&format!("/riders/ride-request/{}/offers", ride.request_id))
+        .insert_header(common::bearer(Role::Rider, rider));
+    let response = call_service(&app, history.to_request()).await;
+    assert_eq!(response.status(), 200);
+    let offers: Vec<RideOffer> = read_body_json(response).await;
+    let outcome_of = |driver: Uuid| offers.iter().find(|o| o.driver_id == driver).map(|o| o.outcome.as_str());
+    assert_eq!(offers.len(), 3);
+    assert!(offers.iter().all(|o| o.round == 1 && o.answered_at.is_some()));
+    assert_eq!(outcome_of(drivers[0]), Some("rejected"));
+    assert_eq!(outcome_of(drivers[1]), Some("withdrawn"));
+    assert_eq!(outcome_of(drivers[2]), Some("accepted"));
+
+    let unknown = TestRequest::get()
+        .uri(&format!("/riders/ride-request/{}/offers", Uuid::new_v4()))
+        .insert_header(common::bearer(Role::Rider, rider));
+    assert_eq!(call_service(&app, unknown.to_request()).await.status(), 404);
+}
+
+#[actix_web::test]
+async fn dispatch_widens_the_radius_when_nobody_is_close() {
+    let Some(pool) = common::test_pool() else { return };
+    let rider = common::insert_rider(&mut pool.get().unwrap());
+    // ~8 km north, outside the default 5 km but inside the second ring
+    let far = common::insert_driver(&mut pool.get().unwrap(), "available", (JOS.0 + 0.072, JOS.1));
+    let ride = common::ride_from(rider, JOS);
+
+    let mut session = listen(far);
+    let data = web::Data::new(pool.clone());
+    let dispatch = actix_web::rt::spawn(run_assign_driver(data.clone(), ride.clone()));
+
+    assert_eq!(next_offer(&mut session).await.request_id, ride.request_id);
+
+    let app = init_service(App::new().wrap(from_fn(authenticate)).app_data(common::authenticator()).app_data(common::quote_signer()).app_data(data).service(riders::routes())).await;
+    assert_eq!(call_service(&app, answer(far, true).to_request()).await.status(), 200);
+    assert_eq!(dispatch.await.unwrap().status(), 200);
+    assert_eq!(common::driver_status(&mut pool.get().unwrap(), far), "busy");
+}
+
+// A dispatch that stopped mid-offer leaves a pending offer and a reserved
+// driver behind, as if the instance running it had been restarted.
+fn orphan(pool: &DbPool, ride: &NewRideRequest, driver: Uuid, offered_secs_ago: i64) {
+    use logic::schema::back_ride_request::dsl::back_ride_request;
+
+    let mut conn = pool.get().unwrap();
+    let offered_at = Utc::now().timestamp() - offered_secs_ago;
+    diesel::insert_into(back_ride_request).values(ride).execute(&mut conn).unwrap();
+    assert!(reserve_driver(&mut conn, driver, ride.request_id, offered_at, common::HOLD_SECS).unwrap());
+    record_offer(&mut conn, ride.request_id, driver, 1, offered_at, offered_at + 50).unwrap();
+}
+
+// One test for both cases, recovery claims every orphan it finds.
+#[actix_web::test]
+async fn orphaned_requests_are_dispatched_again_or_given_up() {
+    let Some(pool) = common::test_pool() else { return };
+    let rider = common::insert_rider(&mut pool.get().unwrap());
+    let data = web::Data::new(pool.clone());
+
+    // Recent: dispatched again, and this time a driver is listening
+    let stuck = driver_ids(&pool, 1, OWERRI)[0];
+    let recent = common::ride_from(rider, OWERRI);
+    orphan(&pool, &recent, stuck, 120);
+    let mut session = listen(stuck);
+    let mut rider_session = gateway::connect(Peer::Rider(rider), None);
+
+    // Old: the rider has long given up waiting
+    let left = driver_ids(&pool, 1, CALABAR)[0];
+    let old = common::ride_from(rider, CALABAR);
+    orphan(&pool, &old, left, 1_000);
+
+    let recovery = recover_orphaned_requests(data.clone()).await.unwrap();
+    assert!(recovery.redispatched.contains(&recent.request_id));
+    assert!(recovery.given_up.contains(&old.request_id));
+    assert!(!recovery.redispatched.contains(&old.request_id));
+
+    assert_eq!(next_offer(&mut session).await.request_id, recent.request_id);
+    let app = init_service(App::new().wrap(from_fn(authenticate)).app_data(common::authenticator()).app_data(common::quote_signer()).app_data(data.clone()).service(riders::routes())).await;
+    assert_eq!(call_service(&app, answer(stuck, true).to_request()).await.status(), 200);
+
+    let assigned = loop {
+        match rider_session.messages.recv().await.expect("session open").message {
+            ServerMessage::TripStatus { status, .. } => break status,
+            _ => continue,
+        }
+    };
+    assert_eq!(assigned, TripStatus::DriverAssigned);
+
+    let mut conn = pool.get().unwrap();
+    let outcomes: Vec<String> = offer_history(&mut conn, recent.request_id).unwrap()
+        .into_iter()
+        .map(|o| o.outcome)
+        .collect();
+    assert_eq!(outcomes, vec!["expired", "accepted"]);
+
+    let outcomes: Vec<String> = offer_history(&mut conn, old.request_id).unwrap()
+        .into_iter()
+        .map(|o| o.outcome)
+        .collect();
+    assert_eq!(outcomes, vec!["expired"]);
+    assert_eq!(common::driver_status(&mut conn, left), "available");
+    assert_eq!(dispatch_status_of(&mut conn, recent.request_id).unwrap(), DispatchStatus::Assigned);
+    assert_eq!(dispatch_status_of(&mut conn, old.request_id).unwrap(), DispatchStatus::NoDriversFound);
+
+    // Nothing is left to claim the second time round
+    let again = recover_orphaned_requests(data).await.unwrap();
+    assert!(!again.redispatched.contains(&recent.request_id));
+    assert!(!again.given_up.contains(&old.request_id));
+}
+
+
+// A request its dispatch left searching with no offer out, `requested_secs_ago`,
+// and each of `settled` an offer made and answered that long ago.
+fn stalled(pool: &DbPool, ride: &NewRideRequest, requested_secs_ago: i64, settled: &[(Uuid, i64, OfferOutcome)]) {
+    use logic::schema::back_ride_request::dsl::{ back_ride_request, requested_at };
+
+    let mut conn = pool.get().unwrap();
+    let now = Utc::now().timestamp();
+    diesel::insert_into(back_ride_request).values(ride).execute(&mut conn).unwrap();
+    diesel::update(back_ride_request.find(ride.request_id))
+        .set(requested_at.eq(now - requested_secs_ago))
+        .execute(&mut conn)
+        .unwrap();
+    for &(driver, secs_ago, how) in settled {
+        let offer = record_offer(&mut conn, ride.request_id, driver, 1, now - secs_ago - 50, now - secs_ago).unwrap();
+        assert!(settle_offer(&mut conn, offer, how, now - secs_ago).unwrap());
+    }
+}
+
+#[actix_web::test]
+async fn requests_left_searching_without_offers_are_dispatched_again() {
+    let Some(pool) = common::test_pool() else { return };
+    let rider = common::insert_rider(&mut pool.get().unwrap());
+    let data = web::Data::new(pool.clone());
+    let driver = common::insert_driver(&mut pool.get().unwrap(), "offline", ASABA);
+
+    // Every offer ran out and the instance died before the next round
+    let between_rounds = common::ride_from(rider, ASABA);
+    stalled(&pool, &between_rounds, 240, &[(driver, 150, OfferOutcome::TimedOut)]);
+    // The instance died before making the first offer
+    let never_offered = common::ride_from(rider, ASABA);
+    stalled(&pool, &never_offered, 240, &[]);
+    // Still going: its last offer was turned down a moment ago
+    let live = common::ride_from(rider, ASABA);
+    stalled(&pool, &live, 240, &[(driver, 5, OfferOutcome::Rejected)]);
+
+    let recovery = recover_orphaned_requests(data.clone()).await.unwrap();
+    assert!(recovery.redispatched.contains(&between_rounds.request_id));
+    assert!(recovery.redispatched.contains(&never_offered.request_id));
+    assert!(!recovery.redispatched.contains(&live.request_id));
+    assert!(!recovery.given_up.contains(&live.request_id));
+
+    // Claimed once, however long the new dispatch takes
+    let again = recover_orphaned_requests(data).await.unwrap();
+    assert!(!again.redispatched.contains(&between_rounds.request_id));
+    assert!(!again.redispatched.contains(&never_offered.request_id));
+    assert_eq!(dispatch_status_of(&mut pool.get().unwrap(), live.request_id).unwrap(), DispatchStatus::Searching);
+}
+
+
+// ─── /riders/ride-request (needs TEST_DATABASE_URL) ──────────────────────────
+
+#[actix_web::test]
+async fn ride_requests_are_answered_straight_away_and_dispatched_in_the_background() {
+    let Some(pool) = common::test_pool() else { return };
+    let rider = common::insert_rider(&mut pool.get().unwrap());
+    let driver = driver_ids(&pool, 1, ABEOKUTA)[0];
+    let mut session = listen(driver);
+    let app = init_service(App::new().wrap(from_fn(authenticate)).app_data(common::authenticator()).app_data(common::quote_signer()).app_data(web::Data::new(pool.clone())).service(riders::routes())).await;
+
+    let response = call_service(&app, ride_request(rider, ABEOKUTA).to_request()).await;
+    assert_eq!(response.status(), 202);
+    let accepted: serde_json::Value = read_body_json(response).await;
+    assert_eq!(accepted["status"], "searching");
+    let request_id = accepted["request_id"].clone();
+
+    let searching: serde_json::Value = read_body_json(call_service(&app, request_status(&request_id, rider).to_request()).await).await;
+    assert_eq!(searching["status"], "searching");
+    assert_eq!(call_service(&app, request_status(&request_id, Uuid::new_v4()).to_request()).await.status(), 403);
+    let unknown = request_status(&serde_json::json!(Uuid::new_v4()), rider);
+    assert_eq!(call_service(&app, unknown.to_request()).await.status(), 404);
+
+    assert_eq!(serde_json::json!(next_offer(&mut session).await.request_id), request_id);
+    assert_eq!(call_service(&app, answer(driver, true).to_request()).await.status(), 200);
+
+    let mut assigned = searching;
+    for _ in 0..50 {
+        assigned = read_body_json(call_service(&app, request_status(&request_id, rider).to_request()).await).await;
+        if assigned["status"] != "searching" {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(assigned["status"], "assigned");
+    assert_eq!(assigned["driver_id"], serde_json::json!(driver));
+    assert_eq!(assigned["driver"]["name"], "Test Driver");
+    assert!(assigned["trip_reference"].is_string());
+
+    // Cancelling now calls off the trip, inside the grace period so for free
+    let cancel = TestRequest::post()
+        .uri(&format!("/riders/ride-request/{}/cancel", request_id.as_str().unwrap()))
+        .insert_header(common::bearer(Role::Rider, rider))
+        .set_json(serde_json::json!({ "rider_id": rider, "reason": "found_alternate_transport" }));
+    let response = call_service(&app, cancel.to_request()).await;
+    assert_eq!(response.status(), 200);
+    let cancelled: serde_json::Value = read_body_json(response).await;
+    assert_eq!(cancelled["status"], "cancelled");
+    assert_eq!(cancelled["reason"], "found_alternate_transport");
+    assert_eq!(cancelled["trip_reference"], assigned["trip_reference"]);
+    assert_eq!(cancelled["cancellation_fee"], serde_json::Value::Null);
+
+    match next_of(&mut session, "trip_cancelled").await {
+        ServerMessage::TripCancelled { trip_reference, reason, cancellation_fee, .. } => {
+            assert_eq!(serde_json::json!(trip_reference), assigned["trip_reference"]);
+            assert_eq!(reason, CancelReason::FoundAlternateTransport);
+            assert_eq!(cancellation_fee, None);
+        }
+        other => panic!("unexpected {}", other.kind()),
+    }
+    assert_eq!(common::driver_status(&mut pool.get().unwrap(), driver), "available");
+}
+
+#[actix_web::test]
+async fn cancelling_a_search_stops_its_dispatch() {
+    let Some(pool) = common::test_pool() else { return };
+    let rider = common::insert_rider(&mut pool.get().unwrap());
+    let driver = driver_ids(&pool, 1, ILORIN)[0];
+    let ride = common::ride_from(rider, ILORIN);
+    let mut session = listen(driver);
+
+    let data = web::Data::new(pool.clone());
+    let dispatch = actix_web::rt::spawn(run_assign_driver(data.clone(), ride.clone()));
+    next_offer(&mut session).await;
+
+    let app = init_service(App::new().wrap(from_fn(authenticate)).app_data(common::authenticator()).app_data(common::quote_signer()).app_data(data).service(riders::routes())).await;
+    let cancel = || TestRequest::post()
+        .uri(&format!("/riders/ride-request/{}/cancel", ride.request_id))
+        .insert_header(common::bearer(Role::Rider, rider))
+        .set_json(serde_json::json!({ "rider_id": rider, "reason": "change_of_plans" }));
+    let response = call_service(&app, cancel().to_request()).await;
+    assert_eq!(response.status(), 200);
+    let cancelled: serde_json::Value = read_body_json(response).await;
+    assert_eq!(cancelled["status"], "cancelled");
+    assert_eq!(cancelled["reason"], "change_of_plans");
+    assert_eq!(call_service(&app, cancel().to_request()).await.status(), 200);
+
+    // Long before the 50 second offer timeout
+    let ended = tokio::time::timeout(std::time::Duration::from_secs(5), dispatch).await;
+    assert_eq!(ended.expect("dispatch stopped").unwrap().status(), 409);
+
+    next_of(&mut session, "offer_withdrawn").await;
+    let outcomes: Vec<String> = offer_history(&mut pool.get().unwrap(), ride.request_id).unwrap()
+        .into_iter()
+        .map(|o| o.outcome)
+        .collect();
+    assert_eq!(outcomes, vec!["withdrawn"]);
+    assert_eq!(common::driver_status(&mut pool.get().unwrap(), driver), "available");
+
+    // Answering the withdrawn offer is too late
+    assert_eq!(call_service(&app, answer(driver, true).to_request()).await.status(), 409);
+    assert_eq!(dispatch_status_of(&mut pool.get().unwrap(), ride.request_id).unwrap(), DispatchStatus::Cancelled);
+}
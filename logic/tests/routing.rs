@@ -0,0 +1,146 @@
+use actix_web::{ web, App, HttpResponse, HttpServer };
+use actix_web::middleware::from_fn;
+use actix_web::test::{ call_service, init_service, read_body_json, TestRequest };
+use logic::api::auth::{ authenticate, Role };
+use logic::api::riders::RideType;
+use logic::services::pricing::{
+    self, decode_polyline, encode_polyline, GeoPoint, OsrmRouter, RouteError, Router,
+};
+use logic::services::traffic::{ self, DEFAULT_TRAFFIC_FACTOR };
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::Duration;
+
+mod common;
+
+const BENIN: (f64, f64) = (6.3350, 5.6037);
+const WARRI: (f64, f64) = (5.5544, 5.7932);
+
+// Held by the tests that install a router, it's one for the whole binary
+static ROUTER: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+
+// Answers /route/v1 like osrm-routed would: roads 1.4 times the straight
+// line, at 24 km/h, by way of a corner. Nothing goes north of 80°.
+async fn stand_in_route(path: web::Path<(String, String)>) -> HttpResponse {
+    let (_profile, coordinates) = path.into_inner();
+    let points: Vec<(f64, f64)> = coordinates
+        .split(';')
+        .map(|pair| {
+            let (lng, lat) = pair.split_once(',').unwrap();
+            (lat.parse().unwrap(), lng.parse().unwrap())
+        })
+        .collect();
+    let (from, to) = (points[0], points[1]);
+    if to.0 > 80.0 {
+        return HttpResponse::BadRequest().json(serde_json::json!({ "code": "NoRoute", "message": "Impossible route" }));
+    }
+
+    let straight = GeoPoint { lat: from.0, lng: from.1, name: None }.distance_to(&GeoPoint { lat: to.0, lng: to.1, name: None });
+    let metres = straight * 1400.0;
+    HttpResponse::Ok().json(serde_json::json!({
+        "code": "Ok",
+        "routes": [{
+            "distance": metres,
+            "duration": metres / (24_000.0 / 3600.0),
+            "geometry": encode_polyline(&[from, (from.0, to.1), to]),
+        }],
+    }))
+}
+
+fn start_stand_in() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        actix_web::rt::System::new().block_on(async move {
+            HttpServer::new(|| App::new().route("/route/v1/{profile}/{coordinates}", web::get().to(stand_in_route)))
+                .workers(1)
+                .listen(listener)
+                .unwrap()
+                .run()
+                .await
+        })
+    });
+    url
+}
+
+fn point((lat, lng): (f64, f64)) -> GeoPoint {
+    GeoPoint { lat, lng, name: None }
+}
+
+
+// ─── OSRM router ─────────────────────────────────────────────────────────────
+
+#[actix_web::test]
+async fn osrm_routes_come_back_by_road() {
+    let router = OsrmRouter::new(&start_stand_in(), "driving", Duration::from_secs(5)).unwrap();
+
+    let route = router.route(&point(BENIN), &point(WARRI), &RideType::ASAP).await.unwrap();
+    let straight = point(BENIN).distance_to(&point(WARRI));
+    assert!((route.distance_km - straight * 1.4).abs() < 0.01);
+    // Benin has no factor fitted, and the default one is for straight lines
+    assert_eq!(traffic::factor_at(&point(BENIN), chrono::Utc::now().timestamp()), DEFAULT_TRAFFIC_FACTOR);
+    assert_eq!(route.duration_min, (route.distance_km / 24.0 * 60.0).round() as i32);
+    assert_eq!(decode_polyline(&route.polyline), Some(vec![BENIN, (BENIN.0, WARRI.1), WARRI]));
+    assert_eq!(route.source, "osrm");
+
+    let nowhere = router.route(&point(BENIN), &point((85.0, 5.0)), &RideType::ASAP).await;
+    assert_eq!(nowhere, Err(RouteError::NoRoute));
+}
+
+#[actix_web::test]
+async fn routes_fall_back_to_the_straight_line() {
+    // Nothing listens there any more
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let router = OsrmRouter::new(&format!("http://{}", closed), "driving", Duration::from_millis(500)).unwrap();
+    let failed = router.route(&point(BENIN), &point(WARRI), &RideType::ASAP).await;
+    assert!(matches!(failed, Err(RouteError::Unavailable(_))));
+
+    let _router = ROUTER.lock().await;
+    pricing::install_router(Arc::new(router));
+    let fallback = pricing::route(&point(BENIN), &point(WARRI), &RideType::ASAP).await;
+    pricing::install_router(Arc::new(pricing::HaversineRouter));
+
+    assert_eq!(fallback.source, "haversine");
+    assert_eq!(fallback.distance_km, point(BENIN).distance_to(&point(WARRI)));
+}
+
+
+// ─── Preflight by road (needs TEST_DATABASE_URL) ─────────────────────────────
+
+#[actix_web::test]
+async fn preflight_prices_the_road_distance() {
+    let Some(pool) = common::test_pool() else { return };
+    let rider = common::insert_rider(&mut pool.get().unwrap());
+    let _router = ROUTER.lock().await;
+    let router = OsrmRouter::new(&start_stand_in(), "driving", Duration::from_secs(5)).unwrap();
+    pricing::install_router(Arc::new(router));
+
+    let app = init_service(
+        App::new()
+            .wrap(from_fn(authenticate))
+            .app_data(common::authenticator())
+            .app_data(common::quote_signer())
+            .app_data(web::Data::new(pool.clone()))
+            .configure(logic::api::init),
+    ).await;
+    let preflight = TestRequest::post()
+        .uri("/drivers/ride-preflight")
+        .insert_header(common::bearer(Role::Rider, rider))
+        .set_json(serde_json::json!({
+            "rider_id": rider,
+            "pick_up": { "lat": BENIN.0, "lng": BENIN.1, "name": null },
+            "drop_off": { "lat": BENIN.0 + 0.05, "lng": BENIN.1 + 0.05, "name": null },
+            "ride_type": "ASAP",
+        }))
+        .to_request();
+    let response = call_service(&app, preflight).await;
+    pricing::install_router(Arc::new(pricing::HaversineRouter));
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = read_body_json(response).await;
+
+    let straight = point(BENIN).distance_to(&point((BENIN.0 + 0.05, BENIN.1 + 0.05)));
+    assert_eq!(body["routed_by"], "osrm");
+    assert!((body["distance_km"].as_f64().unwrap() - straight * 1.4).abs() < 0.01);
+    assert_eq!(decode_polyline(body["route_polyline"].as_str().unwrap()).map(|points| points.len()), Some(3));
+}
@@ -0,0 +1,78 @@
+use actix_web::{ web, App };
+use actix_web::middleware::from_fn;
+use actix_web::test::{ call_service, init_service, read_body_json, TestRequest };
+use logic::api::auth::{ authenticate, Role };
+use logic::api::riders::RideType;
+use logic::services::pricing::{ distance_between, estimated_time_min, GeoPoint };
+use logic::services::surge::{ self, SurgeSettings };
+use logic::services::tariffs::{ self, ServiceZone, Tariff };
+
+mod common;
+
+// Demand is counted per zone, and only this test makes requests in Yola's
+const YOLA: (f64, f64) = (9.2035, 12.4954);
+
+
+// ─── Surge engine (needs TEST_DATABASE_URL) ──────────────────────────────────
+
+#[actix_web::test]
+async fn busy_zones_surge_and_preflight_says_so() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+    let rider = common::insert_rider(&mut conn);
+    let now = chrono::Utc::now().timestamp();
+
+    let yola = ServiceZone {
+        zone: "yola".into(), name: "Yola".into(), center_lat: YOLA.0, center_lng: YOLA.1, radius_km: 5.0, updated_at: 0,
+    };
+    tariffs::save_zone(&mut conn, yola, now).unwrap();
+    tariffs::reload(&mut conn).unwrap();
+
+    // Six requests for the one driver there
+    common::insert_driver(&mut conn, "available", (YOLA.0 + 0.001, YOLA.1));
+    let pick_up = GeoPoint { lat: YOLA.0, lng: YOLA.1, name: Some("Yola".into()) };
+    let drop_off = GeoPoint { lat: YOLA.0 + 0.02, lng: YOLA.1, name: None };
+    for _ in 0..6 {
+        common::insert_ride_request_between(&mut conn, rider, &pick_up, &drop_off);
+    }
+
+    let surging = surge::recompute(&mut conn, now).unwrap();
+    let yola = surging.iter().find(|s| s.zone == "yola").expect("yola is surging");
+    assert_eq!((yola.demand, yola.supply), (6, 1));
+    // Headed for the cap, one step of 0.5 at a time
+    assert_eq!(yola.multiplier, 1.5);
+    assert_eq!(yola.expires_at, now + SurgeSettings::from_env().ttl_secs);
+
+    let app = init_service(
+        App::new()
+            .wrap(from_fn(authenticate))
+            .app_data(common::authenticator())
+            .app_data(common::quote_signer())
+            .app_data(web::Data::new(pool.clone()))
+            .configure(logic::api::init),
+    ).await;
+    let preflight = TestRequest::post()
+        .uri("/drivers/ride-preflight")
+        .insert_header(common::bearer(Role::Rider, rider))
+        .set_json(serde_json::json!({
+            "rider_id": rider,
+            "pick_up": { "lat": YOLA.0, "lng": YOLA.1, "name": null },
+            "drop_off": { "lat": YOLA.0 + 0.02, "lng": YOLA.1, "name": null },
+            "ride_type": "ASAP",
+        }))
+        .to_request();
+    let response = call_service(&app, preflight).await;
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = read_body_json(response).await;
+
+    // One driver isn't enough to serve it, but the price is surged either way
+    assert_eq!(body["can_serve"], false);
+    assert_eq!(body["surge_multiplier"], 1.5);
+    assert_eq!(body["surge_expires_at"], yola.expires_at);
+    let distance_km = distance_between(
+        &GeoPoint { lat: YOLA.0, lng: YOLA.1, name: None },
+        &GeoPoint { lat: YOLA.0 + 0.02, lng: YOLA.1, name: None },
+    );
+    let minutes = estimated_time_min(distance_km, &RideType::ASAP, &GeoPoint { lat: YOLA.0, lng: YOLA.1, name: None });
+    assert_eq!(body["estimated_price"], Tariff::builtin(&RideType::ASAP).surged_fare(distance_km, minutes, 1.5));
+}
@@ -0,0 +1,135 @@
+use actix_web::{ web, App };
+use actix_web::middleware::from_fn;
+use actix_web::test::{ call_service, init_service, read_body_json, TestRequest };
+use diesel::prelude::*;
+use logic::api::auth::{ authenticate, Role };
+use logic::services::pricing::{ distance_between, GeoPoint };
+use logic::services::tariffs::Tariff;
+use uuid::Uuid;
+
+mod common;
+
+const CALABAR: (f64, f64) = (4.9757, 8.3417);
+
+
+// ─── /admin/tariffs (needs TEST_DATABASE_URL) ────────────────────────────────
+
+#[actix_web::test]
+async fn rides_are_priced_with_the_zone_tariff_in_effect() {
+    let Some(pool) = common::test_pool() else { return };
+    let rider = common::insert_rider(&mut pool.get().unwrap());
+    let admin = Uuid::new_v4();
+    let app = init_service(
+        App::new()
+            .wrap(from_fn(authenticate))
+            .app_data(common::authenticator())
+            .app_data(common::quote_signer())
+            .app_data(web::Data::new(pool.clone()))
+            .configure(logic::api::init),
+    ).await;
+    let as_admin = |req: TestRequest| req.insert_header(common::bearer(Role::Admin, admin)).to_request();
+    let now = chrono::Utc::now().timestamp();
+    let rates = |effective_from: Option<i64>, base_fare: f64| serde_json::json!({
+        "ride_type": "ASAP",
+        "zone": "calabar",
+        "base_fare": base_fare,
+        "per_km": 100.0,
+        "per_min": 10.0,
+        "minimum_fare": 1000,
+        "rounding": "up",
+        "round_to": 100,
+        "effective_from": effective_from,
+    });
+
+    // Tariffs need a zone that exists, and only admins set either
+    let early = call_service(&app, as_admin(TestRequest::post().uri("/admin/tariffs").set_json(rates(None, 1500.0)))).await;
+    assert_eq!(early.status(), 404);
+
+    let zone = serde_json::json!({
+        "zone": "calabar", "name": "Calabar", "center_lat": CALABAR.0, "center_lng": CALABAR.1, "radius_km": 10.0,
+    });
+    let by_support = TestRequest::post()
+        .uri("/admin/zones")
+        .insert_header(common::bearer(Role::Support, Uuid::new_v4()))
+        .set_json(&zone)
+        .to_request();
+    assert_eq!(call_service(&app, by_support).await.status(), 403);
+    assert_eq!(call_service(&app, as_admin(TestRequest::post().uri("/admin/zones").set_json(&zone))).await.status(), 200);
+
+    // Starts now, the next one in an hour
+    let created = call_service(&app, as_admin(TestRequest::post().uri("/admin/tariffs").set_json(rates(None, 1500.0)))).await;
+    assert_eq!(created.status(), 201);
+    let current: Tariff = read_body_json(created).await;
+    let next = call_service(&app, as_admin(TestRequest::post().uri("/admin/tariffs").set_json(rates(Some(now + 3600), 2500.0)))).await;
+    assert_eq!(next.status(), 201);
+    let scheduled: Tariff = read_body_json(next).await;
+
+    let backdated = call_service(&app, as_admin(TestRequest::post().uri("/admin/tariffs").set_json(rates(Some(now - 3600), 1500.0)))).await;
+    assert_eq!(backdated.status(), 422);
+    let duplicate = call_service(&app, as_admin(TestRequest::post().uri("/admin/tariffs").set_json(rates(Some(now + 3600), 9000.0)))).await;
+    assert_eq!(duplicate.status(), 409);
+
+    // A ride from Calabar is priced with the tariff that has started, and
+    // the request keeps which one that was
+    let preflight = TestRequest::post()
+        .uri("/drivers/ride-preflight")
+        .insert_header(common::bearer(Role::Rider, rider))
+        .set_json(serde_json::json!({
+            "rider_id": rider,
+            "pick_up": { "lat": CALABAR.0, "lng": CALABAR.1, "name": null },
+            "drop_off": { "lat": CALABAR.0 + 0.02, "lng": CALABAR.1, "name": null },
+            "ride_type": "ASAP",
+        }))
+        .to_request();
+    let quoted: serde_json::Value = read_body_json(call_service(&app, preflight).await).await;
+    let requested = TestRequest::post()
+        .uri("/riders/ride-request")
+        .insert_header(common::bearer(Role::Rider, rider))
+        .set_json(serde_json::json!({
+            "rider_id": rider,
+            "pick_up": { "lat": CALABAR.0, "lng": CALABAR.1, "name": null },
+            "drop_off": { "lat": CALABAR.0 + 0.02, "lng": CALABAR.1, "name": null },
+            "ride_type": "ASAP",
+            "payment_method": "card",
+            "items": [],
+            "quote_token": quoted["quote_token"],
+        }))
+        .to_request();
+    let response = call_service(&app, requested).await;
+    assert_eq!(response.status(), 202);
+    let accepted: serde_json::Value = read_body_json(response).await;
+    let request_id: Uuid = serde_json::from_value(accepted["request_id"].clone()).unwrap();
+
+    let (stored_price, stored_tariff): (i64, Option<Uuid>) = {
+        use logic::schema::back_ride_request::dsl::{ back_ride_request, estimated_price, tariff_id };
+        back_ride_request
+            .find(request_id)
+            .select((estimated_price, tariff_id))
+            .first(&mut pool.get().unwrap())
+            .unwrap()
+    };
+    assert_eq!(stored_tariff, Some(current.tariff_id));
+    assert_eq!(accepted["estimated_price"], stored_price);
+    let minutes = accepted["estimated_time_min"].as_i64().unwrap() as i32;
+    let distance_km = distance_between(
+        &GeoPoint { lat: CALABAR.0, lng: CALABAR.1, name: None },
+        &GeoPoint { lat: CALABAR.0 + 0.02, lng: CALABAR.1, name: None },
+    );
+    assert_eq!(stored_price, current.fare(distance_km, minutes));
+    assert_eq!(stored_price % 100, 0);
+
+    // Both stay on record, and either can be looked up by when it applied
+    let history = call_service(&app, as_admin(TestRequest::get().uri("/admin/tariffs?ride_type=ASAP&zone=calabar"))).await;
+    let history: Vec<Tariff> = read_body_json(history).await;
+    let ids: Vec<Uuid> = history.iter().map(|t| t.tariff_id).collect();
+    assert_eq!(ids, vec![scheduled.tariff_id, current.tariff_id]);
+
+    let at = |ts: i64| as_admin(TestRequest::get().uri(&format!("/admin/tariffs/in-effect?ride_type=ASAP&zone=calabar&at={}", ts)));
+    let then: Tariff = read_body_json(call_service(&app, at(now + 60)).await).await;
+    assert_eq!(then.tariff_id, current.tariff_id);
+    let later: Tariff = read_body_json(call_service(&app, at(now + 7200)).await).await;
+    assert_eq!(later.tariff_id, scheduled.tariff_id);
+    // Before Calabar had its own rates it was priced like anywhere else
+    let before: Tariff = read_body_json(call_service(&app, at(now - 60)).await).await;
+    assert_eq!(before.zone, "default");
+}
@@ -0,0 +1,125 @@
+use actix_web::{ web, App };
+use actix_web::middleware::from_fn;
+use actix_web::test::{ call_service, init_service, read_body_json, TestRequest };
+use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use logic::api::auth::{ authenticate, Role };
+use logic::api::riders::RideType;
+use logic::services::pricing::{ self, GeoPoint };
+use logic::services::tariffs::{ self, ServiceZone };
+use logic::services::traffic::{ self, TrafficSettings };
+use uuid::Uuid;
+
+mod common;
+
+// Factors are fitted per zone, and only this test has trips in Akure's
+const AKURE: (f64, f64) = (7.2571, 5.2058);
+
+
+fn point((lat, lng): (f64, f64)) -> GeoPoint {
+    GeoPoint { lat, lng, name: None }
+}
+
+// 10 km north out of Akure, picked up at `started` and dropped off `minutes` later
+fn completed_trip_from_akure(conn: &mut PgConnection, driver: Uuid, request: Uuid, started: i64, minutes: i64) {
+    let trip_reference = format!("traffic-{}", Uuid::new_v4());
+    common::insert_trip_with(conn, &trip_reference, "rider", "driver", common::TripOverrides {
+        driver,
+        pick_up: serde_json::to_string(&point(AKURE)).unwrap(),
+        drop_off: serde_json::to_string(&point((AKURE.0 + 0.09, AKURE.1))).unwrap(),
+        started: started - 300,
+        picked_up_at: Some(started),
+        ended: started + minutes * 60,
+        distance_km: 10.0,
+        request: Some(request),
+    });
+}
+
+
+// ─── Traffic fit (needs TEST_DATABASE_URL) ───────────────────────────────────
+
+#[actix_web::test]
+async fn factors_are_fitted_from_completed_trips() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+    let rider = common::insert_rider(&mut conn);
+    let now = chrono::Utc::now().timestamp();
+    let admin = Uuid::new_v4();
+
+    let akure = ServiceZone {
+        zone: "akure".into(), name: "Akure".into(), center_lat: AKURE.0, center_lng: AKURE.1, radius_km: 5.0, updated_at: 0,
+    };
+    tariffs::save_zone(&mut conn, akure, now).unwrap();
+    tariffs::reload(&mut conn).unwrap();
+
+    // Five trips in the same hour two hours ago, observed factors 1.25,
+    // 1.25, 1, 0.8 and 0.5, each quoted 12 minutes
+    let hour = (now - 7200).div_euclid(3600) * 3600;
+    let driver = Uuid::new_v4();
+    for (i, minutes) in [16, 16, 20, 25, 40].into_iter().enumerate() {
+        let request = common::insert_ride_request(&mut conn, rider);
+        completed_trip_from_akure(&mut conn, driver, request, hour + i as i64 * 60, minutes);
+    }
+    // The first was driven the way it was priced
+    traffic::record_location(&mut conn, driver, &point(AKURE), hour).unwrap();
+    traffic::record_location(&mut conn, driver, &point((AKURE.0 + 0.09, AKURE.1)), hour + 60).unwrap();
+
+    // Two minutes for 10 km, but by way of Ibadan, so not fitted
+    let detour = Uuid::new_v4();
+    let request = common::insert_ride_request(&mut conn, rider);
+    completed_trip_from_akure(&mut conn, detour, request, hour + 600, 2);
+    traffic::record_location(&mut conn, detour, &point(AKURE), hour + 600).unwrap();
+    traffic::record_location(&mut conn, detour, &point((7.3775, 3.9470)), hour + 660).unwrap();
+    traffic::record_location(&mut conn, detour, &point((AKURE.0 + 0.09, AKURE.1)), hour + 720).unwrap();
+
+    // History from before the window goes
+    let settings = TrafficSettings::from_env();
+    traffic::record_location(&mut conn, detour, &point(AKURE), now - settings.window_secs() - 60).unwrap();
+
+    let fitted = traffic::refit(&mut conn, now).unwrap();
+    let hour_of_week = settings.hour_of_week(hour);
+    let akure = fitted.iter().find(|f| f.zone == "akure").expect("akure is fitted");
+    assert_eq!((akure.hour_of_week, akure.factor, akure.samples), (hour_of_week, 1.0, 5));
+    assert_eq!(traffic::factor_at(&point(AKURE), hour), 1.0);
+    assert_eq!(pricing::travel_time_min(10.0, &RideType::ASAP, traffic::factor_at(&point(AKURE), hour)), 20);
+
+    let kept: i64 = {
+        use logic::schema::back_driver_locations::dsl::*;
+        back_driver_locations.filter(driver_id.eq(detour)).count().get_result(&mut conn).unwrap()
+    };
+    assert_eq!(kept, 3);
+
+    let app = init_service(
+        App::new()
+            .wrap(from_fn(authenticate))
+            .app_data(common::authenticator())
+            .app_data(web::Data::new(pool.clone()))
+            .configure(logic::api::init),
+    ).await;
+    let as_admin = |req: TestRequest| req.insert_header(common::bearer(Role::Admin, admin)).to_request();
+
+    let response = call_service(&app, as_admin(TestRequest::get().uri("/admin/traffic-factors"))).await;
+    assert_eq!(response.status(), 200);
+    let factors: serde_json::Value = read_body_json(response).await;
+    assert!(factors.as_array().unwrap().iter().any(|f| f["zone"] == "akure" && f["factor"] == 1.0));
+
+    let response = call_service(&app, as_admin(TestRequest::get().uri("/admin/eta-report?days=1"))).await;
+    assert_eq!(response.status(), 200);
+    let report: serde_json::Value = read_body_json(response).await;
+    let zone = report["zones"].as_array().unwrap().iter().find(|z| z["zone"] == "akure").expect("akure is reported");
+    // Quoted 12 minutes for trips that took 16 to 40
+    assert_eq!(zone["quoted"]["trips"], 5);
+    assert_eq!(zone["quoted"]["mean_error_min"], -11.4);
+    // 20 minutes at the fitted factor
+    assert_eq!(zone["current"]["mean_error_min"], -3.4);
+    assert_eq!(zone["current"]["mean_abs_error_min"], 6.6);
+
+    // Refitting is a pricing change, support can only look
+    let refit = TestRequest::post()
+        .uri("/admin/traffic-factors/refit")
+        .insert_header(common::bearer(Role::Support, Uuid::new_v4()))
+        .to_request();
+    assert_eq!(call_service(&app, refit).await.status(), 403);
+    let response = call_service(&app, as_admin(TestRequest::post().uri("/admin/traffic-factors/refit"))).await;
+    assert_eq!(response.status(), 200);
+}
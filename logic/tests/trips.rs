@@ -0,0 +1,571 @@
+use actix_web::{ web, App };
+use actix_web::middleware::from_fn;
+use actix_web::test::{ call_service, init_service, read_body_json, TestRequest };
+use diesel::prelude::*;
+use logic::api::auth::{ authenticate, Role };
+use logic::api::drivers::get_ongoing_trips_count;
+use logic::api::riders::{ dispatch_status_of, DispatchStatus };
+use logic::api::trips::{
+    self, cancel_trip, create_trip_for_request, get_trip_by_reference, record_driver_milestone,
+    set_trip_status,
+    CancelReason, CancelTripRequest, CancellationPolicy, DriverMilestone, Trip, TripError, TripParty, TripStatus,
+};
+use logic::services::{ gateway, matching };
+use logic::services::pricing::GeoPoint;
+use uuid::Uuid;
+
+mod common;
+
+
+fn trip_with_status(conn: &mut PgConnection, trip_reference: &str, trip_status: TripStatus) -> Uuid {
+    use logic::schema::back_trips::dsl::*;
+
+    common::insert_trip(conn, trip_reference, "rider", "driver");
+    diesel::update(back_trips.filter(reference.eq(trip_reference)))
+        .set((status.eq(trip_status), end_ts.eq(None::<i64>)))
+        .execute(conn)
+        .unwrap();
+    get_trip_by_reference(conn, trip_reference).unwrap().driver_id
+}
+
+const NO_FEE: CancellationPolicy = CancellationPolicy { fee: 0, grace_secs: 0 };
+
+const PICKUP: (f64, f64) = (6.4531, 3.3958);
+const DROPOFF: (f64, f64) = (6.4280, 3.4219);
+
+fn point((lat, lng): (f64, f64)) -> GeoPoint {
+    GeoPoint { lat, lng, name: None }
+}
+
+// A trip the driver was just assigned to, with real pickup/drop off coordinates.
+fn assigned_trip(conn: &mut PgConnection, trip_reference: &str) -> Trip {
+    use logic::schema::back_trips::dsl::*;
+
+    trip_with_status(conn, trip_reference, TripStatus::DriverAssigned);
+    diesel::update(back_trips.filter(reference.eq(trip_reference)))
+        .set((
+            pick_up.eq(serde_json::to_string(&point(PICKUP)).unwrap()),
+            drop_off.eq(serde_json::to_string(&point(DROPOFF)).unwrap()),
+        ))
+        .execute(conn)
+        .unwrap();
+    get_trip_by_reference(conn, trip_reference).unwrap()
+}
+
+
+// ─── set_trip_status (needs TEST_DATABASE_URL) ───────────────────────────────
+
+#[test]
+fn status_moves_along_the_lifecycle() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+    trip_with_status(&mut conn, "trips-lifecycle", TripStatus::DriverAssigned);
+
+    set_trip_status(&mut conn, "trips-lifecycle", TripStatus::DriverArrived).unwrap();
+    set_trip_status(&mut conn, "trips-lifecycle", TripStatus::InProgress).unwrap();
+    let done = set_trip_status(&mut conn, "trips-lifecycle", TripStatus::Completed).unwrap();
+
+    assert_eq!(done.status, TripStatus::Completed);
+    assert!(done.end_ts.is_some());
+    let stored = get_trip_by_reference(&mut conn, "trips-lifecycle").unwrap();
+    assert_eq!(stored.status, TripStatus::Completed);
+    assert_eq!(stored.end_ts, done.end_ts);
+}
+
+#[test]
+fn invalid_transition_leaves_trip_untouched() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+    trip_with_status(&mut conn, "trips-skip", TripStatus::DriverAssigned);
+
+    let err = set_trip_status(&mut conn, "trips-skip", TripStatus::Completed).unwrap_err();
+    assert!(matches!(
+        err,
+        TripError::InvalidTransition { from: TripStatus::DriverAssigned, to: TripStatus::Completed }
+    ));
+    let stored = get_trip_by_reference(&mut conn, "trips-skip").unwrap();
+    assert_eq!(stored.status, TripStatus::DriverAssigned);
+    assert!(stored.end_ts.is_none());
+}
+
+#[test]
+fn unknown_trip_is_not_found() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+
+    let err = set_trip_status(&mut conn, "trips-missing", TripStatus::Cancelled).unwrap_err();
+    assert!(matches!(err, TripError::NotFound));
+}
+
+#[test]
+fn database_rejects_unnormalized_status() {
+    use logic::schema::back_trips::dsl::*;
+
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+    trip_with_status(&mut conn, "trips-legacy", TripStatus::InProgress);
+
+    let result = diesel::update(back_trips.filter(reference.eq("trips-legacy")))
+        .set(status.eq("Ongoing"))
+        .execute(&mut conn);
+    assert!(result.is_err());
+}
+
+
+// ─── get_ongoing_trips_count ─────────────────────────────────────────────────
+
+#[test]
+fn ongoing_count_includes_every_active_status() {
+    use logic::schema::back_trips::dsl::*;
+
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+
+    let driver = Uuid::new_v4();
+    for (i, s) in TripStatus::ALL.into_iter().enumerate() {
+        let trip_reference = format!("trips-count-{}", i);
+        trip_with_status(&mut conn, &trip_reference, s);
+        diesel::update(back_trips.filter(reference.eq(&trip_reference)))
+            .set(driver_id.eq(driver))
+            .execute(&mut conn)
+            .unwrap();
+    }
+
+    let count = get_ongoing_trips_count(&mut conn, driver).unwrap();
+    assert_eq!(count, TripStatus::ACTIVE.len() as i64);
+}
+
+
+// ─── Driver milestones and cancellation (needs TEST_DATABASE_URL) ────────────
+
+#[test]
+fn driver_walks_trip_from_arrival_to_dropoff() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+    let trip = assigned_trip(&mut conn, "trips-milestones");
+
+    let arrived = record_driver_milestone(
+        &mut conn, "trips-milestones", trip.driver_id, &point(PICKUP), DriverMilestone::Arrived,
+    ).unwrap();
+    assert_eq!(arrived.status, TripStatus::DriverArrived);
+    assert!(arrived.arrived_at.is_some());
+
+    let picked_up = record_driver_milestone(
+        &mut conn, "trips-milestones", trip.driver_id, &point(PICKUP), DriverMilestone::PickedUp,
+    ).unwrap();
+    assert_eq!(picked_up.status, TripStatus::InProgress);
+    assert!(picked_up.picked_up_at.is_some());
+
+    let done = record_driver_milestone(
+        &mut conn, "trips-milestones", trip.driver_id, &point(DROPOFF), DriverMilestone::DroppedOff,
+    ).unwrap();
+    assert_eq!(done.status, TripStatus::Completed);
+    assert!(done.end_ts.is_some());
+    assert_eq!(done.driver_location, serde_json::to_string(&point(DROPOFF)).unwrap());
+}
+
+#[test]
+fn milestone_far_from_pickup_is_rejected() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+    let trip = assigned_trip(&mut conn, "trips-far");
+
+    let err = record_driver_milestone(
+        &mut conn, "trips-far", trip.driver_id, &point(DROPOFF), DriverMilestone::Arrived,
+    ).unwrap_err();
+    assert!(matches!(err, TripError::OutsideGeofence { allowed_m: 150, .. }));
+
+    let stored = get_trip_by_reference(&mut conn, "trips-far").unwrap();
+    assert_eq!(stored.status, TripStatus::DriverAssigned);
+    assert!(stored.arrived_at.is_none());
+}
+
+#[test]
+fn milestone_from_another_driver_is_forbidden() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+    assigned_trip(&mut conn, "trips-other-driver");
+
+    let err = record_driver_milestone(
+        &mut conn, "trips-other-driver", Uuid::new_v4(), &point(PICKUP), DriverMilestone::Arrived,
+    ).unwrap_err();
+    assert!(matches!(err, TripError::Forbidden(_)));
+}
+
+#[test]
+fn pickup_cannot_be_confirmed_before_arrival() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+    let trip = assigned_trip(&mut conn, "trips-early-pickup");
+
+    let err = record_driver_milestone(
+        &mut conn, "trips-early-pickup", trip.driver_id, &point(PICKUP), DriverMilestone::PickedUp,
+    ).unwrap_err();
+    assert!(matches!(err, TripError::InvalidTransition { .. }));
+}
+
+#[test]
+fn rider_cancellation_records_reason() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+    let trip = assigned_trip(&mut conn, "trips-rider-cancel");
+
+    let request = CancelTripRequest {
+        cancelled_by: TripParty::Rider,
+        actor_id: trip.rider_id,
+        reason: CancelReason::DriverTakingTooLong,
+    };
+    let cancelled = cancel_trip(&mut conn, "trips-rider-cancel", &request, &NO_FEE).unwrap();
+
+    assert_eq!(cancelled.status, TripStatus::Cancelled);
+    assert_eq!(cancelled.cancelled_by.as_deref(), Some("rider"));
+    assert_eq!(cancelled.cancel_reason.as_deref(), Some("driver_taking_too_long"));
+    assert!(cancelled.cancelled_at.is_some());
+}
+
+#[test]
+fn riders_cancelling_late_pay_the_fee() {
+    use logic::schema::back_trips::dsl::*;
+
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+    let policy = CancellationPolicy { fee: 500, grace_secs: 120 };
+    let rider_cancels = |trip: &Trip| CancelTripRequest {
+        cancelled_by: TripParty::Rider,
+        actor_id: trip.rider_id,
+        reason: CancelReason::ChangeOfPlans,
+    };
+
+    let late = assigned_trip(&mut conn, "trips-late-rider");
+    diesel::update(back_trips.filter(reference.eq("trips-late-rider")))
+        .set(start_ts.eq(chrono::Utc::now().timestamp() - 600))
+        .execute(&mut conn)
+        .unwrap();
+    let charged = cancel_trip(&mut conn, "trips-late-rider", &rider_cancels(&late), &policy).unwrap();
+    assert_eq!(charged.cancellation_fee, Some(500));
+
+    let early = assigned_trip(&mut conn, "trips-early-rider");
+    diesel::update(back_trips.filter(reference.eq("trips-early-rider")))
+        .set(start_ts.eq(chrono::Utc::now().timestamp()))
+        .execute(&mut conn)
+        .unwrap();
+    let free = cancel_trip(&mut conn, "trips-early-rider", &rider_cancels(&early), &policy).unwrap();
+    assert_eq!(free.cancellation_fee, None);
+}
+
+#[test]
+fn driver_cannot_cancel_with_rider_only_reason() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+    let trip = assigned_trip(&mut conn, "trips-driver-cancel");
+
+    let request = CancelTripRequest {
+        cancelled_by: TripParty::Driver,
+        actor_id: trip.driver_id,
+        reason: CancelReason::DriverTakingTooLong,
+    };
+    let err = cancel_trip(&mut conn, "trips-driver-cancel", &request, &NO_FEE).unwrap_err();
+    assert!(matches!(err, TripError::InvalidRequest(_)));
+
+    let stored = get_trip_by_reference(&mut conn, "trips-driver-cancel").unwrap();
+    assert_eq!(stored.status, TripStatus::DriverAssigned);
+    assert!(stored.cancel_reason.is_none());
+}
+
+#[test]
+fn completed_trip_cannot_be_cancelled() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+    let driver = trip_with_status(&mut conn, "trips-late-cancel", TripStatus::Completed);
+
+    let request = CancelTripRequest {
+        cancelled_by: TripParty::Driver,
+        actor_id: driver,
+        reason: CancelReason::ChangeOfPlans,
+    };
+    let err = cancel_trip(&mut conn, "trips-late-cancel", &request, &NO_FEE).unwrap_err();
+    assert!(matches!(err, TripError::InvalidTransition { .. }));
+}
+
+#[actix_web::test]
+async fn lifecycle_routes_map_errors_to_status_codes() {
+    let Some(pool) = common::test_pool() else { return };
+    let trip = {
+        let mut conn = pool.get().unwrap();
+        assigned_trip(&mut conn, "trips-http")
+    };
+    let app = init_service(
+        App::new()
+            .wrap(from_fn(authenticate))
+            .app_data(common::authenticator())
+            .app_data(web::Data::new(pool))
+            .service(trips::routes()),
+    ).await;
+
+    let anonymous = TestRequest::post()
+        .uri("/trips/driver-arrived/trips-http")
+        .set_json(serde_json::json!({ "driver_id": trip.driver_id, "location": point(PICKUP) }))
+        .to_request();
+    assert_eq!(call_service(&app, anonymous).await.status(), 401);
+
+    let other_driver = TestRequest::post()
+        .uri("/trips/driver-arrived/trips-http")
+        .insert_header(common::bearer(Role::Driver, Uuid::new_v4()))
+        .set_json(serde_json::json!({ "driver_id": trip.driver_id, "location": point(PICKUP) }))
+        .to_request();
+    assert_eq!(call_service(&app, other_driver).await.status(), 403);
+
+    let too_far = TestRequest::post()
+        .uri("/trips/driver-arrived/trips-http")
+        .insert_header(common::bearer(Role::Driver, trip.driver_id))
+        .set_json(serde_json::json!({ "driver_id": trip.driver_id, "location": point(DROPOFF) }))
+        .to_request();
+    assert_eq!(call_service(&app, too_far).await.status(), 422);
+
+    let arrived = TestRequest::post()
+        .uri("/trips/driver-arrived/trips-http")
+        .insert_header(common::bearer(Role::Driver, trip.driver_id))
+        .set_json(serde_json::json!({ "driver_id": trip.driver_id, "location": point(PICKUP) }))
+        .to_request();
+    let resp = call_service(&app, arrived).await;
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["status"], "driver_arrived");
+
+    let stranger_id = Uuid::new_v4();
+    let stranger = TestRequest::post()
+        .uri("/trips/cancel-trip/trips-http")
+        .insert_header(common::bearer(Role::Rider, stranger_id))
+        .set_json(serde_json::json!({
+            "cancelled_by": "rider",
+            "actor_id": stranger_id,
+            "reason": "change_of_plans",
+        }))
+        .to_request();
+    assert_eq!(call_service(&app, stranger).await.status(), 403);
+}
+
+
+#[actix_web::test]
+async fn admin_can_edit_fares_without_moving_the_status() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut trip = {
+        let mut conn = pool.get().unwrap();
+        trip_with_status(&mut conn, "trips-admin-edit", TripStatus::InProgress);
+        get_trip_by_reference(&mut conn, "trips-admin-edit").unwrap()
+    };
+    let app = init_service(
+        App::new()
+            .wrap(from_fn(authenticate))
+            .app_data(common::authenticator())
+            .app_data(web::Data::new(pool))
+            .service(trips::routes()),
+    ).await;
+
+    trip.fare_estimate = Some(1_800);
+    let edit = TestRequest::post()
+        .uri("/trips/update-trip")
+        .insert_header(common::bearer(Role::Admin, Uuid::new_v4()))
+        .set_json(&trip)
+        .to_request();
+    let resp = call_service(&app, edit).await;
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["status"], "in_progress");
+    assert_eq!(body["fare_estimate"], 1_800);
+
+    // A status the trip can't move to is still refused
+    trip.status = TripStatus::DriverAssigned;
+    let backwards = TestRequest::post()
+        .uri("/trips/update-trip")
+        .insert_header(common::bearer(Role::Admin, Uuid::new_v4()))
+        .set_json(&trip)
+        .to_request();
+    assert_eq!(call_service(&app, backwards).await.status(), 409);
+}
+
+
+// ─── create_trip_for_request (needs TEST_DATABASE_URL) ───────────────────────
+
+#[test]
+fn accepted_request_becomes_a_trip_and_driver_goes_busy() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+    let rider = common::insert_rider(&mut conn);
+    let driver = common::insert_driver(&mut conn, "available", PICKUP);
+    let request = common::insert_ride_request(&mut conn, rider);
+
+    let trip = create_trip_for_request(&mut conn, request, driver).unwrap();
+
+    assert_eq!(trip.request_id, Some(request));
+    assert_eq!(trip.rider_id, rider);
+    assert_eq!(trip.driver_id, driver);
+    assert_eq!(trip.status, TripStatus::DriverAssigned);
+    assert_eq!(trip.fare_estimate, Some(2_500));
+    assert_eq!(trip.rider_pubkey, "RiderPubkey1111111111111111111111111111111");
+    assert!(trip.pick_up_point().is_some());
+    assert_eq!(common::driver_status(&mut conn, driver), "busy");
+}
+
+#[test]
+fn busy_driver_cannot_take_a_second_request() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+    let rider = common::insert_rider(&mut conn);
+    let driver = common::insert_driver(&mut conn, "available", PICKUP);
+    let first = common::insert_ride_request(&mut conn, rider);
+    let second = common::insert_ride_request(&mut conn, rider);
+
+    create_trip_for_request(&mut conn, first, driver).unwrap();
+    let err = create_trip_for_request(&mut conn, second, driver).unwrap_err();
+    assert!(matches!(err, TripError::DriverUnavailable));
+
+    use logic::schema::back_trips::dsl::*;
+    let linked: i64 = back_trips.filter(request_id.eq(second)).count().get_result(&mut conn).unwrap();
+    assert_eq!(linked, 0);
+}
+
+#[test]
+fn request_is_turned_into_at_most_one_trip() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+    let rider = common::insert_rider(&mut conn);
+    let first_driver = common::insert_driver(&mut conn, "available", PICKUP);
+    let second_driver = common::insert_driver(&mut conn, "available", PICKUP);
+    let request = common::insert_ride_request(&mut conn, rider);
+
+    create_trip_for_request(&mut conn, request, first_driver).unwrap();
+    assert!(create_trip_for_request(&mut conn, request, second_driver).is_err());
+
+    // The failed attempt rolled back, so the second driver is still free
+    assert_eq!(common::driver_status(&mut conn, second_driver), "available");
+}
+
+#[test]
+fn bike_driver_stays_available_for_a_second_trip() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+    let rider = common::insert_rider(&mut conn);
+    let driver = common::insert_driver_with_vehicle(&mut conn, "available", PICKUP, "Bike");
+
+    let first = common::insert_ride_request(&mut conn, rider);
+    let second = common::insert_ride_request(&mut conn, rider);
+
+    create_trip_for_request(&mut conn, first, driver).unwrap();
+    assert_eq!(common::driver_status(&mut conn, driver), "available");
+
+    create_trip_for_request(&mut conn, second, driver).unwrap();
+    assert_eq!(common::driver_status(&mut conn, driver), "busy");
+}
+
+#[test]
+fn finished_trip_frees_the_driver() {
+    let Some(pool) = common::test_pool() else { return };
+    let mut conn = pool.get().unwrap();
+    let rider = common::insert_rider(&mut conn);
+    let driver = common::insert_driver(&mut conn, "available", PICKUP);
+    let request = common::insert_ride_request(&mut conn, rider);
+    let trip = create_trip_for_request(&mut conn, request, driver).unwrap();
+
+    let cancel = CancelTripRequest {
+        cancelled_by: TripParty::Rider,
+        actor_id: rider,
+        reason: CancelReason::ChangeOfPlans,
+    };
+    cancel_trip(&mut conn, &trip.reference, &cancel, &NO_FEE).unwrap();
+    assert_eq!(common::driver_status(&mut conn, driver), "available");
+    assert_eq!(dispatch_status_of(&mut conn, request).unwrap(), DispatchStatus::Cancelled);
+}
+
+
+// ─── GET /trips/{reference}/events (needs TEST_DATABASE_URL) ─────────────────
+
+// (event, data) pairs of a finished SSE body, keep-alive comments dropped.
+fn sse_events(body: &[u8]) -> Vec<(String, serde_json::Value)> {
+    std::str::from_utf8(body)
+        .unwrap()
+        .split("\n\n")
+        .filter_map(|frame| {
+            let event = frame.lines().find_map(|l| l.strip_prefix("event: "))?;
+            let data = frame.lines().find_map(|l| l.strip_prefix("data: "))?;
+            Some((event.to_string(), serde_json::from_str(data).unwrap()))
+        })
+        .collect()
+}
+
+#[actix_web::test]
+async fn riders_follow_their_trip_as_server_sent_events() {
+    let Some(pool) = common::test_pool() else { return };
+    let (trip, driver) = {
+        use logic::schema::back_trips::dsl::*;
+
+        let mut conn = pool.get().unwrap();
+        let driver = common::insert_driver(&mut conn, "busy", (6.4700, 3.3958));
+        assigned_trip(&mut conn, "trips-events");
+        diesel::update(back_trips.filter(reference.eq("trips-events")))
+            .set(driver_id.eq(driver))
+            .execute(&mut conn)
+            .unwrap();
+        (get_trip_by_reference(&mut conn, "trips-events").unwrap(), driver)
+    };
+    let app = init_service(
+        App::new()
+            .wrap(from_fn(authenticate))
+            .app_data(common::authenticator())
+            .app_data(web::Data::new(pool))
+            .service(trips::routes())
+            .service(matching::routes()),
+    ).await;
+
+    let stranger_id = Uuid::new_v4();
+    let stranger = TestRequest::get()
+        .uri(&format!("/trips/trips-events/events?rider_id={}", stranger_id))
+        .insert_header(common::bearer(Role::Rider, stranger_id))
+        .to_request();
+    assert_eq!(call_service(&app, stranger).await.status(), 403);
+
+    let unknown = TestRequest::get()
+        .uri(&format!("/trips/no-such-trip/events?rider_id={}", trip.rider_id))
+        .insert_header(common::bearer(Role::Rider, trip.rider_id))
+        .to_request();
+    assert_eq!(call_service(&app, unknown).await.status(), 404);
+    // Turned away before anything was subscribed
+    assert!(!gateway::is_watched("no-such-trip"));
+
+    let watch = TestRequest::get()
+        .uri(&format!("/trips/trips-events/events?rider_id={}", trip.rider_id))
+        .insert_header(common::bearer(Role::Rider, trip.rider_id))
+        .to_request();
+    let stream = call_service(&app, watch).await;
+    assert_eq!(stream.status(), 200);
+    assert_eq!(stream.headers().get("content-type").unwrap(), "text/event-stream");
+
+    // The driver moves closer, then calls the trip off
+    let moved = TestRequest::post()
+        .uri(&format!("/matching/process-geolocation/{}", driver))
+        .insert_header(common::bearer(Role::Driver, driver))
+        .set_json(serde_json::json!({ "lat": 6.4600, "lng": 3.3958, "name": "Marina", "kind": "driver_location" }))
+        .to_request();
+    assert_eq!(call_service(&app, moved).await.status(), 200);
+
+    let cancel = TestRequest::post()
+        .uri("/trips/cancel-trip/trips-events")
+        .insert_header(common::bearer(Role::Driver, driver))
+        .set_json(serde_json::json!({ "cancelled_by": "driver", "actor_id": driver, "reason": "change_of_plans" }))
+        .to_request();
+    assert_eq!(call_service(&app, cancel).await.status(), 200);
+
+    // The stream ends by itself after the cancellation
+    let Ok(body) = actix_web::body::to_bytes(stream.into_body()).await else { panic!("event stream failed") };
+    let events = sse_events(&body);
+    let names: Vec<&str> = events.iter().map(|(event, _)| event.as_str()).collect();
+    assert_eq!(names, vec!["trip_status", "driver_location", "driver_location", "trip_status"]);
+
+    assert_eq!(events[0].1["status"], "driver_assigned");
+    let (before, after) = (&events[1].1, &events[2].1);
+    assert_eq!(before["location"]["lat"], 6.47);
+    assert_eq!(after["location"]["lat"], 6.46);
+    assert!(after["eta_min"].as_i64().unwrap() < before["eta_min"].as_i64().unwrap());
+    assert_eq!(after["eta"].as_str().unwrap().len(), "HH:MM".len());
+    assert_eq!(events[3].1["status"], "cancelled");
+}
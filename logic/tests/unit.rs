@@ -1,10 +1,31 @@
 use logic::services::pricing::{
-    GeoPoint, distance_between, minimum_distance_between_driver_and_pickup,
-    calculate_asap, calculate_express, estimated_time_min,
+    GeoPoint, distance_between, minimum_distance_between_driver_and_pickup, estimated_time_min,
+    travel_time_min, decode_polyline, encode_polyline, HaversineRouter,
+};
+use logic::services::traffic::{
+    eta_report, fit, path_km, EtaError, TrafficFactor, TrafficSettings, TrafficTable, TripSample,
+    DEFAULT_TRAFFIC_FACTOR,
+};
+use logic::services::tariffs::{ Fare, Rounding, ServiceZone, Tariff, TariffBook, DEFAULT_ZONE };
+use logic::services::surge::{ readings_by_zone, SurgeBoard, SurgeSettings };
+use std::collections::HashMap;
+use logic::api::riders::{
+    RideType, ItemDetails, DispatchPlan, DispatchStrategy, CreateRideRequest, NewRideRequest,
+};
+use logic::api::drivers::DriverResponse;
+use logic::services::gateway::{ Connection, Envelope, Hub, Peer, Resume, ServerMessage, RESUME_WINDOW };
+use logic::services::bus::BusEvent;
+use std::time::{ Duration, Instant };
+use logic::api::trips::{
+    CancelReason, CancellationPolicy, DriverMilestone, Trip, TripError, TripParty, TripStatus,
 };
-use logic::api::riders::{RideType, ItemDetails};
-use logic::api::trips::Trip;
 use logic::services::escrow::{vec_to_array_32, i64_to_u64};
+use logic::services::matching::{rank_candidates, vehicle_types_for, DispatchSettings};
+use logic::api::drivers::{ reservation_secs, Driver };
+use logic::services::geoindex::DriverIndex;
+use logic::api::auth::{ AuthError, Authenticator, Permission, Principal, Role };
+use logic::services::ratelimit::{ Bucket, Limit, RateLimiter };
+use logic::services::quotes::{ FareQuote, QuoteError, QuoteSigner, QUOTE_MATCH_KM };
 use uuid::Uuid;
 
 
@@ -58,65 +79,769 @@ fn driver_exactly_at_pickup_returns_true() {
 }
 
 
+// ─── rank_candidates ─────────────────────────────────────────────────────────
+
+fn driver_at(location: serde_json::Value) -> Driver {
+    Driver {
+        driver_id: Uuid::new_v4(),
+        driver_pubkey: serde_json::json!("pubkey"),
+        name: "Driver".to_string(),
+        email: "driver@test.com".to_string(),
+        phone: "+2348000000001".to_string(),
+        status: "available".to_string(),
+        driver_location: location,
+        license_number: None,
+        vehicle_type: "EV".to_string(),
+        driver_response: serde_json::json!({}),
+        vehicle: None,
+        reserved_for: None,
+        reserved_until: None,
+    }
+}
+
+fn driver_near(lat: f64, lng: f64) -> Driver {
+    driver_at(serde_json::json!({ "lat": lat, "lng": lng, "name": null }))
+}
+
+const PICKUP: GeoPoint = GeoPoint { lat: 6.4531, lng: 3.3958, name: None };
+
+#[test]
+fn candidates_are_ordered_nearest_first() {
+    let far = driver_near(6.4800, 3.3958);   // ~3 km
+    let near = driver_near(6.4540, 3.3958);  // ~100 m
+    let mid = driver_near(6.4650, 3.3958);   // ~1.3 km
+    let ids = [near.driver_id, mid.driver_id, far.driver_id];
+
+    let settings = DispatchSettings { radius_km: 5.0, max_candidates: 10 };
+    let ranked = rank_candidates(vec![far, near, mid], &PICKUP, &settings);
+
+    let order: Vec<Uuid> = ranked.iter().map(|c| c.driver.driver_id).collect();
+    assert_eq!(order, ids);
+    assert!(ranked[0].distance_km < 0.2);
+}
+
+#[test]
+fn candidates_outside_radius_are_dropped() {
+    let settings = DispatchSettings { radius_km: 2.0, max_candidates: 10 };
+    let ranked = rank_candidates(
+        vec![driver_near(6.4540, 3.3958), driver_near(6.4800, 3.3958)],
+        &PICKUP,
+        &settings,
+    );
+    assert_eq!(ranked.len(), 1);
+}
+
+#[test]
+fn candidate_count_is_capped_after_sorting() {
+    let drivers: Vec<Driver> = (1..=6).rev().map(|i| driver_near(6.4531 + i as f64 * 0.001, 3.3958)).collect();
+    let nearest = drivers.last().unwrap().driver_id;
+
+    let settings = DispatchSettings { radius_km: 5.0, max_candidates: 2 };
+    let ranked = rank_candidates(drivers, &PICKUP, &settings);
+    assert_eq!(ranked.len(), 2);
+    assert_eq!(ranked[0].driver.driver_id, nearest);
+}
+
+#[test]
+fn driver_with_unparseable_location_is_skipped() {
+    let settings = DispatchSettings { radius_km: 5.0, max_candidates: 10 };
+    let ranked = rank_candidates(
+        vec![driver_at(serde_json::json!("Lekki")), driver_near(6.4540, 3.3958)],
+        &PICKUP,
+        &settings,
+    );
+    assert_eq!(ranked.len(), 1);
+}
+
+#[test]
+fn dispatch_settings_default_to_five_km_and_ten_drivers() {
+    let settings = DispatchSettings::for_ride_type(&RideType::ASAP);
+    assert_eq!(settings, DispatchSettings { radius_km: 5.0, max_candidates: 10 });
+}
+
+#[test]
+fn dispatch_settings_are_read_per_ride_type() {
+    std::env::set_var("DISPATCH_ASAPEXPRESS_RADIUS_KM", "8.5");
+    std::env::set_var("DISPATCH_ASAPEXPRESS_MAX_CANDIDATES", "25");
+    let settings = DispatchSettings::for_ride_type(&RideType::ASAPEXPRESS);
+    assert_eq!(settings, DispatchSettings { radius_km: 8.5, max_candidates: 25 });
+}
+
+#[test]
+fn ride_types_map_to_vehicle_types() {
+    assert_eq!(vehicle_types_for(&RideType::ASAP), vec!["EV"]);
+    assert_eq!(vehicle_types_for(&RideType::ASAPEXPRESS), vec!["Bike"]);
+}
+
+
+// ─── DispatchPlan ────────────────────────────────────────────────────────────
+
+#[test]
+fn dispatch_strategy_parses_names() {
+    assert_eq!(DispatchStrategy::parse("sequential", 3), Some(DispatchStrategy::Sequential));
+    assert_eq!(DispatchStrategy::parse(" Broadcast ", 4), Some(DispatchStrategy::Broadcast { fan_out: 4 }));
+    assert_eq!(DispatchStrategy::parse("round-robin", 3), None);
+}
+
+#[test]
+fn dispatch_strategy_offers_at_least_one_driver_at_a_time() {
+    assert_eq!(DispatchStrategy::Sequential.offers_at_once(), 1);
+    assert_eq!(DispatchStrategy::Broadcast { fan_out: 5 }.offers_at_once(), 5);
+    assert_eq!(DispatchStrategy::Broadcast { fan_out: 0 }.offers_at_once(), 1);
+}
+
+#[test]
+fn dispatch_radius_grows_one_ring_per_round() {
+    let base = DispatchSettings { radius_km: 5.0, max_candidates: 10 };
+    let plan = DispatchPlan {
+        strategy: DispatchStrategy::Broadcast { fan_out: 3 },
+        rounds: 4,
+        ring_km: 2.5,
+        offer_timeout: std::time::Duration::from_secs(50),
+    };
+
+    let radii: Vec<f64> = (1..=plan.rounds).map(|round| plan.radius_for_round(&base, round)).collect();
+    assert_eq!(radii, vec![5.0, 7.5, 10.0, 12.5]);
+}
+
+#[test]
+fn reservation_outlasts_the_offer_however_long_it_waits() {
+    for secs in [10, 50, 60, 300] {
+        assert!(reservation_secs(std::time::Duration::from_secs(secs)) > secs as i64);
+    }
+}
+
+
+// ─── Gateway hub ─────────────────────────────────────────────────────────────
+
+fn ride_offer() -> NewRideRequest {
+    let rider = Uuid::new_v4();
+    let drop_off = GeoPoint { lat: 6.4280, lng: 3.4219, name: None };
+    let route = HaversineRouter.straight_line(&PICKUP, &drop_off, &RideType::ASAP);
+    let quote = FareQuote::price(rider, RideType::ASAP, PICKUP, drop_off.clone(), &route, 0, 300);
+    NewRideRequest::new(CreateRideRequest {
+        rider_id: rider,
+        pick_up: PICKUP,
+        drop_off,
+        ride_type: RideType::ASAP,
+        payment_method: "card".to_string(),
+        items: vec![],
+        order_id: None,
+        user_id: None,
+        user_phone_number: None,
+        vendor_phone_number: None,
+        quote_token: None,
+    }, &quote)
+}
+
+fn drain(session: &mut Connection) -> Vec<Envelope> {
+    std::iter::from_fn(|| session.messages.try_recv().ok()).collect()
+}
+
+fn kinds(envelopes: &[Envelope]) -> Vec<(u64, &'static str)> {
+    envelopes.iter().map(|e| (e.seq, e.message.kind())).collect()
+}
+
+fn offer(driver: Uuid, ride: &NewRideRequest) -> BusEvent {
+    BusEvent::Offer { driver_id: driver, ride: Box::new(ride.clone()) }
+}
+
+#[test]
+fn watchers_that_went_away_are_dropped_on_the_next_watch() {
+    let mut hub = Hub::default();
+
+    let gone = hub.watch_trip("trip-left");
+    drop(gone);
+    let _live = hub.watch_trip("trip-open");
+
+    assert!(!hub.is_watched("trip-left"));
+    assert!(hub.is_watched("trip-open"));
+}
+
+#[test]
+fn offers_need_a_driver_session() {
+    let mut hub = Hub::default();
+    assert!(hub.apply(offer(Uuid::new_v4(), &ride_offer()), Instant::now()).is_empty());
+}
+
+#[test]
+fn a_driver_answers_the_offer_they_were_sent() {
+    let mut hub = Hub::default();
+    let now = Instant::now();
+    let driver = Uuid::new_v4();
+    let ride = ride_offer();
+
+    let mut session = hub.connect(Peer::Driver(driver), None, now);
+    let (mut delivered, mut answer) = hub.await_answer(driver, ride.request_id);
+    let follow_ups = hub.apply(offer(driver, &ride), now);
+    assert_eq!(kinds(&drain(&mut session)), vec![(0, "welcome"), (1, "ride_offer")]);
+    assert!(delivered.try_recv().is_err());
+
+    // The offer is confirmed back to the dispatching side
+    assert!(matches!(follow_ups.as_slice(), [BusEvent::Offered { .. }]));
+    for event in follow_ups {
+        hub.apply(event, now);
+    }
+    assert!(delivered.try_recv().is_ok());
+
+    let stale = BusEvent::Answer { driver_id: driver, request_id: Uuid::new_v4(), response: DriverResponse::Accepted };
+    hub.apply(stale, now);
+    assert!(answer.try_recv().is_err());
+    assert!(hub.has_offer(driver));
+
+    let current = BusEvent::Answer { driver_id: driver, request_id: ride.request_id, response: DriverResponse::Accepted };
+    hub.apply(current, now);
+    assert!(matches!(answer.try_recv(), Ok(DriverResponse::Accepted)));
+    assert!(!hub.has_offer(driver));
+}
+
+#[test]
+fn an_answer_that_overtakes_the_delivery_counts_as_delivered() {
+    let mut hub = Hub::default();
+    let now = Instant::now();
+    let driver = Uuid::new_v4();
+    let ride = ride_offer();
+
+    let (mut delivered, mut answer) = hub.await_answer(driver, ride.request_id);
+    let current = BusEvent::Answer { driver_id: driver, request_id: ride.request_id, response: DriverResponse::Accepted };
+    hub.apply(current, now);
+    assert!(delivered.try_recv().is_ok());
+    assert!(matches!(answer.try_recv(), Ok(DriverResponse::Accepted)));
+}
+
+#[test]
+fn withdrawn_offers_are_taken_off_the_drivers_app() {
+    let mut hub = Hub::default();
+    let now = Instant::now();
+    let driver = Uuid::new_v4();
+    let ride = ride_offer();
+
+    let mut session = hub.connect(Peer::Driver(driver), None, now);
+    let (_delivered, mut answer) = hub.await_answer(driver, ride.request_id);
+    hub.apply(offer(driver, &ride), now);
+    hub.forget_answer(driver, ride.request_id);
+    hub.apply(BusEvent::Withdraw { driver_id: driver, request_id: ride.request_id }, now);
+
+    assert!(!hub.has_offer(driver));
+    assert!(answer.try_recv().is_err());
+    assert_eq!(kinds(&drain(&mut session)), vec![(0, "welcome"), (1, "ride_offer"), (2, "offer_withdrawn")]);
+}
+
+#[test]
+fn aborting_a_request_stops_only_its_dispatch() {
+    let mut hub = Hub::default();
+    let now = Instant::now();
+    let (cancelled, other) = (Uuid::new_v4(), Uuid::new_v4());
+
+    let mut aborted = hub.watch_dispatch(cancelled);
+    let mut running = hub.watch_dispatch(other);
+    hub.apply(BusEvent::Abort { request_id: cancelled }, now);
+
+    assert!(aborted.try_recv().is_ok());
+    assert!(running.try_recv().is_err());
+
+    // A dispatch that already ended isn't there to abort
+    hub.forget_dispatch(other);
+    assert!(hub.apply(BusEvent::Abort { request_id: other }, now).is_empty());
+}
+
+#[test]
+fn bus_events_round_trip_through_json() {
+    let event = BusEvent::Deliver {
+        peer: Peer::Rider(Uuid::nil()),
+        message: ServerMessage::OfferWithdrawn { request_id: Uuid::nil() },
+    };
+    let json = serde_json::to_value(&event).unwrap();
+    assert_eq!(json, serde_json::json!({
+        "event": "deliver",
+        "peer": { "role": "rider", "id": Uuid::nil() },
+        "message": { "type": "offer_withdrawn", "request_id": Uuid::nil() },
+    }));
+    assert!(matches!(serde_json::from_value(json).unwrap(), BusEvent::Deliver { peer: Peer::Rider(_), .. }));
+}
+
+#[test]
+fn resuming_replays_what_was_missed() {
+    let mut hub = Hub::default();
+    let now = Instant::now();
+    let rider = Uuid::new_v4();
+    let status = |reference: &str| ServerMessage::TripStatus {
+        trip_reference: reference.to_string(),
+        status: TripStatus::DriverAssigned,
+    };
+
+    let mut first = hub.connect(Peer::Rider(rider), None, now);
+    hub.send(Peer::Rider(rider), status("one"), now);
+    assert_eq!(kinds(&drain(&mut first)), vec![(0, "welcome"), (1, "trip_status")]);
+
+    hub.disconnect(first.session_id, first.connection, now);
+    assert!(hub.send(Peer::Rider(rider), status("two"), now));
+    assert!(hub.send(Peer::Rider(rider), status("three"), now));
+
+    let resume = Resume { session_id: first.session_id, last_seq: 1 };
+    let mut second = hub.connect(Peer::Rider(rider), Some(resume), now);
+    assert!(second.resumed);
+    assert_eq!(second.session_id, first.session_id);
+    assert_eq!(kinds(&drain(&mut second)), vec![(0, "welcome"), (2, "trip_status"), (3, "trip_status")]);
+
+    // The old socket's late disconnect doesn't detach the new one
+    hub.disconnect(first.session_id, first.connection, now);
+    hub.send(Peer::Rider(rider), status("four"), now);
+    assert_eq!(kinds(&drain(&mut second)), vec![(4, "trip_status")]);
+}
+
+#[test]
+fn a_fresh_connection_is_offered_the_pending_ride_again() {
+    let mut hub = Hub::default();
+    let now = Instant::now();
+    let driver = Uuid::new_v4();
+
+    let first = hub.connect(Peer::Driver(driver), None, now);
+    hub.apply(offer(driver, &ride_offer()), now);
+    hub.disconnect(first.session_id, first.connection, now);
+
+    let mut second = hub.connect(Peer::Driver(driver), None, now);
+    assert!(!second.resumed);
+    assert_ne!(second.session_id, first.session_id);
+    assert_eq!(kinds(&drain(&mut second)), vec![(0, "welcome"), (1, "ride_offer")]);
+}
+
+#[test]
+fn sessions_expire_after_the_resume_window() {
+    let mut hub = Hub::default();
+    let now = Instant::now();
+    let driver = Uuid::new_v4();
+
+    let session = hub.connect(Peer::Driver(driver), None, now);
+    hub.disconnect(session.session_id, session.connection, now);
+    assert!(hub.is_reachable(Peer::Driver(driver), now + RESUME_WINDOW));
+
+    let later = now + RESUME_WINDOW + Duration::from_secs(1);
+    assert!(!hub.is_reachable(Peer::Driver(driver), later));
+    assert!(hub.apply(offer(driver, &ride_offer()), later).is_empty());
+}
+
+#[test]
+fn envelopes_put_the_seq_next_to_the_message_type() {
+    let envelope = Envelope {
+        seq: 7,
+        message: ServerMessage::OfferWithdrawn { request_id: Uuid::nil() },
+    };
+    let json = serde_json::to_value(&envelope).unwrap();
+    assert_eq!(json, serde_json::json!({
+        "seq": 7,
+        "type": "offer_withdrawn",
+        "request_id": Uuid::nil(),
+    }));
+}
+
+
+// ─── DriverIndex ─────────────────────────────────────────────────────────────
+
+fn ev() -> Vec<String> {
+    vec!["EV".to_string()]
+}
+
+#[test]
+fn index_returns_k_nearest_in_order() {
+    let near = driver_near(6.4540, 3.3958);
+    let mid = driver_near(6.4650, 3.3958);
+    let far = driver_near(6.4800, 3.3958);
+    let expected = vec![near.driver_id, mid.driver_id];
+    let index = DriverIndex::from_drivers(1.0, vec![far, mid, near]);
+
+    let found: Vec<Uuid> = index.nearest(&PICKUP, &ev(), 5.0, 2).into_iter().map(|(id, _)| id).collect();
+    assert_eq!(found, expected);
+}
+
+#[test]
+fn index_skips_other_vehicles_busy_drivers_and_far_ones() {
+    let mut bike = driver_near(6.4540, 3.3958);
+    bike.vehicle_type = "Bike".to_string();
+    let mut busy = driver_near(6.4541, 3.3958);
+    busy.status = "busy".to_string();
+    let far = driver_near(6.5531, 3.3958); // ~11 km
+    let index = DriverIndex::from_drivers(1.0, vec![bike, busy, far]);
+
+    assert!(index.nearest(&PICKUP, &ev(), 5.0, 10).is_empty());
+}
+
+#[test]
+fn index_follows_moves_across_cells() {
+    let driver = driver_near(6.5531, 3.3958);
+    let id = driver.driver_id;
+    let mut index = DriverIndex::from_drivers(1.0, vec![driver]);
+    assert!(index.nearest(&PICKUP, &ev(), 5.0, 1).is_empty());
+
+    assert!(index.update_location(id, GeoPoint { lat: 6.4535, lng: 3.3958, name: None }));
+    let found = index.nearest(&PICKUP, &ev(), 5.0, 1);
+    assert_eq!(found[0].0, id);
+    assert!(found[0].1 < 0.1);
+}
+
+#[test]
+fn index_status_changes_hide_and_show_drivers() {
+    let driver = driver_near(6.4540, 3.3958);
+    let id = driver.driver_id;
+    let mut index = DriverIndex::from_drivers(1.0, vec![driver]);
+
+    index.set_status(id, "reserved");
+    assert!(index.nearest(&PICKUP, &ev(), 5.0, 1).is_empty());
+    index.set_status(id, "available");
+    assert_eq!(index.nearest(&PICKUP, &ev(), 5.0, 1).len(), 1);
+
+    index.remove(&id);
+    assert!(index.is_empty());
+}
+
+#[test]
+fn unknown_driver_location_update_is_reported() {
+    let mut index = DriverIndex::new(1.0);
+    assert!(!index.update_location(Uuid::new_v4(), PICKUP));
+    assert!(!index.is_warm());
+}
+
+#[test]
+fn index_matches_ranking_every_row() {
+    // Same answer as measuring every driver, across cell boundaries
+    let drivers: Vec<Driver> = (0..400)
+        .map(|i| driver_near(6.40 + (i % 20) as f64 * 0.006, 3.34 + (i / 20) as f64 * 0.006))
+        .collect();
+    let settings = DispatchSettings { radius_km: 3.0, max_candidates: 15 };
+    let by_rank: Vec<Uuid> = rank_candidates(drivers.clone(), &PICKUP, &settings)
+        .into_iter()
+        .map(|c| c.driver.driver_id)
+        .collect();
+
+    let index = DriverIndex::from_drivers(0.5, drivers);
+    let by_index: Vec<Uuid> = index.nearest(&PICKUP, &ev(), 3.0, 15).into_iter().map(|(id, _)| id).collect();
+    assert_eq!(by_index, by_rank);
+}
+
+
 // ─── Pricing ─────────────────────────────────────────────────────────────────
 
+fn tariff(ride_type: &RideType, zone: &str, effective_from: i64, base_fare: f64) -> Tariff {
+    Tariff { tariff_id: Uuid::new_v4(), zone: zone.to_string(), base_fare, effective_from, ..Tariff::builtin(ride_type) }
+}
+
+fn zone(id: &str, (lat, lng): (f64, f64), radius_km: f64) -> ServiceZone {
+    ServiceZone { zone: id.to_string(), name: id.to_string(), center_lat: lat, center_lng: lng, radius_km, updated_at: 0 }
+}
+
 #[test]
-fn calculate_asap_base_fare_only() {
+fn builtin_tariff_base_fare_only() {
     // 0 km, 0 min → base fare only = 900
-    assert_eq!(calculate_asap(0.0, 0), 900);
+    assert_eq!(Tariff::builtin(&RideType::ASAP).fare(0.0, 0), 900);
 }
 
 #[test]
-fn calculate_asap_with_distance_and_time() {
+fn builtin_tariff_with_distance_and_time() {
     // 900 + (10 * 25.76) + (20 * 12.267) = 900 + 257.6 + 245.34 = 1402.94 → 1403
-    assert_eq!(calculate_asap(10.0, 20), 1403);
+    assert_eq!(Tariff::builtin(&RideType::ASAP).fare(10.0, 20), 1403);
+    assert_eq!(Tariff::builtin(&RideType::ASAPEXPRESS).fare(10.0, 20), 1403);
 }
 
 #[test]
-fn calculate_express_base_fare_only() {
-    assert_eq!(calculate_express(0.0, 0), 900);
+fn fares_are_rounded_then_held_to_the_minimum() {
+    let mut rates = Tariff::builtin(&RideType::ASAP);
+    rates.round_to = 50;
+    rates.rounding = Rounding::Up;
+    assert_eq!(rates.fare(10.0, 20), 1450);
+    rates.rounding = Rounding::Down;
+    assert_eq!(rates.fare(10.0, 20), 1400);
+    rates.rounding = Rounding::Nearest;
+    assert_eq!(rates.fare(10.0, 20), 1400);
+
+    rates.minimum_fare = 1500;
+    assert_eq!(rates.fare(10.0, 20), 1500);
+}
+
+#[test]
+fn a_pickup_is_priced_in_the_smallest_zone_it_falls_in() {
+    let book = TariffBook::new(
+        vec![zone("lagos", (6.5244, 3.3792), 40.0), zone("lagos-island", (6.4531, 3.3958), 3.0)],
+        vec![],
+    );
+    assert_eq!(book.zone_for(&GeoPoint { lat: 6.4535, lng: 3.3960, name: None }), "lagos-island");
+    assert_eq!(book.zone_for(&GeoPoint { lat: 6.6018, lng: 3.3515, name: None }), "lagos");
+    assert_eq!(book.zone_for(&GeoPoint { lat: 9.0765, lng: 7.3986, name: None }), DEFAULT_ZONE);
 }
 
 #[test]
-fn calculate_express_matches_asap_at_same_inputs() {
-    // Both use identical rates currently — this test documents that intentionally
-    assert_eq!(calculate_asap(5.0, 10), calculate_express(5.0, 10));
+fn the_latest_tariff_that_has_started_is_in_effect() {
+    let asap = RideType::ASAP;
+    let old = tariff(&asap, "lagos", 100, 1000.0);
+    let current = tariff(&asap, "lagos", 200, 1200.0);
+    let scheduled = tariff(&asap, "lagos", 300, 1500.0);
+    let fallback = tariff(&asap, DEFAULT_ZONE, 0, 800.0);
+    let express = tariff(&RideType::ASAPEXPRESS, "lagos", 0, 2000.0);
+    let book = TariffBook::new(
+        vec![zone("lagos", (6.5244, 3.3792), 40.0)],
+        vec![old.clone(), current.clone(), scheduled, fallback.clone(), express.clone()],
+    );
+
+    assert_eq!(book.in_effect(&asap, "lagos", 150).unwrap().tariff_id, old.tariff_id);
+    assert_eq!(book.in_effect(&asap, "lagos", 250).unwrap().tariff_id, current.tariff_id);
+    // Before the zone had its own rates, and in zones that never did
+    assert_eq!(book.in_effect(&asap, "lagos", 50).unwrap().tariff_id, fallback.tariff_id);
+    assert_eq!(book.in_effect(&asap, "abuja", 250).unwrap().tariff_id, fallback.tariff_id);
+
+    let fare = book.quote(&RideType::ASAPEXPRESS, "lagos", 0.0, 0, 250, 1.0);
+    assert_eq!(
+        fare,
+        Fare { amount: 2000, tariff_id: Some(express.tariff_id), zone: "lagos".to_string(), surge_multiplier: 1.0 },
+    );
+    assert_ne!(fare.amount, book.quote(&asap, "lagos", 0.0, 0, 250, 1.0).amount);
+}
+
+#[test]
+fn nothing_loaded_prices_at_the_builtin_rates() {
+    let fare = TariffBook::default().quote(&RideType::ASAP, DEFAULT_ZONE, 10.0, 20, 0, 1.0);
+    assert_eq!(fare, Fare { amount: 1403, tariff_id: None, zone: DEFAULT_ZONE.to_string(), surge_multiplier: 1.0 });
+}
+
+#[test]
+fn surge_is_applied_before_rounding_and_the_minimum() {
+    let mut rates = Tariff::builtin(&RideType::ASAP);
+    // 1402.94 * 1.5 = 2104.41
+    assert_eq!(rates.surged_fare(10.0, 20, 1.5), 2104);
+    rates.round_to = 100;
+    rates.rounding = Rounding::Up;
+    assert_eq!(rates.surged_fare(10.0, 20, 1.5), 2200);
+    rates.minimum_fare = 5000;
+    assert_eq!(rates.surged_fare(10.0, 20, 1.5), 5000);
+}
+
+
+// ─── Surge ───────────────────────────────────────────────────────────────────
+
+#[test]
+fn surge_targets_requests_per_driver_over_the_threshold() {
+    let settings = SurgeSettings::default();
+    assert_eq!(settings.target(3, 3), 1.0);
+    assert_eq!(settings.target(0, 0), 1.0);
+    // 2 requests a driver, one over the threshold
+    assert_eq!(settings.target(10, 5), 1.5);
+    // Nobody to take them, counted as one driver
+    assert_eq!(settings.target(3, 0), 2.0);
+    assert_eq!(settings.target(100, 1), settings.max_multiplier);
+}
+
+#[test]
+fn surge_moves_part_way_and_never_more_than_a_step() {
+    let settings = SurgeSettings::default();
+    assert_eq!(settings.smooth(1.0, 1.5), 1.25);
+    // Halfway to 2.5 would be 1.75, the step holds it to 1.5
+    assert_eq!(settings.smooth(1.0, 2.5), 1.5);
+    assert_eq!(settings.smooth(2.0, 1.0), 1.5);
+    assert_eq!(settings.smooth(1.1, 0.5), 1.0);
+}
+
+#[test]
+fn the_surge_board_eases_off_and_expires() {
+    let settings = SurgeSettings::default();
+    let mut board = SurgeBoard::new(settings);
+    let busy: HashMap<String, (u32, u32)> = HashMap::from([("lagos".to_string(), (20, 2))]);
+
+    board.update(&busy, 1000);
+    let surge = board.current("lagos", 1000);
+    assert_eq!((surge.multiplier, surge.demand, surge.supply), (1.5, 20, 2));
+    assert_eq!(surge.expires_at, 1000 + settings.ttl_secs);
+    board.update(&busy, 1060);
+    assert_eq!(board.current("lagos", 1060).multiplier, 2.0);
+    assert_eq!(board.surging(1060).len(), 1);
+
+    // Requests dry up, it comes back down a step at a time
+    board.update(&HashMap::new(), 1120);
+    assert_eq!(board.current("lagos", 1120).multiplier, 1.5);
+
+    // Nobody recomputes it, it stops applying
+    let stale = board.current("lagos", 1120 + settings.ttl_secs);
+    assert_eq!(stale.multiplier, 1.0);
+    assert!(board.surging(1120 + settings.ttl_secs).is_empty());
+    assert_eq!(board.current("abuja", 1120).multiplier, 1.0);
+}
+
+#[test]
+fn surge_readings_count_requests_and_drivers_per_zone() {
+    let book = TariffBook::new(vec![zone("lagos-island", (6.4531, 3.3958), 3.0)], vec![]);
+    let abuja = GeoPoint { lat: 9.0765, lng: 7.3986, name: None };
+    let readings = readings_by_zone(&book, &[PICKUP, PICKUP, abuja.clone()], &[PICKUP, abuja.clone(), abuja]);
+    assert_eq!(readings["lagos-island"], (2, 1));
+    assert_eq!(readings[DEFAULT_ZONE], (1, 2));
 }
 
 #[test]
 fn estimated_time_asap_30km() {
     // 30 / (30 * 1.3) = 0.7692 hrs = 46.15 min → 46
-    assert_eq!(estimated_time_min(30.0, &RideType::ASAP), 46);
+    assert_eq!(travel_time_min(30.0, &RideType::ASAP, DEFAULT_TRAFFIC_FACTOR), 46);
 }
 
 #[test]
 fn estimated_time_express_30km() {
     // 30 / (40 * 1.3) = 0.5769 hrs = 34.6 min → 35
-    assert_eq!(estimated_time_min(30.0, &RideType::ASAPEXPRESS), 35);
+    assert_eq!(travel_time_min(30.0, &RideType::ASAPEXPRESS, DEFAULT_TRAFFIC_FACTOR), 35);
 }
 
 #[test]
 fn express_is_faster_than_asap_for_same_distance() {
-    let asap_time    = estimated_time_min(20.0, &RideType::ASAP);
-    let express_time = estimated_time_min(20.0, &RideType::ASAPEXPRESS);
+    let asap_time    = estimated_time_min(20.0, &RideType::ASAP, &PICKUP);
+    let express_time = estimated_time_min(20.0, &RideType::ASAPEXPRESS, &PICKUP);
     assert!(express_time < asap_time, "express should be faster than asap");
 }
 
+#[test]
+fn polylines_encode_like_googles_example() {
+    let points = [(38.5, -120.2), (40.7, -120.95), (43.252, -126.453)];
+    let encoded = encode_polyline(&points);
+    assert_eq!(encoded, "_p~iF~ps|U_ulLnnqC_mqNvxq`@");
+    assert_eq!(decode_polyline(&encoded), Some(points.to_vec()));
+    assert_eq!(decode_polyline("_p~iF~ps|U_ulL"), None);
+    assert_eq!(decode_polyline(""), Some(vec![]));
+}
+
+#[test]
+fn haversine_routes_are_the_straight_line() {
+    let drop_off = GeoPoint { lat: 6.4280, lng: 3.4219, name: None };
+    let route = HaversineRouter.straight_line(&PICKUP, &drop_off, &RideType::ASAPEXPRESS);
+    assert_eq!(route.distance_km, distance_between(&PICKUP, &drop_off));
+    assert_eq!(route.duration_min, estimated_time_min(route.distance_km, &RideType::ASAPEXPRESS, &PICKUP));
+    assert_eq!(decode_polyline(&route.polyline), Some(vec![(PICKUP.lat, PICKUP.lng), (drop_off.lat, drop_off.lng)]));
+    assert_eq!(route.source, "haversine");
+}
+
+
+// ─── Traffic factors ─────────────────────────────────────────────────────────
+
+fn monday_midnight_wat() -> i64 {
+    chrono::NaiveDate::from_ymd_opt(2026, 10, 19).unwrap().and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp() - 3600
+}
+
+// 10 km by ASAP in `minutes`, observed factor 20 / minutes
+fn sample(zone: &str, hour_of_week: i32, minutes: f64) -> TripSample {
+    TripSample {
+        zone: zone.to_string(),
+        hour_of_week,
+        ride_type: RideType::ASAP,
+        distance_km: 10.0,
+        actual_min: minutes,
+        quoted_min: Some(26),
+    }
+}
+
+fn factor(zone: &str, hour_of_week: i32, factor: f64) -> TrafficFactor {
+    TrafficFactor { zone: zone.to_string(), hour_of_week, factor, samples: 5, fitted_at: 0 }
+}
+
+#[test]
+fn hours_of_the_week_start_monday_midnight_local() {
+    let settings = TrafficSettings::default();
+    let monday = monday_midnight_wat();
+    assert_eq!(settings.hour_of_week(monday), 0);
+    assert_eq!(settings.hour_of_week(monday + 3599), 0);
+    assert_eq!(settings.hour_of_week(monday + 8 * 3600), 8);
+    assert_eq!(settings.hour_of_week(monday - 1), 167);
+    assert_eq!(settings.hour_of_week(monday + 7 * 86_400), 0);
+
+    let utc = TrafficSettings { utc_offset_hours: 0, ..settings };
+    assert_eq!(utc.hour_of_week(monday), 167);
+}
+
+#[test]
+fn factors_are_the_median_of_each_zone_and_hour() {
+    let settings = TrafficSettings::default();
+    // Observed 2.0, 1.0, 0.5, 1.25, 0.8 at 8am, one trip at 9am
+    let mut samples: Vec<TripSample> = [10.0, 20.0, 40.0, 16.0, 25.0].iter().map(|&m| sample("ikeja", 8, m)).collect();
+    samples.push(sample("ikeja", 9, 20.0));
+
+    let fitted = fit(&samples, &settings, 1_000);
+    assert_eq!(fitted, vec![TrafficFactor { zone: "ikeja".into(), hour_of_week: 8, factor: 1.0, samples: 5, fitted_at: 1_000 }]);
+
+    let one_is_enough = TrafficSettings { min_samples: 1, ..settings };
+    let fitted = fit(&samples[..2], &one_is_enough, 1_000);
+    assert_eq!(fitted[0].factor, 1.5);
+}
+
+#[test]
+fn fitted_factors_stay_within_bounds() {
+    let settings = TrafficSettings { min_samples: 1, ..TrafficSettings::default() };
+    // 10 km in a minute, and in ten hours
+    let fitted = fit(&[sample("ikeja", 8, 1.0), sample("ikeja", 9, 600.0)], &settings, 0);
+    assert_eq!(fitted[0].factor, settings.max_factor);
+    assert_eq!(fitted[1].factor, settings.min_factor);
+}
+
+#[test]
+fn zones_without_a_factor_fall_back_to_the_default_zone() {
+    let table = TrafficTable::new(
+        TrafficSettings::default(),
+        vec![factor("ikeja", 8, 2.0), factor(DEFAULT_ZONE, 8, 1.6)],
+    );
+    assert_eq!(table.factor_for("ikeja", 8), 2.0);
+    assert_eq!(table.factor_for("lekki", 8), 1.6);
+    assert_eq!(table.factor_for("ikeja", 9), DEFAULT_TRAFFIC_FACTOR);
+    assert_eq!(table.factor_at("ikeja", monday_midnight_wat() + 8 * 3600 + 60), 2.0);
+    assert_eq!(table.factors().first().map(|f| f.zone.as_str()), Some(DEFAULT_ZONE));
+}
+
+#[test]
+fn driven_paths_add_up_their_legs() {
+    let corner = GeoPoint { lat: PICKUP.lat, lng: 3.4219, name: None };
+    let drop_off = GeoPoint { lat: 6.4280, lng: 3.4219, name: None };
+    let path = [PICKUP, corner.clone(), drop_off.clone()];
+    assert_eq!(path_km(&path), PICKUP.distance_to(&corner) + corner.distance_to(&drop_off));
+    assert_eq!(path_km(&path[..1]), 0.0);
+}
+
+#[test]
+fn eta_errors_are_predicted_minus_actual() {
+    assert_eq!(EtaError::of(&[(30.0, 20.0), (10.0, 20.0)]), EtaError {
+        trips: 2,
+        mean_error_min: 0.0,
+        mean_abs_error_min: 10.0,
+        mean_abs_pct_error: 50.0,
+    });
+    assert_eq!(EtaError::of(&[]).trips, 0);
+}
+
+#[test]
+fn eta_report_compares_quotes_and_the_current_factors() {
+    let table = TrafficTable::new(TrafficSettings::default(), vec![factor("ikeja", 8, 1.0)]);
+    let mut untracked = sample("lekki", 8, 30.0);
+    untracked.quoted_min = None;
+    let samples = [sample("ikeja", 8, 20.0), untracked];
+
+    let report = eta_report(&samples, &table, 0);
+    // Quoted 26 against 20, and only the ikeja trip was quoted
+    assert_eq!(report.quoted.trips, 1);
+    assert_eq!(report.quoted.mean_error_min, 6.0);
+    // Ikeja's factor of 1 predicts 20, lekki's default 15 against 30
+    assert_eq!(report.current.trips, 2);
+    assert_eq!(report.current.mean_error_min, -7.5);
+    assert_eq!(report.zones.iter().map(|z| z.zone.as_str()).collect::<Vec<_>>(), vec!["ikeja", "lekki"]);
+    assert_eq!(report.zones[0].current.mean_abs_error_min, 0.0);
+}
+
 
 // ─── Trip ────────────────────────────────────────────────────────────────────
 
+fn geo_text(lat: f64, lng: f64) -> String {
+    serde_json::to_string(&GeoPoint { lat, lng, name: None }).unwrap()
+}
+
 fn make_trip() -> Trip {
     Trip {
         trip_id: vec![0u8; 32],
         rider_id: Uuid::new_v4(),
         reference: "ref-test-001".to_string(),
-        pick_up: "Lagos Island".to_string(),
-        drop_off: "Victoria Island".to_string(),
-        driver_location: "Lekki Phase 1".to_string(),
+        pick_up: geo_text(6.4531, 3.3958),
+        drop_off: geo_text(6.4280, 3.4219),
+        driver_location: geo_text(6.4474, 3.4720),
         rider_pubkey: "rider_pubkey_string".to_string(),
         driver_pubkey: "driver_pubkey_string".to_string(),
         driver_id: Uuid::new_v4(),
-        status: "Ongoing".to_string(),
+        status: TripStatus::InProgress,
         start_ts: 1700000000,
         end_ts: None,
         distance_km: 10.0,
@@ -124,26 +849,17 @@ fn make_trip() -> Trip {
         fare_estimate: Some(1500),
         fare_lamports: None,
         rider_email: "rider@test.com".to_string(),
+        onchain_signature: None,
+        arrived_at: None,
+        picked_up_at: None,
+        cancelled_by: None,
+        cancel_reason: None,
+        cancelled_at: None,
+        request_id: None,
+        cancellation_fee: None,
     }
 }
 
-#[test]
-fn trip_status_unchanged_when_driver_not_at_dropoff() {
-    let mut trip = make_trip();
-    trip.update_status();
-    assert_eq!(trip.status, "Ongoing");
-    assert!(trip.end_ts.is_none());
-}
-
-#[test]
-fn trip_status_completes_when_driver_at_dropoff() {
-    let mut trip = make_trip();
-    trip.driver_location = trip.drop_off.clone();
-    trip.update_status();
-    assert_eq!(trip.status, "Completed");
-    assert!(trip.end_ts.is_some());
-}
-
 #[test]
 fn trip_compute_fare_lamports_from_estimate() {
     let mut trip = make_trip(); // fare_estimate = 1500
@@ -161,12 +877,186 @@ fn trip_compute_fare_lamports_none_when_no_estimate() {
 }
 
 #[test]
-fn trip_end_ts_set_on_completion() {
+fn trip_eta_is_to_the_pickup_then_the_drop_off() {
+    let mut trip = make_trip();
+    let driver_at = GeoPoint { lat: 6.5531, lng: 3.3958, name: None };
+    let pickup = trip.pick_up_point().unwrap();
+    let drop_off = trip.drop_off_point().unwrap();
+
+    trip.status = TripStatus::DriverAssigned;
+    let to_pickup = estimated_time_min(driver_at.distance_to(&pickup), &RideType::ASAP, &driver_at);
+    assert_eq!(trip.eta_min(&driver_at, &RideType::ASAP), Some(to_pickup));
+    assert!(to_pickup > 0);
+
+    trip.status = TripStatus::InProgress;
+    let to_drop_off = estimated_time_min(driver_at.distance_to(&drop_off), &RideType::ASAPEXPRESS, &driver_at);
+    assert_eq!(trip.eta_min(&driver_at, &RideType::ASAPEXPRESS), Some(to_drop_off));
+
+    trip.status = TripStatus::Completed;
+    assert_eq!(trip.eta_min(&driver_at, &RideType::ASAP), None);
+}
+
+#[test]
+fn trip_eta_needs_stored_coordinates() {
     let mut trip = make_trip();
-    trip.driver_location = trip.drop_off.clone();
-    trip.update_status();
-    let ts = trip.end_ts.unwrap();
-    assert!(ts > 0, "end_ts should be a valid unix timestamp");
+    trip.status = TripStatus::DriverAssigned;
+    trip.pick_up = "Lagos Island".to_string();
+    assert_eq!(trip.eta_min(&PICKUP, &RideType::ASAP), None);
+}
+
+#[test]
+fn milestone_geofence_uses_pickup_then_dropoff() {
+    let trip = make_trip();
+    let at_pickup = GeoPoint { lat: 6.4535, lng: 3.3958, name: None };
+    assert!(DriverMilestone::Arrived.check_geofence(&trip, &at_pickup).is_ok());
+    assert!(DriverMilestone::PickedUp.check_geofence(&trip, &at_pickup).is_ok());
+
+    let err = DriverMilestone::DroppedOff.check_geofence(&trip, &at_pickup).unwrap_err();
+    match err {
+        TripError::OutsideGeofence { distance_m, allowed_m } => {
+            assert_eq!(allowed_m, 200);
+            assert!(distance_m > 3_000, "got {} m", distance_m);
+        }
+        other => panic!("expected OutsideGeofence, got {:?}", other),
+    }
+}
+
+#[test]
+fn milestone_geofence_needs_stored_coordinates() {
+    let mut trip = make_trip();
+    trip.pick_up = "Lagos Island".to_string();
+    let here = GeoPoint { lat: 6.4531, lng: 3.3958, name: None };
+    assert!(matches!(
+        DriverMilestone::Arrived.check_geofence(&trip, &here),
+        Err(TripError::InvalidRequest(_))
+    ));
+}
+
+
+// ─── CancelReason ────────────────────────────────────────────────────────────
+
+#[test]
+fn cancel_reason_labels_match_rider_prompt() {
+    assert_eq!(
+        CancelReason::labels(),
+        vec![
+            "Change of plans",
+            "Driver taking too long",
+            "Found alternate transport",
+            "Incorrect destination",
+        ]
+    );
+}
+
+#[test]
+fn cancel_reason_deserializes_from_code() {
+    let reason: CancelReason = serde_json::from_str("\"found_alternate_transport\"").unwrap();
+    assert_eq!(reason, CancelReason::FoundAlternateTransport);
+    assert_eq!(reason.as_str(), "found_alternate_transport");
+}
+
+#[test]
+fn driver_cannot_blame_themselves_for_lateness() {
+    assert!(CancelReason::DriverTakingTooLong.allowed_for(TripParty::Rider));
+    assert!(!CancelReason::DriverTakingTooLong.allowed_for(TripParty::Driver));
+    assert!(CancelReason::ChangeOfPlans.allowed_for(TripParty::Driver));
+}
+
+#[test]
+fn cancelling_is_free_within_the_grace_period() {
+    let policy = CancellationPolicy { fee: 500, grace_secs: 120 };
+    let mut trip = make_trip();
+    trip.status = TripStatus::DriverAssigned;
+
+    assert_eq!(policy.fee_for(&trip, trip.start_ts + 120), None);
+    assert_eq!(policy.fee_for(&trip, trip.start_ts + 121), Some(500));
+
+    trip.status = TripStatus::DriverArrived;
+    trip.fare_estimate = Some(300);
+    assert_eq!(policy.fee_for(&trip, trip.start_ts + 600), Some(300), "never more than the fare");
+
+    trip.status = TripStatus::InProgress;
+    assert_eq!(policy.fee_for(&trip, trip.start_ts + 600), None);
+    trip.status = TripStatus::DriverAssigned;
+    assert_eq!(CancellationPolicy { fee: 0, ..policy }.fee_for(&trip, trip.start_ts + 600), None);
+}
+
+
+// ─── TripStatus ──────────────────────────────────────────────────────────────
+
+#[test]
+fn trip_status_happy_path_transitions_are_allowed() {
+    let path = [
+        TripStatus::Requested,
+        TripStatus::DriverAssigned,
+        TripStatus::DriverArrived,
+        TripStatus::InProgress,
+        TripStatus::Completed,
+    ];
+    for pair in path.windows(2) {
+        assert!(pair[0].can_transition_to(pair[1]), "{} -> {}", pair[0], pair[1]);
+    }
+}
+
+#[test]
+fn trip_status_cannot_skip_steps() {
+    assert!(!TripStatus::DriverAssigned.can_transition_to(TripStatus::InProgress));
+    assert!(!TripStatus::DriverAssigned.can_transition_to(TripStatus::Completed));
+    assert!(!TripStatus::Requested.can_transition_to(TripStatus::Completed));
+}
+
+#[test]
+fn trip_status_cannot_go_backwards() {
+    assert!(!TripStatus::InProgress.can_transition_to(TripStatus::DriverArrived));
+    assert!(!TripStatus::Completed.can_transition_to(TripStatus::InProgress));
+    assert!(!TripStatus::Cancelled.can_transition_to(TripStatus::DriverAssigned));
+}
+
+#[test]
+fn trip_status_cancel_only_before_trip_starts() {
+    assert!(TripStatus::Requested.can_transition_to(TripStatus::Cancelled));
+    assert!(TripStatus::DriverAssigned.can_transition_to(TripStatus::Cancelled));
+    assert!(TripStatus::DriverArrived.can_transition_to(TripStatus::Cancelled));
+    assert!(!TripStatus::InProgress.can_transition_to(TripStatus::Cancelled));
+    assert!(!TripStatus::Completed.can_transition_to(TripStatus::Cancelled));
+}
+
+#[test]
+fn trip_status_disputes_can_be_resolved() {
+    assert!(TripStatus::Completed.can_transition_to(TripStatus::Disputed));
+    assert!(TripStatus::Disputed.can_transition_to(TripStatus::Completed));
+    assert!(TripStatus::Disputed.can_transition_to(TripStatus::Cancelled));
+}
+
+#[test]
+fn trip_status_no_self_transitions() {
+    for s in TripStatus::ALL {
+        assert!(!s.can_transition_to(s), "{} -> {}", s, s);
+    }
+}
+
+#[test]
+fn trip_status_transition_to_reports_both_ends() {
+    let err = TripStatus::Completed.transition_to(TripStatus::InProgress).unwrap_err();
+    assert_eq!(err.to_string(), "Trip cannot move from completed to in_progress");
+}
+
+#[test]
+fn trip_status_round_trips_through_its_name() {
+    for s in TripStatus::ALL {
+        assert_eq!(s.as_str().parse::<TripStatus>().unwrap(), s);
+        assert_eq!(serde_json::to_value(s).unwrap(), serde_json::json!(s.as_str()));
+    }
+    assert!("Ongoing".parse::<TripStatus>().is_err());
+}
+
+#[test]
+fn trip_status_active_set_is_the_in_flight_states() {
+    assert!(TripStatus::DriverAssigned.is_active());
+    assert!(TripStatus::InProgress.is_active());
+    assert!(!TripStatus::Requested.is_active());
+    assert!(!TripStatus::Completed.is_active());
+    assert!(!TripStatus::Cancelled.is_active());
 }
 
 
@@ -265,3 +1155,200 @@ fn vec_to_array_32_wrong_length_err() {
 fn vec_to_array_32_empty_err() {
     assert!(vec_to_array_32(vec![]).is_err());
 }
+
+
+// ─── Authenticator ───────────────────────────────────────────────────────────
+
+#[test]
+fn issued_tokens_verify_as_their_principal() {
+    let auth = Authenticator::new(b"unit-secret", 600);
+    let principal = Principal::new(Uuid::new_v4(), Role::Driver);
+    let now = chrono::Utc::now().timestamp();
+    let issued = auth.issue(principal, now).unwrap();
+
+    assert_eq!(issued.token_type, "Bearer");
+    assert_eq!(issued.expires_at, now + 600);
+    assert_eq!(auth.verify(&issued.access_token).unwrap(), principal);
+}
+
+#[test]
+fn expired_tokens_are_rejected() {
+    let auth = Authenticator::new(b"unit-secret", 60);
+    let principal = Principal::new(Uuid::new_v4(), Role::Rider);
+    let issued = auth.issue(principal, chrono::Utc::now().timestamp() - 120).unwrap();
+
+    assert!(matches!(auth.verify(&issued.access_token), Err(AuthError::InvalidToken(_))));
+}
+
+#[test]
+fn tokens_signed_with_another_secret_are_rejected() {
+    let ours = Authenticator::new(b"unit-secret", 600);
+    let theirs = Authenticator::new(b"someone-else", 600);
+    let issued = theirs.issue(Principal::new(Uuid::new_v4(), Role::Admin), chrono::Utc::now().timestamp()).unwrap();
+
+    assert!(matches!(ours.verify(&issued.access_token), Err(AuthError::InvalidToken(_))));
+    assert!(ours.verify("not-a-token").is_err());
+}
+
+#[test]
+fn principals_act_only_for_themselves_unless_admin() {
+    let id = Uuid::new_v4();
+    let rider = Principal::new(id, Role::Rider);
+    assert!(rider.acts_for_rider(id).is_ok());
+    assert!(matches!(rider.acts_for_rider(Uuid::new_v4()), Err(AuthError::Forbidden(_))));
+    // Same id, wrong role
+    assert!(rider.acts_for_driver(id).is_err());
+    assert!(rider.acts_for(TripParty::Rider, id).is_ok());
+
+    let admin = Principal::new(Uuid::new_v4(), Role::Admin);
+    assert!(admin.acts_for_rider(id).is_ok());
+    assert!(admin.acts_for_driver(Uuid::new_v4()).is_ok());
+}
+
+#[test]
+fn roles_round_trip_through_their_names() {
+    for role in Role::ALL {
+        assert_eq!(role.as_str().parse::<Role>().unwrap(), role);
+        assert_eq!(serde_json::to_value(role).unwrap(), role.as_str());
+    }
+    assert!("superuser".parse::<Role>().is_err());
+}
+
+#[test]
+fn support_acts_for_others_but_only_admins_manage_roles() {
+    let support = Principal::new(Uuid::new_v4(), Role::Support);
+    assert!(support.acts_for_rider(Uuid::new_v4()).is_ok());
+    assert!(support.require(Permission::ViewAccounts).is_ok());
+    assert!(matches!(support.require(Permission::ManageRoles), Err(AuthError::Forbidden(_))));
+    assert!(!support.can(Permission::Sockets));
+
+    let admin = Principal::new(Uuid::new_v4(), Role::Admin);
+    assert!(Permission::ALL.iter().all(|p| admin.can(*p)));
+
+    let rider = Principal::new(Uuid::new_v4(), Role::Rider);
+    assert!(!rider.can(Permission::ViewAccounts));
+}
+
+#[test]
+fn partners_book_rides_as_themselves() {
+    let id = Uuid::new_v4();
+    let partner = Principal::new(id, Role::Partner);
+    assert!(partner.acts_for_rider(id).is_ok());
+    assert!(partner.acts_for_rider(Uuid::new_v4()).is_err());
+    assert!(partner.acts_for_driver(id).is_err());
+    assert!(!partner.can(Permission::Sockets));
+}
+
+
+// ─── Rate limiter ────────────────────────────────────────────────────────────
+
+#[test]
+fn a_bucket_spends_its_burst_then_refills_at_the_rate() {
+    let limit = Limit { per_minute: 60, burst: 3 };
+    let start = Instant::now();
+    let mut bucket = Bucket::full(limit, start);
+
+    for _ in 0..3 {
+        assert!(bucket.take(limit, start).is_ok());
+    }
+    let wait = bucket.take(limit, start).unwrap_err();
+    assert!((wait.as_secs_f64() - 1.0).abs() < 1e-6);
+
+    // One a second comes back
+    assert!(bucket.take(limit, start + Duration::from_millis(500)).is_err());
+    assert!(bucket.take(limit, start + Duration::from_secs(1)).is_ok());
+
+    // Never more than the burst, however long it's left
+    let later = start + Duration::from_secs(600);
+    assert!(bucket.is_full(limit, later));
+    for _ in 0..3 {
+        assert!(bucket.take(limit, later).is_ok());
+    }
+    assert!(bucket.take(limit, later).is_err());
+}
+
+#[test]
+fn the_limiter_keeps_callers_and_scopes_apart() {
+    let mut limiter = RateLimiter::new();
+    limiter.set_limit("ride-request", Limit { per_minute: 6, burst: 1 });
+    limiter.set_limit("trips", Limit { per_minute: 60, burst: 1 });
+    let now = Instant::now();
+
+    assert!(limiter.check("ride-request", "user:a", now).is_ok());
+    assert!(limiter.check("ride-request", "user:a", now).is_err());
+    assert!(limiter.check("ride-request", "user:b", now).is_ok());
+    assert!(limiter.check("trips", "user:a", now).is_ok());
+
+    let stats = limiter.stats(now);
+    let ride_request = stats.iter().find(|s| s.scope == "ride-request").unwrap();
+    assert_eq!((ride_request.allowed, ride_request.limited, ride_request.tracked_keys), (2, 1, 2));
+
+    // Idle callers' buckets fill back up and are dropped, the counts stay
+    let stats = limiter.stats(now + Duration::from_secs(60));
+    let ride_request = stats.iter().find(|s| s.scope == "ride-request").unwrap();
+    assert_eq!((ride_request.allowed, ride_request.limited, ride_request.tracked_keys), (2, 1, 0));
+}
+
+#[test]
+fn scope_limits_come_from_the_environment() {
+    std::env::set_var("RATE_LIMIT_UNIT_SCOPE_PER_MIN", "30");
+    std::env::set_var("RATE_LIMIT_UNIT_SCOPE_BURST", "5");
+    assert_eq!(Limit::for_scope("unit-scope"), Limit { per_minute: 30, burst: 5 });
+    assert_eq!(Limit::for_scope("ride-preflight"), Limit { per_minute: 12, burst: 4 });
+}
+
+
+// ─── Quotes ──────────────────────────────────────────────────────────────────
+
+fn quote_to_airport(rider: Uuid, now: i64) -> FareQuote {
+    let airport = GeoPoint { lat: 6.5774, lng: 3.3212, name: None };
+    let route = HaversineRouter.straight_line(&PICKUP, &airport, &RideType::ASAP);
+    FareQuote::price(rider, RideType::ASAP, PICKUP, airport, &route, now, 300)
+}
+
+#[test]
+fn quotes_round_trip_until_they_expire() {
+    let signer = QuoteSigner::new(b"quote-secret", 300);
+    let quote = quote_to_airport(Uuid::new_v4(), 1_000);
+    let token = signer.sign(&quote).unwrap();
+
+    assert_eq!(signer.verify(&token, 1_000), Ok(quote.clone()));
+    assert_eq!(signer.verify(&token, 1_299).map(|q| q.estimated_price), Ok(quote.estimated_price));
+    assert_eq!(signer.verify(&token, 1_300), Err(QuoteError::Expired));
+}
+
+#[test]
+fn quotes_signed_elsewhere_or_edited_are_rejected() {
+    let quote = quote_to_airport(Uuid::new_v4(), 1_000);
+    let token = QuoteSigner::new(b"quote-secret", 300).sign(&quote).unwrap();
+    assert!(matches!(QuoteSigner::new(b"other-secret", 300).verify(&token, 1_000), Err(QuoteError::Invalid(_))));
+
+    // A cheaper price under the original signature
+    let cheaper = QuoteSigner::new(b"quote-secret", 300).sign(&FareQuote { estimated_price: 1, ..quote }).unwrap();
+    let original: Vec<&str> = token.split('.').collect();
+    let edited: Vec<&str> = cheaper.split('.').collect();
+    let forged = format!("{}.{}.{}", edited[0], edited[1], original[2]);
+    assert!(matches!(QuoteSigner::new(b"quote-secret", 300).verify(&forged, 1_000), Err(QuoteError::Invalid(_))));
+
+    // Access tokens aren't quotes, even under the same secret
+    let access = Authenticator::new(b"quote-secret", 3600).issue(Principal::new(Uuid::new_v4(), Role::Rider), 1_000).unwrap();
+    assert!(matches!(QuoteSigner::new(b"quote-secret", 300).verify(&access.access_token, 1_000), Err(QuoteError::Invalid(_))));
+}
+
+#[test]
+fn quotes_only_cover_the_ride_they_priced() {
+    let rider = Uuid::new_v4();
+    let quote = quote_to_airport(rider, 1_000);
+    let drop_off = quote.drop_off.clone();
+
+    assert_eq!(quote.covers(rider, &RideType::ASAP, &PICKUP, &drop_off), Ok(()));
+    // A few metres out is still the same ride
+    let nudged = GeoPoint { lat: PICKUP.lat + 0.0005, ..PICKUP };
+    assert!(PICKUP.distance_to(&nudged) < QUOTE_MATCH_KM);
+    assert_eq!(quote.covers(rider, &RideType::ASAP, &nudged, &drop_off), Ok(()));
+
+    let elsewhere = GeoPoint { lat: PICKUP.lat + 0.01, ..PICKUP };
+    assert!(matches!(quote.covers(rider, &RideType::ASAP, &elsewhere, &drop_off), Err(QuoteError::Mismatch(_))));
+    assert!(matches!(quote.covers(rider, &RideType::ASAPEXPRESS, &PICKUP, &drop_off), Err(QuoteError::Mismatch(_))));
+    assert!(matches!(quote.covers(Uuid::new_v4(), &RideType::ASAP, &PICKUP, &drop_off), Err(QuoteError::Mismatch(_))));
+}
@@ -0,0 +1 @@
+DROP TABLE back_payment_events;
@@ -0,0 +1,17 @@
+-- One row per Paystack webhook delivery, keyed by Paystack's transaction id
+-- and event name so retried deliveries collapse onto the same row.
+CREATE TABLE back_payment_events (
+    paystack_id BIGINT NOT NULL,
+    event TEXT NOT NULL,
+    reference TEXT NOT NULL,
+    status TEXT NOT NULL,             -- processing | processed | ignored | failed
+    outcome TEXT,
+    payload JSONB NOT NULL,
+    attempts INT NOT NULL DEFAULT 1,
+    received_at BIGINT NOT NULL,
+    last_attempt_at BIGINT NOT NULL,
+    processed_at BIGINT,
+    PRIMARY KEY (paystack_id, event)
+);
+
+CREATE INDEX back_payment_events_reference_idx ON back_payment_events (reference);
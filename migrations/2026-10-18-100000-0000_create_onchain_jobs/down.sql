@@ -0,0 +1,4 @@
+ALTER TABLE back_trips
+    DROP COLUMN onchain_signature;
+
+DROP TABLE back_onchain_jobs;
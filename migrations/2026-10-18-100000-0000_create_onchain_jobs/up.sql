@@ -0,0 +1,20 @@
+-- Outbox for Solana transactions that must eventually land, written in the
+-- same transaction as the payment that triggers them.
+CREATE TABLE back_onchain_jobs (
+    job_id UUID PRIMARY KEY,
+    kind TEXT NOT NULL,               -- record_ride
+    trip_reference TEXT NOT NULL,
+    status TEXT NOT NULL,             -- pending | submitting | confirmed | failed
+    attempts INT NOT NULL DEFAULT 0,
+    next_attempt_at BIGINT NOT NULL,
+    last_error TEXT,
+    signature TEXT,
+    created_at BIGINT NOT NULL,
+    updated_at BIGINT NOT NULL
+);
+
+CREATE UNIQUE INDEX back_onchain_jobs_kind_reference_idx ON back_onchain_jobs (kind, trip_reference);
+CREATE INDEX back_onchain_jobs_due_idx ON back_onchain_jobs (status, next_attempt_at);
+
+ALTER TABLE back_trips
+    ADD COLUMN onchain_signature TEXT;
@@ -0,0 +1,12 @@
+DROP INDEX back_trips_driver_status_idx;
+
+ALTER TABLE back_trips
+    DROP CONSTRAINT back_trips_status_check;
+
+UPDATE back_trips
+SET status = CASE status
+    WHEN 'completed' THEN 'Completed'
+    WHEN 'cancelled' THEN 'Cancelled'
+    WHEN 'disputed' THEN 'Disputed'
+    ELSE 'ongoing'
+END;
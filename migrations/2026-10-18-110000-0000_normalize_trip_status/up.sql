@@ -0,0 +1,32 @@
+-- Trips were written as 'ongoing', 'Ongoing' and 'Completed'. Fold every
+-- spelling into the TripStatus names and keep it that way.
+UPDATE back_trips
+SET status = CASE lower(replace(trim(status), ' ', '_'))
+    WHEN 'requested' THEN 'requested'
+    WHEN 'driver_assigned' THEN 'driver_assigned'
+    WHEN 'driverassigned' THEN 'driver_assigned'
+    WHEN 'driver_arrived' THEN 'driver_arrived'
+    WHEN 'driverarrived' THEN 'driver_arrived'
+    WHEN 'ongoing' THEN 'in_progress'
+    WHEN 'in_progress' THEN 'in_progress'
+    WHEN 'inprogress' THEN 'in_progress'
+    WHEN 'completed' THEN 'completed'
+    WHEN 'cancelled' THEN 'cancelled'
+    WHEN 'canceled' THEN 'cancelled'
+    WHEN 'disputed' THEN 'disputed'
+    -- Anything else needs a human to look at it
+    ELSE 'disputed'
+END;
+
+ALTER TABLE back_trips
+    ADD CONSTRAINT back_trips_status_check CHECK (status IN (
+        'requested',
+        'driver_assigned',
+        'driver_arrived',
+        'in_progress',
+        'completed',
+        'cancelled',
+        'disputed'
+    ));
+
+CREATE INDEX back_trips_driver_status_idx ON back_trips (driver_id, status);
@@ -0,0 +1,6 @@
+ALTER TABLE back_trips
+    DROP COLUMN cancelled_at,
+    DROP COLUMN cancel_reason,
+    DROP COLUMN cancelled_by,
+    DROP COLUMN picked_up_at,
+    DROP COLUMN arrived_at;
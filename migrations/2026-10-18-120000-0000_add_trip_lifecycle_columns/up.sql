@@ -0,0 +1,6 @@
+ALTER TABLE back_trips
+    ADD COLUMN arrived_at BIGINT,
+    ADD COLUMN picked_up_at BIGINT,
+    ADD COLUMN cancelled_by TEXT,           -- rider | driver
+    ADD COLUMN cancel_reason TEXT,          -- CancelReason code
+    ADD COLUMN cancelled_at BIGINT;
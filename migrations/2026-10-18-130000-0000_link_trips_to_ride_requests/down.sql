@@ -0,0 +1,2 @@
+DROP INDEX back_trips_request_id_key;
+ALTER TABLE back_trips DROP COLUMN request_id;
@@ -0,0 +1,6 @@
+-- A trip is created when a driver accepts a ride request. Trips created by
+-- hand before this keep a NULL request_id.
+ALTER TABLE back_trips
+    ADD COLUMN request_id UUID REFERENCES back_ride_request (request_id);
+
+CREATE UNIQUE INDEX back_trips_request_id_key ON back_trips (request_id);
@@ -0,0 +1,5 @@
+DROP INDEX back_drivers_reserved_until_idx;
+UPDATE back_drivers SET status = 'available' WHERE status = 'reserved';
+ALTER TABLE back_drivers
+    DROP COLUMN reserved_until,
+    DROP COLUMN reserved_for;
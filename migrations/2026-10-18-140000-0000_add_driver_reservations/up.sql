@@ -0,0 +1,10 @@
+-- A driver is offered one ride request at a time: dispatch moves them from
+-- 'available' to 'reserved' with a conditional UPDATE and hands them back
+-- on reject or timeout.
+ALTER TABLE back_drivers
+    ADD COLUMN reserved_for UUID,       -- back_ride_request.request_id
+    ADD COLUMN reserved_until BIGINT;   -- unix seconds
+
+CREATE INDEX back_drivers_reserved_until_idx
+    ON back_drivers (reserved_until)
+    WHERE status = 'reserved';
@@ -0,0 +1 @@
+DROP TABLE back_ride_offers;
@@ -0,0 +1,18 @@
+-- Every offer dispatch makes, so a restart mid-dispatch can be picked up
+-- again and a request's offer history can be looked up.
+CREATE TABLE back_ride_offers (
+    offer_id UUID PRIMARY KEY,
+    request_id UUID NOT NULL REFERENCES back_ride_request (request_id),
+    driver_id UUID NOT NULL,
+    round INT NOT NULL,
+    offered_at BIGINT NOT NULL,       -- unix seconds
+    expires_at BIGINT NOT NULL,
+    outcome TEXT NOT NULL,            -- pending | accepted | rejected | timed_out | withdrawn
+                                      -- | unreachable | unavailable | failed | expired
+    answered_at BIGINT
+);
+
+CREATE INDEX back_ride_offers_request_idx ON back_ride_offers (request_id, offered_at);
+CREATE INDEX back_ride_offers_pending_idx
+    ON back_ride_offers (expires_at)
+    WHERE outcome = 'pending';
@@ -0,0 +1,10 @@
+-- Ride requests are dispatched in the background, the rider polls this.
+ALTER TABLE back_ride_request
+    ADD COLUMN dispatch_status TEXT NOT NULL DEFAULT 'searching';  -- searching | assigned | no_drivers_found | cancelled
+
+-- Requests from before were answered in the same HTTP call
+UPDATE back_ride_request r
+SET dispatch_status = CASE
+    WHEN EXISTS (SELECT 1 FROM back_trips t WHERE t.request_id = r.request_id) THEN 'assigned'
+    ELSE 'no_drivers_found'
+END;
@@ -0,0 +1,6 @@
+ALTER TABLE back_trips
+    DROP COLUMN cancellation_fee;
+
+ALTER TABLE back_ride_request
+    DROP COLUMN cancel_reason,
+    DROP COLUMN cancelled_at;
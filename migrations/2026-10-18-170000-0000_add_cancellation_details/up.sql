@@ -0,0 +1,8 @@
+-- Why and when the rider called off a ride request, searching or assigned
+ALTER TABLE back_ride_request
+    ADD COLUMN cancel_reason TEXT,
+    ADD COLUMN cancelled_at BIGINT;
+
+-- Charged to a rider cancelling once the driver has been on the way a while
+ALTER TABLE back_trips
+    ADD COLUMN cancellation_fee BIGINT;
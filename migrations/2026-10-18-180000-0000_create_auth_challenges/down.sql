@@ -0,0 +1 @@
+DROP TABLE back_auth_challenges;
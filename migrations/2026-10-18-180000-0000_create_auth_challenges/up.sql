@@ -0,0 +1,13 @@
+-- Sign-in with a Solana wallet: the nonces handed out to sign, each good for
+-- one sign-in before it expires.
+CREATE TABLE back_auth_challenges (
+    nonce TEXT PRIMARY KEY,
+    subject UUID NOT NULL,            -- rider_id or driver_id
+    role TEXT NOT NULL,               -- rider | driver
+    pubkey TEXT NOT NULL,             -- the wallet the nonce must be signed with
+    issued_at BIGINT NOT NULL,        -- unix seconds
+    expires_at BIGINT NOT NULL,
+    used_at BIGINT
+);
+
+CREATE INDEX back_auth_challenges_expires_idx ON back_auth_challenges (expires_at);
@@ -0,0 +1,3 @@
+DROP TABLE back_role_audit;
+DROP TABLE back_user_roles;
+-- Postgres can't drop enum values, 'support' and 'partner' stay in custom_roles.
@@ -0,0 +1,25 @@
+ALTER TYPE custom_roles ADD VALUE IF NOT EXISTS 'support';
+ALTER TYPE custom_roles ADD VALUE IF NOT EXISTS 'partner';
+
+-- Roles granted on top of the rider or driver account someone has. A
+-- custom_users row's custom_role counts as granted too.
+CREATE TABLE back_user_roles (
+    user_id UUID NOT NULL,
+    role custom_roles NOT NULL,
+    granted_by UUID NOT NULL,
+    granted_at BIGINT NOT NULL,       -- unix seconds
+    PRIMARY KEY (user_id, role)
+);
+
+-- Every grant and revoke, who made it and why.
+CREATE TABLE back_role_audit (
+    audit_id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    role custom_roles NOT NULL,
+    action TEXT NOT NULL,             -- grant | revoke
+    actor_id UUID NOT NULL,
+    reason TEXT,
+    recorded_at BIGINT NOT NULL
+);
+
+CREATE INDEX back_role_audit_user_idx ON back_role_audit (user_id, recorded_at);
@@ -0,0 +1,3 @@
+ALTER TABLE back_ride_request DROP COLUMN tariff_id;
+DROP TABLE back_tariffs;
+DROP TABLE back_service_zones;
@@ -0,0 +1,39 @@
+-- Areas priced on their own. A pickup is in the smallest zone whose circle
+-- it falls in, anywhere else is the 'default' zone.
+CREATE TABLE back_service_zones (
+    zone TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    center_lat DOUBLE PRECISION NOT NULL,
+    center_lng DOUBLE PRECISION NOT NULL,
+    radius_km DOUBLE PRECISION NOT NULL,
+    updated_at BIGINT NOT NULL            -- unix seconds
+);
+
+-- Rates per ride type and zone. Rows are never changed: a new rate is a new
+-- row with a later effective_from, so the tariff behind any past quote can
+-- still be looked up.
+CREATE TABLE back_tariffs (
+    tariff_id UUID PRIMARY KEY,
+    ride_type TEXT NOT NULL,              -- ASAP | ASAPEXPRESS
+    zone TEXT NOT NULL,                   -- a back_service_zones zone, or 'default'
+    base_fare DOUBLE PRECISION NOT NULL,
+    per_km DOUBLE PRECISION NOT NULL,
+    per_min DOUBLE PRECISION NOT NULL,
+    minimum_fare BIGINT NOT NULL,
+    rounding TEXT NOT NULL,               -- nearest | up | down
+    round_to BIGINT NOT NULL,             -- to a multiple of this, in naira
+    effective_from BIGINT NOT NULL,       -- unix seconds
+    created_by UUID,
+    created_at BIGINT NOT NULL,
+    UNIQUE (ride_type, zone, effective_from)
+);
+
+-- The rates that were hardcoded in pricing.rs
+INSERT INTO back_tariffs
+    (tariff_id, ride_type, zone, base_fare, per_km, per_min, minimum_fare, rounding, round_to, effective_from, created_by, created_at)
+VALUES
+    ('00000000-0000-0000-0000-00000000a5a9', 'ASAP', 'default', 900, 25.76, 12.267, 900, 'nearest', 1, 0, NULL, 0),
+    ('00000000-0000-0000-0000-00000000e595', 'ASAPEXPRESS', 'default', 900, 25.76, 12.267, 900, 'nearest', 1, 0, NULL, 0);
+
+-- The tariff a request was priced with
+ALTER TABLE back_ride_request ADD COLUMN tariff_id UUID REFERENCES back_tariffs (tariff_id);
@@ -0,0 +1,3 @@
+ALTER TABLE back_ride_request DROP COLUMN surge_multiplier;
+DROP INDEX back_ride_request_requested_at_idx;
+ALTER TABLE back_ride_request DROP COLUMN requested_at;
@@ -0,0 +1,10 @@
+-- When each request was made, for the surge engine's demand count. Rows from
+-- before this have none, so they don't all look recent at once.
+ALTER TABLE back_ride_request ADD COLUMN requested_at BIGINT;
+ALTER TABLE back_ride_request ALTER COLUMN requested_at SET DEFAULT EXTRACT(EPOCH FROM now())::BIGINT;
+
+CREATE INDEX back_ride_request_requested_at_idx ON back_ride_request (requested_at);
+
+-- The multiplier estimated_price was surged by, with tariff_id that's
+-- everything needed to price the request again.
+ALTER TABLE back_ride_request ADD COLUMN surge_multiplier DOUBLE PRECISION NOT NULL DEFAULT 1;
@@ -0,0 +1,2 @@
+ALTER TABLE back_ride_request DROP COLUMN quote_token;
+ALTER TABLE back_ride_request DROP COLUMN quote_id;
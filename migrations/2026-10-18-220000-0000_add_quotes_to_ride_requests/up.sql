@@ -0,0 +1,4 @@
+-- The preflight quote a request was made with. Unique, so one quote is only
+-- ever good for one ride.
+ALTER TABLE back_ride_request ADD COLUMN quote_id UUID UNIQUE;
+ALTER TABLE back_ride_request ADD COLUMN quote_token TEXT;
@@ -0,0 +1,2 @@
+DROP TABLE back_traffic_factors;
+DROP TABLE back_driver_locations;
@@ -0,0 +1,23 @@
+-- Where drivers were, from their location updates. Kept for as long as the
+-- traffic fit looks back.
+CREATE TABLE back_driver_locations (
+    driver_id UUID NOT NULL,
+    recorded_at BIGINT NOT NULL,
+    lat DOUBLE PRECISION NOT NULL,
+    lng DOUBLE PRECISION NOT NULL,
+    PRIMARY KEY (driver_id, recorded_at)
+);
+
+CREATE INDEX back_driver_locations_recorded_at_idx ON back_driver_locations (recorded_at);
+
+-- What a ride type's average speed is multiplied by in a zone's traffic (above
+-- 1 is faster), by hour of the week (0 is Monday midnight, local time).
+-- Refitted from completed trips.
+CREATE TABLE back_traffic_factors (
+    zone TEXT NOT NULL,
+    hour_of_week INTEGER NOT NULL CHECK (hour_of_week BETWEEN 0 AND 167),
+    factor DOUBLE PRECISION NOT NULL,
+    samples INTEGER NOT NULL,
+    fitted_at BIGINT NOT NULL,
+    PRIMARY KEY (zone, hour_of_week)
+);
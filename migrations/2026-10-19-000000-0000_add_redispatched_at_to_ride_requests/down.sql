@@ -0,0 +1 @@
+ALTER TABLE back_ride_request DROP COLUMN redispatched_at;
@@ -0,0 +1,4 @@
+-- When offer recovery last started dispatch again for a request that had
+-- stopped searching without an offer out. Claiming a request sets it, so two
+-- instances recovering at once don't both dispatch it.
+ALTER TABLE back_ride_request ADD COLUMN redispatched_at BIGINT;